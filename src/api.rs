@@ -0,0 +1,561 @@
+use crate::brc20_index::{
+    event::{block_event_id, Brc20Event, Brc20EventType, EventFilter},
+    history,
+    store::{next_block_height, Brc20Store, Page},
+};
+use axum::{
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{
+        sse::{Event as SseEvent, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    routing::get,
+    Json, Router,
+};
+use futures_util::stream::{self, Stream, StreamExt};
+use log::{error, info};
+use mongodb::bson::Bson;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+const DEFAULT_LIMIT: u64 = 100;
+const MAX_LIMIT: u64 = 1000;
+// how often an event stream at the end of the log looks for new blocks
+const STREAM_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+type SharedStore = Arc<dyn Brc20Store>;
+
+/// Serves the index read-only over HTTP until the process is stopped.
+pub async fn serve(store: SharedStore, address: &str) -> anyhow::Result<()> {
+    let address: SocketAddr = address.parse()?;
+    info!("Serving the BRC20 API on {}", address);
+
+    axum::Server::bind(&address)
+        .serve(router(store).into_make_service())
+        .await?;
+
+    Ok(())
+}
+
+/// Routes of the API, every response is JSON.
+///
+/// Listings take `offset` and `limit` query parameters and answer with the
+/// page they were given and its `results`. Balances and holders take a
+/// `block_height` to answer as of that block instead of the latest one.
+///
+/// The event log is paged with an `after` cursor instead, the id of the last
+/// event of the previous page, answered as `next`. `/events/stream` sends the
+/// events of each completed block as Server-Sent Events, see `stream_events`.
+pub fn router(store: SharedStore) -> Router {
+    Router::new()
+        .route("/status", get(get_status))
+        .route("/blocks/:block_height", get(get_block))
+        .route("/tickers/:tick", get(get_ticker))
+        .route("/tickers/:tick/holders", get(get_holders))
+        .route("/addresses/:address/balances", get(get_balances))
+        .route("/addresses/:address/entries", get(get_entries))
+        .route("/transfers/:tx_id", get(get_transfers))
+        .route("/events", get(get_events))
+        .route("/events/stream", get(stream_events))
+        .with_state(store)
+}
+
+#[derive(Debug, Deserialize)]
+struct Pagination {
+    offset: Option<u64>,
+    limit: Option<u64>,
+}
+
+impl Pagination {
+    fn page(&self) -> Page {
+        Page {
+            offset: self.offset.unwrap_or(0),
+            limit: self.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct AtBlock {
+    block_height: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct EntryFilter {
+    tick: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct EventCursor {
+    after: Option<String>,
+    limit: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct StreamQuery {
+    after: Option<String>,
+    // comma-separated lists, see `EventFilter`
+    ticks: Option<String>,
+    addresses: Option<String>,
+    event_types: Option<String>,
+}
+
+#[derive(Debug)]
+struct ApiError(StatusCode, String);
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, Json(json!({ "error": self.1 }))).into_response()
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(e: anyhow::Error) -> Self {
+        if let Some(not_completed) = e.downcast_ref::<history::BlockNotCompleted>() {
+            return ApiError(StatusCode::NOT_FOUND, not_completed.to_string());
+        }
+        error!("API request failed: {:?}", e);
+        ApiError(StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+    }
+}
+
+type ApiResult = Result<Json<Value>, ApiError>;
+
+fn page_response<T: serde::Serialize>(page: Page, results: Vec<T>) -> ApiResult {
+    Ok(Json(json!({
+        "offset": page.offset,
+        "limit": page.limit,
+        "results": results,
+    })))
+}
+
+async fn get_status(State(store): State<SharedStore>) -> ApiResult {
+    let block_height = store.get_last_completed_block_height().await?;
+    let (block_hash, state_hash) = match block_height {
+        Some(block_height) => (
+            store.get_completed_block_hash(block_height).await?,
+            store.get_state_hash(block_height).await?,
+        ),
+        None => (None, None),
+    };
+
+    Ok(Json(json!({
+        "last_completed_block_height": block_height,
+        "last_completed_block_hash": block_hash,
+        "last_completed_state_hash": state_hash,
+    })))
+}
+
+async fn get_block(State(store): State<SharedStore>, Path(block_height): Path<i64>) -> ApiResult {
+    match store.get_completed_block_hash(block_height).await? {
+        Some(block_hash) => Ok(Json(json!({
+            "block_height": block_height,
+            "block_hash": block_hash,
+            "state_hash": store.get_state_hash(block_height).await?,
+        }))),
+        None => Err(ApiError(
+            StatusCode::NOT_FOUND,
+            format!("Block not completed: {}", block_height),
+        )),
+    }
+}
+
+async fn get_ticker(State(store): State<SharedStore>, Path(tick): Path<String>) -> ApiResult {
+    match store.get_ticker(&tick.to_lowercase()).await? {
+        Some(ticker) => Ok(Json(json!(ticker))),
+        None => Err(ApiError(
+            StatusCode::NOT_FOUND,
+            format!("Ticker not found: {}", tick),
+        )),
+    }
+}
+
+async fn get_holders(
+    State(store): State<SharedStore>,
+    Path(tick): Path<String>,
+    Query(at_block): Query<AtBlock>,
+    Query(pagination): Query<Pagination>,
+) -> ApiResult {
+    let page = pagination.page();
+    let tick = tick.to_lowercase();
+    let holders = match at_block.block_height {
+        Some(block_height) => history::get_holders_at(store.as_ref(), &tick, block_height)
+            .await?
+            .into_iter()
+            .skip(page.offset as usize)
+            .take(page.limit as usize)
+            .collect(),
+        None => store.get_holders(&tick, page).await?,
+    };
+
+    page_response(page, holders)
+}
+
+async fn get_balances(
+    State(store): State<SharedStore>,
+    Path(address): Path<String>,
+    Query(at_block): Query<AtBlock>,
+) -> ApiResult {
+    let user_balances = match at_block.block_height {
+        Some(block_height) => {
+            history::get_user_balances_at(store.as_ref(), &address, block_height).await?
+        }
+        None => store.get_user_balances(&address).await?,
+    };
+
+    Ok(Json(json!({ "results": user_balances })))
+}
+
+async fn get_entries(
+    State(store): State<SharedStore>,
+    Path(address): Path<String>,
+    Query(filter): Query<EntryFilter>,
+    Query(pagination): Query<Pagination>,
+) -> ApiResult {
+    let page = pagination.page();
+    let tick = filter.tick.map(|tick| tick.to_lowercase());
+    let entries = store
+        .get_user_balance_entries(&address, tick.as_deref(), page)
+        .await?;
+
+    page_response(page, entries)
+}
+
+async fn get_transfers(State(store): State<SharedStore>, Path(tx_id): Path<String>) -> ApiResult {
+    let transfers: Vec<Value> = store
+        .get_transfers_by_tx_id(&tx_id)
+        .await?
+        .into_iter()
+        .map(|mut transfer| {
+            transfer.remove("_id");
+            Bson::Document(transfer).into_relaxed_extjson()
+        })
+        .collect();
+
+    Ok(Json(json!({ "results": transfers })))
+}
+
+async fn get_events(
+    State(store): State<SharedStore>,
+    Query(cursor): Query<EventCursor>,
+) -> ApiResult {
+    let limit = cursor.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+    // only the events of completed blocks, a block being indexed may still be
+    // rolled back on restart
+    let next_event_id = block_event_id(next_block_height(store.as_ref()).await?);
+    let events: Vec<Brc20Event> = store
+        .get_events(cursor.after.as_deref(), limit)
+        .await?
+        .into_iter()
+        .take_while(|event| event.id < next_event_id)
+        .collect();
+    // the cursor stays put at the end of the log, to poll for new events
+    let next = events
+        .last()
+        .map(|event| event.id.clone())
+        .or(cursor.after.clone());
+
+    Ok(Json(json!({
+        "after": cursor.after,
+        "limit": limit,
+        "results": events,
+        "next": next,
+    })))
+}
+
+/// Streams the events matching `ticks`, `addresses` and `event_types` as each
+/// block is completed, one Server-Sent Event per event with the event id as its
+/// id and the event as JSON data.
+///
+/// The stream starts after the event in the `Last-Event-ID` header, which a
+/// reconnecting `EventSource` sends, or in `after`, and at the next block
+/// without either.
+async fn stream_events(
+    State(store): State<SharedStore>,
+    headers: HeaderMap,
+    Query(query): Query<StreamQuery>,
+) -> Result<Sse<impl Stream<Item = Result<SseEvent, axum::Error>>>, ApiError> {
+    let event_types = split_list(&query.event_types)
+        .iter()
+        .map(|event_type| event_type.parse::<Brc20EventType>())
+        .collect::<Result<Vec<_>, String>>()
+        .map_err(|e| ApiError(StatusCode::BAD_REQUEST, e))?;
+    let filter = EventFilter {
+        ticks: split_list(&query.ticks),
+        addresses: split_list(&query.addresses),
+        event_types,
+    };
+
+    let last_event_id = headers
+        .get("last-event-id")
+        .and_then(|id| id.to_str().ok())
+        .map(str::to_string);
+    let after = match last_event_id.or(query.after) {
+        Some(after) => after,
+        None => block_event_id(next_block_height(store.as_ref()).await?),
+    };
+    info!("Streaming events after {:?} matching {:?}", after, filter);
+
+    let events = event_stream(store, after, filter)
+        .map(|event| SseEvent::default().id(&event.id).json_data(&event));
+
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+fn split_list(list: &Option<String>) -> Vec<String> {
+    list.iter()
+        .flat_map(|list| list.split(','))
+        .map(|item| item.trim().to_string())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+/// Streams the events after `after` matching `filter`, only the events of
+/// completed blocks, and waits for new blocks at the end of the log.
+///
+/// When a rollback takes the index below the stream's cursor, the stream goes on
+/// from the first block the index doesn't have, sending the events of the blocks
+/// indexed again.
+fn event_stream(
+    store: SharedStore,
+    after: String,
+    filter: EventFilter,
+) -> impl Stream<Item = Brc20Event> {
+    let state = (store, after, filter, VecDeque::new());
+    stream::unfold(
+        state,
+        |(store, mut cursor, filter, mut pending)| async move {
+            loop {
+                if let Some(event) = pending.pop_front() {
+                    return Some((event, (store, cursor, filter, pending)));
+                }
+
+                match next_events(store.as_ref(), &mut cursor).await {
+                    Ok(events) if !events.is_empty() => {
+                        pending.extend(events.into_iter().filter(|event| filter.matches(event)))
+                    }
+                    Ok(_) => tokio::time::sleep(STREAM_POLL_INTERVAL).await,
+                    Err(e) => {
+                        error!("Failed to read the event log: {:?}", e);
+                        tokio::time::sleep(STREAM_POLL_INTERVAL).await;
+                    }
+                }
+            }
+        },
+    )
+}
+
+// reads the events of completed blocks after the cursor and moves it past them
+async fn next_events(
+    store: &dyn Brc20Store,
+    cursor: &mut String,
+) -> anyhow::Result<Vec<Brc20Event>> {
+    let next_event_id = block_event_id(next_block_height(store).await?);
+    if *cursor > next_event_id {
+        *cursor = next_event_id.clone();
+    }
+
+    let events: Vec<Brc20Event> = store
+        .get_events(Some(cursor.as_str()), DEFAULT_LIMIT)
+        .await?
+        .into_iter()
+        .take_while(|event| event.id < next_event_id)
+        .collect();
+    if let Some(event) = events.last() {
+        *cursor = event.id.clone();
+    }
+
+    Ok(events)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brc20_index::consts;
+    use crate::brc20_index::memory::MemoryStore;
+    use crate::brc20_index::testing::{index_chain, transfer_chain};
+
+    async fn indexed_store() -> (SharedStore, String, String) {
+        let (chain, alice, bob) = transfer_chain();
+        let store: SharedStore = Arc::new(MemoryStore::new());
+        index_chain(&chain, store.as_ref()).await;
+
+        (store, alice, bob)
+    }
+
+    fn pagination(offset: u64, limit: u64) -> Query<Pagination> {
+        Query(Pagination {
+            offset: Some(offset),
+            limit: Some(limit),
+        })
+    }
+
+    #[tokio::test]
+    async fn test_ticker_and_status() {
+        let (store, _, _) = indexed_store().await;
+
+        let Json(ticker) = get_ticker(State(store.clone()), Path("ORDI".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(ticker["tick"], "ordi");
+        assert_eq!(ticker["total_minted"], "1000");
+
+        let missing = get_ticker(State(store.clone()), Path("sats".to_string())).await;
+        assert_eq!(missing.unwrap_err().0, StatusCode::NOT_FOUND);
+
+        let Json(status) = get_status(State(store.clone())).await.unwrap();
+        // the chain deploys and mints, inscribes the transfer and sends it in three blocks
+        let tip_height = consts::BRC20_STARTING_BLOCK_HEIGHT + 2;
+        assert_eq!(status["last_completed_block_height"], json!(tip_height));
+
+        let Json(block) = get_block(State(store.clone()), Path(tip_height))
+            .await
+            .unwrap();
+        assert_eq!(block["state_hash"], status["last_completed_state_hash"]);
+        let missing = get_block(State(store), Path(tip_height + 1)).await;
+        assert_eq!(missing.unwrap_err().0, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_balances_holders_and_entries() {
+        let (store, alice, bob) = indexed_store().await;
+
+        let Json(balances) = get_balances(
+            State(store.clone()),
+            Path(alice.clone()),
+            Query(AtBlock { block_height: None }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(balances["results"][0]["overall_balance"], "600");
+        assert_eq!(balances["results"][0]["transferable_balance"], "0");
+
+        // before the send alice still held the transferable amount
+        let Json(balances) = get_balances(
+            State(store.clone()),
+            Path(alice.clone()),
+            Query(AtBlock {
+                block_height: Some(consts::BRC20_STARTING_BLOCK_HEIGHT + 1),
+            }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(balances["results"][0]["overall_balance"], "1000");
+        assert_eq!(balances["results"][0]["transferable_balance"], "400");
+
+        // the chain's tip is the third block
+        let missing = get_balances(
+            State(store.clone()),
+            Path(alice.clone()),
+            Query(AtBlock {
+                block_height: Some(consts::BRC20_STARTING_BLOCK_HEIGHT + 3),
+            }),
+        )
+        .await;
+        assert_eq!(missing.unwrap_err().0, StatusCode::NOT_FOUND);
+
+        let Json(holders) = get_holders(
+            State(store.clone()),
+            Path("ordi".to_string()),
+            Query(AtBlock { block_height: None }),
+            pagination(1, 10),
+        )
+        .await
+        .unwrap();
+        let expected = if alice < bob { &bob } else { &alice };
+        assert_eq!(holders["results"].as_array().unwrap().len(), 1);
+        assert_eq!(holders["results"][0]["address"], json!(expected));
+
+        let Json(entries) = get_entries(
+            State(store),
+            Path(bob),
+            Query(EntryFilter {
+                tick: Some("ORDI".to_string()),
+            }),
+            pagination(0, 10),
+        )
+        .await
+        .unwrap();
+        assert_eq!(entries["results"][0]["amt"], "400");
+    }
+
+    #[tokio::test]
+    async fn test_events_are_paged_with_a_cursor() {
+        let (store, alice, bob) = indexed_store().await;
+
+        let Json(page) = get_events(
+            State(store.clone()),
+            Query(EventCursor {
+                after: None,
+                limit: Some(3),
+            }),
+        )
+        .await
+        .unwrap();
+        let events = page["results"].as_array().unwrap();
+        let event_types: Vec<&Value> = events.iter().map(|event| &event["event_type"]).collect();
+        assert_eq!(event_types, vec!["deploy", "mint", "inscribe_transfer"]);
+        assert_eq!(page["next"], events[2]["id"]);
+
+        let Json(page) = get_events(
+            State(store.clone()),
+            Query(EventCursor {
+                after: page["next"].as_str().map(str::to_string),
+                limit: None,
+            }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(page["results"].as_array().unwrap().len(), 1);
+        let send = &page["results"][0];
+        assert_eq!(send["event_type"], "transfer_send");
+        assert_eq!(send["amount"], "400");
+        assert_eq!(send["from"], json!(alice));
+        assert_eq!(send["to"], json!(bob));
+
+        // past the end the cursor stays on the last event
+        let Json(end) = get_events(
+            State(store),
+            Query(EventCursor {
+                after: page["next"].as_str().map(str::to_string),
+                limit: None,
+            }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(end["results"], json!([]));
+        assert_eq!(end["next"], page["next"]);
+    }
+
+    #[tokio::test]
+    async fn test_event_stream_filters_and_resumes() {
+        let (store, _, bob) = indexed_store().await;
+        let events = store.get_events(None, 10).await.unwrap();
+
+        // bob only takes part in the send
+        let filter = EventFilter {
+            addresses: vec![bob],
+            ..Default::default()
+        };
+        let mut stream = Box::pin(event_stream(store.clone(), String::new(), filter));
+        assert_eq!(stream.next().await, Some(events[3].clone()));
+
+        // a client reconnecting after the mint
+        let mut stream = Box::pin(event_stream(
+            store,
+            events[1].id.clone(),
+            EventFilter::default(),
+        ));
+        assert_eq!(stream.next().await, Some(events[2].clone()));
+        assert_eq!(stream.next().await, Some(events[3].clone()));
+
+        // nothing past the last completed block yet
+        let next = tokio::time::timeout(Duration::from_millis(50), stream.next()).await;
+        assert!(next.is_err());
+    }
+}
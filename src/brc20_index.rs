@@ -1,711 +1,706 @@
-use crate::brc20_index::utils::{
-    update_receiver_balance_document, update_sender_user_balance_document,
-};
-
 use self::{
+    amount::{get_amount, Amount},
     deploy::handle_deploy_operation,
+    event::{Brc20Event, Brc20EventType},
+    fee_spend::{CoinbaseSats, FeeSpendPolicy},
     mint::handle_mint_operation,
-    mongo::MongoClient,
+    satpoint::{locate_offset, SatPoint},
+    store::{BlockUpdate, Brc20Store},
     transfer::{handle_transfer_operation, Brc20ActiveTransfer},
-    user_balance::UserBalanceEntryType,
-    utils::{extract_and_process_witness_data, get_owner_of_vout, get_witness_data_from_raw_tx},
+    user_balance::{UserBalanceEntry, UserBalanceEntryType},
+    utils::{
+        extract_brc20_inscription, get_inscriptions_from_raw_tx, get_owner_of_vout,
+        update_receiver_balance, update_sender_user_balance,
+    },
 };
+use bitcoin::{Block, BlockHash};
 use bitcoincore_rpc::bitcoincore_rpc_json::{
     GetRawTransactionResult, GetRawTransactionResultVin, GetRawTransactionResultVout,
     GetRawTransactionResultVoutScriptPubKey,
 };
-use bitcoincore_rpc::{self, Client, RpcApi};
-use log::{debug, error, info, warn};
-use mongodb::{
-    bson::{doc, Document},
-    options::UpdateOptions,
-};
+use bitcoincore_rpc::{self, RpcApi};
+use log::{error, info, warn};
+use mongodb::bson::{doc, Document};
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
     thread::sleep,
     time::{Duration, Instant},
 };
 
+pub mod amount;
 mod brc20_ticker;
 pub mod consts;
 mod deploy;
+pub mod event;
+pub mod fee_spend;
+pub mod history;
+pub mod inscription;
 mod invalid_brc20;
+pub mod invariants;
+pub mod memory;
 mod mint;
 pub mod mongo;
+pub mod postgres;
+pub mod rebuild;
+pub mod reorg;
+mod satpoint;
+pub mod sled_store;
+pub mod snapshot;
+pub mod sqlite;
+pub mod state_hash;
+pub mod store;
+#[cfg(test)]
+pub mod testing;
 mod transfer;
 mod user_balance;
 mod utils;
+pub mod verify;
+pub mod webhook;
 
 pub async fn index_brc20(
-    rpc: &Client,
-    mongo_client: &MongoClient,
+    rpc: &impl RpcApi,
+    store: &dyn Brc20Store,
     start_block_height: u32,
+    fee_spend_policy: FeeSpendPolicy,
+    check_invariants: bool,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let mut current_block_height = start_block_height;
 
     loop {
         match rpc.get_block_hash(current_block_height.into()) {
-            Ok(current_block_hash) => {
-                match rpc.get_block(&current_block_hash) {
-                    Ok(block) => {
-                        let length = block.txdata.len();
-                        info!(
-                            "Fetched block: {:?}, Transactions: {:?}, Block: {:?}",
-                            current_block_hash, length, current_block_height
-                        );
-
-                        let mut active_transfers_opt =
-                            mongo_client.load_active_transfers_with_retry().await?;
-
-                        // If active_transfers_opt is None, initialize it with a new HashMap
-                        if active_transfers_opt.is_none() {
-                            active_transfers_opt = Some(HashMap::new());
-                        }
-
-                        // Vectors for mongo bulk writes
-                        let mut mint_documents = Vec::new();
-                        let mut transfer_documents = Vec::new();
-                        let mut deploy_documents = Vec::new();
-                        let mut invalid_brc20_documents = Vec::new();
-                        let mut user_balance_entry_documents = Vec::new();
-                        let mut tickers: HashMap<String, Document> = HashMap::new();
-                        let mut user_balance_docs_to_update: HashMap<(String, String), Document> =
-                            HashMap::new();
-                        let mut user_balance_docs_to_insert: HashMap<(String, String), Document> =
-                            HashMap::new();
-
-                        // time to process the block
-                        let process_block_start_time = Instant::now();
-
-                        let mut tx_height = 0u32;
-                        for transaction in block.txdata {
-                            let txid = transaction.txid();
-                            // Get Raw Transaction Info
-                            let raw_tx = match rpc.get_raw_transaction_info(&txid, None) {
-                                Ok(tx) => tx,
-                                Err(e) => {
-                                    error!("Failed to get raw transaction info: {:?}", e);
-                                    continue; // This will skip the current iteration of the loop
-                                }
-                            };
-
-                            // Get witness data from raw transaction
-                            let witness_data = match get_witness_data_from_raw_tx(&raw_tx) {
-                                Ok(data) => data,
-                                Err(e) => {
-                                    error!("Failed to get witness data: {:?}", e);
-                                    continue;
-                                }
-                            };
-
-                            let mut inscription_found = false;
-                            for witness in witness_data {
-                                if let Some(inscription) = extract_and_process_witness_data(witness)
-                                {
-                                    // log raw brc20 data
-                                    let pretty_json =
-                                        serde_json::to_string(&inscription).unwrap_or_default();
-                                    info!("Raw Brc-20 data: {}", pretty_json);
-
-                                    // get owner address, inscription is first satoshi of first output
-                                    let owner = match get_owner_of_vout(&raw_tx, 0) {
-                                        Ok(owner) => owner,
-                                        Err(e) => {
-                                            error!("Failed to get owner: {:?}", e);
-                                            continue;
-                                        }
-                                    };
-
-                                    match &inscription.op[..] {
-                                        "deploy" => {
-                                            match handle_deploy_operation(
-                                                mongo_client,
-                                                inscription,
-                                                &raw_tx,
-                                                owner,
-                                                current_block_height,
-                                                tx_height,
-                                                &mut invalid_brc20_documents,
-                                            )
-                                            .await
-                                            {
-                                                Ok(deploy) => {
-                                                    inscription_found = deploy.is_valid();
-                                                    if inscription_found {
-                                                        deploy_documents.push(deploy.to_document());
-                                                    }
-                                                }
-                                                Err(e) => {
-                                                    error!(
-                                                        "Error handling deploy operation: {:?}",
-                                                        e
-                                                    );
-                                                }
-                                            };
-                                        }
-                                        "mint" => {
-                                            match handle_mint_operation(
-                                                mongo_client,
-                                                current_block_height,
-                                                tx_height,
-                                                owner,
-                                                inscription,
-                                                &raw_tx,
-                                                &mut tickers,
-                                                &mut invalid_brc20_documents,
-                                            )
-                                            .await
-                                            {
-                                                Ok((mint, user_balance_entry)) => {
-                                                    inscription_found = mint.is_valid();
-                                                    if inscription_found {
-                                                        mint_documents.push(mint.to_document());
-                                                        user_balance_entry_documents
-                                                            .push(user_balance_entry.to_document());
-
-                                                        // Update user balance docs
-                                                        match update_receiver_balance_document(
-                                                            mongo_client,
-                                                            &mut user_balance_docs_to_update,
-                                                            &mut user_balance_docs_to_insert,
-                                                            &user_balance_entry,
-                                                        )
-                                                        .await
-                                                        {
-                                                            Ok(_) => {}
-                                                            Err(e) => {
-                                                                error!(
-                                                                    "Error updating user balance docs: {:?}",
-                                                                    e
-                                                                );
-                                                            }
-                                                        }
-                                                    }
-                                                }
-                                                Err(e) => {
-                                                    error!(
-                                                        "Error handling mint operation: {:?}",
-                                                        e
-                                                    );
-                                                }
-                                            };
-                                        }
-                                        "transfer" => {
-                                            match handle_transfer_operation(
-                                                mongo_client,
-                                                current_block_height,
-                                                tx_height,
-                                                inscription,
-                                                &raw_tx,
-                                                owner,
-                                                &mut active_transfers_opt,
-                                                &mut user_balance_docs_to_update,
-                                                &mut user_balance_docs_to_insert,
-                                                &mut invalid_brc20_documents,
-                                            )
-                                            .await
-                                            {
-                                                Ok((transfer, user_balance_entry)) => {
-                                                    inscription_found = transfer.is_valid();
-                                                    if inscription_found {
-                                                        transfer_documents
-                                                            .push(transfer.to_document());
-
-                                                        user_balance_entry_documents
-                                                            .push(user_balance_entry.to_document());
-                                                    }
-                                                }
-                                                Err(e) => {
-                                                    error!(
-                                                        "Error handling transfer inscription: {:?}",
-                                                        e
-                                                    );
-                                                }
-                                            };
-                                        }
-                                        _ => {
-                                            // Unexpected operation
-                                            error!("Unexpected operation: {}", inscription.op);
-                                        }
-                                    }
-                                }
-                            }
+            Ok(current_block_hash) => match rpc.get_block(&current_block_hash) {
+                Ok(block) => {
+                    current_block_height = index_block(
+                        rpc,
+                        store,
+                        current_block_height,
+                        &current_block_hash,
+                        &block,
+                        fee_spend_policy,
+                        check_invariants,
+                    )
+                    .await?;
+                }
+                Err(e) => {
+                    error!("Failed to fetch block: {:?}, retrying...", e);
+                    sleep(Duration::from_secs(60));
+                }
+            },
+            Err(e) => {
+                error!("Failed to fetch block hash for height: {:?}, retrying", e);
+                sleep(Duration::from_secs(60));
+            }
+        }
+    }
+}
+
+/// Indexes a block fetched from the node and returns the height to index next.
+///
+/// When the block doesn't build on the last indexed block, everything after the
+/// common ancestor is rolled back instead and indexing resumes right after it.
+/// With `check_invariants`, a block breaking an invariant fails before anything
+/// of it is written.
+pub async fn index_block(
+    rpc: &impl RpcApi,
+    store: &dyn Brc20Store,
+    block_height: u32,
+    block_hash: &BlockHash,
+    block: &Block,
+    fee_spend_policy: FeeSpendPolicy,
+    check_invariants: bool,
+) -> Result<u32, Box<dyn std::error::Error>> {
+    let length = block.txdata.len();
+    info!(
+        "Fetched block: {:?}, Transactions: {:?}, Block: {:?}",
+        block_hash, length, block_height
+    );
+
+    // make sure this block builds on the last block we indexed
+    if let Some(fork_height) = reorg::detect_reorg(rpc, store, block_height.into(), block).await? {
+        warn!("Rolling back to common ancestor at block {}", fork_height);
+        reorg::rollback_to_block_height(store, fork_height + 1).await?;
+        return Ok((fork_height + 1).try_into()?);
+    }
+
+    // everything indexed in this block, written to the store at the end
+    let mut block_update = BlockUpdate {
+        block_height: block_height.into(),
+        block_hash: block_hash.to_string(),
+        previous_block_hash: block.header.prev_blockhash.to_string(),
+        active_transfers: store.load_active_transfers().await?,
+        ..Default::default()
+    };
+
+    // time to process the block
+    let process_block_start_time = Instant::now();
 
-                            // if no inscription found, check for transfer send
-                            if !inscription_found {
-                                if active_transfers_opt.is_none() {
-                                    active_transfers_opt = Some(HashMap::new());
+    // sats spent as fee land in the coinbase outputs
+    let mut coinbase_sats = CoinbaseSats::new(block, block_height.into());
+
+    for (tx_index, transaction) in block.txdata.iter().enumerate() {
+        let tx_height: u32 = tx_index.try_into()?;
+        let txid = transaction.txid();
+        // Get Raw Transaction Info
+        let raw_tx = match rpc.get_raw_transaction_info(&txid, None) {
+            Ok(tx) => tx,
+            Err(e) => {
+                error!("Failed to get raw transaction info: {:?}", e);
+                continue; // This will skip the current iteration of the loop
+            }
+        };
+
+        // inputs are spent before anything is inscribed, move the
+        // transfer inscriptions this transaction spends first
+        match check_for_transfer_send(
+            store,
+            rpc,
+            &raw_tx,
+            block_height.into(),
+            tx_height.into(),
+            fee_spend_policy,
+            &mut coinbase_sats,
+            &mut block_update,
+        )
+        .await
+        {
+            Ok(_) => (),
+            Err(e) => {
+                error!("Error checking for transfer send: {:?}", e);
+            }
+        };
+
+        // Get inscriptions revealed in all inputs of the raw transaction
+        let inscriptions = match get_inscriptions_from_raw_tx(rpc, &raw_tx) {
+            Ok(inscriptions) => inscriptions,
+            Err(e) => {
+                error!("Failed to get inscriptions: {:?}", e);
+                continue;
+            }
+        };
+
+        for revealed in inscriptions {
+            if let Some(inscription) = extract_brc20_inscription(&revealed.inscription) {
+                // log raw brc20 data
+                let pretty_json = serde_json::to_string(&inscription).unwrap_or_default();
+                info!("Raw Brc-20 data: {} ({})", pretty_json, revealed.id);
+
+                // get owner address, the output holding the inscribed sat
+                let (vout, offset) = match revealed.location {
+                    Some(location) => location,
+                    None => {
+                        warn!("Inscription {} revealed as fee", revealed.id);
+                        continue;
+                    }
+                };
+                let owner = match get_owner_of_vout(&raw_tx, vout) {
+                    Ok(owner) => owner,
+                    Err(e) => {
+                        error!("Failed to get owner: {:?}", e);
+                        continue;
+                    }
+                };
+
+                // logged once the operation is handled, as invalid when it was rejected
+                let mut event = Brc20Event {
+                    output_index: Some(vout.try_into()?),
+                    ..Brc20Event::inscribed(
+                        Brc20EventType::Invalid,
+                        block_height.into(),
+                        tx_height.into(),
+                        &txid.to_string(),
+                        revealed.index.try_into()?,
+                        &revealed.id,
+                        &inscription.tick,
+                    )
+                };
+                let owner_address = owner.to_string();
+                let invalids = block_update.invalids.len();
+
+                match &inscription.op[..] {
+                    "deploy" => {
+                        match handle_deploy_operation(
+                            store,
+                            revealed.id,
+                            inscription,
+                            &raw_tx,
+                            owner,
+                            block_height,
+                            tx_height,
+                            &mut block_update.tickers,
+                            &mut block_update.invalids,
+                        )
+                        .await
+                        {
+                            Ok(deploy) => {
+                                if deploy.is_valid() {
+                                    block_update.deploys.push(deploy.to_document());
+                                    event.event_type = Brc20EventType::Deploy;
+                                    event.amount = Some(deploy.get_max_supply());
+                                    event.to = Some(owner_address);
                                 }
-                                if let Some(ref mut active_transfers) = &mut active_transfers_opt {
-                                    match check_for_transfer_send(
-                                        mongo_client,
-                                        &rpc,
-                                        &raw_tx,
-                                        current_block_height.into(),
-                                        tx_height.into(),
-                                        active_transfers,
-                                        &mut transfer_documents,
-                                        &mut user_balance_entry_documents,
-                                        &mut user_balance_docs_to_update,
-                                        &mut user_balance_docs_to_insert,
+                            }
+                            Err(e) => {
+                                error!("Error handling deploy operation: {:?}", e);
+                            }
+                        };
+                    }
+                    "mint" => {
+                        match handle_mint_operation(
+                            store,
+                            block_height,
+                            tx_height,
+                            owner,
+                            revealed.id,
+                            inscription,
+                            &raw_tx,
+                            &mut block_update.tickers,
+                            &mut block_update.invalids,
+                        )
+                        .await
+                        {
+                            Ok((mint, user_balance_entry)) => {
+                                if mint.is_valid() {
+                                    block_update.mints.push(mint.to_document());
+
+                                    // Update user balances
+                                    match update_receiver_balance(
+                                        store,
+                                        &mut block_update.updated_user_balances,
+                                        &mut block_update.new_user_balances,
+                                        &user_balance_entry,
                                     )
                                     .await
                                     {
-                                        Ok(_) => (),
+                                        Ok(_) => {}
                                         Err(e) => {
-                                            error!("Error checking for transfer send: {:?}", e);
+                                            error!("Error updating user balance docs: {:?}", e);
                                         }
-                                    };
+                                    }
+                                    event.event_type = Brc20EventType::Mint;
+                                    event.amount = Some(user_balance_entry.amt);
+                                    event.to = Some(owner_address);
+                                    block_update.user_balance_entries.push(user_balance_entry);
                                 }
                             }
-
-                            // Increment the tx height
-                            tx_height += 1;
-                        }
-
-                        // time to process the block
-                        warn!(
-                            "Transactions Processed: {} in {:?}",
+                            Err(e) => {
+                                error!("Error handling mint operation: {:?}", e);
+                            }
+                        };
+                    }
+                    "transfer" => {
+                        match handle_transfer_operation(
+                            store,
+                            block_height,
                             tx_height,
-                            process_block_start_time.elapsed()
-                        );
-
-                        // write the updated and new user balance documents back to MongoDB
-                        if !user_balance_docs_to_update.is_empty()
-                            || !user_balance_docs_to_insert.is_empty()
+                            revealed.id,
+                            SatPoint::new(txid.to_string(), vout.try_into()?, offset.try_into()?),
+                            inscription,
+                            &raw_tx,
+                            owner,
+                            &mut block_update.tickers,
+                            &mut block_update.active_transfers,
+                            &mut block_update.updated_user_balances,
+                            &mut block_update.new_user_balances,
+                            &mut block_update.invalids,
+                        )
+                        .await
                         {
-                            let start = Instant::now();
-                            let start_len = user_balance_docs_to_update.len();
-                            // This removes all UserBalance with 0 in all the balance fields.
-                            user_balance_docs_to_update.retain(|_, user_balance_doc| {
-                                let overall_balance = user_balance_doc
-                                    .get_f64("overall_balance")
-                                    .unwrap_or_default();
-                                let available_balance = user_balance_doc
-                                    .get_f64("available_balance")
-                                    .unwrap_or_default();
-                                let transferable_balance = user_balance_doc
-                                    .get_f64("transferable_balance")
-                                    .unwrap_or_default();
-
-                                overall_balance != 0.0
-                                    || available_balance != 0.0
-                                    || transferable_balance != 0.0
-                            });
-
-                            let len = user_balance_docs_to_update.len();
-
-                            warn!(
-                                "Zeroed User Balances removed: {} in {:?}",
-                                start_len - len,
-                                start.elapsed()
-                            );
-
-                            info!("Inserting User Balances...");
-                            // write user balance documents to mongodb
-                            match mongo_client
-                                .update_user_balances(
-                                    user_balance_docs_to_update,
-                                    user_balance_docs_to_insert,
-                                )
-                                .await
-                            {
-                                Ok(_) => {}
-                                Err(e) => {
-                                    error!("Failed to update user balance documents: {:?}", e);
+                            Ok((transfer, user_balance_entry)) => {
+                                if transfer.is_valid() {
+                                    block_update.transfers.push(transfer.to_document());
+                                    event.event_type = Brc20EventType::InscribeTransfer;
+                                    event.amount = Some(user_balance_entry.amt);
+                                    event.from = Some(owner_address);
+                                    block_update.user_balance_entries.push(user_balance_entry);
                                 }
                             }
-                        }
-
-                        insert_documents_to_mongo_after_each_block(
-                            mongo_client,
-                            mint_documents,
-                            transfer_documents,
-                            deploy_documents,
-                            invalid_brc20_documents,
-                            user_balance_entry_documents,
-                        )
-                        .await?;
-
-                        // Bulk update tickers in mongodb
-                        if !tickers.is_empty() {
-                            // convert tickers hashmap to vec<Document>
-                            let tickers: Vec<Document> =
-                                tickers.into_iter().map(|(_, ticker)| ticker).collect();
-
-                            debug!("tickers main loop: {:?}", tickers);
-
-                            let start = Instant::now();
-                            for ticker in &tickers {
-                                let filter_doc = doc! {
-                                    "tick": ticker.get_str("tick").unwrap_or_default(),
-                                };
-
-                                let update_doc = doc! {
-                                    "$set": ticker,
-                                };
-
-                                mongo_client
-                                    .update_one_with_retries(
-                                        consts::COLLECTION_TICKERS,
-                                        filter_doc,
-                                        update_doc,
-                                        None,
-                                    )
-                                    .await?;
-                            }
-
-                            warn!(
-                                "Tickers updated after block: {} in {:?}",
-                                tickers.len(),
-                                start.elapsed()
-                            );
-                        }
-
-                        // drop mongodb collection right before inserting active transfers
-                        mongo_client
-                            .drop_collection(consts::COLLECTION_BRC20_ACTIVE_TRANSFERS)
-                            .await?;
-
-                        // store active transfer collection, if any
-                        if let Some(active_transfers) = active_transfers_opt {
-                            let length = active_transfers.len();
-                            if !active_transfers.is_empty() {
-                                let start = Instant::now();
-                                mongo_client
-                                    .insert_active_transfers_to_mongodb(active_transfers)
-                                    .await?;
-
-                                info!(
-                                    "Active Transfers inserted to MongoDB after block: {} in {:?}",
-                                    length,
-                                    start.elapsed()
-                                );
-                            }
-                        }
-
-                        // After successfully processing the block, store the current_block_height
-                        match mongo_client
-                            .store_completed_block(current_block_height.into())
-                            .await
-                        {
-                            Ok(_) => (),
                             Err(e) => {
-                                error!("Failed to store last processed block height: {:?}", e);
+                                error!("Error handling transfer inscription: {:?}", e);
                             }
-                        }
-
-                        // Increment the block height
-                        current_block_height += 1;
+                        };
                     }
-                    Err(e) => {
-                        error!("Failed to fetch block: {:?}, retrying...", e);
-                        sleep(Duration::from_secs(60));
+                    _ => {
+                        // Unexpected operation
+                        error!("Unexpected operation: {}", inscription.op);
                     }
                 }
+
+                if event.event_type != Brc20EventType::Invalid {
+                    block_update.events.push(event);
+                } else if let Some(invalid) = block_update.invalids.get(invalids) {
+                    event.reason = invalid.get_str("reason").ok().map(str::to_string);
+                    block_update.events.push(event);
+                }
             }
-            Err(e) => {
-                error!("Failed to fetch block hash for height: {:?}, retrying", e);
-                sleep(Duration::from_secs(60));
+        }
+    }
+
+    // time to process the block
+    warn!(
+        "Transactions Processed: {} in {:?}",
+        length,
+        process_block_start_time.elapsed()
+    );
+
+    if check_invariants {
+        let start = Instant::now();
+        let violations = invariants::check_block(store, &block_update).await?;
+        if !violations.is_empty() {
+            for violation in &violations {
+                error!(
+                    "Invariant violated at block {}: {}",
+                    block_height, violation
+                );
             }
+            return Err(format!(
+                "Block {} violates {} invariants, stopped before writing it",
+                block_height,
+                violations.len()
+            )
+            .into());
         }
+        warn!(
+            "Invariants checked: {} in {:?}",
+            block_height,
+            start.elapsed()
+        );
     }
+
+    block_update.state_hash = state_hash::compute_state_hash(store, &block_update).await?;
+    info!(
+        "State hash: {} at block {}",
+        block_update.state_hash, block_height
+    );
+
+    // write everything indexed in the block and mark it completed
+    let start = Instant::now();
+    store.write_block(block_update).await?;
+    warn!("Block written: {} in {:?}", block_height, start.elapsed());
+
+    Ok(block_height + 1)
 }
 
-/// Checks for transfer send events in a transaction and performs the necessary updates in MongoDB.
+/// Checks for transfer send events in a transaction and updates the block's state.
 ///
-/// This function checks if there are transfer send events in the given transaction and performs the following actions:
-/// - Updates user balances and entries for the sender and receiver.
-/// - Updates the transfer document in MongoDB, either by updating an existing document or inserting a new one.
-/// - Updates user available and transferable balances for the sender in MongoDB.
-/// - Updates user overall balance for the receiver in MongoDB.
+/// Every input spending an outpoint that holds transfer inscriptions sends them. The
+/// inscribed sat is followed with first-in-first-out sat flow: its offset among all
+/// sats spent by the transaction is the value of the preceding inputs plus its offset
+/// within the spent output, and the output covering that offset receives the transfer.
+/// Sats past the last output are spent as fee and handled by `fee_spend_policy`.
 ///
 /// # Arguments
 ///
-/// * `mongo_client` - The MongoDB client for performing database operations.
+/// * `store` - The store to load transfers and balances from.
 /// * `rpc` - The RPC client for interacting with the blockchain.
 /// * `raw_tx_info` - The raw transaction information.
 /// * `block_height` - The block height of the transaction.
 /// * `tx_height` - The transaction height.
-/// * `active_transfers` - A hashmap containing active transfers.
-/// * `transfer_documents` - A vector of transfer documents.
-/// * `user_balance_entry_documents` - A vector of user balance entry documents.
-/// * `user_balances` - A hashmap containing user balances.
+/// * `fee_spend_policy` - What happens to transfers spent as fee.
+/// * `coinbase_sats` - The coinbase output sat ranges of the block.
+/// * `block_update` - The block's active transfers, transfers, entries and balances.
 ///
 /// # Returns
 ///
 /// This function returns `Ok(())` if the operation is successful, or an error if any error occurs during the process.
 pub async fn check_for_transfer_send(
-    mongo_client: &MongoClient,
-    rpc: &Client,
+    store: &dyn Brc20Store,
+    rpc: &impl RpcApi,
     raw_tx_info: &GetRawTransactionResult,
     block_height: u64,
     tx_height: i64,
-    active_transfers: &mut HashMap<(String, i64), Brc20ActiveTransfer>,
-    transfer_documents: &mut Vec<Document>,
-    user_balance_entry_documents: &mut Vec<Document>,
-    user_balance_docs_to_update: &mut HashMap<(String, String), Document>,
-    user_balances_to_insert: &mut HashMap<(String, String), Document>,
+    fee_spend_policy: FeeSpendPolicy,
+    coinbase_sats: &mut CoinbaseSats<'_>,
+    block_update: &mut BlockUpdate,
 ) -> Result<(), anyhow::Error> {
     let transaction = raw_tx_info.transaction()?;
-
+    let send_txid = transaction.txid().to_string();
+    let output_values: Vec<u64> = transaction
+        .output
+        .iter()
+        .map(|output| output.value)
+        .collect();
+    let total_output_value: u64 = output_values.iter().sum();
+
+    let mut input_values = Vec::new();
     for (input_index, input) in transaction.input.iter().enumerate() {
-        let txid = input.previous_output.txid.to_string();
-        let vout = input.previous_output.vout as i64;
-        let key = (txid.clone(), vout);
+        let key = (
+            input.previous_output.txid.to_string(),
+            input.previous_output.vout as i64,
+        );
 
-        // Check if active transfer exists in the HashMap
-        if active_transfers.contains_key(&key) {
-            active_transfers.remove(&key);
-        } else {
-            continue;
+        // Check if active transfers exist for the spent outpoint
+        let spent_transfers = match block_update.active_transfers.remove(&key) {
+            Some(spent_transfers) => spent_transfers,
+            None => continue,
+        };
+
+        let input_offset =
+            utils::input_offset(rpc, &transaction.input, input_index, &mut input_values)?;
+
+        for active_transfer in spent_transfers {
+            let offset = input_offset + u64::try_from(active_transfer.satpoint.offset)?;
+            let destination = match locate_offset(&output_values, offset) {
+                Some((vout, output_offset)) => TransferDestination::Output(
+                    SatPoint::new(send_txid.clone(), vout as i64, output_offset as i64),
+                    get_owner_of_vout(raw_tx_info, vout)?.to_string(),
+                ),
+                None => fee_spend_destination(
+                    rpc,
+                    fee_spend_policy,
+                    coinbase_sats,
+                    tx_height.try_into()?,
+                    offset - total_output_value,
+                )?,
+            };
+
+            send_transfer(
+                store,
+                raw_tx_info,
+                block_height,
+                tx_height,
+                input_index,
+                active_transfer,
+                destination,
+                block_update,
+            )
+            .await?;
         }
-        info!("Transfer Send Found: {:?}", key);
-        // Check if transfer exists in the transfer_documents vector in memory
-        let index = transfer_documents.iter().position(|doc| {
-            if let Ok(tx) = doc.get_document("tx") {
-                if let Ok(txid) = tx.get_str("txid") {
-                    return txid == txid;
-                }
-            }
-            false
-        });
+    }
 
-        let transfer_doc = if let Some(index) = index {
-            // Document found in the vector, remove it from the vector
-            transfer_documents.remove(index)
-        } else {
-            info!("Checking in MongoDB: {:?}", key);
-            // Document not found in the vector, fetch it from MongoDB
-            let filter_doc = doc! {"tx.txid": txid.clone()};
-            match mongo_client
-                .get_document_by_filter(consts::COLLECTION_TRANSFERS, filter_doc)
-                .await?
-            {
-                Some(doc) => doc,
-                None => {
-                    error!(
-                        "Transfer inscription not found for txid: {}, vout: {}",
-                        txid, vout
-                    );
-                    continue;
-                }
-            }
-        };
+    Ok(())
+}
 
-        let mut tick = String::new();
-        if let Some(inscription) = transfer_doc.get_document("inscription").ok() {
-            if let Some(tck) = inscription.get_str("tick").ok() {
-                tick = tck.to_string();
-            } else {
-                error!("Failed to get 'tick' field from 'inscription'");
-            }
-        } else {
-            error!("Failed to get 'inscription' document");
+// where a sent transfer inscription ends up, with the address receiving it
+enum TransferDestination {
+    // an output of the sending transaction
+    Output(SatPoint, String),
+    // a coinbase output of the block, after being spent as fee
+    Coinbase(SatPoint, String),
+    // spent as fee, the sender keeps the amount
+    ReturnToSender,
+}
+
+// follows a sat spent as fee according to the fee spend policy
+fn fee_spend_destination(
+    rpc: &impl RpcApi,
+    fee_spend_policy: FeeSpendPolicy,
+    coinbase_sats: &mut CoinbaseSats<'_>,
+    tx_index: usize,
+    fee_offset: u64,
+) -> Result<TransferDestination, anyhow::Error> {
+    if fee_spend_policy == FeeSpendPolicy::ReturnToSender {
+        return Ok(TransferDestination::ReturnToSender);
+    }
+
+    let (vout, offset) = match coinbase_sats.locate(rpc, tx_index, fee_offset)? {
+        Some(location) => location,
+        None => {
+            warn!("Transfer sent as Miner Fee wasn't claimed by the coinbase");
+            return Ok(TransferDestination::ReturnToSender);
         }
+    };
 
-        let from = mongo_client.get_string(&transfer_doc, "from")?;
-        let amount = match mongo_client.get_f64(&transfer_doc, "amt") {
-            Some(amt) => amt,
-            None => 0.0,
-        };
+    match (
+        coinbase_sats.coinbase_txid(),
+        coinbase_sats.owner_of_vout(vout),
+    ) {
+        (Some(coinbase_txid), Some(owner)) => Ok(TransferDestination::Coinbase(
+            SatPoint::new(coinbase_txid, vout as i64, offset as i64),
+            owner.to_string(),
+        )),
+        _ => {
+            warn!("Transfer sent as Miner Fee landed in a coinbase output without address");
+            Ok(TransferDestination::ReturnToSender)
+        }
+    }
+}
 
-        let proper_vout = if input_index > 0 {
-            // if not in first input, get values of all inputs only up to this input
-            let input_values =
-                utils::transaction_inputs_to_values(rpc, &transaction.input[0..input_index])?;
-
-            // then get the sum these input values
-            let input_value_sum: u64 = input_values.iter().sum();
-            let total_output_value: u64 =
-                transaction.output.iter().map(|output| output.value).sum();
-
-            // If the sum of input values (up to the current input index) is greater than the total output value,
-            // assume that the sender is the receiver.
-            if input_value_sum >= total_output_value {
-                std::usize::MAX // use MAX as a sentinel value
-            } else {
-                // Calculate the index of the output (vout) which is the recipient of the
-                // inscribed satoshi by finding the first output whose value is greater than
-                // the sum of all preceding input values. This is based on the ordinal theory that satoshis are processed in order.
-                transaction
-                    .output
-                    .iter()
-                    .scan(0, |acc, output| {
-                        *acc += output.value;
-                        Some(*acc)
-                    })
-                    .position(|value| value > input_value_sum)
-                    .unwrap_or(transaction.output.len() - 1)
+// moves one transfer inscription to its destination
+async fn send_transfer(
+    store: &dyn Brc20Store,
+    raw_tx_info: &GetRawTransactionResult,
+    block_height: u64,
+    tx_height: i64,
+    input_index: usize,
+    active_transfer: Brc20ActiveTransfer,
+    destination: TransferDestination,
+    block_update: &mut BlockUpdate,
+) -> Result<(), anyhow::Error> {
+    let inscription_id = active_transfer.inscription_id;
+    let txid = active_transfer.tx_id;
+    info!(
+        "Transfer Send Found: {} ({})",
+        active_transfer.satpoint, inscription_id
+    );
+
+    // Check if transfer was inscribed in this block
+    let index = block_update.transfers.iter().position(|doc| {
+        doc.get_str("inscription_id")
+            .map_or(false, |id| id == inscription_id)
+    });
+
+    let mut transfer_doc = if let Some(index) = index {
+        // Document found in the block, it is written with the send
+        block_update.transfers.remove(index)
+    } else {
+        info!("Checking in the store: {}", inscription_id);
+        // Document not found in the block, load it from the store
+        match store.get_transfer(&inscription_id, &txid).await? {
+            Some(doc) => doc,
+            None => {
+                error!(
+                    "Transfer inscription not found: {} ({})",
+                    inscription_id, active_transfer.satpoint
+                );
+                return Ok(());
             }
-        } else {
-            0
-        };
+        }
+    };
 
-        let receiver_address = if proper_vout == std::usize::MAX {
-            error!("Transfer sent as Miner Fee. Balance sent back to sender.");
-            from.clone() // If sentinel value is present, use sender's address as receiver's address
+    let mut tick = String::new();
+    if let Some(inscription) = transfer_doc.get_document("inscription").ok() {
+        if let Some(tck) = inscription.get_str("tick").ok() {
+            tick = tck.to_string();
         } else {
-            get_owner_of_vout(&raw_tx_info, proper_vout)?.to_string()
-        };
-
-        // Update user overall balance and available for the from address(sender)
-        let user_entry_from = mongo_client
-            .insert_user_balance_entry(
-                &from,
-                amount,
-                &tick,
-                block_height,
-                UserBalanceEntryType::Send,
-            )
-            .await?;
+            error!("Failed to get 'tick' field from 'inscription'");
+        }
+    } else {
+        error!("Failed to get 'inscription' document");
+    }
 
-        user_balance_entry_documents.push(user_entry_from.to_document());
+    let from = transfer_doc.get_str("from")?.to_string();
+    let amount = match get_amount(&transfer_doc, "amt") {
+        Some(amt) => amt,
+        None => Amount::ZERO,
+    };
 
-        // Update user overall balance and available for the to address(receiver)
-        let user_entry_to = mongo_client
-            .insert_user_balance_entry(
-                &receiver_address,
-                amount,
-                &tick,
-                block_height,
-                UserBalanceEntryType::Receive,
-            )
-            .await?;
+    let (receiver_address, send_satpoint, fee_spend) = match destination {
+        TransferDestination::Output(satpoint, receiver) => (receiver, Some(satpoint), None),
+        TransferDestination::Coinbase(satpoint, receiver) => {
+            warn!(
+                "Transfer sent as Miner Fee. Balance sent to coinbase output {}",
+                satpoint
+            );
+            (receiver, Some(satpoint), Some(FeeSpendPolicy::Coinbase))
+        }
+        TransferDestination::ReturnToSender => {
+            warn!("Transfer sent as Miner Fee. Balance sent back to sender.");
+            (from.clone(), None, Some(FeeSpendPolicy::ReturnToSender))
+        }
+    };
 
-        user_balance_entry_documents.push(user_entry_to.to_document());
+    // Record the send on the transfer document, written with the block
+    mark_transfer_sent(
+        &mut transfer_doc,
+        &receiver_address,
+        send_satpoint.as_ref(),
+        fee_spend,
+        block_height.try_into()?,
+        tx_height,
+        raw_tx_info,
+    );
+    if index.is_some() {
+        block_update.transfers.push(transfer_doc);
+    } else {
+        block_update.sent_transfers.push(transfer_doc);
+    }
 
-        //-------------MONGODB-------------------//
-        // Pass the transfer document to the update_transfer_document function
-        update_transfer_document(
-            mongo_client,
-            transfer_doc,
-            &txid,
-            &receiver_address,
-            block_height.try_into().unwrap(),
+    let send_txid = raw_tx_info.txid.to_string();
+    let event_type = match fee_spend {
+        Some(FeeSpendPolicy::ReturnToSender) => Brc20EventType::TransferReturn,
+        _ => Brc20EventType::TransferSend,
+    };
+    block_update.events.push(Brc20Event {
+        // a transfer sent as fee lands in the coinbase, not in this transaction
+        output_index: send_satpoint
+            .filter(|_| fee_spend.is_none())
+            .map(|satpoint| satpoint.vout),
+        amount: Some(amount),
+        from: Some(from.clone()),
+        to: Some(receiver_address.clone()),
+        ..Brc20Event::spent(
+            event_type,
+            block_height.try_into()?,
             tx_height,
-            &raw_tx_info,
+            &send_txid,
+            input_index.try_into()?,
+            &inscription_id,
+            &tick,
         )
-        .await?;
-
-        // Update user available and transferable balance for the sender in MongoDB
-        update_sender_user_balance_document(
-            mongo_client,
-            user_balance_docs_to_update,
-            user_balances_to_insert,
-            &user_entry_from,
+    });
+
+    if fee_spend == Some(FeeSpendPolicy::ReturnToSender) {
+        // Move the amount from transferable back to available for the sender
+        let user_entry_from = UserBalanceEntry::new(
+            from.clone(),
+            tick,
+            block_height,
+            amount,
+            UserBalanceEntryType::FeeReturn,
         )
-        .await?;
+        .with_source(&send_txid, tx_height, &inscription_id);
 
-        // Update user overall balance for the receiver in MongoDB
-        update_receiver_balance_document(
-            mongo_client,
-            user_balance_docs_to_update,
-            user_balances_to_insert,
-            &user_entry_to,
+        update_sender_user_balance(
+            store,
+            &mut block_update.updated_user_balances,
+            &mut block_update.new_user_balances,
+            &user_entry_from,
         )
         .await?;
 
-        info!(
-            "Transfer inscription found for txid: {}, vout: {}",
-            txid, vout
-        );
-        info!("Amount transferred: {}, to: {}", amount, receiver_address);
-    }
-
-    Ok(())
-}
+        block_update.user_balance_entries.push(user_entry_from);
 
-/// Inserts different types of documents to MongoDB in their respective collections.
-///
-/// # Arguments
-///
-/// * `mongo_client` - A reference to the MongoDB client used to interact with the database.
-/// * `mint_documents` - A vector of mint documents to be inserted into MongoDB.
-/// * `transfer_documents` - A vector of transfer documents to be inserted into MongoDB.
-/// * `deploy_documents` - A vector of deploy documents to be inserted into MongoDB.
-/// * `invalid_brc20_documents` - A vector of invalid BRC20 documents to be inserted into MongoDB.
-/// * `user_balance_entry_documents` - A vector of user balance entry documents to be inserted into MongoDB.
-///
-/// # Errors
-///
-/// This function will return an error if the insertion of any type of documents fails.
-pub async fn insert_documents_to_mongo_after_each_block(
-    mongo_client: &MongoClient,
-    mint_documents: Vec<Document>,
-    transfer_documents: Vec<Document>,
-    deploy_documents: Vec<Document>,
-    invalid_brc20_documents: Vec<Document>,
-    user_balance_entry_documents: Vec<Document>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    // If there are mint documents, insert them into the mints collection
-    if !mint_documents.is_empty() {
-        let start = Instant::now();
-        mongo_client
-            .insert_many_with_retries(consts::COLLECTION_MINTS, &mint_documents)
-            .await?;
-        warn!(
-            "Mints inserted after block: {} in {:?}",
-            mint_documents.len(),
-            start.elapsed()
-        );
-    }
-
-    // If there are transfer documents, insert them into the transfers collection
-    if !transfer_documents.is_empty() {
-        let start = Instant::now();
-        mongo_client
-            .insert_many_with_retries(consts::COLLECTION_TRANSFERS, &transfer_documents)
-            .await?;
-        warn!(
-            "Transfers inserted after block: {} in {:?}",
-            transfer_documents.len(),
-            start.elapsed()
-        );
-    }
-
-    // If there are deploy documents, insert them into the deploys collection
-    if !deploy_documents.is_empty() {
-        let start = Instant::now();
-        mongo_client
-            .insert_many_with_retries(consts::COLLECTION_DEPLOYS, &deploy_documents)
-            .await?;
-        warn!(
-            "Deploys inserted after block: {} in {:?}",
-            deploy_documents.len(),
-            start.elapsed()
-        );
-    }
+        info!("Transfer inscription returned: {}", inscription_id);
+        info!("Amount returned: {}, to: {}", amount, from);
 
-    // If there are invalid BRC20 documents, insert them into the invalids collection
-    if !invalid_brc20_documents.is_empty() {
-        let start = Instant::now();
-        mongo_client
-            .insert_many_with_retries(consts::COLLECTION_INVALIDS, &invalid_brc20_documents)
-            .await?;
-        warn!(
-            "Invalids inserted after block: {} in {:?}",
-            invalid_brc20_documents.len(),
-            start.elapsed()
-        );
+        return Ok(());
     }
 
-    // If there are user balance entry documents, insert them into the user balance entry collection
-    if !user_balance_entry_documents.is_empty() {
-        let start = Instant::now();
-        mongo_client
-            .insert_many_with_retries(
-                consts::COLLECTION_USER_BALANCE_ENTRY,
-                &user_balance_entry_documents,
-            )
-            .await?;
-        warn!(
-            "User Balance Entries inserted after block: {} in {:?}",
-            user_balance_entry_documents.len(),
-            start.elapsed()
-        );
-    }
+    // Update user overall balance and available for the from address(sender)
+    let user_entry_from = UserBalanceEntry::new(
+        from.clone(),
+        tick.clone(),
+        block_height,
+        amount,
+        UserBalanceEntryType::Send,
+    )
+    .with_source(&send_txid, tx_height, &inscription_id)
+    .with_counterparty(&receiver_address);
+
+    // Update user overall balance and available for the to address(receiver)
+    let user_entry_to = UserBalanceEntry::new(
+        receiver_address.clone(),
+        tick,
+        block_height,
+        amount,
+        UserBalanceEntryType::Receive,
+    )
+    .with_source(&send_txid, tx_height, &inscription_id)
+    .with_counterparty(&from);
+
+    // Update user available and transferable balance for the sender
+    update_sender_user_balance(
+        store,
+        &mut block_update.updated_user_balances,
+        &mut block_update.new_user_balances,
+        &user_entry_from,
+    )
+    .await?;
+
+    // Update user overall balance for the receiver
+    update_receiver_balance(
+        store,
+        &mut block_update.updated_user_balances,
+        &mut block_update.new_user_balances,
+        &user_entry_to,
+    )
+    .await?;
+
+    block_update.user_balance_entries.push(user_entry_from);
+    block_update.user_balance_entries.push(user_entry_to);
+
+    info!("Transfer inscription sent: {}", inscription_id);
+    info!("Amount transferred: {}, to: {}", amount, receiver_address);
 
     Ok(())
 }
@@ -813,40 +808,40 @@ impl ToDocument for GetRawTransactionResultVout {
     }
 }
 
-pub async fn update_transfer_document(
-    mongo_client: &MongoClient,
-    transfer_doc: Document,
-    tx_id: &str,
+/// Records the send of a transfer inscription on its transfer document.
+pub fn mark_transfer_sent(
+    transfer_doc: &mut Document,
     receiver_address: &str,
+    send_satpoint: Option<&SatPoint>,
+    fee_spend: Option<FeeSpendPolicy>,
     send_block_height: i64,
     send_tx_height: i64,
     send_tx: &GetRawTransactionResult,
-) -> Result<(), anyhow::Error> {
-    // Update the fields of the document
-    let updated_doc = {
-        let mut updated_doc = transfer_doc;
-        updated_doc.insert("to", receiver_address);
-        updated_doc.insert("send_tx", send_tx.to_document());
-        updated_doc.insert("send_block_height", send_block_height);
-        updated_doc.insert("send_tx_height", send_tx_height);
-        updated_doc
-    };
+) {
+    transfer_doc.insert("to", receiver_address);
+    transfer_doc.insert("send_tx", send_tx.to_document());
+    transfer_doc.insert(
+        "send_satpoint",
+        send_satpoint.map(|satpoint| satpoint.to_string()),
+    );
+    // how the transfer was resolved when its sat was spent as fee
+    transfer_doc.insert("fee_spend", fee_spend.map(|policy| policy.to_string()));
+    transfer_doc.insert("send_block_height", send_block_height);
+    transfer_doc.insert("send_tx_height", send_tx_height);
+}
 
-    // Update or insert the document in MongoDB
-    // We can save to MongoDB without worrying about needing to
-    // delete in case of restart, they will just be overwritten by the new ones
-    // and will not affect any balances that need to be recalculated
-    let filter = doc! { "tx.txid": tx_id };
-    let update_doc = doc! { "$set": updated_doc };
-    let options = UpdateOptions::builder().upsert(true).build();
-    mongo_client
-        .update_one_with_retries(
-            consts::COLLECTION_TRANSFERS,
-            filter,
-            update_doc,
-            Some(options),
-        )
-        .await?;
+#[cfg(test)]
+mod tests {
+    use super::memory::MemoryStore;
+    use super::testing;
 
-    Ok(())
+    #[tokio::test]
+    async fn test_index_deploy_mint_and_transfer() {
+        testing::check_deploy_mint_and_transfer(&MemoryStore::new()).await;
+    }
+
+    #[tokio::test]
+    async fn test_reorg_restores_sent_transfer() {
+        testing::check_reorg_restores_sent_transfer(&MemoryStore::new()).await;
+    }
 }
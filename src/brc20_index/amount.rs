@@ -0,0 +1,204 @@
+use mongodb::bson::{Bson, Document};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+
+/// The most decimal places a BRC-20 ticker can have, every amount is scaled to it.
+pub const MAX_DECIMALS: u8 = 18;
+const SCALE: u128 = 1_000_000_000_000_000_000;
+
+/// Exact token amount stored as an unsigned integer scaled by 10^18.
+///
+/// Amounts are written to MongoDB as decimal strings so that no precision is
+/// lost for 18 decimal tickers or supplies above 2^53.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Amount(u128);
+
+impl Amount {
+    pub const ZERO: Amount = Amount(0);
+
+    pub fn from_raw(raw: u128) -> Self {
+        Amount(raw)
+    }
+
+    pub fn raw(&self) -> u128 {
+        self.0
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn checked_add(self, other: Amount) -> Option<Amount> {
+        self.0.checked_add(other.0).map(Amount)
+    }
+
+    pub fn checked_sub(self, other: Amount) -> Option<Amount> {
+        self.0.checked_sub(other.0).map(Amount)
+    }
+
+    /// Parses a decimal string with no more than `decimals` digits after the decimal point.
+    pub fn parse(number_string: &str, decimals: u8) -> Result<Amount, &'static str> {
+        let parts: Vec<&str> = number_string.split('.').collect();
+        let (integer_part, fraction_part) = match parts.len() {
+            1 => (parts[0], ""),
+            2 => (parts[0], parts[1]),
+            _ => return Err("Malformed inscription"), // More than one decimal point
+        };
+
+        if integer_part.is_empty() && fraction_part.is_empty() {
+            return Err("Malformed inscription");
+        }
+        if !integer_part
+            .chars()
+            .chain(fraction_part.chars())
+            .all(|c| c.is_ascii_digit())
+        {
+            return Err("Malformed inscription");
+        }
+        if fraction_part.len() > decimals.min(MAX_DECIMALS) as usize {
+            return Err("There are too many digits to the right of the decimal");
+        }
+
+        let integer = if integer_part.is_empty() {
+            0
+        } else {
+            integer_part
+                .parse::<u128>()
+                .map_err(|_| "Amount is out of range")?
+        };
+
+        let mut fraction = 0u128;
+        if !fraction_part.is_empty() {
+            let padding = MAX_DECIMALS as u32 - fraction_part.len() as u32;
+            fraction = fraction_part
+                .parse::<u128>()
+                .map_err(|_| "Malformed inscription")?
+                * 10u128.pow(padding);
+        }
+
+        integer
+            .checked_mul(SCALE)
+            .and_then(|value| value.checked_add(fraction))
+            .map(Amount)
+            .ok_or("Amount is out of range")
+    }
+
+    /// Reads an amount from a BSON value, accepting the f64 values written by older versions.
+    pub fn from_bson(value: &Bson) -> Option<Amount> {
+        match value {
+            Bson::String(value) => value.parse().ok(),
+            Bson::Int32(value) => u128::try_from(*value)
+                .ok()
+                .and_then(|value| value.checked_mul(SCALE))
+                .map(Amount),
+            Bson::Int64(value) => u128::try_from(*value)
+                .ok()
+                .and_then(|value| value.checked_mul(SCALE))
+                .map(Amount),
+            Bson::Double(value) if *value >= 0.0 => {
+                // f64 Display never uses an exponent, cut off digits we can't represent
+                let value = value.to_string();
+                let value = match value.find('.') {
+                    Some(pos) if value.len() - pos - 1 > MAX_DECIMALS as usize => {
+                        &value[..pos + 1 + MAX_DECIMALS as usize]
+                    }
+                    _ => &value[..],
+                };
+                value.parse().ok()
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Gets an amount field from a document, `None` if it is missing or malformed.
+pub fn get_amount(doc: &Document, field: &str) -> Option<Amount> {
+    doc.get(field).and_then(Amount::from_bson)
+}
+
+/// Gets a ticker's decimals from its document, defaulting to the BRC-20 maximum of 18.
+pub fn get_decimals(ticker_doc: &Document) -> u8 {
+    match ticker_doc.get("decimals") {
+        Some(Bson::Int32(decimals)) => u8::try_from(*decimals).unwrap_or(MAX_DECIMALS),
+        Some(Bson::Int64(decimals)) => u8::try_from(*decimals).unwrap_or(MAX_DECIMALS),
+        _ => MAX_DECIMALS,
+    }
+}
+
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let integer = self.0 / SCALE;
+        let fraction = self.0 % SCALE;
+        if fraction == 0 {
+            return write!(f, "{}", integer);
+        }
+
+        let fraction = format!("{:018}", fraction);
+        write!(f, "{}.{}", integer, fraction.trim_end_matches('0'))
+    }
+}
+
+impl FromStr for Amount {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Amount::parse(s, MAX_DECIMALS)
+    }
+}
+
+impl From<Amount> for Bson {
+    fn from(amount: Amount) -> Self {
+        Bson::String(amount.to_string())
+    }
+}
+
+impl Serialize for Amount {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for Amount {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        value.parse().map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_amount_keeps_all_18_decimals() {
+        let amount = Amount::parse("0.123456789012345678", 18).unwrap();
+        assert_eq!(amount.raw(), 123456789012345678);
+        assert_eq!(amount.to_string(), "0.123456789012345678");
+    }
+
+    #[test]
+    fn test_amount_above_2_pow_53() {
+        let amount = Amount::parse("18446744073709551615", 18).unwrap();
+        let one = Amount::parse("1", 18).unwrap();
+        assert_eq!(
+            amount.checked_add(one).unwrap().to_string(),
+            "18446744073709551616"
+        );
+    }
+
+    #[test]
+    fn test_amount_checked_sub_underflow() {
+        let small = Amount::parse("1.5", 18).unwrap();
+        let large = Amount::parse("2", 18).unwrap();
+        assert!(small.checked_sub(large).is_none());
+        assert_eq!(large.checked_sub(small).unwrap().to_string(), "0.5");
+    }
+
+    #[test]
+    fn test_amount_from_legacy_double() {
+        let amount = Amount::from_bson(&Bson::Double(1234.56)).unwrap();
+        assert_eq!(amount.to_string(), "1234.56");
+        assert!(Amount::from_bson(&Bson::Double(-1.0)).is_none());
+    }
+}
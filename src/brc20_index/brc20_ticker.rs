@@ -1,15 +1,21 @@
-use super::{deploy::Brc20Deploy, ToDocument};
-use mongodb::bson::{doc, Document};
-use serde::Serialize;
+use super::{
+    amount::{get_amount, get_decimals, Amount},
+    deploy::Brc20Deploy,
+    ToDocument,
+};
+use mongodb::bson::{doc, Bson, Document};
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Brc20Ticker {
     pub tick: String,
-    pub limit: f64,
-    pub max_supply: f64,
-    pub total_minted: f64,
+    pub limit: Amount,
+    pub max_supply: Amount,
+    pub total_minted: Amount,
     pub decimals: u8,
-    pub deploy: Brc20Deploy,
+    pub block_height: u32,
+    // last block that changed total_minted, rollbacks recalculate it from there
+    pub updated_at_block: u32,
 }
 
 impl ToDocument for Brc20Ticker {
@@ -20,13 +26,14 @@ impl ToDocument for Brc20Ticker {
             "max_supply": self.max_supply,
             "decimals": self.decimals as i64,
             "total_minted": self.total_minted,
-            "block_height": self.deploy.block_height,
+            "block_height": self.block_height,
+            "updated_at_block": self.updated_at_block,
         }
     }
 }
 
 impl Brc20Ticker {
-    pub fn new(deploy: Brc20Deploy) -> Brc20Ticker {
+    pub fn new(deploy: &Brc20Deploy) -> Brc20Ticker {
         let tick = deploy.get_deploy_script().tick.to_lowercase().clone();
         let limit = deploy.get_limit();
         let max_supply = deploy.get_max_supply();
@@ -36,12 +43,38 @@ impl Brc20Ticker {
             tick,
             limit,
             max_supply,
-            total_minted: 0.0,
+            total_minted: Amount::ZERO,
             decimals,
-            deploy,
+            block_height: deploy.block_height,
+            updated_at_block: deploy.block_height,
         }
     }
 
+    pub fn from_document(document: &Document) -> Result<Self, String> {
+        let tick = document
+            .get_str("tick")
+            .map_err(|_| "Invalid tick".to_string())?
+            .to_lowercase();
+
+        // block heights are stored as both Int32 and Int64 depending on the writer
+        let get_height = |field: &str| match document.get(field) {
+            Some(Bson::Int32(value)) => u32::try_from(*value).ok(),
+            Some(Bson::Int64(value)) => u32::try_from(*value).ok(),
+            _ => None,
+        };
+        let block_height = get_height("block_height").unwrap_or_default();
+
+        Ok(Brc20Ticker {
+            tick,
+            limit: get_amount(document, "limit").unwrap_or_default(),
+            max_supply: get_amount(document, "max_supply").unwrap_or_default(),
+            total_minted: get_amount(document, "total_minted").unwrap_or_default(),
+            decimals: get_decimals(document),
+            block_height,
+            updated_at_block: get_height("updated_at_block").unwrap_or(block_height),
+        })
+    }
+
     pub fn get_ticker(&self) -> String {
         self.tick.to_lowercase()
     }
@@ -8,6 +8,9 @@ pub const COLLECTION_USER_BALANCE_ENTRY: &str = "brc20_user_balance_entry";
 pub const COLLECTION_BLOCKS_COMPLETED: &str = "blocks_completed";
 pub const COLLECTION_BRC20_ACTIVE_TRANSFERS: &str = "brc20_active_transfers";
 pub const COLLECTION_TOTAL_MINTED_AT_BLOCK_HEIGHT: &str = "total_minted_at_block_height";
+pub const COLLECTION_EVENTS: &str = "brc20_events";
+pub const COLLECTION_WEBHOOK_CURSORS: &str = "brc20_webhook_cursors";
+pub const COLLECTION_PENDING_ROLLBACK: &str = "brc20_pending_rollback";
 pub const MONGO_RETRIES: u32 = 10000000;
 
 pub const BRC20_STARTING_BLOCK_HEIGHT: i64 = 779832;
@@ -16,3 +19,14 @@ pub const KEY_BLOCK_HEIGHT: &str = "block_height";
 pub const OVERALL_BALANCE: &str = "overall_balance";
 pub const TRANSFERABLE_BALANCE: &str = "transferable_balance";
 pub const AVAILABLE_BALANCE: &str = "available_balance";
+pub const KEY_BLOCK_HASH: &str = "block_hash";
+pub const KEY_PREVIOUS_BLOCK_HASH: &str = "previous_block_hash";
+pub const KEY_STATE_HASH: &str = "state_hash";
+
+// how far back we walk looking for a common ancestor after a reorg
+pub const REORG_MAX_DEPTH: i64 = 100;
+
+// attempts to post a batch of events to a webhook after the first one fails
+pub const WEBHOOK_RETRIES: u32 = 5;
+// events read from the event log at a time, the matching ones are posted as one batch
+pub const WEBHOOK_BATCH_SIZE: u64 = 100;
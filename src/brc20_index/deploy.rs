@@ -1,18 +1,21 @@
 use super::invalid_brc20::InvalidBrc20Tx;
-use super::mongo::MongoClient;
+use super::store::Brc20Store;
 use super::ToDocument;
-use super::{brc20_ticker::Brc20Ticker, utils::convert_to_float, Brc20Inscription};
-use crate::brc20_index::consts;
+use super::{
+    amount::Amount, brc20_ticker::Brc20Ticker, utils::convert_to_amount, Brc20Inscription,
+};
 use bitcoin::Address;
 use bitcoincore_rpc::bitcoincore_rpc_json::GetRawTransactionResult;
 use log::{error, info};
 use mongodb::bson::{doc, Bson, DateTime, Document};
 use serde::Serialize;
+use std::collections::HashMap;
 
 #[derive(Debug, Clone, Serialize)]
 pub struct Brc20Deploy {
-    pub max: f64,
-    pub lim: f64,
+    pub inscription_id: String,
+    pub max: Amount,
+    pub lim: Amount,
     pub dec: u8,
     pub block_height: u32,
     pub tx_height: u32,
@@ -25,6 +28,7 @@ pub struct Brc20Deploy {
 impl Brc20Deploy {
     pub fn new(
         tx: &GetRawTransactionResult,
+        inscription_id: String,
         inscription: Brc20Inscription,
         block_height: u32,
         tx_height: u32,
@@ -32,8 +36,9 @@ impl Brc20Deploy {
     ) -> Self {
         // populate with default values
         Brc20Deploy {
-            max: 0.0,
-            lim: 0.0,
+            inscription_id,
+            max: Amount::ZERO,
+            lim: Amount::ZERO,
             dec: 18,
             block_height,
             tx_height,
@@ -44,11 +49,11 @@ impl Brc20Deploy {
         }
     }
 
-    pub fn get_max_supply(&self) -> f64 {
+    pub fn get_max_supply(&self) -> Amount {
         self.max
     }
 
-    pub fn get_limit(&self) -> f64 {
+    pub fn get_limit(&self) -> Amount {
         self.lim
     }
 
@@ -71,14 +76,15 @@ impl Brc20Deploy {
 
     pub async fn validate_deploy_script(
         mut self,
-        mongo_client: &MongoClient,
+        store: &dyn Brc20Store,
+        tickers: &HashMap<String, Brc20Ticker>,
         invalid_brc20_docs: &mut Vec<Document>,
     ) -> Result<Self, Box<dyn std::error::Error>> {
         let ticker_symbol = self.inscription.tick.to_lowercase();
         let mut reasons = vec![];
 
         match self
-            .validate_ticker_symbol(&ticker_symbol, mongo_client)
+            .validate_ticker_symbol(&ticker_symbol, store, tickers)
             .await
         {
             Ok(_) => {}
@@ -136,13 +142,16 @@ impl Brc20Deploy {
     async fn validate_ticker_symbol(
         &self,
         ticker_symbol: &String,
-        mongo_client: &MongoClient,
+        store: &dyn Brc20Store,
+        tickers: &HashMap<String, Brc20Ticker>,
     ) -> Result<(), String> {
-        //check if ticker symbol already exists in MongoDB
-        let ticker_exists = mongo_client
-            .ticker_exists(consts::COLLECTION_TICKERS, doc! { "tick": ticker_symbol })
-            .await
-            .map_err(|e| e.to_string())?; // Convert mongodb::error::Error to String
+        //check if ticker symbol was already deployed in this block or before
+        let ticker_exists = tickers.contains_key(ticker_symbol)
+            || store
+                .get_ticker(ticker_symbol)
+                .await
+                .map_err(|e| e.to_string())?
+                .is_some();
 
         if ticker_exists {
             Err("Ticker symbol already exists".to_string())
@@ -172,11 +181,11 @@ impl Brc20Deploy {
         Ok(())
     }
 
-    fn validate_max_field(&self) -> Result<f64, String> {
+    fn validate_max_field(&self) -> Result<Amount, String> {
         match &self.inscription.max {
-            Some(max_str) => match convert_to_float(max_str, self.dec) {
+            Some(max_str) => match convert_to_amount(max_str, self.dec) {
                 Ok(max) => {
-                    if max > 0.0 && decimal_places(max) <= self.dec.into() {
+                    if !max.is_zero() {
                         Ok(max)
                     } else {
                         Err("Max supply must be greater than 0 and the number of decimal places must not exceed the decimal value.".to_string())
@@ -188,11 +197,11 @@ impl Brc20Deploy {
         }
     }
 
-    fn validate_limit_field(&self, max: f64) -> Result<f64, String> {
+    fn validate_limit_field(&self, max: Amount) -> Result<Amount, String> {
         match &self.inscription.lim {
-            Some(lim_str) => match convert_to_float(lim_str, self.dec) {
+            Some(lim_str) => match convert_to_amount(lim_str, self.dec) {
                 Ok(limit) => {
-                    if limit <= max && decimal_places(limit) <= self.dec.into() {
+                    if limit <= max {
                         Ok(limit)
                     } else {
                         Err("Limit must be less than or equal to max supply and the number of decimal places must not exceed the decimal value.".to_string())
@@ -208,8 +217,9 @@ impl Brc20Deploy {
 impl ToDocument for Brc20Deploy {
     fn to_document(&self) -> Document {
         doc! {
-            "max": &self.max.to_string(),
-            "lim": &self.lim,
+            "inscription_id": &self.inscription_id,
+            "max": self.max,
+            "lim": self.lim,
             "dec": &self.dec.to_string(),
             "block_height": &self.block_height,
             "tx_height": &self.tx_height,
@@ -223,41 +233,36 @@ impl ToDocument for Brc20Deploy {
 }
 
 pub async fn handle_deploy_operation(
-    mongo_client: &MongoClient,
+    store: &dyn Brc20Store,
+    inscription_id: String,
     inscription: Brc20Inscription,
     raw_tx: &GetRawTransactionResult,
     owner: Address,
     block_height: u32,
     tx_height: u32,
+    tickers: &mut HashMap<String, Brc20Ticker>,
     invalid_brc20_docs: &mut Vec<Document>,
 ) -> Result<Brc20Deploy, Box<dyn std::error::Error>> {
     // if invalid vaiidate_deploy_script handles and adds invalid to mongodb
-    let validated_deploy_tx = Brc20Deploy::new(raw_tx, inscription, block_height, tx_height, owner)
-        .validate_deploy_script(&mongo_client, invalid_brc20_docs)
-        .await?;
+    let validated_deploy_tx = Brc20Deploy::new(
+        raw_tx,
+        inscription_id,
+        inscription,
+        block_height,
+        tx_height,
+        owner,
+    )
+    .validate_deploy_script(store, tickers, invalid_brc20_docs)
+    .await?;
 
     if validated_deploy_tx.is_valid() {
         info!("VALID Deploy: {}", validated_deploy_tx.inscription);
 
-        // A valid deploy means new BRC20Ticker to MongoDB
+        // A valid deploy means new BRC20Ticker, written to the store with the block
         // Instantiate a new `Brc20Ticker` struct and update the hashmap with the deploy information.
-        let ticker = Brc20Ticker::new(validated_deploy_tx.clone());
-
-        // Insert ticker into MongoDB
-        mongo_client
-            .insert_document(consts::COLLECTION_TICKERS, ticker.to_document())
-            .await?;
+        let ticker = Brc20Ticker::new(&validated_deploy_tx);
+        tickers.insert(ticker.get_ticker(), ticker);
     }
 
     Ok(validated_deploy_tx)
 }
-
-// A helper function to find out the decimal places of the given float
-fn decimal_places(num: f64) -> u32 {
-    let s = num.to_string();
-    if let Some(pos) = s.find('.') {
-        s[pos + 1..].len() as u32
-    } else {
-        0
-    }
-}
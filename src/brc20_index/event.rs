@@ -0,0 +1,317 @@
+use super::{
+    amount::{get_amount, Amount},
+    store::get_integer,
+    ToDocument,
+};
+use mongodb::bson::{doc, Document};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// What a logged event did to the BRC-20 state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Brc20EventType {
+    Deploy,
+    Mint,
+    InscribeTransfer,
+    TransferSend,
+    // a transfer inscription spent as fee, the amount is available to the sender again
+    TransferReturn,
+    // a deploy, mint or transfer inscription rejected with a reason
+    Invalid,
+}
+
+impl fmt::Display for Brc20EventType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Brc20EventType::Deploy => write!(f, "deploy"),
+            Brc20EventType::Mint => write!(f, "mint"),
+            Brc20EventType::InscribeTransfer => write!(f, "inscribe_transfer"),
+            Brc20EventType::TransferSend => write!(f, "transfer_send"),
+            Brc20EventType::TransferReturn => write!(f, "transfer_return"),
+            Brc20EventType::Invalid => write!(f, "invalid"),
+        }
+    }
+}
+
+impl FromStr for Brc20EventType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "deploy" => Ok(Brc20EventType::Deploy),
+            "mint" => Ok(Brc20EventType::Mint),
+            "inscribe_transfer" => Ok(Brc20EventType::InscribeTransfer),
+            "transfer_send" => Ok(Brc20EventType::TransferSend),
+            "transfer_return" => Ok(Brc20EventType::TransferReturn),
+            "invalid" => Ok(Brc20EventType::Invalid),
+            other => Err(format!("Invalid event type: {}", other)),
+        }
+    }
+}
+
+/// An entry of the event log, every change to the BRC-20 state in the order it
+/// happened.
+///
+/// Events are only appended, a rollback removes the events of the blocks it
+/// removes. Their ids sort in the order the events happened, see `event_id`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Brc20Event {
+    pub id: String,
+    pub event_type: Brc20EventType,
+    pub block_height: i64,
+    pub tx_index: i64,
+    pub txid: String,
+    // input spending the transfer inscription, for sends and returns
+    pub input_index: Option<i64>,
+    // output holding the inscription afterwards, when it's in this transaction
+    pub output_index: Option<i64>,
+    pub inscription_id: String,
+    pub tick: String,
+    pub amount: Option<Amount>,
+    pub from: Option<String>,
+    pub to: Option<String>,
+    // why an invalid inscription was rejected
+    pub reason: Option<String>,
+}
+
+impl Brc20Event {
+    /// An event of the `inscription_index`th inscription revealed by a
+    /// transaction, the output holding it is set by the caller.
+    pub fn inscribed(
+        event_type: Brc20EventType,
+        block_height: i64,
+        tx_index: i64,
+        txid: &str,
+        inscription_index: i64,
+        inscription_id: &str,
+        tick: &str,
+    ) -> Self {
+        Brc20Event {
+            id: event_id(
+                block_height,
+                tx_index,
+                'r',
+                inscription_index,
+                inscription_id,
+            ),
+            output_index: None,
+            input_index: None,
+            ..Brc20Event::new(
+                event_type,
+                block_height,
+                tx_index,
+                txid,
+                inscription_id,
+                tick,
+            )
+        }
+    }
+
+    /// An event of the transfer inscription spent by `input_index` of a transaction.
+    pub fn spent(
+        event_type: Brc20EventType,
+        block_height: i64,
+        tx_index: i64,
+        txid: &str,
+        input_index: i64,
+        inscription_id: &str,
+        tick: &str,
+    ) -> Self {
+        Brc20Event {
+            id: event_id(block_height, tx_index, 'i', input_index, inscription_id),
+            input_index: Some(input_index),
+            output_index: None,
+            ..Brc20Event::new(
+                event_type,
+                block_height,
+                tx_index,
+                txid,
+                inscription_id,
+                tick,
+            )
+        }
+    }
+
+    fn new(
+        event_type: Brc20EventType,
+        block_height: i64,
+        tx_index: i64,
+        txid: &str,
+        inscription_id: &str,
+        tick: &str,
+    ) -> Self {
+        Brc20Event {
+            id: String::new(),
+            event_type,
+            block_height,
+            tx_index,
+            txid: txid.to_string(),
+            input_index: None,
+            output_index: None,
+            inscription_id: inscription_id.to_string(),
+            tick: tick.to_lowercase(),
+            amount: None,
+            from: None,
+            to: None,
+            reason: None,
+        }
+    }
+
+    pub fn from_document(document: &Document) -> Result<Self, String> {
+        let get_string = |field: &str| {
+            document
+                .get_str(field)
+                .map(str::to_string)
+                .map_err(|_| format!("Invalid {}", field))
+        };
+        let get_optional_string = |field: &str| document.get_str(field).ok().map(str::to_string);
+
+        Ok(Brc20Event {
+            id: get_string("id")?,
+            event_type: get_string("event_type")?.parse()?,
+            block_height: get_integer(document, "block_height")
+                .ok_or_else(|| "Invalid block_height".to_string())?,
+            tx_index: get_integer(document, "tx_index")
+                .ok_or_else(|| "Invalid tx_index".to_string())?,
+            txid: get_string("txid")?,
+            input_index: get_integer(document, "input_index"),
+            output_index: get_integer(document, "output_index"),
+            inscription_id: get_string("inscription_id")?,
+            tick: get_string("tick")?,
+            amount: get_amount(document, "amount"),
+            from: get_optional_string("from"),
+            to: get_optional_string("to"),
+            reason: get_optional_string("reason"),
+        })
+    }
+}
+
+impl ToDocument for Brc20Event {
+    fn to_document(&self) -> Document {
+        doc! {
+            "id": &self.id,
+            "event_type": self.event_type.to_string(),
+            "block_height": self.block_height,
+            "tx_index": self.tx_index,
+            "txid": &self.txid,
+            "input_index": self.input_index,
+            "output_index": self.output_index,
+            "inscription_id": &self.inscription_id,
+            "tick": &self.tick,
+            "amount": self.amount,
+            "from": self.from.clone(),
+            "to": self.to.clone(),
+            "reason": self.reason.clone(),
+        }
+    }
+}
+
+/// Events of some tickers, addresses or event types, every event for a list left empty.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct EventFilter {
+    #[serde(default)]
+    pub ticks: Vec<String>,
+    // events from or to one of these addresses
+    #[serde(default)]
+    pub addresses: Vec<String>,
+    #[serde(default)]
+    pub event_types: Vec<Brc20EventType>,
+}
+
+impl EventFilter {
+    /// Whether the event passes every list of the filter.
+    pub fn matches(&self, event: &Brc20Event) -> bool {
+        let tick = self.ticks.is_empty()
+            || self
+                .ticks
+                .iter()
+                .any(|tick| tick.to_lowercase() == event.tick);
+        let address = self.addresses.is_empty()
+            || [&event.from, &event.to]
+                .iter()
+                .filter_map(|address| address.as_ref())
+                .any(|address| self.addresses.contains(address));
+        let event_type =
+            self.event_types.is_empty() || self.event_types.contains(&event.event_type);
+
+        tick && address && event_type
+    }
+}
+
+/// Id of an event: the block height, the transaction index and the index of
+/// the input spending the inscription or of the inscription among those the
+/// transaction reveals, zero-padded so ids sort in the order events happened,
+/// and the inscription id.
+///
+/// Inputs are spent before a transaction inscribes anything, so `i` inputs sort
+/// before `r` reveals, e.g. `0000779832:000001:r000000:<inscription id>`.
+pub fn event_id(
+    block_height: i64,
+    tx_index: i64,
+    input_or_reveal: char,
+    index: i64,
+    inscription_id: &str,
+) -> String {
+    format!(
+        "{:010}:{:06}:{}{:06}:{}",
+        block_height, tx_index, input_or_reveal, index, inscription_id
+    )
+}
+
+/// Lowest id of the events of a block, events at or above a height have ids from it.
+pub fn block_event_id(block_height: i64) -> String {
+    format!("{:010}:", block_height)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_ids_sort_in_event_order() {
+        let send = Brc20Event::spent(
+            Brc20EventType::TransferSend,
+            779833,
+            2,
+            "tx",
+            1,
+            "ai0",
+            "ORDI",
+        );
+        let mint = Brc20Event::inscribed(Brc20EventType::Mint, 779833, 2, "tx", 2, "txi2", "ordi");
+        // revealed after the mint, whatever output it lands in
+        let next =
+            Brc20Event::inscribed(Brc20EventType::Mint, 779833, 2, "tx", 10, "txi10", "ordi");
+        let later = Brc20Event::inscribed(Brc20EventType::Mint, 779833, 10, "tx", 0, "bi0", "ordi");
+        assert_eq!(send.id, "0000779833:000002:i000001:ai0");
+        assert_eq!(mint.id, "0000779833:000002:r000002:txi2");
+        assert_eq!(send.tick, "ordi");
+        assert!(block_event_id(779833) < send.id);
+        assert!(send.id < mint.id);
+        assert!(mint.id < next.id);
+        assert!(next.id < later.id);
+        assert!(later.id < block_event_id(779834));
+    }
+
+    #[test]
+    fn test_event_document_round_trip() {
+        let event = Brc20Event {
+            amount: Some(Amount::parse("400", 18).unwrap()),
+            from: Some("bc1qsender".to_string()),
+            to: Some("bc1qreceiver".to_string()),
+            ..Brc20Event::spent(
+                Brc20EventType::TransferSend,
+                779833,
+                2,
+                "tx",
+                1,
+                "ai0",
+                "ordi",
+            )
+        };
+
+        assert_eq!(Brc20Event::from_document(&event.to_document()), Ok(event));
+    }
+}
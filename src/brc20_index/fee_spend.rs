@@ -0,0 +1,149 @@
+use super::{satpoint::locate_offset, utils::transaction_inputs_to_values};
+use bitcoin::{Address, Block, Network, Transaction};
+use bitcoincore_rpc::RpcApi;
+use std::fmt;
+use std::str::FromStr;
+
+const INITIAL_SUBSIDY: u64 = 50 * 100_000_000;
+const SUBSIDY_HALVING_INTERVAL: u64 = 210_000;
+
+/// What happens to a transfer inscription whose sat is spent as fee.
+///
+/// Set with `BRC20_FEE_SPEND_POLICY`, `return_to_sender` by default.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FeeSpendPolicy {
+    /// The transfer is cancelled and its amount is available to the sender again.
+    #[default]
+    ReturnToSender,
+    /// The sat is followed into the coinbase and the miner's output receives the
+    /// transfer. It is returned to the sender when the sat isn't claimed by any
+    /// coinbase output or the output has no address.
+    Coinbase,
+}
+
+impl fmt::Display for FeeSpendPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FeeSpendPolicy::ReturnToSender => write!(f, "return_to_sender"),
+            FeeSpendPolicy::Coinbase => write!(f, "coinbase"),
+        }
+    }
+}
+
+impl FromStr for FeeSpendPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "return_to_sender" => Ok(FeeSpendPolicy::ReturnToSender),
+            "coinbase" => Ok(FeeSpendPolicy::Coinbase),
+            other => Err(format!("Invalid fee spend policy: {}", other)),
+        }
+    }
+}
+
+/// New sats created by the block at `block_height`.
+pub fn block_subsidy(block_height: u64) -> u64 {
+    let halvings = block_height / SUBSIDY_HALVING_INTERVAL;
+    if halvings >= 64 {
+        return 0;
+    }
+    INITIAL_SUBSIDY >> halvings
+}
+
+/// Sat ranges assigned to the coinbase outputs of a block.
+///
+/// The coinbase outputs hold the subsidy first, followed by the fees of every
+/// transaction in block order. Fees are computed over RPC, only up to the
+/// transaction that spends an inscription as fee.
+pub struct CoinbaseSats<'a> {
+    txdata: &'a [Transaction],
+    subsidy: u64,
+    // fees of txdata[1..], in block order
+    fees: Vec<u64>,
+}
+
+impl<'a> CoinbaseSats<'a> {
+    pub fn new(block: &'a Block, block_height: u64) -> Self {
+        CoinbaseSats {
+            txdata: &block.txdata,
+            subsidy: block_subsidy(block_height),
+            fees: Vec::new(),
+        }
+    }
+
+    /// Finds the coinbase output holding the sat at `fee_offset` of the fees paid by
+    /// transaction `tx_index` of the block, `None` if the miner didn't claim it.
+    pub fn locate(
+        &mut self,
+        rpc: &impl RpcApi,
+        tx_index: usize,
+        fee_offset: u64,
+    ) -> anyhow::Result<Option<(usize, u64)>> {
+        let coinbase = self
+            .txdata
+            .first()
+            .ok_or_else(|| anyhow::anyhow!("Block has no coinbase"))?;
+        if tx_index == 0 || tx_index >= self.txdata.len() {
+            return Err(anyhow::anyhow!("No fee paying transaction at {}", tx_index));
+        }
+
+        while self.fees.len() + 1 < tx_index {
+            let transaction = &self.txdata[self.fees.len() + 1];
+            let input_value: u64 = transaction_inputs_to_values(rpc, &transaction.input)?
+                .iter()
+                .sum();
+            let output_value: u64 = transaction.output.iter().map(|output| output.value).sum();
+            self.fees.push(input_value.saturating_sub(output_value));
+        }
+
+        let offset = self.subsidy + self.fees[..tx_index - 1].iter().sum::<u64>() + fee_offset;
+        let output_values: Vec<u64> = coinbase.output.iter().map(|output| output.value).collect();
+
+        Ok(locate_offset(&output_values, offset))
+    }
+
+    pub fn coinbase_txid(&self) -> Option<String> {
+        self.txdata
+            .first()
+            .map(|coinbase| coinbase.txid().to_string())
+    }
+
+    /// Address of a coinbase output, `None` for scripts without one.
+    pub fn owner_of_vout(&self, vout: usize) -> Option<Address> {
+        let output = self.txdata.first()?.output.get(vout)?;
+        Address::from_script(&output.script_pubkey, Network::Bitcoin).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brc20_index::{memory::MemoryStore, testing};
+
+    #[test]
+    fn test_block_subsidy() {
+        assert_eq!(block_subsidy(0), 50 * 100_000_000);
+        assert_eq!(block_subsidy(779_832), 625_000_000);
+        assert_eq!(block_subsidy(840_000), 312_500_000);
+        assert_eq!(block_subsidy(64 * 210_000), 0);
+    }
+
+    #[test]
+    fn test_parse_fee_spend_policy() {
+        assert_eq!(
+            "coinbase".parse::<FeeSpendPolicy>(),
+            Ok(FeeSpendPolicy::Coinbase)
+        );
+        assert_eq!(
+            "Return_To_Sender".parse::<FeeSpendPolicy>(),
+            Ok(FeeSpendPolicy::ReturnToSender)
+        );
+        assert!("burn".parse::<FeeSpendPolicy>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_transfer_spent_as_fee_goes_to_the_coinbase() {
+        testing::check_transfer_spent_as_fee_to_coinbase(&MemoryStore::new()).await;
+    }
+}
@@ -0,0 +1,186 @@
+use super::{
+    store::Brc20Store,
+    user_balance::{UserBalance, UserBalanceEntry},
+    utils::apply_user_balance_entry,
+};
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// A block height asked about that isn't completed yet, its entries may still be
+/// incomplete or not exist at all.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BlockNotCompleted(pub i64);
+
+impl fmt::Display for BlockNotCompleted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Block not completed: {}", self.0)
+    }
+}
+
+impl std::error::Error for BlockNotCompleted {}
+
+// fails with `BlockNotCompleted` past the last completed block
+async fn check_block_completed(store: &dyn Brc20Store, block_height: i64) -> anyhow::Result<()> {
+    match store.get_last_completed_block_height().await? {
+        Some(last_completed) if block_height <= last_completed => Ok(()),
+        _ => Err(BlockNotCompleted(block_height).into()),
+    }
+}
+
+/// Gets the balances of an address for every ticker as they were once a block was
+/// indexed, ordered by ticker.
+///
+/// Balances are folded from the entries indexed up to and including the block,
+/// the same way a rollback rebuilds them. Tickers the address got after the block
+/// aren't listed. Fails with `BlockNotCompleted` past the last completed block.
+pub async fn get_user_balances_at(
+    store: &dyn Brc20Store,
+    address: &str,
+    block_height: i64,
+) -> anyhow::Result<Vec<UserBalance>> {
+    check_block_completed(store, block_height).await?;
+    let entries = store
+        .get_user_balance_entries_until(Some(address), None, block_height)
+        .await?;
+
+    Ok(fold_user_balance_entries(&entries)?.into_values().collect())
+}
+
+/// Gets the holders of a ticker as they were once a block was indexed, ordered by
+/// address. Like the current holders, balances that went back to zero aren't listed.
+/// Fails with `BlockNotCompleted` past the last completed block.
+pub async fn get_holders_at(
+    store: &dyn Brc20Store,
+    tick: &str,
+    block_height: i64,
+) -> anyhow::Result<Vec<UserBalance>> {
+    check_block_completed(store, block_height).await?;
+    let entries = store
+        .get_user_balance_entries_until(None, Some(tick), block_height)
+        .await?;
+
+    Ok(fold_user_balance_entries(&entries)?
+        .into_values()
+        .filter(|user_balance| !user_balance.is_zero())
+        .collect())
+}
+
+/// Gets the holders of every ticker as they were once a block was indexed, ordered
+/// by ticker and then address. Fails with `BlockNotCompleted` past the last
+/// completed block.
+pub async fn get_all_holders_at(
+    store: &dyn Brc20Store,
+    block_height: i64,
+) -> anyhow::Result<Vec<UserBalance>> {
+    check_block_completed(store, block_height).await?;
+    let entries = store
+        .get_user_balance_entries_until(None, None, block_height)
+        .await?;
+
+    let mut holders: Vec<UserBalance> = fold_user_balance_entries(&entries)?
+        .into_values()
+        .filter(|user_balance| !user_balance.is_zero())
+        .collect();
+    holders.sort_by(|a, b| (&a.tick, &a.address).cmp(&(&b.tick, &b.address)));
+
+    Ok(holders)
+}
+
+/// Folds balance entries into balances by (address, tick), each balance at the
+/// height of its last entry.
+pub fn fold_user_balance_entries(
+    entries: &[UserBalanceEntry],
+) -> anyhow::Result<BTreeMap<(String, String), UserBalance>> {
+    let mut user_balances = BTreeMap::new();
+    for entry in entries {
+        let user_balance = user_balances
+            .entry((entry.address.clone(), entry.tick.clone()))
+            .or_insert_with(|| {
+                UserBalance::new(
+                    entry.address.clone(),
+                    entry.tick.clone(),
+                    entry.block_height,
+                )
+            });
+        apply_user_balance_entry(user_balance, entry)?;
+    }
+
+    Ok(user_balances)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brc20_index::{
+        amount::Amount,
+        consts,
+        memory::MemoryStore,
+        testing::{index_chain, transfer_chain},
+    };
+
+    #[tokio::test]
+    async fn test_balances_before_and_after_send() {
+        let (chain, alice, bob) = transfer_chain();
+        let store = MemoryStore::new();
+        index_chain(&chain, &store).await;
+
+        // the transfer is inscribed in the second block and sent in the third
+        let inscribed_height = consts::BRC20_STARTING_BLOCK_HEIGHT + 1;
+        let alice_balances = get_user_balances_at(&store, &alice, inscribed_height)
+            .await
+            .unwrap();
+        assert_eq!(alice_balances.len(), 1);
+        assert_eq!(
+            alice_balances[0].overall_balance,
+            Amount::parse("1000", 18).unwrap()
+        );
+        assert_eq!(
+            alice_balances[0].transferable_balance,
+            Amount::parse("400", 18).unwrap()
+        );
+
+        let holders = get_holders_at(&store, "ordi", inscribed_height)
+            .await
+            .unwrap();
+        assert_eq!(holders.len(), 1);
+        assert_eq!(holders[0].address, alice);
+
+        // at the tip the history matches the current balances
+        let tip_height = i64::from(chain.tip_height());
+        let holders = get_holders_at(&store, "ordi", tip_height).await.unwrap();
+        assert_eq!(holders.len(), 2);
+        for holder in holders {
+            let current = store
+                .get_user_balance(&holder.address, "ordi")
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(holder.overall_balance, current.overall_balance);
+            assert_eq!(holder.available_balance, current.available_balance);
+            assert_eq!(holder.transferable_balance, current.transferable_balance);
+        }
+
+        assert!(get_user_balances_at(&store, &bob, inscribed_height)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_balances_past_the_last_completed_block() {
+        let (chain, alice, _) = transfer_chain();
+        let store = MemoryStore::new();
+        index_chain(&chain, &store).await;
+
+        let block_height = i64::from(chain.tip_height()) + 1;
+        let error = get_user_balances_at(&store, &alice, block_height)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<BlockNotCompleted>(),
+            Some(&BlockNotCompleted(block_height))
+        );
+        assert!(get_holders_at(&store, "ordi", block_height).await.is_err());
+        assert!(get_all_holders_at(&store, block_height).await.is_err());
+    }
+}
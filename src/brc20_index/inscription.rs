@@ -0,0 +1,319 @@
+use bitcoin::blockdata::opcodes::all::{OP_ENDIF, OP_IF, OP_PUSHNUM_1, OP_PUSHNUM_16};
+use bitcoin::blockdata::script::{Instruction, Script};
+use bitcoin::Witness;
+
+const PROTOCOL_ID: &[u8] = b"ord";
+const TAPROOT_ANNEX_PREFIX: u8 = 0x50;
+
+// envelope field tags, an empty push starts the body
+const CONTENT_TYPE_TAG: u8 = 1;
+const POINTER_TAG: u8 = 2;
+const PARENT_TAG: u8 = 3;
+const METADATA_TAG: u8 = 5;
+const CONTENT_ENCODING_TAG: u8 = 9;
+
+/// An inscription decoded from an `OP_FALSE OP_IF "ord" ... OP_ENDIF` envelope.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Inscription {
+    pub content_type: Option<String>,
+    pub content_encoding: Option<String>,
+    pub pointer: Option<u64>,
+    pub parent: Option<String>,
+    pub metadata: Option<Vec<u8>>,
+    pub body: Option<Vec<u8>>,
+    // an even tag we don't know about, ord doesn't bind these inscriptions normally
+    pub unrecognized_even_field: bool,
+}
+
+impl Inscription {
+    /// Parses every inscription envelope in the tapscript of a script path spend.
+    pub fn from_witness(witness: &Witness) -> Vec<Inscription> {
+        match tapscript(witness) {
+            Some(script) => Inscription::from_tapscript(script),
+            None => Vec::new(),
+        }
+    }
+
+    /// Parses every inscription envelope in a tapscript, in order of appearance.
+    pub fn from_tapscript(script: &Script) -> Vec<Inscription> {
+        // a malformed push ends the script, envelopes before it are still valid
+        let instructions: Vec<Instruction> = script.instructions().map_while(Result::ok).collect();
+
+        let mut inscriptions = Vec::new();
+        let mut index = 0;
+        while index + 2 < instructions.len() {
+            let is_envelope_start = push_bytes(&instructions[index]) == Some(Vec::new())
+                && instructions[index + 1] == Instruction::Op(OP_IF)
+                && push_bytes(&instructions[index + 2]).as_deref() == Some(PROTOCOL_ID);
+
+            if !is_envelope_start {
+                index += 1;
+                continue;
+            }
+
+            // collect the payload pushes up to OP_ENDIF
+            let mut payload = Vec::new();
+            let mut end = None;
+            for (offset, instruction) in instructions[index + 3..].iter().enumerate() {
+                if *instruction == Instruction::Op(OP_ENDIF) {
+                    end = Some(index + 3 + offset);
+                    break;
+                }
+                match push_bytes(instruction) {
+                    Some(bytes) => payload.push(bytes),
+                    None => break,
+                }
+            }
+
+            match end {
+                Some(end) => {
+                    inscriptions.push(Inscription::from_payload(payload));
+                    index = end + 1;
+                }
+                // not terminated, or contains opcodes other than pushes
+                None => index += 1,
+            }
+        }
+
+        inscriptions
+    }
+
+    fn from_payload(payload: Vec<Vec<u8>>) -> Inscription {
+        let mut inscription = Inscription::default();
+        let mut metadata: Option<Vec<u8>> = None;
+
+        let mut pushes = payload.into_iter();
+        while let Some(tag) = pushes.next() {
+            // an empty push starts the body, all remaining pushes are concatenated
+            if tag.is_empty() {
+                inscription.body = Some(pushes.by_ref().flatten().collect());
+                break;
+            }
+
+            let value = match pushes.next() {
+                Some(value) => value,
+                // a tag without a value is ignored
+                None => break,
+            };
+
+            match tag.as_slice() {
+                [CONTENT_TYPE_TAG] => {
+                    inscription
+                        .content_type
+                        .get_or_insert_with(|| String::from_utf8_lossy(&value).into_owned());
+                }
+                [CONTENT_ENCODING_TAG] => {
+                    inscription
+                        .content_encoding
+                        .get_or_insert_with(|| String::from_utf8_lossy(&value).into_owned());
+                }
+                [POINTER_TAG] => {
+                    if inscription.pointer.is_none() {
+                        inscription.pointer = decode_pointer(&value);
+                    }
+                }
+                [PARENT_TAG] => {
+                    if inscription.parent.is_none() {
+                        inscription.parent = decode_inscription_id(&value);
+                    }
+                }
+                // metadata may be split over several pushes
+                [METADATA_TAG] => metadata.get_or_insert_with(Vec::new).extend(value),
+                // tags are little endian, the first byte decides the parity
+                tag if tag.first().is_some_and(|byte| byte % 2 == 0) => {
+                    inscription.unrecognized_even_field = true
+                }
+                _ => {}
+            }
+        }
+
+        inscription.metadata = metadata;
+        inscription
+    }
+
+    /// The content type without parameters, e.g. `text/plain` for `text/plain;charset=utf-8`.
+    pub fn media_type(&self) -> Option<&str> {
+        self.content_type
+            .as_deref()
+            .map(|content_type| content_type.split(';').next().unwrap_or_default().trim())
+    }
+}
+
+/// An inscription revealed by a transaction input and the sat it was inscribed on.
+#[derive(Debug, Clone)]
+pub struct RevealedInscription {
+    /// `<txid>i<n>`, where n counts envelopes over all inputs of the transaction
+    pub id: String,
+    /// n of the id, the order the transaction reveals its inscriptions in
+    pub index: usize,
+    pub input_index: usize,
+    /// output index and offset within that output, `None` if the sat went to fees
+    pub location: Option<(usize, u64)>,
+    pub inscription: Inscription,
+}
+
+// the tapscript is the second to last element of a script path spend, ignoring the annex
+fn tapscript(witness: &Witness) -> Option<&Script> {
+    let last = witness.last()?;
+    let script_index = if witness.len() >= 2 && last.first() == Some(&TAPROOT_ANNEX_PREFIX) {
+        witness.len().checked_sub(3)?
+    } else {
+        witness.len().checked_sub(2)?
+    };
+
+    witness.nth(script_index).map(Script::from_bytes)
+}
+
+// data pushes, OP_1 through OP_16 count as single byte pushes
+fn push_bytes(instruction: &Instruction) -> Option<Vec<u8>> {
+    match instruction {
+        Instruction::PushBytes(bytes) => Some(bytes.as_bytes().to_vec()),
+        Instruction::Op(op)
+            if op.to_u8() >= OP_PUSHNUM_1.to_u8() && op.to_u8() <= OP_PUSHNUM_16.to_u8() =>
+        {
+            Some(vec![op.to_u8() - OP_PUSHNUM_1.to_u8() + 1])
+        }
+        _ => None,
+    }
+}
+
+// little endian integer, trailing zero bytes are allowed
+fn decode_pointer(value: &[u8]) -> Option<u64> {
+    if value.iter().skip(8).any(|byte| *byte != 0) {
+        return None;
+    }
+
+    let mut pointer = 0u64;
+    for (index, byte) in value.iter().take(8).enumerate() {
+        pointer |= u64::from(*byte) << (8 * index);
+    }
+    Some(pointer)
+}
+
+// 32 byte txid in internal byte order followed by a little endian index
+fn decode_inscription_id(value: &[u8]) -> Option<String> {
+    if value.len() < 32 || value.len() > 36 {
+        return None;
+    }
+
+    let mut txid = value[..32].to_vec();
+    txid.reverse();
+
+    let mut index = 0u32;
+    for (position, byte) in value[32..].iter().enumerate() {
+        index |= u32::from(*byte) << (8 * position);
+    }
+
+    Some(format!("{}i{}", hex::encode(txid), index))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::blockdata::opcodes::OP_FALSE;
+    use bitcoin::blockdata::script::{Builder, PushBytes, ScriptBuf};
+
+    fn envelope(pushes: &[&[u8]]) -> ScriptBuf {
+        let mut builder = Builder::new()
+            .push_opcode(OP_FALSE)
+            .push_opcode(OP_IF)
+            .push_slice(b"ord");
+        for push in pushes {
+            let data: &PushBytes = (*push).try_into().unwrap();
+            builder = builder.push_slice(data);
+        }
+        builder.push_opcode(OP_ENDIF).into_script()
+    }
+
+    #[test]
+    fn test_parse_content_type_and_body() {
+        let script = envelope(&[
+            &[1],
+            b"text/plain;charset=utf-8",
+            &[],
+            b"{\"p\":",
+            b"\"brc-20\"}",
+        ]);
+        let inscriptions = Inscription::from_tapscript(&script);
+
+        assert_eq!(inscriptions.len(), 1);
+        assert_eq!(inscriptions[0].media_type(), Some("text/plain"));
+        assert_eq!(
+            inscriptions[0].body.as_deref(),
+            Some(&b"{\"p\":\"brc-20\"}"[..])
+        );
+    }
+
+    #[test]
+    fn test_parse_tag_fields() {
+        let mut parent = vec![0xab; 32];
+        parent.push(1);
+        let script = envelope(&[
+            &[2],
+            &[0x10, 0x27],
+            &[3],
+            &parent,
+            &[9],
+            b"br",
+            &[5],
+            &[0xa0],
+            &[],
+        ]);
+        let inscription = &Inscription::from_tapscript(&script)[0];
+
+        assert_eq!(inscription.pointer, Some(10000));
+        assert_eq!(inscription.parent, Some(format!("{}i1", "ab".repeat(32))));
+        assert_eq!(inscription.content_encoding.as_deref(), Some("br"));
+        assert_eq!(inscription.metadata, Some(vec![0xa0]));
+        assert_eq!(inscription.body, Some(Vec::new()));
+    }
+
+    #[test]
+    fn test_flag_unrecognized_even_field() {
+        let script = envelope(&[&[1], b"text/plain", &[11], b"x", &[], b"a"]);
+        assert!(!Inscription::from_tapscript(&script)[0].unrecognized_even_field);
+
+        let script = envelope(&[&[1], b"text/plain", &[22], b"x", &[], b"a"]);
+        assert!(Inscription::from_tapscript(&script)[0].unrecognized_even_field);
+
+        let script = envelope(&[&[1], b"text/plain", &[0x02, 0x01], b"x", &[], b"a"]);
+        assert!(Inscription::from_tapscript(&script)[0].unrecognized_even_field);
+
+        let script = envelope(&[&[1], b"text/plain", &[0x03, 0x01], b"x", &[], b"a"]);
+        assert!(!Inscription::from_tapscript(&script)[0].unrecognized_even_field);
+    }
+
+    #[test]
+    fn test_ignore_non_ord_envelope() {
+        let script = Builder::new()
+            .push_opcode(OP_FALSE)
+            .push_opcode(OP_IF)
+            .push_slice(b"xyz")
+            .push_opcode(OP_ENDIF)
+            .into_script();
+
+        assert!(Inscription::from_tapscript(&script).is_empty());
+    }
+
+    #[test]
+    fn test_ignore_unterminated_envelope() {
+        let script = Builder::new()
+            .push_opcode(OP_FALSE)
+            .push_opcode(OP_IF)
+            .push_slice(b"ord")
+            .push_slice(b"text/plain")
+            .into_script();
+
+        assert!(Inscription::from_tapscript(&script).is_empty());
+    }
+
+    #[test]
+    fn test_parse_multiple_envelopes() {
+        let mut bytes = envelope(&[&[1], b"text/plain", &[], b"a"]).into_bytes();
+        bytes.extend(envelope(&[&[1], b"text/plain", &[], b"b"]).into_bytes());
+        let inscriptions = Inscription::from_tapscript(Script::from_bytes(&bytes));
+
+        assert_eq!(inscriptions.len(), 2);
+        assert_eq!(inscriptions[1].body.as_deref(), Some(&b"b"[..]));
+    }
+}
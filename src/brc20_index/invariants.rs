@@ -0,0 +1,221 @@
+use super::{
+    amount::{get_amount, Amount},
+    store::{BlockUpdate, Brc20Store},
+    user_balance::{UserBalance, UserBalanceEntry},
+    utils::apply_user_balance_entry,
+    verify::Violation,
+};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Checks a block's changes against the store before they're written and returns
+/// the invariants they break.
+///
+/// Every balance the block touches must be the stored balance with the block's
+/// entries applied, without going negative, and its available and transferable
+/// balances must add up to its overall balance. Every ticker the block changes or
+/// mints must stay within its max supply, its total minted growing by exactly the
+/// amounts minted in the block.
+pub async fn check_block(
+    store: &dyn Brc20Store,
+    block: &BlockUpdate,
+) -> anyhow::Result<Vec<Violation>> {
+    let mut violations = Vec::new();
+
+    // the block's entries by (address, tick), in the order they were indexed, and
+    // the balances it changed without one
+    let mut entries: BTreeMap<(String, String), Vec<&UserBalanceEntry>> = BTreeMap::new();
+    for entry in &block.user_balance_entries {
+        entries
+            .entry((entry.address.clone(), entry.tick.clone()))
+            .or_default()
+            .push(entry);
+    }
+    for key in block
+        .updated_user_balances
+        .keys()
+        .chain(block.new_user_balances.keys())
+    {
+        entries.entry(key.clone()).or_default();
+    }
+
+    for ((address, tick), entries) in entries {
+        let mut expected = match store.get_user_balance(&address, &tick).await? {
+            Some(user_balance) => user_balance,
+            None => UserBalance::new(address.clone(), tick.clone(), block.block_height as u64),
+        };
+        if let Some(reason) = entries
+            .iter()
+            .find_map(|entry| apply_user_balance_entry(&mut expected, entry).err())
+        {
+            violations.push(Violation::NegativeBalance {
+                address,
+                tick,
+                reason: reason.to_string(),
+            });
+            continue;
+        }
+
+        // balances the block doesn't change stay as stored
+        let key = (address, tick);
+        let balance = block
+            .updated_user_balances
+            .get(&key)
+            .or_else(|| block.new_user_balances.get(&key));
+        let matches = match balance {
+            Some(balance) => same_balances(balance, &expected),
+            None => entries.is_empty(),
+        };
+        if !matches {
+            let (address, tick) = key;
+            violations.push(Violation::BalanceNotEntries {
+                address,
+                tick,
+                balance: balance.cloned(),
+                entries: expected,
+            });
+        }
+    }
+
+    for user_balance in block
+        .updated_user_balances
+        .values()
+        .chain(block.new_user_balances.values())
+    {
+        let available_and_transferable = user_balance
+            .available_balance
+            .checked_add(user_balance.transferable_balance);
+        if available_and_transferable != Some(user_balance.overall_balance) {
+            violations.push(Violation::BalanceMismatch {
+                address: user_balance.address.clone(),
+                tick: user_balance.tick.clone(),
+                overall: user_balance.overall_balance,
+                available: user_balance.available_balance,
+                transferable: user_balance.transferable_balance,
+            });
+        }
+    }
+
+    // amounts minted in the block by ticker
+    let mut minted: BTreeMap<String, Amount> = BTreeMap::new();
+    for mint in &block.mints {
+        let tick = mint.get_document("inscription")?.get_str("tick")?;
+        let amount = minted.entry(tick.to_lowercase()).or_default();
+        *amount = amount
+            .checked_add(get_amount(mint, "amt").unwrap_or_default())
+            .ok_or_else(|| anyhow::anyhow!("Total minted overflow"))?;
+    }
+
+    let ticks: BTreeSet<&String> = block.tickers.keys().chain(minted.keys()).collect();
+    for tick in ticks {
+        let stored = store.get_ticker(tick).await?;
+        let previous = stored
+            .as_ref()
+            .map_or(Amount::ZERO, |ticker| ticker.total_minted);
+        let ticker = match block.tickers.get(tick).or(stored.as_ref()) {
+            Some(ticker) => ticker,
+            None => {
+                // minted without being deployed, the mints are all there is
+                violations.push(Violation::TotalMintedNotMints {
+                    tick: tick.clone(),
+                    previous,
+                    minted: minted[tick],
+                    total_minted: Amount::ZERO,
+                });
+                continue;
+            }
+        };
+
+        let minted = minted.get(tick).copied().unwrap_or_default();
+        if previous.checked_add(minted) != Some(ticker.total_minted) {
+            violations.push(Violation::TotalMintedNotMints {
+                tick: tick.clone(),
+                previous,
+                minted,
+                total_minted: ticker.total_minted,
+            });
+        }
+
+        if ticker.total_minted > ticker.max_supply {
+            violations.push(Violation::TotalMintedAboveMaxSupply {
+                tick: tick.clone(),
+                total_minted: ticker.total_minted,
+                max_supply: ticker.max_supply,
+            });
+        }
+    }
+
+    Ok(violations)
+}
+
+fn same_balances(a: &UserBalance, b: &UserBalance) -> bool {
+    a.overall_balance == b.overall_balance
+        && a.available_balance == b.available_balance
+        && a.transferable_balance == b.transferable_balance
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brc20_index::{
+        memory::MemoryStore,
+        testing::{index_chain, transfer_chain},
+        user_balance::UserBalanceEntryType,
+    };
+
+    #[tokio::test]
+    async fn test_check_block_reports_violations() {
+        let (chain, alice, bob) = transfer_chain();
+        let store = MemoryStore::new();
+        index_chain(&chain, &store).await;
+
+        let block_height = i64::from(chain.tip_height()) + 1;
+        let mut block = BlockUpdate {
+            block_height,
+            ..Default::default()
+        };
+        assert_eq!(check_block(&store, &block).await.unwrap(), vec![]);
+
+        // bob sends more than is transferable, the ticker grows without a mint
+        block.user_balance_entries.push(UserBalanceEntry::new(
+            bob.clone(),
+            "ordi".to_string(),
+            block_height as u64,
+            Amount::parse("1", 18).unwrap(),
+            UserBalanceEntryType::Send,
+        ));
+        let mut ticker = store.get_ticker("ordi").await.unwrap().unwrap();
+        ticker.total_minted = ticker
+            .total_minted
+            .checked_add(Amount::parse("1", 18).unwrap())
+            .unwrap();
+        block.tickers.insert("ordi".to_string(), ticker);
+        // alice's balance changed without an entry
+        let mut alice_balance = store
+            .get_user_balance(&alice, "ordi")
+            .await
+            .unwrap()
+            .unwrap();
+        alice_balance.available_balance = Amount::ZERO;
+        block
+            .updated_user_balances
+            .insert((alice.clone(), "ordi".to_string()), alice_balance);
+
+        let violations = check_block(&store, &block).await.unwrap();
+        assert_eq!(violations.len(), 4);
+        assert!(violations.iter().any(|violation| matches!(
+            violation,
+            Violation::NegativeBalance { address, .. } if *address == bob
+        )));
+        assert!(violations.iter().any(|violation| matches!(
+            violation,
+            Violation::BalanceNotEntries { address, .. } if *address == alice
+        )));
+        assert!(violations.iter().any(|violation| matches!(
+            violation,
+            Violation::BalanceMismatch { address, .. } if *address == alice
+        )));
+        assert!(violations
+            .iter()
+            .any(|violation| matches!(violation, Violation::TotalMintedNotMints { .. })));
+    }
+}
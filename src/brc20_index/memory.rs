@@ -0,0 +1,468 @@
+use super::{
+    amount::{get_amount, Amount},
+    brc20_ticker::Brc20Ticker,
+    event::{block_event_id, Brc20Event},
+    satpoint::SatPoint,
+    store::{get_integer, BlockUpdate, Brc20Store, Page, StateRepair},
+    transfer::{
+        insert_active_transfer, ActiveTransfers, Brc20ActiveTransfer, TRANSFER_SEND_FIELDS,
+    },
+    user_balance::{UserBalance, UserBalanceEntry},
+    utils::apply_user_balance_entry,
+};
+use async_trait::async_trait;
+use log::info;
+use mongodb::bson::{Bson, Document};
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Bound;
+use std::sync::{Mutex, MutexGuard};
+
+/// Keeps the whole index in memory, nothing survives the process.
+///
+/// Follows the MongoDB backend: events are kept as the same documents, and a
+/// rollback deletes everything at or above a height, recalculates the tickers'
+/// total minted and rebuilds the deleted balances from the entries below it.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    state: Mutex<MemoryState>,
+}
+
+#[derive(Debug, Default, Clone)]
+struct MemoryState {
+    deploys: Vec<Document>,
+    mints: Vec<Document>,
+    transfers: Vec<Document>,
+    invalids: Vec<Document>,
+    user_balance_entries: Vec<UserBalanceEntry>,
+    tickers: HashMap<String, Brc20Ticker>,
+    user_balances: HashMap<(String, String), UserBalance>,
+    active_transfers: ActiveTransfers,
+    // block hash, previous block hash and state hash of completed blocks, by height
+    blocks_completed: BTreeMap<i64, (String, String, String)>,
+    // the event log, by id
+    events: BTreeMap<String, Brc20Event>,
+    // id of the last event delivered to each webhook, by url
+    webhook_cursors: HashMap<String, String>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore::default()
+    }
+
+    fn state(&self) -> anyhow::Result<MutexGuard<'_, MemoryState>> {
+        self.state
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Memory store lock poisoned"))
+    }
+}
+
+#[async_trait]
+impl Brc20Store for MemoryStore {
+    async fn get_ticker(&self, tick: &str) -> anyhow::Result<Option<Brc20Ticker>> {
+        Ok(self.state()?.tickers.get(tick).cloned())
+    }
+
+    async fn get_user_balance(
+        &self,
+        address: &str,
+        tick: &str,
+    ) -> anyhow::Result<Option<UserBalance>> {
+        let key = (address.to_string(), tick.to_string());
+        Ok(self.state()?.user_balances.get(&key).cloned())
+    }
+
+    async fn get_transfer(
+        &self,
+        inscription_id: &str,
+        tx_id: &str,
+    ) -> anyhow::Result<Option<Document>> {
+        Ok(self
+            .state()?
+            .transfers
+            .iter()
+            .find(|transfer| is_transfer(transfer, inscription_id, tx_id))
+            .cloned())
+    }
+
+    async fn load_active_transfers(&self) -> anyhow::Result<ActiveTransfers> {
+        Ok(self.state()?.active_transfers.clone())
+    }
+
+    async fn get_last_completed_block_height(&self) -> anyhow::Result<Option<i64>> {
+        Ok(self.state()?.blocks_completed.keys().next_back().copied())
+    }
+
+    async fn get_completed_block_hash(&self, block_height: i64) -> anyhow::Result<Option<String>> {
+        Ok(self
+            .state()?
+            .blocks_completed
+            .get(&block_height)
+            .map(|(block_hash, _, _)| block_hash.clone()))
+    }
+
+    async fn get_state_hash(&self, block_height: i64) -> anyhow::Result<Option<String>> {
+        Ok(self
+            .state()?
+            .blocks_completed
+            .get(&block_height)
+            .map(|(_, _, state_hash)| state_hash.clone()))
+    }
+
+    async fn get_events(&self, after: Option<&str>, limit: u64) -> anyhow::Result<Vec<Brc20Event>> {
+        let start = match after {
+            Some(after) => Bound::Excluded(after.to_string()),
+            None => Bound::Unbounded,
+        };
+
+        Ok(self
+            .state()?
+            .events
+            .range((start, Bound::Unbounded))
+            .take(limit as usize)
+            .map(|(_, event)| event.clone())
+            .collect())
+    }
+
+    async fn get_webhook_cursor(&self, url: &str) -> anyhow::Result<Option<String>> {
+        Ok(self.state()?.webhook_cursors.get(url).cloned())
+    }
+
+    async fn set_webhook_cursor(&self, url: &str, event_id: &str) -> anyhow::Result<()> {
+        self.state()?
+            .webhook_cursors
+            .insert(url.to_string(), event_id.to_string());
+        Ok(())
+    }
+
+    async fn get_user_balances(&self, address: &str) -> anyhow::Result<Vec<UserBalance>> {
+        let mut user_balances: Vec<UserBalance> = self
+            .state()?
+            .user_balances
+            .values()
+            .filter(|user_balance| user_balance.address == address)
+            .cloned()
+            .collect();
+        user_balances.sort_by(|a, b| a.tick.cmp(&b.tick));
+
+        Ok(user_balances)
+    }
+
+    async fn get_holders(&self, tick: &str, page: Page) -> anyhow::Result<Vec<UserBalance>> {
+        let mut holders: Vec<UserBalance> = self
+            .state()?
+            .user_balances
+            .values()
+            .filter(|user_balance| user_balance.tick == tick && !user_balance.is_zero())
+            .cloned()
+            .collect();
+        holders.sort_by(|a, b| a.address.cmp(&b.address));
+
+        Ok(paginate(holders, page))
+    }
+
+    async fn get_user_balance_entries(
+        &self,
+        address: &str,
+        tick: Option<&str>,
+        page: Page,
+    ) -> anyhow::Result<Vec<UserBalanceEntry>> {
+        let entries = self
+            .state()?
+            .user_balance_entries
+            .iter()
+            .filter(|entry| {
+                entry.address == address && tick.map_or(true, |tick| entry.tick == tick)
+            })
+            .cloned()
+            .collect();
+
+        Ok(paginate(entries, page))
+    }
+
+    async fn get_user_balance_entries_until(
+        &self,
+        address: Option<&str>,
+        tick: Option<&str>,
+        block_height: i64,
+    ) -> anyhow::Result<Vec<UserBalanceEntry>> {
+        Ok(self
+            .state()?
+            .user_balance_entries
+            .iter()
+            .filter(|entry| {
+                address.map_or(true, |address| entry.address == address)
+                    && tick.map_or(true, |tick| entry.tick == tick)
+                    && entry.block_height as i64 <= block_height
+            })
+            .cloned()
+            .collect())
+    }
+
+    async fn get_transfers_by_tx_id(&self, tx_id: &str) -> anyhow::Result<Vec<Document>> {
+        Ok(self
+            .state()?
+            .transfers
+            .iter()
+            .filter(|transfer| {
+                transfer
+                    .get_document("tx")
+                    .and_then(|tx| tx.get_str("txid"))
+                    .map_or(false, |stored_tx_id| stored_tx_id == tx_id)
+            })
+            .cloned()
+            .collect())
+    }
+
+    async fn get_tickers(&self) -> anyhow::Result<Vec<Brc20Ticker>> {
+        let mut tickers: Vec<Brc20Ticker> = self.state()?.tickers.values().cloned().collect();
+        tickers.sort_by(|a, b| a.tick.cmp(&b.tick));
+
+        Ok(tickers)
+    }
+
+    async fn get_all_user_balances(&self) -> anyhow::Result<Vec<UserBalance>> {
+        let mut user_balances: Vec<UserBalance> =
+            self.state()?.user_balances.values().cloned().collect();
+        user_balances.sort_by(|a, b| (&a.address, &a.tick).cmp(&(&b.address, &b.tick)));
+
+        Ok(user_balances)
+    }
+
+    async fn get_minted_amounts(
+        &self,
+        block_height: i64,
+    ) -> anyhow::Result<HashMap<String, Amount>> {
+        let mut minted_amounts: HashMap<String, Amount> = HashMap::new();
+        for mint in &self.state()?.mints {
+            if get_integer(mint, "block_height").map_or(true, |height| height > block_height) {
+                continue;
+            }
+            let tick = mint.get_document("inscription")?.get_str("tick")?;
+            let minted = minted_amounts.entry(tick.to_lowercase()).or_default();
+            *minted = minted
+                .checked_add(get_amount(mint, "amt").unwrap_or_default())
+                .ok_or_else(|| anyhow::anyhow!("Total minted overflow"))?;
+        }
+
+        Ok(minted_amounts)
+    }
+
+    async fn write_block(&self, block: BlockUpdate) -> anyhow::Result<()> {
+        let mut state = self.state()?;
+
+        state.user_balances.extend(block.updated_user_balances);
+        state.user_balances.extend(block.new_user_balances);
+
+        state.deploys.extend(block.deploys);
+        state.mints.extend(block.mints);
+        state.transfers.extend(block.transfers);
+        state.invalids.extend(block.invalids);
+        state
+            .user_balance_entries
+            .extend(block.user_balance_entries);
+
+        // transfers inscribed in an earlier block are overwritten with their send
+        for transfer in block.sent_transfers {
+            let inscription_id = transfer.get_str("inscription_id").unwrap_or_default();
+            let tx_id = transfer
+                .get_document("tx")
+                .and_then(|tx| tx.get_str("txid"))
+                .unwrap_or_default();
+
+            match state
+                .transfers
+                .iter()
+                .position(|stored| is_transfer(stored, inscription_id, tx_id))
+            {
+                Some(index) => state.transfers[index] = transfer,
+                None => state.transfers.push(transfer),
+            }
+        }
+
+        state.tickers.extend(block.tickers);
+        state.active_transfers = block.active_transfers;
+        state.events.extend(
+            block
+                .events
+                .into_iter()
+                .map(|event| (event.id.clone(), event)),
+        );
+        state.blocks_completed.insert(
+            block.block_height,
+            (
+                block.block_hash,
+                block.previous_block_hash,
+                block.state_hash,
+            ),
+        );
+
+        Ok(())
+    }
+
+    async fn rollback_to_block_height(&self, start_block_height: i64) -> anyhow::Result<()> {
+        let mut guard = self.state()?;
+        // work on a copy so a failed rollback leaves the store untouched
+        let mut state = guard.clone();
+        let is_rolled_back = |document: &Document| {
+            get_integer(document, "block_height")
+                .map_or(false, |height| height >= start_block_height)
+        };
+
+        // transfers sent after the rollback point are active again
+        let mut restored = 0;
+        for transfer in state.transfers.iter_mut() {
+            let sent_after = get_integer(transfer, "send_block_height")
+                .map_or(false, |height| height >= start_block_height);
+            if is_rolled_back(transfer) || !sent_after {
+                continue;
+            }
+
+            let txid = transfer.get_document("tx")?.get_str("txid")?.to_string();
+            let block_height = get_integer(transfer, "block_height").unwrap_or_default();
+            // transfers stored before satpoints were recorded sit at offset 0
+            let vout = get_integer(transfer, "vout").unwrap_or_default();
+            let offset = get_integer(transfer, "offset").unwrap_or_default();
+            let inscription_id = match transfer.get_str("inscription_id") {
+                Ok(inscription_id) => inscription_id.to_string(),
+                Err(_) => format!("{}i0", txid),
+            };
+
+            let satpoint = SatPoint::new(txid.clone(), vout, offset);
+            insert_active_transfer(
+                &mut state.active_transfers,
+                Brc20ActiveTransfer::new(txid, satpoint, block_height, inscription_id),
+            );
+
+            for field in TRANSFER_SEND_FIELDS {
+                transfer.insert(field, Bson::Null);
+            }
+            restored += 1;
+        }
+        info!("Active Transfers restored: {}", restored);
+
+        // delete deploys, mints, transfers, tickers, invalids, entries
+        state.deploys.retain(|document| !is_rolled_back(document));
+        state.mints.retain(|document| !is_rolled_back(document));
+        state.transfers.retain(|document| !is_rolled_back(document));
+        state.invalids.retain(|document| !is_rolled_back(document));
+        state
+            .user_balance_entries
+            .retain(|entry| (entry.block_height as i64) < start_block_height);
+        state
+            .tickers
+            .retain(|_, ticker| i64::from(ticker.block_height) < start_block_height);
+        // event ids start with their block height
+        let first_event_id = block_event_id(start_block_height);
+        state.events.split_off(&first_event_id);
+        for event_id in state.webhook_cursors.values_mut() {
+            if *event_id > first_event_id {
+                *event_id = first_event_id.clone();
+            }
+        }
+        for active_transfers in state.active_transfers.values_mut() {
+            active_transfers
+                .retain(|active_transfer| active_transfer.block_height < start_block_height);
+        }
+        state
+            .active_transfers
+            .retain(|_, active_transfers| !active_transfers.is_empty());
+        state
+            .blocks_completed
+            .retain(|block_height, _| *block_height < start_block_height);
+
+        // recalculate total_minted of tickers minted after the rollback point
+        let mints = &state.mints;
+        for ticker in state.tickers.values_mut() {
+            if i64::from(ticker.updated_at_block) < start_block_height {
+                continue;
+            }
+
+            let mut total_minted = Amount::ZERO;
+            for mint in mints.iter().filter(|mint| {
+                mint.get_document("inscription")
+                    .and_then(|inscription| inscription.get_str("tick"))
+                    .map_or(false, |tick| tick == ticker.tick)
+            }) {
+                let amount = get_amount(mint, "amt").unwrap_or_default();
+                total_minted = total_minted
+                    .checked_add(amount)
+                    .ok_or_else(|| anyhow::anyhow!("Total minted overflow"))?;
+            }
+
+            info!("Reset total_minted for ticker: {}", ticker.tick);
+            ticker.total_minted = total_minted;
+            ticker.updated_at_block = u32::try_from(start_block_height)?;
+        }
+
+        // rebuild the balances changed after the rollback point from their entries
+        let deleted_user_balances: Vec<(String, String)> = state
+            .user_balances
+            .iter()
+            .filter(|(_, user_balance)| user_balance.block_height as i64 >= start_block_height)
+            .map(|(key, _)| key.clone())
+            .collect();
+        info!("Deleted User Balances: {:?}", deleted_user_balances);
+
+        for key in deleted_user_balances {
+            state.user_balances.remove(&key);
+
+            let mut entries = state
+                .user_balance_entries
+                .iter()
+                .filter(|entry| entry.address == key.0 && entry.tick == key.1)
+                .peekable();
+            if entries.peek().is_none() {
+                continue;
+            }
+
+            let mut user_balance =
+                UserBalance::new(key.0.clone(), key.1.clone(), start_block_height as u64);
+            for entry in entries {
+                apply_user_balance_entry(&mut user_balance, entry)?;
+            }
+            user_balance.block_height = start_block_height as u64;
+
+            state.user_balances.insert(key, user_balance);
+        }
+
+        *guard = state;
+
+        Ok(())
+    }
+
+    async fn repair_state(&self, repair: StateRepair) -> anyhow::Result<()> {
+        let mut state = self.state()?;
+        for ticker in repair.tickers {
+            state.tickers.insert(ticker.tick.clone(), ticker);
+        }
+        for user_balance in repair.user_balances {
+            let key = (user_balance.address.clone(), user_balance.tick.clone());
+            state.user_balances.insert(key, user_balance);
+        }
+        for key in &repair.deleted_user_balances {
+            state.user_balances.remove(key);
+        }
+
+        Ok(())
+    }
+}
+
+// same match as `transfer_document_filter`
+fn is_transfer(transfer: &Document, inscription_id: &str, tx_id: &str) -> bool {
+    match transfer.get_str("inscription_id") {
+        Ok(stored_id) => stored_id == inscription_id,
+        Err(_) => transfer
+            .get_document("tx")
+            .and_then(|tx| tx.get_str("txid"))
+            .map_or(false, |stored_tx_id| stored_tx_id == tx_id),
+    }
+}
+
+fn paginate<T>(items: Vec<T>, page: Page) -> Vec<T> {
+    items
+        .into_iter()
+        .skip(page.offset as usize)
+        .take(page.limit as usize)
+        .collect()
+}
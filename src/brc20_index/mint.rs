@@ -3,8 +3,8 @@ use std::collections::HashMap;
 use crate::brc20_index::user_balance::UserBalanceEntryType;
 
 use super::{
-    consts, invalid_brc20::InvalidBrc20Tx, mongo::MongoClient, user_balance::UserBalanceEntry,
-    utils::convert_to_float, Brc20Inscription, ToDocument,
+    amount::Amount, brc20_ticker::Brc20Ticker, invalid_brc20::InvalidBrc20Tx, store::Brc20Store,
+    user_balance::UserBalanceEntry, utils::convert_to_amount, Brc20Inscription, ToDocument,
 };
 use bitcoin::Address;
 use bitcoincore_rpc::bitcoincore_rpc_json::GetRawTransactionResult;
@@ -14,7 +14,8 @@ use serde::Serialize;
 
 #[derive(Debug, Clone, Serialize)]
 pub struct Brc20Mint {
-    pub amt: f64,
+    pub inscription_id: String,
+    pub amt: Amount,
     pub block_height: u32,
     pub tx_height: u32,
     pub to: Address,
@@ -26,6 +27,7 @@ pub struct Brc20Mint {
 impl ToDocument for Brc20Mint {
     fn to_document(&self) -> Document {
         doc! {
+            "inscription_id": &self.inscription_id,
             "amt": self.amt,
             "block_height": self.block_height,
             "tx_height": self.tx_height,
@@ -41,13 +43,15 @@ impl ToDocument for Brc20Mint {
 impl Brc20Mint {
     pub fn new(
         tx: &GetRawTransactionResult,
+        inscription_id: String,
         inscription: Brc20Inscription,
         block_height: u32,
         tx_height: u32,
         to: Address,
     ) -> Self {
         Brc20Mint {
-            amt: 0.0,
+            inscription_id,
+            amt: Amount::ZERO,
             block_height,
             tx_height,
             to,
@@ -67,39 +71,29 @@ impl Brc20Mint {
 
     pub async fn validate_mint<'a>(
         mut self,
-        ticker_doc_opt: Option<&Document>,
+        ticker_opt: Option<&Brc20Ticker>,
         invalid_brc20_docs: &mut Vec<Document>,
     ) -> Result<Brc20Mint, Box<dyn std::error::Error>> {
         let mut reason = String::new();
 
-        if let Some(ticker_doc) = ticker_doc_opt {
-            // get values from ticker doc
-            let limit = ticker_doc
-                .get("limit")
-                .and_then(Bson::as_f64)
-                .unwrap_or_default();
-            let max_supply = ticker_doc
-                .get("max_supply")
-                .and_then(Bson::as_f64)
-                .unwrap_or_default();
-            let total_minted = ticker_doc
-                .get("total_minted")
-                .and_then(Bson::as_f64)
-                .unwrap_or_default();
-            let decimals = ticker_doc
-                .get("decimals")
-                .and_then(Bson::as_i32)
-                .unwrap_or_default();
+        if let Some(ticker) = ticker_opt {
+            // get values from ticker
+            let limit = ticker.limit;
+            let max_supply = ticker.max_supply;
+            let total_minted = ticker.total_minted;
+            let decimals = ticker.decimals;
 
             // get amount from inscription
             let amount = match self.inscription.amt.as_ref().map(String::as_str) {
-                Some(amt_str) => convert_to_float(amt_str, decimals.try_into().unwrap()),
-                None => Ok(0.0),
+                Some(amt_str) => convert_to_amount(amt_str, decimals),
+                None => Ok(Amount::ZERO),
             };
 
             // validate mint amount against ticker limit and max supply
             match amount {
                 Ok(amount) => {
+                    let remaining_amount = max_supply.checked_sub(total_minted);
+
                     // Check if the amount is greater than the limit
                     if amount > limit {
                         reason = "Mint amount exceeds limit".to_string();
@@ -107,10 +101,11 @@ impl Brc20Mint {
                     } else if total_minted >= max_supply {
                         reason = "Total minted is already at max supply".to_string();
                     // Check if the total minted amount + requested mint amount exceeds the max supply
-                    } else if total_minted + amount > max_supply {
+                    } else if let Some(remaining_amount) =
+                        remaining_amount.filter(|remaining| amount > *remaining)
+                    {
                         self.is_valid = true;
                         // Adjust the mint amount to mint remaining tokens
-                        let remaining_amount = max_supply - total_minted;
                         self.amt = remaining_amount;
                     } else {
                         self.is_valid = true;
@@ -143,106 +138,105 @@ impl Brc20Mint {
     }
 }
 
-// This function will try to get a ticker's document from the hashmap
-// If the ticker is not in the hashmap, it will fetch the document from MongoDB and store it in the hashmap
-async fn get_ticker<'a>(
-    tickers: &'a mut HashMap<String, Document>,
+// This function will try to get a ticker from the hashmap
+// If the ticker is not in the hashmap, it will load it from the store and keep it in the hashmap
+pub async fn get_ticker<'a>(
+    tickers: &'a mut HashMap<String, Brc20Ticker>,
     ticker_symbol: &String,
-    mongo_client: &MongoClient,
-) -> Option<&'a Document> {
+    store: &dyn Brc20Store,
+) -> Result<Option<&'a mut Brc20Ticker>, anyhow::Error> {
     // Check if the hashmap contains the ticker
-    if tickers.contains_key(ticker_symbol) {
-        tickers.get(ticker_symbol)
-    } else {
-        // If not, fetch the ticker from MongoDB and store it in the hashmap
-        match mongo_client
-            .get_document_by_field(consts::COLLECTION_TICKERS, "tick", ticker_symbol)
-            .await
-        {
-            Ok(Some(ticker_doc)) => {
-                tickers.insert(ticker_symbol.clone(), ticker_doc.clone());
-                tickers.get(ticker_symbol)
+    if !tickers.contains_key(ticker_symbol) {
+        // If not, load the ticker from the store and keep it in the hashmap
+        match store.get_ticker(ticker_symbol).await? {
+            Some(ticker) => {
+                tickers.insert(ticker_symbol.clone(), ticker);
             }
-            Ok(None) => None,
-            Err(_) => None,
+            None => return Ok(None),
         }
     }
+
+    Ok(tickers.get_mut(ticker_symbol))
 }
 
-// This function will update the total minted tokens for a given ticker in MongoDB and the in-memory hashmap
+// This function will update the total minted tokens for a given ticker in the in-memory hashmap
 async fn update_ticker_total_minted(
     ticker_symbol: &String,
-    mint_amount: f64,
-    tickers: &mut HashMap<String, Document>,
-    mongo_client: &MongoClient,
+    mint_amount: Amount,
+    tickers: &mut HashMap<String, Brc20Ticker>,
+    store: &dyn Brc20Store,
     block_height: u32,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    // Check if the hashmap contains the ticker
-    if let Some(ticker_doc) = get_ticker(tickers, ticker_symbol, mongo_client).await {
+    if let Some(ticker) = get_ticker(tickers, ticker_symbol, store).await? {
         // Update the total minted amount in the hashmap
-        let new_total_minted = ticker_doc
-            .get("total_minted")
-            .and_then(Bson::as_f64)
-            .unwrap_or(0.0)
-            + mint_amount;
-
-        // Create a new document with the updated total_minted
-        let mut updated_ticker_doc = ticker_doc.clone();
-        updated_ticker_doc.insert("total_minted", Bson::Double(new_total_minted));
-        updated_ticker_doc.insert("updated_at_block", block_height as i32);
-
-        // Replace the old ticker_doc in the hashmap with the updated one
-        tickers.insert(ticker_symbol.clone(), updated_ticker_doc);
+        ticker.total_minted = ticker
+            .total_minted
+            .checked_add(mint_amount)
+            .ok_or("Total minted overflow")?;
+        ticker.updated_at_block = block_height;
     }
 
     Ok(())
 }
 
 pub async fn update_balances_and_ticker(
-    mongo_client: &MongoClient,
+    store: &dyn Brc20Store,
     validated_mint_tx: &Brc20Mint,
-    tickers: &mut HashMap<String, Document>,
+    tickers: &mut HashMap<String, Brc20Ticker>,
     block_height: u32,
 ) -> Result<UserBalanceEntry, Box<dyn std::error::Error>> {
-    // Update total minted tokens for this ticker in MongoDB and in-memory hashmap
+    // Update total minted tokens for this ticker in the in-memory hashmap
     update_ticker_total_minted(
         &validated_mint_tx.inscription.tick.to_lowercase(),
         validated_mint_tx.amt,
         tickers,
-        mongo_client,
+        store,
         block_height,
     )
     .await?;
 
     // return user balance entry
-    Ok(mongo_client
-        .insert_user_balance_entry(
-            &validated_mint_tx.to.to_string(),
-            validated_mint_tx.amt,
-            &validated_mint_tx.inscription.tick.to_lowercase(),
-            validated_mint_tx.block_height.into(),
-            UserBalanceEntryType::Receive,
-        )
-        .await?)
+    Ok(UserBalanceEntry::new(
+        validated_mint_tx.to.to_string(),
+        validated_mint_tx.inscription.tick.to_lowercase(),
+        validated_mint_tx.block_height.into(),
+        validated_mint_tx.amt,
+        UserBalanceEntryType::Receive,
+    )
+    .with_source(
+        &validated_mint_tx.tx.txid.to_string(),
+        validated_mint_tx.tx_height.into(),
+        &validated_mint_tx.inscription_id,
+    ))
 }
 
 pub async fn handle_mint_operation(
-    mongo_client: &MongoClient,
+    store: &dyn Brc20Store,
     block_height: u32,
     tx_height: u32,
     owner: Address,
+    inscription_id: String,
     inscription: Brc20Inscription,
     raw_tx: &GetRawTransactionResult,
-    tickers: &mut HashMap<String, Document>,
+    tickers: &mut HashMap<String, Brc20Ticker>,
     invalid_brc20_docs: &mut Vec<Document>,
 ) -> Result<(Brc20Mint, UserBalanceEntry), Box<dyn std::error::Error>> {
-    // Try to get the ticker from the hashmap if not, then mongodb
-    let ticker_doc_opt = get_ticker(tickers, &inscription.tick.to_lowercase(), mongo_client).await;
+    // Try to get the ticker from the hashmap if not, then the store
+    let ticker_opt = get_ticker(tickers, &inscription.tick.to_lowercase(), store)
+        .await?
+        .cloned();
 
     // Create a new Brc20Mint instance
-    let new_mint = Brc20Mint::new(&raw_tx, inscription, block_height, tx_height, owner);
+    let new_mint = Brc20Mint::new(
+        &raw_tx,
+        inscription_id,
+        inscription,
+        block_height,
+        tx_height,
+        owner,
+    );
     let validated_mint_tx = new_mint
-        .validate_mint(ticker_doc_opt, invalid_brc20_docs)
+        .validate_mint(ticker_opt.as_ref(), invalid_brc20_docs)
         .await?;
 
     let mut user_balance_entry = UserBalanceEntry::default();
@@ -254,8 +248,7 @@ pub async fn handle_mint_operation(
 
         // update_balances_and_ticker
         user_balance_entry =
-            update_balances_and_ticker(mongo_client, &validated_mint_tx, tickers, block_height)
-                .await?;
+            update_balances_and_ticker(store, &validated_mint_tx, tickers, block_height).await?;
     }
 
     Ok((validated_mint_tx, user_balance_entry))
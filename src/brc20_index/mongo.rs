@@ -1,21 +1,33 @@
 use std::collections::HashMap;
 use std::env;
-use std::time::Duration;
-
-use super::transfer::Brc20ActiveTransfer;
-use super::user_balance::{UserBalanceEntry, UserBalanceEntryType};
-use crate::brc20_index::consts;
+use std::time::{Duration, Instant};
+
+use super::amount::{get_amount, Amount};
+use super::brc20_ticker::Brc20Ticker;
+use super::event::{block_event_id, Brc20Event};
+use super::satpoint::SatPoint;
+use super::store::{get_integer, BlockUpdate, Brc20Store, Page, StateRepair};
+use super::transfer::{
+    insert_active_transfer, transfer_document_filter, ActiveTransfers, Brc20ActiveTransfer,
+};
+use super::user_balance::{UserBalance, UserBalanceEntry};
+use super::utils::apply_user_balance_entry;
+use crate::brc20_index::{consts, ToDocument};
+use async_trait::async_trait;
 use futures_util::stream::TryStreamExt;
 use futures_util::StreamExt;
 use log::{error, info, warn};
 use mongodb::bson::{doc, Bson, DateTime, Document};
+use mongodb::error::{TRANSIENT_TRANSACTION_ERROR, UNKNOWN_TRANSACTION_COMMIT_RESULT};
 use mongodb::options::{FindOneOptions, FindOptions, IndexOptions, UpdateOptions};
 use mongodb::{bson, options::ClientOptions, Client};
-use mongodb::{Cursor, IndexModel};
+use mongodb::{ClientSession, Cursor, IndexModel};
 
 pub struct MongoClient {
     client: Client,
     db_name: String,
+    // whether each block is written in a single multi-document transaction
+    transactions: bool,
 }
 
 impl MongoClient {
@@ -35,9 +47,25 @@ impl MongoClient {
 
         let client = Client::with_options(client_options)?;
 
+        // Multi-document transactions need a replica set or a sharded cluster. On a
+        // standalone server a block's writes are made one after the other, with the
+        // block completed last, and an interrupted block is rolled back on restart.
+        // A rollback is recorded before it starts and its steps can be repeated, so
+        // an interrupted rollback is finished by the next one.
+        let transactions = match env::var("MONGO_TRANSACTIONS") {
+            Ok(transactions) => transactions.to_lowercase() == "true",
+            Err(_) => supports_transactions(&client).await,
+        };
+        if transactions {
+            info!("Writing each block in a MongoDB transaction");
+        } else {
+            warn!("MongoDB transactions are disabled, blocks are not written atomically");
+        }
+
         Ok(Self {
             client,
             db_name: db_name.to_string(),
+            transactions,
         })
     }
 
@@ -234,29 +262,15 @@ impl MongoClient {
             .await
     }
 
-    pub async fn insert_user_balance_entry(
+    pub async fn store_completed_block(
         &self,
-        address: &String,
-        amount: f64,
-        tick: &str,
-        block_height: u64,
-        entry_type: UserBalanceEntryType,
-    ) -> Result<UserBalanceEntry, anyhow::Error> {
-        // instantiate a new user balance entry
-        Ok(UserBalanceEntry::new(
-            address.to_string(),
-            tick.to_string(),
-            block_height,
-            amount,
-            entry_type,
-        ))
-    }
-
-    pub async fn store_completed_block(&self, block_height: i64) -> anyhow::Result<()> {
-        let document = doc! {
-            consts::KEY_BLOCK_HEIGHT: block_height,
-            "created_at": Bson::DateTime(DateTime::now())
-        };
+        block_height: i64,
+        block_hash: &str,
+        previous_block_hash: &str,
+        state_hash: &str,
+    ) -> anyhow::Result<()> {
+        let document =
+            completed_block_document(block_height, block_hash, previous_block_hash, state_hash);
 
         // Insert into MongoDB collection
         self.insert_document(consts::COLLECTION_BLOCKS_COMPLETED, document)
@@ -271,35 +285,68 @@ impl MongoClient {
         let find_options = FindOneOptions::builder().sort(sort_doc).build();
 
         // Find one document (the latest) with the sorted criteria
-        if let Some(result) = self
+        let last_completed = self
             .find_one_with_retries(
                 consts::COLLECTION_BLOCKS_COMPLETED,
                 doc! {}, // No filter, we want any document
                 Some(find_options),
             )
             .await?
-        {
-            if let Ok(block_height) = result.get_i64(consts::KEY_BLOCK_HEIGHT) {
-                return Ok(Some(block_height));
-            }
+            .and_then(|result| result.get_i64(consts::KEY_BLOCK_HEIGHT).ok());
+
+        // blocks from an interrupted rollback on aren't completed anymore, even if
+        // their completion wasn't deleted yet
+        match self.get_pending_rollback().await? {
+            Some(start_block_height) => Ok(last_completed
+                .map(|block_height| block_height.min(start_block_height - 1))
+                .filter(|block_height| *block_height >= consts::BRC20_STARTING_BLOCK_HEIGHT)),
+            None => Ok(last_completed),
         }
+    }
 
-        // No processed blocks found or unable to get the block_height field
-        Ok(None)
+    // the height a rollback that didn't finish was rolling back to
+    async fn get_pending_rollback(&self) -> anyhow::Result<Option<i64>> {
+        Ok(self
+            .find_one_with_retries(consts::COLLECTION_PENDING_ROLLBACK, doc! {}, None)
+            .await?
+            .and_then(|document| get_integer(&document, consts::KEY_BLOCK_HEIGHT)))
     }
 
-    pub async fn delete_from_collection(
+    // returns the hash stored for a completed block, None if the block was never
+    // completed or was completed before block hashes were being recorded
+    pub async fn get_completed_block_hash(
         &self,
-        collection_name: &str,
-        start_block_height: i64,
-    ) -> anyhow::Result<()> {
-        self.delete_many_with_retries(
-            collection_name,
-            doc! { "block_height": { "$gte": start_block_height } },
-        )
-        .await?;
+        block_height: i64,
+    ) -> Result<Option<String>, anyhow::Error> {
+        let filter = doc! { consts::KEY_BLOCK_HEIGHT: block_height };
 
-        Ok(())
+        let result = self
+            .find_one_with_retries(consts::COLLECTION_BLOCKS_COMPLETED, filter, None)
+            .await?;
+
+        Ok(result.and_then(|document| {
+            document
+                .get_str(consts::KEY_BLOCK_HASH)
+                .ok()
+                .map(|hash| hash.to_string())
+        }))
+    }
+
+    // returns the state hash stored for a completed block, None if the block was
+    // never completed or was completed before state hashes were being recorded
+    pub async fn get_state_hash(&self, block_height: i64) -> Result<Option<String>, anyhow::Error> {
+        let filter = doc! { consts::KEY_BLOCK_HEIGHT: block_height };
+
+        let result = self
+            .find_one_with_retries(consts::COLLECTION_BLOCKS_COMPLETED, filter, None)
+            .await?;
+
+        Ok(result.and_then(|document| {
+            document
+                .get_str(consts::KEY_STATE_HASH)
+                .ok()
+                .map(|hash| hash.to_string())
+        }))
     }
 
     pub async fn drop_collection(&self, collection_name: &str) -> anyhow::Result<()> {
@@ -363,46 +410,9 @@ impl MongoClient {
     //     Ok(())
     // }
 
-    pub async fn ticker_exists(
-        &self,
-        collection: &str,
-        filter: Document,
-    ) -> Result<bool, anyhow::Error> {
-        match self.find_one_with_retries(collection, filter, None).await {
-            Ok(Some(_)) => Ok(true),
-            Ok(None) => Ok(false),
-            Err(e) => Err(e),
-        }
-    }
-
-    pub fn get_double(&self, doc: &Document, field: &str) -> Option<f64> {
-        match doc.get(field) {
-            Some(Bson::Double(value)) => Some(*value),
-            _ => None,
-        }
-    }
-
-    pub fn get_f64(&self, doc: &Document, field: &str) -> Option<f64> {
-        match doc.get(field) {
-            Some(Bson::Double(value)) => Some(*value),
-            _ => None,
-        }
-    }
-
-    pub fn get_string(
-        &self,
-        doc: &Document,
-        key: &str,
-    ) -> Result<String, mongodb::bson::document::ValueAccessError> {
-        match doc.get_str(key) {
-            Ok(value) => Ok(value.to_string()),
-            Err(e) => Err(e),
-        }
-    }
-
     pub async fn load_active_transfers_with_retry(
         &self,
-    ) -> Result<Option<HashMap<(String, i64), Brc20ActiveTransfer>>, String> {
+    ) -> Result<Option<ActiveTransfers>, String> {
         let retries = consts::MONGO_RETRIES;
         for attempt in 0..=retries {
             match self.load_active_transfers().await {
@@ -425,9 +435,7 @@ impl MongoClient {
         ))
     }
 
-    pub async fn load_active_transfers(
-        &self,
-    ) -> Result<Option<HashMap<(String, i64), Brc20ActiveTransfer>>, String> {
+    pub async fn load_active_transfers(&self) -> Result<Option<ActiveTransfers>, String> {
         let mut active_transfers = HashMap::new();
 
         let db = self.client.database(&self.db_name);
@@ -453,8 +461,7 @@ impl MongoClient {
             match result {
                 Ok(document) => {
                     let active_transfer = Brc20ActiveTransfer::from_document(document)?;
-                    let key = (active_transfer.tx_id.clone(), active_transfer.vout);
-                    active_transfers.insert(key, active_transfer);
+                    insert_active_transfer(&mut active_transfers, active_transfer);
                 }
                 Err(e) => return Err(e.to_string()),
             }
@@ -465,21 +472,91 @@ impl MongoClient {
 
     pub async fn insert_active_transfers_to_mongodb(
         &self,
-        active_transfers: HashMap<(String, i64), Brc20ActiveTransfer>,
+        active_transfers: ActiveTransfers,
     ) -> Result<(), anyhow::Error> {
         // Convert the HashMap to a Vec<bson::Document>.
-        let documents: Result<Vec<bson::Document>, _> = active_transfers
+        let documents: Vec<bson::Document> = active_transfers
             .values()
-            .map(|value| bson::to_document(value))
-            .collect::<Result<Vec<_>, _>>();
+            .flatten()
+            .map(|active_transfer| active_transfer.to_document())
+            .collect();
 
         // Insert the documents into the collection with retries.
-        self.insert_many_with_retries(consts::COLLECTION_BRC20_ACTIVE_TRANSFERS, &documents?)
+        self.insert_many_with_retries(consts::COLLECTION_BRC20_ACTIVE_TRANSFERS, &documents)
             .await?;
 
         Ok(())
     }
 
+    // Transfers inscribed before start_block_height but sent at or after it become
+    // active again: the inscription is put back into the active transfers collection
+    // and the send is cleared from the transfer document. Upserted by inscription
+    // id, so restoring again after an interruption doesn't duplicate them.
+    async fn restore_active_transfers(
+        &self,
+        start_block_height: i64,
+        session: &mut ClientSession,
+    ) -> anyhow::Result<usize> {
+        let filter = doc! {
+            "block_height": { "$lt": start_block_height },
+            "send_block_height": { "$gte": start_block_height },
+        };
+        let transfers = self
+            .find_all_with_session(consts::COLLECTION_TRANSFERS, filter.clone(), None, session)
+            .await?;
+
+        let active_transfers = self
+            .client
+            .database(&self.db_name)
+            .collection::<Document>(consts::COLLECTION_BRC20_ACTIVE_TRANSFERS);
+        for document in &transfers {
+            let txid = document.get_document("tx")?.get_str("txid")?.to_string();
+            let block_height = get_integer(document, consts::KEY_BLOCK_HEIGHT).unwrap_or_default();
+
+            // transfers stored before satpoints were recorded sit at offset 0
+            let vout = get_integer(document, "vout").unwrap_or_default();
+            let offset = get_integer(document, "offset").unwrap_or_default();
+            let satpoint = SatPoint::new(txid.clone(), vout, offset);
+            let inscription_id = match document.get_str("inscription_id") {
+                Ok(inscription_id) => inscription_id.to_string(),
+                Err(_) => format!("{}i0", txid),
+            };
+
+            let active_transfer =
+                Brc20ActiveTransfer::new(txid, satpoint, block_height, inscription_id);
+            active_transfers
+                .update_one_with_session(
+                    doc! { "inscription_id": &active_transfer.inscription_id },
+                    doc! { "$set": active_transfer.to_document() },
+                    UpdateOptions::builder().upsert(true).build(),
+                    session,
+                )
+                .await?;
+        }
+
+        if transfers.is_empty() {
+            return Ok(0);
+        }
+
+        let update = doc! {
+            "$set": {
+                "to": Bson::Null,
+                "send_tx": Bson::Null,
+                "send_satpoint": Bson::Null,
+                "fee_spend": Bson::Null,
+                "send_block_height": Bson::Null,
+                "send_tx_height": Bson::Null,
+            }
+        };
+        self.client
+            .database(&self.db_name)
+            .collection::<Document>(consts::COLLECTION_TRANSFERS)
+            .update_many_with_session(filter, update, None, session)
+            .await?;
+
+        Ok(transfers.len())
+    }
+
     pub async fn load_user_balance_with_retry(
         &self,
         key: &(String, String),
@@ -550,6 +627,39 @@ impl MongoClient {
             .create_index(block_height_index_model, None)
             .await?;
 
+        // Create an index on the 'tick' and 'address' fields for listing holders
+        let holders_index_model = IndexModel::builder()
+            .keys(doc! { "tick": 1, "address": 1 }) // 1 for ascending
+            .options(IndexOptions::default())
+            .build();
+
+        user_balances_collection
+            .create_index(holders_index_model, None)
+            .await?;
+
+        // Create an index on the 'address', 'tick' and 'block_height' fields for
+        // COLLECTION_USER_BALANCE_ENTRY
+        let user_balance_entry_collection =
+            db.collection::<bson::Document>(consts::COLLECTION_USER_BALANCE_ENTRY);
+        let user_balance_entry_index_model = IndexModel::builder()
+            .keys(doc! { "address": 1, "tick": 1, "block_height": 1 }) // 1 for ascending
+            .options(IndexOptions::default())
+            .build();
+
+        user_balance_entry_collection
+            .create_index(user_balance_entry_index_model, None)
+            .await?;
+
+        // Create an index on the 'tick' and 'block_height' fields for historical holders
+        let user_balance_entry_tick_index_model = IndexModel::builder()
+            .keys(doc! { "tick": 1, "block_height": 1 }) // 1 for ascending
+            .options(IndexOptions::default())
+            .build();
+
+        user_balance_entry_collection
+            .create_index(user_balance_entry_tick_index_model, None)
+            .await?;
+
         // Create an index on the 'tick' field for COLLECTION_TICKERS
         let tickers_collection = db.collection::<bson::Document>(consts::COLLECTION_TICKERS);
         let tickers_index_model = IndexModel::builder()
@@ -574,6 +684,16 @@ impl MongoClient {
             .create_index(txid_index_model, None)
             .await?;
 
+        // Create an index on the 'inscription_id' field for COLLECTION_TRANSFERS
+        let inscription_id_index_model = IndexModel::builder()
+            .keys(doc! { "inscription_id": 1 }) // 1 for ascending
+            .options(IndexOptions::default())
+            .build();
+
+        transfers_collection
+            .create_index(inscription_id_index_model, None)
+            .await?;
+
         // Create an index on the 'inscription.tick' field for COLLECTION_MINTS
         let mints_collection = db.collection::<bson::Document>(consts::COLLECTION_MINTS);
         let mints_index_model = IndexModel::builder()
@@ -586,212 +706,463 @@ impl MongoClient {
             .create_index(mints_index_model, None)
             .await?;
 
+        // Create a unique index on the 'id' field for paging through COLLECTION_EVENTS
+        let events_collection = db.collection::<bson::Document>(consts::COLLECTION_EVENTS);
+        let events_index_model = IndexModel::builder()
+            .keys(doc! { "id": 1 }) // 1 for ascending
+            .options(IndexOptions::builder().unique(true).build())
+            .build();
+
+        events_collection
+            .create_index(events_index_model, None)
+            .await?;
+
         Ok(())
     }
 
-    // delete all UserBalances with block_height >= start_block_height
-    //and return a list of user addresses, and return the associated "tick" field
-    // that had their balances deleted
-    pub async fn delete_user_balances_by_block_height(
+    // Rebuilds the balances changed at or after start_block_height from their
+    // entries before it, and deletes the ones without any. Rebuilt balances keep
+    // start_block_height, so rebuilding again after an interruption finds and
+    // rebuilds them again. Returns the rebuilt (address, tick) pairs.
+    async fn rebuild_user_balances(
         &self,
         start_block_height: i64,
-    ) -> Result<Vec<(String, String)>, anyhow::Error> {
-        let filter = doc! { "block_height": { "$gte": start_block_height }};
-
-        let mut deleted_user_balances = Vec::new();
-
-        let mut cursor = self
-            .find_with_retries(consts::COLLECTION_USER_BALANCES, Some(filter.clone()), None)
+        session: &mut ClientSession,
+    ) -> anyhow::Result<Vec<(String, String)>> {
+        let user_balances = self
+            .client
+            .database(&self.db_name)
+            .collection::<Document>(consts::COLLECTION_USER_BALANCES);
+        let changed = self
+            .find_all_with_session(
+                consts::COLLECTION_USER_BALANCES,
+                doc! { "block_height": { "$gte": start_block_height } },
+                None,
+                session,
+            )
             .await?;
 
-        while let Some(result) = cursor.next().await {
-            match result {
-                Ok(document) => {
-                    if let (Ok(address), Ok(tick)) =
-                        (document.get_str("address"), document.get_str("tick"))
-                    {
-                        deleted_user_balances.push((address.to_string(), tick.to_string()));
-                    }
-                }
-                Err(e) => return Err(e.into()),
+        let mut rebuilt = Vec::new();
+        for document in changed {
+            let address = document.get_str("address")?.to_string();
+            let tick = document.get_str("tick")?.to_string();
+            let filter = doc! { "address": &address, "tick": &tick };
+
+            // entries in the order they were written
+            let entries = self
+                .find_all_with_session(
+                    consts::COLLECTION_USER_BALANCE_ENTRY,
+                    doc! {
+                        "address": &address,
+                        "tick": &tick,
+                        "block_height": { "$lt": start_block_height },
+                    },
+                    Some(FindOptions::builder().sort(doc! { "_id": 1 }).build()),
+                    session,
+                )
+                .await?;
+            if entries.is_empty() {
+                user_balances
+                    .delete_one_with_session(filter, None, session)
+                    .await?;
+                continue;
             }
-        }
 
-        // check if there are any user balances to delete
-        if deleted_user_balances.is_empty() {
-            return Ok(deleted_user_balances);
+            let mut user_balance =
+                UserBalance::new(address.clone(), tick.clone(), start_block_height as u64);
+            for entry in &entries {
+                let entry = UserBalanceEntry::from_document(entry).map_err(anyhow::Error::msg)?;
+                apply_user_balance_entry(&mut user_balance, &entry)?;
+            }
+            user_balance.block_height = start_block_height as u64;
+
+            user_balances
+                .update_one_with_session(
+                    filter,
+                    doc! { "$set": user_balance.to_document() },
+                    UpdateOptions::builder().upsert(true).build(),
+                    session,
+                )
+                .await?;
+            rebuilt.push((address, tick));
         }
 
-        self.delete_many_with_retries(consts::COLLECTION_USER_BALANCES, filter.clone())
+        Ok(rebuilt)
+    }
+
+    // Recalculates total_minted of the tickers updated at or after
+    // start_block_height from their remaining mints. Returns the reset ticks.
+    async fn reset_tickers_total_minted(
+        &self,
+        start_block_height: i64,
+        session: &mut ClientSession,
+    ) -> anyhow::Result<Vec<String>> {
+        let tickers = self
+            .find_all_with_session(
+                consts::COLLECTION_TICKERS,
+                doc! { "updated_at_block": { "$gte": start_block_height } },
+                None,
+                session,
+            )
             .await?;
 
-        println!(
-            "Deleted {} user balances with block_height >= {}",
-            deleted_user_balances.len(),
-            start_block_height
-        );
+        let mut reset_tickers = Vec::new();
+        for ticker in tickers {
+            let tick = ticker.get_str("tick")?.to_string();
+            let mints = self
+                .find_all_with_session(
+                    consts::COLLECTION_MINTS,
+                    doc! { "inscription.tick": &tick },
+                    None,
+                    session,
+                )
+                .await?;
 
-        Ok(deleted_user_balances)
+            let mut total_minted = Amount::ZERO;
+            for amount in mints.iter().filter_map(|mint| get_amount(mint, "amt")) {
+                total_minted = total_minted
+                    .checked_add(amount)
+                    .ok_or_else(|| anyhow::anyhow!("Total minted overflow"))?;
+            }
+
+            self.client
+                .database(&self.db_name)
+                .collection::<Document>(consts::COLLECTION_TICKERS)
+                .update_one_with_session(
+                    doc! { "tick": &tick },
+                    doc! { "$set": {
+                        "total_minted": total_minted,
+                        "updated_at_block": start_block_height,
+                    } },
+                    None,
+                    session,
+                )
+                .await?;
+            reset_tickers.push(tick);
+        }
+
+        Ok(reset_tickers)
     }
 
-    pub async fn rebuild_deleted_user_balances(
+    async fn find_all_with_session(
         &self,
-        start_block_height: i64,
-        deleted_user_balances: Vec<(String, String)>,
-    ) -> anyhow::Result<()> {
-        let mut user_balances: HashMap<String, HashMap<String, (f64, f64, f64)>> = HashMap::new();
+        collection_name: &str,
+        filter: Document,
+        options: Option<FindOptions>,
+        session: &mut ClientSession,
+    ) -> mongodb::error::Result<Vec<Document>> {
+        let mut cursor = self
+            .client
+            .database(&self.db_name)
+            .collection::<Document>(collection_name)
+            .find_with_session(filter, options, session)
+            .await?;
 
-        for (address, tick) in deleted_user_balances {
-            let filter = doc! {
-                "address": address.clone(),
-                "tick": tick.clone(),
-                "block_height": { "$lt": start_block_height },
-            };
+        cursor.stream(session).try_collect().await
+    }
 
-            let mut cursor = self
-                .find_with_retries(consts::COLLECTION_USER_BALANCE_ENTRY, Some(filter), None)
-                .await?;
+    // Writes a block in a single transaction, retried as a whole on transient errors
+    // such as a primary election.
+    async fn write_block_in_transaction(&self, block: &BlockUpdate) -> anyhow::Result<()> {
+        let retries = consts::MONGO_RETRIES;
+        let mut session = self.client.start_session(None).await?;
 
-            while let Some(result) = cursor.next().await {
-                match result {
-                    Ok(document) => {
-                        let amount = document.get_f64("amt")?;
-                        let entry_type: UserBalanceEntryType =
-                            UserBalanceEntryType::from(document.get_str("entry_type")?);
-
-                        let user_balance = user_balances
-                            .entry(address.clone())
-                            .or_insert_with(HashMap::new);
-                        let balance = user_balance.entry(tick.clone()).or_insert((0.0, 0.0, 0.0)); // (available_balance, transferable_balance, overall balance)
-
-                        match entry_type {
-                            UserBalanceEntryType::Receive => {
-                                balance.0 += amount; // Increase the available balance
-                                balance.2 += amount; // Increase the overall balance
-                            }
-                            UserBalanceEntryType::Send => {
-                                balance.1 -= amount; // Decrease the transferable balance
-                                balance.2 -= amount; // Decrease the overall balance
-                            }
-                            UserBalanceEntryType::Inscription => {
-                                balance.0 -= amount; // Decrease the available balance
-                                balance.1 += amount; // Increase the transferable balance
-                            }
-                        }
-                    }
-                    Err(e) => return Err(e.into()),
+        for attempt in 0..=retries {
+            session.start_transaction(None).await?;
+
+            let result = match self.write_block_with_session(block, &mut session).await {
+                Ok(()) => commit_transaction_with_retry(&mut session).await,
+                Err(e) => {
+                    // the server may have aborted it already
+                    let _ = session.abort_transaction().await;
+                    Err(e)
                 }
+            };
+
+            match result {
+                Ok(()) => return Ok(()),
+                Err(e) if e.contains_label(TRANSIENT_TRANSACTION_ERROR) => {
+                    error!(
+                        "Attempt {}/{} failed with error: {}. Retrying...",
+                        attempt + 1,
+                        retries,
+                        e,
+                    );
+                    tokio::time::sleep(Duration::from_secs(2)).await;
+                }
+                Err(e) => return Err(e.into()),
             }
         }
+        Err(anyhow::anyhow!(
+            "Failed to write block {} after all retries",
+            block.block_height
+        ))
+    }
 
-        for (address, ticker_balances) in user_balances {
-            for (ticker, (available_balance, transferable_balance, overall_balance)) in
-                ticker_balances
-            {
-                let new_user_balance = doc! {
-                    "address": &address,
-                    "tick": &ticker,
-                    "available_balance": available_balance,
-                    "transferable_balance": transferable_balance,
-                    "overall_balance": overall_balance,
-                    "block_height": start_block_height,
-                };
-
-                self.insert_document(consts::COLLECTION_USER_BALANCES, new_user_balance)
-                    .await?;
+    async fn write_block_with_session(
+        &self,
+        block: &BlockUpdate,
+        session: &mut ClientSession,
+    ) -> mongodb::error::Result<()> {
+        let db = self.client.database(&self.db_name);
+
+        let user_balances = db.collection::<Document>(consts::COLLECTION_USER_BALANCES);
+        for (key, user_balance) in &block.updated_user_balances {
+            let filter = doc! { "address": &key.0, "tick": &key.1 };
+            let update = doc! { "$set": user_balance.to_document() };
+            user_balances
+                .update_one_with_session(filter, update, None, session)
+                .await?;
+        }
+
+        let new_user_balances: Vec<Document> = block
+            .new_user_balances
+            .values()
+            .map(|user_balance| user_balance.to_document())
+            .collect();
+        let user_balance_entries: Vec<Document> = block
+            .user_balance_entries
+            .iter()
+            .map(|entry| entry.to_document())
+            .collect();
+        let events: Vec<Document> = block
+            .events
+            .iter()
+            .map(|event| event.to_document())
+            .collect();
+
+        let inserts = [
+            (consts::COLLECTION_USER_BALANCES, &new_user_balances),
+            (consts::COLLECTION_MINTS, &block.mints),
+            (consts::COLLECTION_TRANSFERS, &block.transfers),
+            (consts::COLLECTION_DEPLOYS, &block.deploys),
+            (consts::COLLECTION_INVALIDS, &block.invalids),
+            (consts::COLLECTION_USER_BALANCE_ENTRY, &user_balance_entries),
+            (consts::COLLECTION_EVENTS, &events),
+        ];
+        for (collection_name, documents) in inserts {
+            // insert_many rejects an empty list
+            if documents.is_empty() {
+                continue;
             }
+
+            db.collection::<Document>(collection_name)
+                .insert_many_with_session(documents, None, session)
+                .await?;
+        }
+
+        // transfers inscribed in an earlier block are overwritten with their send
+        let transfers = db.collection::<Document>(consts::COLLECTION_TRANSFERS);
+        for transfer in &block.sent_transfers {
+            let mut transfer = transfer.clone();
+            transfer.remove("_id");
+            let inscription_id = transfer.get_str("inscription_id").unwrap_or_default();
+            let tx_id = transfer
+                .get_document("tx")
+                .and_then(|tx| tx.get_str("txid"))
+                .unwrap_or_default();
+            let filter = transfer_document_filter(inscription_id, tx_id);
+
+            let update = doc! { "$set": &transfer };
+            let options = UpdateOptions::builder().upsert(true).build();
+            transfers
+                .update_one_with_session(filter, update, options, session)
+                .await?;
         }
 
+        // deployed tickers are inserted
+        let tickers = db.collection::<Document>(consts::COLLECTION_TICKERS);
+        for ticker in block.tickers.values() {
+            let filter = doc! { "tick": &ticker.tick };
+            let update = doc! { "$set": ticker.to_document() };
+            let options = UpdateOptions::builder().upsert(true).build();
+            tickers
+                .update_one_with_session(filter, update, options, session)
+                .await?;
+        }
+
+        // replace the stored active transfers
+        let active_transfers = db.collection::<Document>(consts::COLLECTION_BRC20_ACTIVE_TRANSFERS);
+        active_transfers
+            .delete_many_with_session(doc! {}, None, session)
+            .await?;
+        let documents: Vec<Document> = block
+            .active_transfers
+            .values()
+            .flatten()
+            .map(|active_transfer| active_transfer.to_document())
+            .collect();
+        if !documents.is_empty() {
+            active_transfers
+                .insert_many_with_session(documents, None, session)
+                .await?;
+        }
+
+        db.collection::<Document>(consts::COLLECTION_BLOCKS_COMPLETED)
+            .insert_one_with_session(
+                completed_block_document(
+                    block.block_height,
+                    &block.block_hash,
+                    &block.previous_block_hash,
+                    &block.state_hash,
+                ),
+                None,
+                session,
+            )
+            .await?;
+
         Ok(())
     }
 
-    pub async fn reset_tickers_total_minted(
-        &self,
-        block_height: i64,
-    ) -> Result<Vec<Document>, Box<dyn std::error::Error>> {
-        let db = self.client.database(&self.db_name);
-        let collection = db.collection::<bson::Document>(consts::COLLECTION_TICKERS);
+    // Records a rollback before anything is deleted, so a rollback interrupted
+    // without transactions is finished by the next one, which starts at the lower
+    // of the two heights. Returns the height to roll back to.
+    async fn start_rollback(&self, start_block_height: i64) -> anyhow::Result<i64> {
+        let start_block_height = match self.get_pending_rollback().await? {
+            Some(pending) if pending < start_block_height => {
+                warn!("Finishing the interrupted rollback to block {}", pending);
+                pending
+            }
+            _ => start_block_height,
+        };
 
-        let filter = doc! { "updated_at_block": { "$gte": block_height } };
+        self.update_one_with_retries(
+            consts::COLLECTION_PENDING_ROLLBACK,
+            doc! {},
+            doc! { "$set": { consts::KEY_BLOCK_HEIGHT: start_block_height } },
+            Some(UpdateOptions::builder().upsert(true).build()),
+        )
+        .await?;
 
-        let options = FindOptions::builder().build();
-        let mut cursor = collection.find(filter, options).await?;
-        let mut updated_tickers: Vec<Document> = Vec::new();
+        Ok(start_block_height)
+    }
 
-        // Iterate over the documents that match the filter
-        while let Some(result) = cursor.next().await {
-            let mut doc = result?;
+    // Rolls back in a single transaction, retried as a whole on transient errors
+    // like writing a block. A rollback over many blocks can outgrow MongoDB's
+    // transaction limits, roll back with MONGO_TRANSACTIONS=false then.
+    async fn rollback_in_transaction(&self, start_block_height: i64) -> anyhow::Result<()> {
+        let retries = consts::MONGO_RETRIES;
+        let mut session = self.client.start_session(None).await?;
 
-            // Reset total_minted to 0.0
-            doc.insert("total_minted", 0.0);
-            doc.insert("updated_at_block", block_height);
+        for attempt in 0..=retries {
+            session.start_transaction(None).await?;
 
-            // Call the calculate_and_update_total_minted function
-            match self
-                .calculate_and_update_total_minted_for_ticker(&mut doc)
+            let result = match self
+                .rollback_with_session(start_block_height, &mut session)
                 .await
             {
-                Ok(_) => {}
+                Ok(()) => commit_transaction_with_retry(&mut session)
+                    .await
+                    .map_err(anyhow::Error::from),
                 Err(e) => {
-                    error!("Error calculating and updating total_minted: {}", e);
-                    continue;
+                    // the server may have aborted it already
+                    let _ = session.abort_transaction().await;
+                    Err(e)
                 }
-            }
+            };
 
-            updated_tickers.push(doc);
+            match result {
+                Ok(()) => return Ok(()),
+                Err(e) if is_transient_transaction_error(&e) => {
+                    error!(
+                        "Attempt {}/{} failed with error: {}. Retrying...",
+                        attempt + 1,
+                        retries,
+                        e,
+                    );
+                    tokio::time::sleep(Duration::from_secs(2)).await;
+                }
+                Err(e) => return Err(e),
+            }
         }
-
-        Ok(updated_tickers)
+        Err(anyhow::anyhow!(
+            "Failed to roll back to block {} after all retries",
+            start_block_height
+        ))
     }
 
-    pub async fn calculate_and_update_total_minted_for_ticker(
+    // Removes everything indexed at or after start_block_height, in the session's
+    // transaction if it has one. Without, every step can run again after an
+    // interruption and the block completions are deleted last.
+    async fn rollback_with_session(
         &self,
-        ticker_doc: &mut Document,
-    ) -> Result<(), Box<dyn std::error::Error>> {
+        start_block_height: i64,
+        session: &mut ClientSession,
+    ) -> anyhow::Result<()> {
         let db = self.client.database(&self.db_name);
-        let tickers_coll = db.collection::<bson::Document>(consts::COLLECTION_TICKERS);
-        let mints_coll = db.collection::<bson::Document>(consts::COLLECTION_MINTS);
+        let filter = doc! { "block_height": { "$gte": start_block_height } };
 
-        let mut update_doc = ticker_doc.clone();
+        // transfers sent after the rollback point are active again
+        let restored = self
+            .restore_active_transfers(start_block_height, session)
+            .await?;
+        info!("Active Transfers restored: {}", restored);
+
+        // delete deploys, mints, transfers, inscriptions, tickers, invalids, entries
+        let collections = [
+            consts::COLLECTION_DEPLOYS,
+            consts::COLLECTION_MINTS,
+            consts::COLLECTION_TRANSFERS,
+            consts::COLLECTION_INVALIDS,
+            consts::COLLECTION_TICKERS,
+            consts::COLLECTION_USER_BALANCE_ENTRY,
+            consts::COLLECTION_EVENTS,
+            consts::COLLECTION_BRC20_ACTIVE_TRANSFERS,
+            consts::COLLECTION_TOTAL_MINTED_AT_BLOCK_HEIGHT,
+        ];
+        for collection_name in collections {
+            db.collection::<Document>(collection_name)
+                .delete_many_with_session(filter.clone(), None, session)
+                .await?;
+        }
 
-        let tick = ticker_doc.get_str("tick")?;
+        // events indexed again are delivered again
+        let first_event_id = block_event_id(start_block_height);
+        db.collection::<Document>(consts::COLLECTION_WEBHOOK_CURSORS)
+            .update_many_with_session(
+                doc! { "event_id": { "$gt": &first_event_id } },
+                doc! { "$set": { "event_id": &first_event_id } },
+                None,
+                session,
+            )
+            .await?;
 
-        let filter = doc! { "inscription.tick": tick };
-        let cursor = mints_coll.find(filter, None).await?;
-        let mints: Vec<Document> = cursor.try_collect().await?;
+        let reset_tickers = self
+            .reset_tickers_total_minted(start_block_height, session)
+            .await?;
+        info!("Reset total_minted for tickers: {:?}", reset_tickers);
 
-        let total_minted: f64 = mints
-            .iter()
-            .filter_map(|mint| mint.get_f64("amt").ok())
-            .sum();
+        let rebuilt_user_balances = self
+            .rebuild_user_balances(start_block_height, session)
+            .await?;
+        info!("Rebuilt User Balances: {:?}", rebuilt_user_balances);
 
-        update_doc.insert("total_minted", total_minted);
-        let update = doc! { "$set": update_doc};
-        let filter = doc! { "tick": tick };
-        tickers_coll.update_one(filter, update, None).await?;
+        db.collection::<Document>(consts::COLLECTION_BLOCKS_COMPLETED)
+            .delete_many_with_session(filter, None, session)
+            .await?;
 
         Ok(())
     }
 
     pub async fn update_user_balances(
         &self,
-        user_balance_docs_to_update: HashMap<(String, String), Document>,
-        user_balance_docs_to_insert: HashMap<(String, String), Document>,
+        user_balances_to_update: HashMap<(String, String), UserBalance>,
+        user_balances_to_insert: HashMap<(String, String), UserBalance>,
     ) -> Result<(), anyhow::Error> {
         let collection_name = consts::COLLECTION_USER_BALANCES;
 
         //time the process
         let start = std::time::Instant::now();
-        let len = user_balance_docs_to_update.len();
+        let len = user_balances_to_update.len();
         // Update user balance documents
-        for (key, document) in user_balance_docs_to_update {
+        for (key, user_balance) in user_balances_to_update {
             let filter = doc! {
                 "address": key.0,
                 "tick": key.1,
             };
 
             let update = doc! {
-                "$set": document,
+                "$set": user_balance.to_document(),
             };
 
             self.update_one_with_retries(collection_name, filter, update, None)
@@ -800,8 +1171,10 @@ impl MongoClient {
         warn!("Updated {} user balances in: {:?}", len, start.elapsed());
 
         // Insert new user balance documents using insert_many_with_retries
-        let documents_to_insert: Vec<Document> =
-            user_balance_docs_to_insert.values().cloned().collect();
+        let documents_to_insert: Vec<Document> = user_balances_to_insert
+            .values()
+            .map(|user_balance| user_balance.to_document())
+            .collect();
         if !documents_to_insert.is_empty() {
             let start = std::time::Instant::now();
             self.insert_many_with_retries(collection_name, &documents_to_insert)
@@ -914,3 +1287,531 @@ impl MongoClient {
     //     Ok(())
     // }
 }
+
+#[async_trait]
+impl Brc20Store for MongoClient {
+    async fn get_ticker(&self, tick: &str) -> anyhow::Result<Option<Brc20Ticker>> {
+        match self
+            .get_document_by_field(consts::COLLECTION_TICKERS, "tick", tick)
+            .await?
+        {
+            Some(document) => Ok(Some(
+                Brc20Ticker::from_document(&document).map_err(anyhow::Error::msg)?,
+            )),
+            None => Ok(None),
+        }
+    }
+
+    async fn get_user_balance(
+        &self,
+        address: &str,
+        tick: &str,
+    ) -> anyhow::Result<Option<UserBalance>> {
+        let key = (address.to_string(), tick.to_string());
+        match self.load_user_balance_with_retry(&key).await? {
+            Some(document) => Ok(Some(
+                UserBalance::from_document(&document).map_err(anyhow::Error::msg)?,
+            )),
+            None => Ok(None),
+        }
+    }
+
+    async fn get_transfer(
+        &self,
+        inscription_id: &str,
+        tx_id: &str,
+    ) -> anyhow::Result<Option<Document>> {
+        let filter = transfer_document_filter(inscription_id, tx_id);
+        self.get_document_by_filter(consts::COLLECTION_TRANSFERS, filter)
+            .await
+    }
+
+    async fn load_active_transfers(&self) -> anyhow::Result<ActiveTransfers> {
+        let active_transfers = self
+            .load_active_transfers_with_retry()
+            .await
+            .map_err(anyhow::Error::msg)?;
+
+        Ok(active_transfers.unwrap_or_default())
+    }
+
+    async fn get_last_completed_block_height(&self) -> anyhow::Result<Option<i64>> {
+        MongoClient::get_last_completed_block_height(self).await
+    }
+
+    async fn get_completed_block_hash(&self, block_height: i64) -> anyhow::Result<Option<String>> {
+        MongoClient::get_completed_block_hash(self, block_height).await
+    }
+
+    async fn get_state_hash(&self, block_height: i64) -> anyhow::Result<Option<String>> {
+        MongoClient::get_state_hash(self, block_height).await
+    }
+
+    async fn get_events(&self, after: Option<&str>, limit: u64) -> anyhow::Result<Vec<Brc20Event>> {
+        let filter = match after {
+            Some(after) => doc! { "id": { "$gt": after } },
+            None => doc! {},
+        };
+        let options = FindOptions::builder()
+            .sort(doc! { "id": 1 })
+            .limit(limit as i64)
+            .build();
+        let cursor = self
+            .find_with_retries(consts::COLLECTION_EVENTS, Some(filter), Some(options))
+            .await?;
+        let documents: Vec<Document> = cursor.try_collect().await?;
+
+        documents
+            .iter()
+            .map(|document| Brc20Event::from_document(document).map_err(anyhow::Error::msg))
+            .collect()
+    }
+
+    async fn get_webhook_cursor(&self, url: &str) -> anyhow::Result<Option<String>> {
+        let result = self
+            .find_one_with_retries(
+                consts::COLLECTION_WEBHOOK_CURSORS,
+                doc! { "url": url },
+                None,
+            )
+            .await?;
+
+        Ok(result.and_then(|document| {
+            document
+                .get_str("event_id")
+                .ok()
+                .map(|event_id| event_id.to_string())
+        }))
+    }
+
+    async fn set_webhook_cursor(&self, url: &str, event_id: &str) -> anyhow::Result<()> {
+        let options = UpdateOptions::builder().upsert(true).build();
+        self.update_one_with_retries(
+            consts::COLLECTION_WEBHOOK_CURSORS,
+            doc! { "url": url },
+            doc! { "$set": { "event_id": event_id } },
+            Some(options),
+        )
+        .await
+    }
+
+    async fn get_user_balances(&self, address: &str) -> anyhow::Result<Vec<UserBalance>> {
+        let options = FindOptions::builder().sort(doc! { "tick": 1 }).build();
+        let cursor = self
+            .find_with_retries(
+                consts::COLLECTION_USER_BALANCES,
+                Some(doc! { "address": address }),
+                Some(options),
+            )
+            .await?;
+        let documents: Vec<Document> = cursor.try_collect().await?;
+
+        documents
+            .iter()
+            .map(|document| UserBalance::from_document(document).map_err(anyhow::Error::msg))
+            .collect()
+    }
+
+    async fn get_holders(&self, tick: &str, page: Page) -> anyhow::Result<Vec<UserBalance>> {
+        let filter = doc! {
+            "tick": tick,
+            "overall_balance": { "$nin": ["0", 0] },
+        };
+        let options = FindOptions::builder()
+            .sort(doc! { "address": 1 })
+            .skip(page.offset)
+            .limit(page.limit as i64)
+            .build();
+        let cursor = self
+            .find_with_retries(
+                consts::COLLECTION_USER_BALANCES,
+                Some(filter),
+                Some(options),
+            )
+            .await?;
+        let documents: Vec<Document> = cursor.try_collect().await?;
+
+        documents
+            .iter()
+            .map(|document| UserBalance::from_document(document).map_err(anyhow::Error::msg))
+            .collect()
+    }
+
+    async fn get_user_balance_entries(
+        &self,
+        address: &str,
+        tick: Option<&str>,
+        page: Page,
+    ) -> anyhow::Result<Vec<UserBalanceEntry>> {
+        let mut filter = doc! { "address": address };
+        if let Some(tick) = tick {
+            filter.insert("tick", tick);
+        }
+        // entries of a block are inserted in order, so _id breaks the ties
+        let options = FindOptions::builder()
+            .sort(doc! { "block_height": 1, "_id": 1 })
+            .skip(page.offset)
+            .limit(page.limit as i64)
+            .build();
+        let cursor = self
+            .find_with_retries(
+                consts::COLLECTION_USER_BALANCE_ENTRY,
+                Some(filter),
+                Some(options),
+            )
+            .await?;
+        let documents: Vec<Document> = cursor.try_collect().await?;
+
+        documents
+            .iter()
+            .map(|document| UserBalanceEntry::from_document(document).map_err(anyhow::Error::msg))
+            .collect()
+    }
+
+    async fn get_user_balance_entries_until(
+        &self,
+        address: Option<&str>,
+        tick: Option<&str>,
+        block_height: i64,
+    ) -> anyhow::Result<Vec<UserBalanceEntry>> {
+        let mut filter = doc! { "block_height": { "$lte": block_height } };
+        if let Some(address) = address {
+            filter.insert("address", address);
+        }
+        if let Some(tick) = tick {
+            filter.insert("tick", tick);
+        }
+        let options = FindOptions::builder()
+            .sort(doc! { "block_height": 1, "_id": 1 })
+            .build();
+        let cursor = self
+            .find_with_retries(
+                consts::COLLECTION_USER_BALANCE_ENTRY,
+                Some(filter),
+                Some(options),
+            )
+            .await?;
+        let documents: Vec<Document> = cursor.try_collect().await?;
+
+        documents
+            .iter()
+            .map(|document| UserBalanceEntry::from_document(document).map_err(anyhow::Error::msg))
+            .collect()
+    }
+
+    async fn get_transfers_by_tx_id(&self, tx_id: &str) -> anyhow::Result<Vec<Document>> {
+        let cursor = self
+            .find_with_retries(
+                consts::COLLECTION_TRANSFERS,
+                Some(doc! { "tx.txid": tx_id }),
+                None,
+            )
+            .await?;
+
+        Ok(cursor.try_collect().await?)
+    }
+
+    async fn get_tickers(&self) -> anyhow::Result<Vec<Brc20Ticker>> {
+        let options = FindOptions::builder().sort(doc! { "tick": 1 }).build();
+        let cursor = self
+            .find_with_retries(consts::COLLECTION_TICKERS, None, Some(options))
+            .await?;
+        let documents: Vec<Document> = cursor.try_collect().await?;
+
+        documents
+            .iter()
+            .map(|document| Brc20Ticker::from_document(document).map_err(anyhow::Error::msg))
+            .collect()
+    }
+
+    async fn get_all_user_balances(&self) -> anyhow::Result<Vec<UserBalance>> {
+        let options = FindOptions::builder()
+            .sort(doc! { "address": 1, "tick": 1 })
+            .build();
+        let mut cursor = self
+            .find_with_retries(consts::COLLECTION_USER_BALANCES, None, Some(options))
+            .await?;
+
+        let mut user_balances = Vec::new();
+        while let Some(document) = cursor.try_next().await? {
+            user_balances.push(UserBalance::from_document(&document).map_err(anyhow::Error::msg)?);
+        }
+
+        Ok(user_balances)
+    }
+
+    async fn get_minted_amounts(
+        &self,
+        block_height: i64,
+    ) -> anyhow::Result<HashMap<String, Amount>> {
+        // amounts are strings, they are summed here rather than in an aggregation
+        let options = FindOptions::builder()
+            .projection(doc! { "inscription.tick": 1, "amt": 1 })
+            .build();
+        let mut cursor = self
+            .find_with_retries(
+                consts::COLLECTION_MINTS,
+                Some(doc! { "block_height": { "$lte": block_height } }),
+                Some(options),
+            )
+            .await?;
+
+        let mut minted_amounts: HashMap<String, Amount> = HashMap::new();
+        while let Some(mint) = cursor.try_next().await? {
+            let tick = mint.get_document("inscription")?.get_str("tick")?;
+            let minted = minted_amounts.entry(tick.to_lowercase()).or_default();
+            *minted = minted
+                .checked_add(get_amount(&mint, "amt").unwrap_or_default())
+                .ok_or_else(|| anyhow::anyhow!("Total minted overflow"))?;
+        }
+
+        Ok(minted_amounts)
+    }
+
+    async fn write_block(&self, block: BlockUpdate) -> anyhow::Result<()> {
+        if self.transactions {
+            let start = Instant::now();
+            self.write_block_in_transaction(&block).await?;
+            warn!(
+                "Block {} committed in {:?}",
+                block.block_height,
+                start.elapsed()
+            );
+            return Ok(());
+        }
+
+        // write the updated and new user balance documents
+        if !block.updated_user_balances.is_empty() || !block.new_user_balances.is_empty() {
+            info!("Inserting User Balances...");
+            self.update_user_balances(block.updated_user_balances, block.new_user_balances)
+                .await?;
+        }
+
+        let user_balance_entries: Vec<Document> = block
+            .user_balance_entries
+            .iter()
+            .map(|entry| entry.to_document())
+            .collect();
+        let events: Vec<Document> = block
+            .events
+            .iter()
+            .map(|event| event.to_document())
+            .collect();
+
+        let inserts = [
+            (consts::COLLECTION_MINTS, "Mints", &block.mints),
+            (consts::COLLECTION_TRANSFERS, "Transfers", &block.transfers),
+            (consts::COLLECTION_DEPLOYS, "Deploys", &block.deploys),
+            (consts::COLLECTION_INVALIDS, "Invalids", &block.invalids),
+            (
+                consts::COLLECTION_USER_BALANCE_ENTRY,
+                "User Balance Entries",
+                &user_balance_entries,
+            ),
+            (consts::COLLECTION_EVENTS, "Events", &events),
+        ];
+
+        for (collection_name, label, documents) in inserts {
+            // insert_many rejects an empty list
+            if documents.is_empty() {
+                continue;
+            }
+
+            let start = Instant::now();
+            self.insert_many_with_retries(collection_name, documents)
+                .await?;
+            warn!(
+                "{} inserted after block: {} in {:?}",
+                label,
+                documents.len(),
+                start.elapsed()
+            );
+        }
+
+        // transfers inscribed in an earlier block are overwritten with their send
+        for mut transfer in block.sent_transfers {
+            transfer.remove("_id");
+            let inscription_id = transfer.get_str("inscription_id").unwrap_or_default();
+            let tx_id = transfer
+                .get_document("tx")
+                .and_then(|tx| tx.get_str("txid"))
+                .unwrap_or_default();
+            let filter = transfer_document_filter(inscription_id, tx_id);
+
+            let update = doc! { "$set": &transfer };
+            let options = UpdateOptions::builder().upsert(true).build();
+            self.update_one_with_retries(
+                consts::COLLECTION_TRANSFERS,
+                filter,
+                update,
+                Some(options),
+            )
+            .await?;
+        }
+
+        // Bulk update tickers, deployed tickers are inserted
+        if !block.tickers.is_empty() {
+            let start = Instant::now();
+            for ticker in block.tickers.values() {
+                let filter = doc! { "tick": &ticker.tick };
+                let update = doc! { "$set": ticker.to_document() };
+                let options = UpdateOptions::builder().upsert(true).build();
+
+                self.update_one_with_retries(
+                    consts::COLLECTION_TICKERS,
+                    filter,
+                    update,
+                    Some(options),
+                )
+                .await?;
+            }
+
+            warn!(
+                "Tickers updated after block: {} in {:?}",
+                block.tickers.len(),
+                start.elapsed()
+            );
+        }
+
+        // replace the stored active transfers
+        self.drop_collection(consts::COLLECTION_BRC20_ACTIVE_TRANSFERS)
+            .await?;
+
+        if !block.active_transfers.is_empty() {
+            let start = Instant::now();
+            let length = block.active_transfers.len();
+            self.insert_active_transfers_to_mongodb(block.active_transfers)
+                .await?;
+
+            info!(
+                "Active Transfers inserted to MongoDB after block: {} in {:?}",
+                length,
+                start.elapsed()
+            );
+        }
+
+        // After successfully writing the block, store it as completed
+        self.store_completed_block(
+            block.block_height,
+            &block.block_hash,
+            &block.previous_block_hash,
+            &block.state_hash,
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    async fn rollback_to_block_height(&self, start_block_height: i64) -> anyhow::Result<()> {
+        // recorded first, blocks from it on aren't completed anymore until it's done
+        let start_block_height = self.start_rollback(start_block_height).await?;
+
+        if self.transactions {
+            self.rollback_in_transaction(start_block_height).await?;
+        } else {
+            let mut session = self.client.start_session(None).await?;
+            self.rollback_with_session(start_block_height, &mut session)
+                .await?;
+        }
+
+        self.delete_many_with_retries(consts::COLLECTION_PENDING_ROLLBACK, doc! {})
+            .await?;
+
+        Ok(())
+    }
+
+    // upserts and deletes are idempotent, without transactions an interrupted
+    // repair is finished by running it again
+    async fn repair_state(&self, repair: StateRepair) -> anyhow::Result<()> {
+        let options = UpdateOptions::builder().upsert(true).build();
+        for ticker in &repair.tickers {
+            self.update_one_with_retries(
+                consts::COLLECTION_TICKERS,
+                doc! { "tick": &ticker.tick },
+                doc! { "$set": ticker.to_document() },
+                Some(options.clone()),
+            )
+            .await?;
+        }
+
+        for user_balance in &repair.user_balances {
+            self.update_one_with_retries(
+                consts::COLLECTION_USER_BALANCES,
+                doc! { "address": &user_balance.address, "tick": &user_balance.tick },
+                doc! { "$set": user_balance.to_document() },
+                Some(options.clone()),
+            )
+            .await?;
+        }
+
+        for (address, tick) in &repair.deleted_user_balances {
+            self.delete_many_with_retries(
+                consts::COLLECTION_USER_BALANCES,
+                doc! { "address": address, "tick": tick },
+            )
+            .await?;
+        }
+
+        Ok(())
+    }
+}
+
+fn completed_block_document(
+    block_height: i64,
+    block_hash: &str,
+    previous_block_hash: &str,
+    state_hash: &str,
+) -> Document {
+    doc! {
+        consts::KEY_BLOCK_HEIGHT: block_height,
+        consts::KEY_BLOCK_HASH: block_hash,
+        consts::KEY_PREVIOUS_BLOCK_HASH: previous_block_hash,
+        consts::KEY_STATE_HASH: state_hash,
+        "created_at": Bson::DateTime(DateTime::now())
+    }
+}
+
+// replica set members report their set name, mongos reports isdbgrid
+async fn supports_transactions(client: &Client) -> bool {
+    match client
+        .database("admin")
+        .run_command(doc! { "hello": 1 }, None)
+        .await
+    {
+        Ok(hello) => {
+            hello.contains_key("setName")
+                || hello.get_str("msg").map_or(false, |msg| msg == "isdbgrid")
+        }
+        Err(e) => {
+            warn!("Failed to detect MongoDB topology: {}", e);
+            false
+        }
+    }
+}
+
+fn is_transient_transaction_error(error: &anyhow::Error) -> bool {
+    matches!(
+        error.downcast_ref::<mongodb::error::Error>(),
+        Some(error) if error.contains_label(TRANSIENT_TRANSACTION_ERROR)
+    )
+}
+
+// the commit is retried when its outcome is unknown, committing twice is safe
+async fn commit_transaction_with_retry(session: &mut ClientSession) -> mongodb::error::Result<()> {
+    let retries = consts::MONGO_RETRIES;
+    let mut attempt = 0;
+    loop {
+        match session.commit_transaction().await {
+            Err(e) if e.contains_label(UNKNOWN_TRANSACTION_COMMIT_RESULT) && attempt < retries => {
+                error!(
+                    "Commit attempt {}/{} failed with error: {}. Retrying...",
+                    attempt + 1,
+                    retries,
+                    e,
+                );
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
@@ -0,0 +1,1060 @@
+use super::{
+    amount::{get_amount, Amount},
+    brc20_ticker::Brc20Ticker,
+    event::{block_event_id, Brc20Event},
+    satpoint::SatPoint,
+    store::{
+        document_from_json, document_to_json, get_integer, BlockUpdate, Brc20Store, Page,
+        StateRepair,
+    },
+    transfer::{
+        insert_active_transfer, ActiveTransfers, Brc20ActiveTransfer, TRANSFER_SEND_FIELDS,
+    },
+    user_balance::{UserBalance, UserBalanceEntry, UserBalanceEntryType},
+    utils::apply_user_balance_entry,
+    ToDocument,
+};
+use async_trait::async_trait;
+use log::{error, info};
+use mongodb::bson::{Bson, Document};
+use std::collections::HashMap;
+use tokio::sync::Mutex;
+use tokio_postgres::{Client, NoTls, Row, Transaction};
+
+// Amounts are NUMERIC columns, they are written and read as text so they keep
+// all 18 decimals. Event tables keep a few columns to query on next to the full
+// event document.
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS tickers (
+    tick TEXT PRIMARY KEY,
+    lim NUMERIC NOT NULL,
+    max_supply NUMERIC NOT NULL,
+    total_minted NUMERIC NOT NULL,
+    decimals SMALLINT NOT NULL,
+    block_height BIGINT NOT NULL,
+    updated_at_block BIGINT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS tickers_block_height ON tickers (block_height);
+CREATE INDEX IF NOT EXISTS tickers_updated_at_block ON tickers (updated_at_block);
+
+CREATE TABLE IF NOT EXISTS deploys (
+    inscription_id TEXT PRIMARY KEY,
+    tick TEXT NOT NULL,
+    block_height BIGINT NOT NULL,
+    document JSONB NOT NULL
+);
+CREATE INDEX IF NOT EXISTS deploys_block_height ON deploys (block_height);
+
+CREATE TABLE IF NOT EXISTS mints (
+    inscription_id TEXT PRIMARY KEY,
+    tick TEXT NOT NULL,
+    address TEXT NOT NULL,
+    amt NUMERIC NOT NULL,
+    block_height BIGINT NOT NULL,
+    document JSONB NOT NULL
+);
+CREATE INDEX IF NOT EXISTS mints_tick ON mints (tick);
+CREATE INDEX IF NOT EXISTS mints_block_height ON mints (block_height);
+
+CREATE TABLE IF NOT EXISTS transfers (
+    inscription_id TEXT PRIMARY KEY,
+    tx_id TEXT NOT NULL,
+    tick TEXT NOT NULL,
+    from_address TEXT NOT NULL,
+    to_address TEXT,
+    amt NUMERIC NOT NULL,
+    vout BIGINT NOT NULL,
+    sat_offset BIGINT NOT NULL,
+    block_height BIGINT NOT NULL,
+    send_block_height BIGINT,
+    document JSONB NOT NULL
+);
+CREATE INDEX IF NOT EXISTS transfers_tx_id ON transfers (tx_id);
+CREATE INDEX IF NOT EXISTS transfers_block_height ON transfers (block_height);
+CREATE INDEX IF NOT EXISTS transfers_send_block_height ON transfers (send_block_height);
+
+CREATE TABLE IF NOT EXISTS invalids (
+    id BIGSERIAL PRIMARY KEY,
+    tx_id TEXT NOT NULL,
+    reason TEXT NOT NULL,
+    block_height BIGINT NOT NULL,
+    document JSONB NOT NULL
+);
+CREATE INDEX IF NOT EXISTS invalids_block_height ON invalids (block_height);
+
+CREATE TABLE IF NOT EXISTS user_balances (
+    address TEXT NOT NULL,
+    tick TEXT NOT NULL,
+    overall_balance NUMERIC NOT NULL,
+    available_balance NUMERIC NOT NULL,
+    transferable_balance NUMERIC NOT NULL,
+    block_height BIGINT NOT NULL,
+    PRIMARY KEY (address, tick)
+);
+CREATE INDEX IF NOT EXISTS user_balances_tick ON user_balances (tick, address);
+CREATE INDEX IF NOT EXISTS user_balances_block_height ON user_balances (block_height);
+
+CREATE TABLE IF NOT EXISTS user_balance_entries (
+    id BIGSERIAL PRIMARY KEY,
+    address TEXT NOT NULL,
+    tick TEXT NOT NULL,
+    block_height BIGINT NOT NULL,
+    amt NUMERIC NOT NULL,
+    entry_type TEXT NOT NULL,
+    txid TEXT,
+    tx_index BIGINT,
+    inscription_id TEXT,
+    counterparty TEXT
+);
+-- databases created before entry sources were recorded
+ALTER TABLE user_balance_entries ADD COLUMN IF NOT EXISTS txid TEXT;
+ALTER TABLE user_balance_entries ADD COLUMN IF NOT EXISTS tx_index BIGINT;
+ALTER TABLE user_balance_entries ADD COLUMN IF NOT EXISTS inscription_id TEXT;
+ALTER TABLE user_balance_entries ADD COLUMN IF NOT EXISTS counterparty TEXT;
+CREATE INDEX IF NOT EXISTS user_balance_entries_address_tick
+    ON user_balance_entries (address, tick, block_height);
+CREATE INDEX IF NOT EXISTS user_balance_entries_block_height
+    ON user_balance_entries (block_height);
+CREATE INDEX IF NOT EXISTS user_balance_entries_tick
+    ON user_balance_entries (tick, block_height);
+
+CREATE TABLE IF NOT EXISTS active_transfers (
+    inscription_id TEXT PRIMARY KEY,
+    tx_id TEXT NOT NULL,
+    satpoint TEXT NOT NULL,
+    block_height BIGINT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS active_transfers_block_height ON active_transfers (block_height);
+
+CREATE TABLE IF NOT EXISTS blocks_completed (
+    block_height BIGINT PRIMARY KEY,
+    block_hash TEXT NOT NULL,
+    previous_block_hash TEXT NOT NULL,
+    state_hash TEXT
+);
+-- databases created before state hashes were recorded
+ALTER TABLE blocks_completed ADD COLUMN IF NOT EXISTS state_hash TEXT;
+
+-- event ids are compared byte by byte, whatever the database collation
+CREATE TABLE IF NOT EXISTS events (
+    id TEXT COLLATE \"C\" PRIMARY KEY,
+    event_type TEXT NOT NULL,
+    block_height BIGINT NOT NULL,
+    document JSONB NOT NULL
+);
+CREATE INDEX IF NOT EXISTS events_block_height ON events (block_height);
+
+CREATE TABLE IF NOT EXISTS webhook_cursors (
+    url TEXT PRIMARY KEY,
+    event_id TEXT COLLATE \"C\" NOT NULL
+);
+";
+
+const INSERT_DEPLOY: &str = "
+INSERT INTO deploys (inscription_id, tick, block_height, document)
+VALUES ($1, $2, $3, $4::TEXT::JSONB)";
+
+const INSERT_MINT: &str = "
+INSERT INTO mints (inscription_id, tick, address, amt, block_height, document)
+VALUES ($1, $2, $3, $4::TEXT::NUMERIC, $5, $6::TEXT::JSONB)";
+
+const UPSERT_TRANSFER: &str = "
+INSERT INTO transfers
+    (inscription_id, tx_id, tick, from_address, to_address, amt, vout, sat_offset,
+     block_height, send_block_height, document)
+VALUES ($1, $2, $3, $4, $5, $6::TEXT::NUMERIC, $7, $8, $9, $10, $11::TEXT::JSONB)
+ON CONFLICT (inscription_id) DO UPDATE SET
+    to_address = EXCLUDED.to_address,
+    send_block_height = EXCLUDED.send_block_height,
+    document = EXCLUDED.document";
+
+const INSERT_EVENT: &str = "
+INSERT INTO events (id, event_type, block_height, document)
+VALUES ($1, $2, $3, $4::TEXT::JSONB)";
+
+const INSERT_INVALID: &str = "
+INSERT INTO invalids (tx_id, reason, block_height, document)
+VALUES ($1, $2, $3, $4::TEXT::JSONB)";
+
+const UPSERT_USER_BALANCE: &str = "
+INSERT INTO user_balances
+    (address, tick, overall_balance, available_balance, transferable_balance, block_height)
+VALUES ($1, $2, $3::TEXT::NUMERIC, $4::TEXT::NUMERIC, $5::TEXT::NUMERIC, $6)
+ON CONFLICT (address, tick) DO UPDATE SET
+    overall_balance = EXCLUDED.overall_balance,
+    available_balance = EXCLUDED.available_balance,
+    transferable_balance = EXCLUDED.transferable_balance,
+    block_height = EXCLUDED.block_height";
+
+const INSERT_USER_BALANCE_ENTRY: &str = "
+INSERT INTO user_balance_entries
+    (address, tick, block_height, amt, entry_type, txid, tx_index, inscription_id, counterparty)
+VALUES ($1, $2, $3, $4::TEXT::NUMERIC, $5, $6, $7, $8, $9)";
+
+const UPSERT_TICKER: &str = "
+INSERT INTO tickers
+    (tick, lim, max_supply, total_minted, decimals, block_height, updated_at_block)
+VALUES ($1, $2::TEXT::NUMERIC, $3::TEXT::NUMERIC, $4::TEXT::NUMERIC, $5, $6, $7)
+ON CONFLICT (tick) DO UPDATE SET
+    total_minted = EXCLUDED.total_minted,
+    updated_at_block = EXCLUDED.updated_at_block";
+
+const UPSERT_ACTIVE_TRANSFER: &str = "
+INSERT INTO active_transfers (inscription_id, tx_id, satpoint, block_height)
+VALUES ($1, $2, $3, $4)
+ON CONFLICT (inscription_id) DO UPDATE SET
+    tx_id = EXCLUDED.tx_id,
+    satpoint = EXCLUDED.satpoint,
+    block_height = EXCLUDED.block_height";
+
+// tables whose rows are deleted by height on a rollback
+const ROLLBACK_TABLES: [&str; 9] = [
+    "deploys",
+    "mints",
+    "transfers",
+    "invalids",
+    "tickers",
+    "user_balance_entries",
+    "active_transfers",
+    "blocks_completed",
+    "events",
+];
+
+/// Keeps the index in PostgreSQL.
+///
+/// Every block is written in a single transaction, so the database never holds
+/// a partially indexed block. Rollbacks follow the MongoDB backend.
+pub struct PostgresStore {
+    client: Mutex<Client>,
+}
+
+impl PostgresStore {
+    /// Connects to the database at `connection_string` and creates the tables in
+    /// `schema` if needed.
+    pub async fn connect(connection_string: &str, schema: &str) -> anyhow::Result<Self> {
+        let (client, connection) = tokio_postgres::connect(connection_string, NoTls).await?;
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                error!("PostgreSQL connection error: {}", e);
+            }
+        });
+
+        let schema = quote_identifier(schema);
+        client
+            .batch_execute(&format!(
+                "CREATE SCHEMA IF NOT EXISTS {}; SET search_path TO {};",
+                schema, schema
+            ))
+            .await?;
+        client.batch_execute(SCHEMA).await?;
+        info!("Connected to PostgreSQL, schema: {}", schema);
+
+        Ok(PostgresStore {
+            client: Mutex::new(client),
+        })
+    }
+}
+
+#[async_trait]
+impl Brc20Store for PostgresStore {
+    async fn get_ticker(&self, tick: &str) -> anyhow::Result<Option<Brc20Ticker>> {
+        let client = self.client.lock().await;
+        let row = client
+            .query_opt(
+                format!("SELECT {} FROM tickers WHERE tick = $1", TICKER_COLUMNS).as_str(),
+                &[&tick],
+            )
+            .await?;
+
+        row.as_ref().map(ticker_from_row).transpose()
+    }
+
+    async fn get_user_balance(
+        &self,
+        address: &str,
+        tick: &str,
+    ) -> anyhow::Result<Option<UserBalance>> {
+        let client = self.client.lock().await;
+        let row = client
+            .query_opt(
+                &format!(
+                    "SELECT {} FROM user_balances WHERE address = $1 AND tick = $2",
+                    USER_BALANCE_COLUMNS
+                ),
+                &[&address, &tick],
+            )
+            .await?;
+
+        row.as_ref().map(user_balance_from_row).transpose()
+    }
+
+    async fn get_transfer(
+        &self,
+        inscription_id: &str,
+        _tx_id: &str,
+    ) -> anyhow::Result<Option<Document>> {
+        // every transfer stored here has its inscription id
+        let client = self.client.lock().await;
+        let row = client
+            .query_opt(
+                "SELECT document::TEXT FROM transfers WHERE inscription_id = $1",
+                &[&inscription_id],
+            )
+            .await?;
+
+        match row {
+            Some(row) => Ok(Some(document_from_json(row.try_get(0)?)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn load_active_transfers(&self) -> anyhow::Result<ActiveTransfers> {
+        let client = self.client.lock().await;
+        let rows = client
+            .query(
+                "SELECT tx_id, satpoint, block_height, inscription_id FROM active_transfers",
+                &[],
+            )
+            .await?;
+
+        let mut active_transfers = ActiveTransfers::new();
+        for row in rows {
+            let satpoint: SatPoint = row
+                .try_get::<_, &str>(1)?
+                .parse()
+                .map_err(anyhow::Error::msg)?;
+            insert_active_transfer(
+                &mut active_transfers,
+                Brc20ActiveTransfer::new(
+                    row.try_get(0)?,
+                    satpoint,
+                    row.try_get(2)?,
+                    row.try_get(3)?,
+                ),
+            );
+        }
+
+        Ok(active_transfers)
+    }
+
+    async fn get_last_completed_block_height(&self) -> anyhow::Result<Option<i64>> {
+        let client = self.client.lock().await;
+        let row = client
+            .query_one("SELECT MAX(block_height) FROM blocks_completed", &[])
+            .await?;
+
+        Ok(row.try_get(0)?)
+    }
+
+    async fn get_completed_block_hash(&self, block_height: i64) -> anyhow::Result<Option<String>> {
+        let client = self.client.lock().await;
+        let row = client
+            .query_opt(
+                "SELECT block_hash FROM blocks_completed WHERE block_height = $1",
+                &[&block_height],
+            )
+            .await?;
+
+        match row {
+            Some(row) => Ok(Some(row.try_get(0)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn get_state_hash(&self, block_height: i64) -> anyhow::Result<Option<String>> {
+        let client = self.client.lock().await;
+        let row = client
+            .query_opt(
+                "SELECT state_hash FROM blocks_completed WHERE block_height = $1",
+                &[&block_height],
+            )
+            .await?;
+
+        match row {
+            Some(row) => Ok(row.try_get(0)?),
+            None => Ok(None),
+        }
+    }
+
+    async fn get_events(&self, after: Option<&str>, limit: u64) -> anyhow::Result<Vec<Brc20Event>> {
+        let client = self.client.lock().await;
+        // every id sorts after the empty string
+        let rows = client
+            .query(
+                "SELECT document::TEXT FROM events WHERE id > $1 ORDER BY id LIMIT $2",
+                &[&after.unwrap_or_default(), &(limit as i64)],
+            )
+            .await?;
+
+        rows.iter()
+            .map(|row| {
+                Brc20Event::from_document(&document_from_json(row.try_get(0)?)?)
+                    .map_err(anyhow::Error::msg)
+            })
+            .collect()
+    }
+
+    async fn get_webhook_cursor(&self, url: &str) -> anyhow::Result<Option<String>> {
+        let client = self.client.lock().await;
+        let row = client
+            .query_opt(
+                "SELECT event_id FROM webhook_cursors WHERE url = $1",
+                &[&url],
+            )
+            .await?;
+
+        match row {
+            Some(row) => Ok(Some(row.try_get(0)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn set_webhook_cursor(&self, url: &str, event_id: &str) -> anyhow::Result<()> {
+        let client = self.client.lock().await;
+        client
+            .execute(
+                "INSERT INTO webhook_cursors (url, event_id) VALUES ($1, $2)
+                 ON CONFLICT (url) DO UPDATE SET event_id = EXCLUDED.event_id",
+                &[&url, &event_id],
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn get_user_balances(&self, address: &str) -> anyhow::Result<Vec<UserBalance>> {
+        let client = self.client.lock().await;
+        let rows = client
+            .query(
+                &format!(
+                    "SELECT {} FROM user_balances WHERE address = $1 ORDER BY tick",
+                    USER_BALANCE_COLUMNS
+                ),
+                &[&address],
+            )
+            .await?;
+
+        rows.iter().map(user_balance_from_row).collect()
+    }
+
+    async fn get_holders(&self, tick: &str, page: Page) -> anyhow::Result<Vec<UserBalance>> {
+        let client = self.client.lock().await;
+        let rows = client
+            .query(
+                &format!(
+                    "SELECT {} FROM user_balances WHERE tick = $1 AND overall_balance > 0
+                     ORDER BY address LIMIT $2 OFFSET $3",
+                    USER_BALANCE_COLUMNS
+                ),
+                &[&tick, &(page.limit as i64), &(page.offset as i64)],
+            )
+            .await?;
+
+        rows.iter().map(user_balance_from_row).collect()
+    }
+
+    async fn get_user_balance_entries(
+        &self,
+        address: &str,
+        tick: Option<&str>,
+        page: Page,
+    ) -> anyhow::Result<Vec<UserBalanceEntry>> {
+        let client = self.client.lock().await;
+        let rows = client
+            .query(
+                &format!(
+                    "SELECT {} FROM user_balance_entries
+                     WHERE address = $1 AND ($2::TEXT IS NULL OR tick = $2)
+                     ORDER BY id LIMIT $3 OFFSET $4",
+                    ENTRY_COLUMNS
+                ),
+                &[&address, &tick, &(page.limit as i64), &(page.offset as i64)],
+            )
+            .await?;
+
+        rows.iter().map(entry_from_row).collect()
+    }
+
+    async fn get_user_balance_entries_until(
+        &self,
+        address: Option<&str>,
+        tick: Option<&str>,
+        block_height: i64,
+    ) -> anyhow::Result<Vec<UserBalanceEntry>> {
+        let client = self.client.lock().await;
+        let rows = client
+            .query(
+                &format!(
+                    "SELECT {} FROM user_balance_entries
+                     WHERE ($1::TEXT IS NULL OR address = $1) AND ($2::TEXT IS NULL OR tick = $2)
+                     AND block_height <= $3
+                     ORDER BY id",
+                    ENTRY_COLUMNS
+                ),
+                &[&address, &tick, &block_height],
+            )
+            .await?;
+
+        rows.iter().map(entry_from_row).collect()
+    }
+
+    async fn get_transfers_by_tx_id(&self, tx_id: &str) -> anyhow::Result<Vec<Document>> {
+        let client = self.client.lock().await;
+        let rows = client
+            .query(
+                "SELECT document::TEXT FROM transfers WHERE tx_id = $1 ORDER BY inscription_id",
+                &[&tx_id],
+            )
+            .await?;
+
+        rows.iter()
+            .map(|row| document_from_json(row.try_get(0)?))
+            .collect()
+    }
+
+    async fn get_tickers(&self) -> anyhow::Result<Vec<Brc20Ticker>> {
+        let client = self.client.lock().await;
+        let rows = client
+            .query(
+                format!("SELECT {} FROM tickers ORDER BY tick", TICKER_COLUMNS).as_str(),
+                &[],
+            )
+            .await?;
+
+        rows.iter().map(ticker_from_row).collect()
+    }
+
+    async fn get_all_user_balances(&self) -> anyhow::Result<Vec<UserBalance>> {
+        let client = self.client.lock().await;
+        let rows = client
+            .query(
+                format!(
+                    "SELECT {} FROM user_balances ORDER BY address, tick",
+                    USER_BALANCE_COLUMNS
+                )
+                .as_str(),
+                &[],
+            )
+            .await?;
+
+        rows.iter().map(user_balance_from_row).collect()
+    }
+
+    async fn get_minted_amounts(
+        &self,
+        block_height: i64,
+    ) -> anyhow::Result<HashMap<String, Amount>> {
+        let client = self.client.lock().await;
+        let rows = client
+            .query(
+                "SELECT tick, SUM(amt)::TEXT FROM mints WHERE block_height <= $1 GROUP BY tick",
+                &[&block_height],
+            )
+            .await?;
+
+        let mut minted_amounts = HashMap::new();
+        for row in rows {
+            minted_amounts.insert(row.try_get::<_, String>(0)?, get_row_amount(&row, 1)?);
+        }
+
+        Ok(minted_amounts)
+    }
+
+    async fn write_block(&self, block: BlockUpdate) -> anyhow::Result<()> {
+        let mut client = self.client.lock().await;
+        let transaction = client.transaction().await?;
+
+        for user_balance in block
+            .updated_user_balances
+            .values()
+            .chain(block.new_user_balances.values())
+        {
+            upsert_user_balance(&transaction, user_balance).await?;
+        }
+
+        if !block.deploys.is_empty() {
+            let statement = transaction.prepare(INSERT_DEPLOY).await?;
+            for deploy in &block.deploys {
+                transaction
+                    .execute(
+                        &statement,
+                        &[
+                            &deploy.get_str("inscription_id")?,
+                            &get_tick(deploy)?,
+                            &get_block_height(deploy)?,
+                            &document_to_json(deploy),
+                        ],
+                    )
+                    .await?;
+            }
+        }
+
+        if !block.mints.is_empty() {
+            let statement = transaction.prepare(INSERT_MINT).await?;
+            for mint in &block.mints {
+                transaction
+                    .execute(
+                        &statement,
+                        &[
+                            &mint.get_str("inscription_id")?,
+                            &get_tick(mint)?,
+                            &mint.get_str("to")?,
+                            &get_amount(mint, "amt").unwrap_or_default().to_string(),
+                            &get_block_height(mint)?,
+                            &document_to_json(mint),
+                        ],
+                    )
+                    .await?;
+            }
+        }
+
+        // transfers inscribed in an earlier block are overwritten with their send
+        for transfer in block.transfers.iter().chain(&block.sent_transfers) {
+            upsert_transfer(&transaction, transfer).await?;
+        }
+
+        if !block.invalids.is_empty() {
+            let statement = transaction.prepare(INSERT_INVALID).await?;
+            for invalid in &block.invalids {
+                transaction
+                    .execute(
+                        &statement,
+                        &[
+                            &invalid.get_str("tx_id")?,
+                            &invalid.get_str("reason")?,
+                            &get_block_height(invalid)?,
+                            &document_to_json(invalid),
+                        ],
+                    )
+                    .await?;
+            }
+        }
+
+        if !block.user_balance_entries.is_empty() {
+            let statement = transaction.prepare(INSERT_USER_BALANCE_ENTRY).await?;
+            for entry in &block.user_balance_entries {
+                transaction
+                    .execute(
+                        &statement,
+                        &[
+                            &entry.address,
+                            &entry.tick,
+                            &(entry.block_height as i64),
+                            &entry.amt.to_string(),
+                            &entry.entry_type.to_string(),
+                            &entry.txid,
+                            &entry.tx_index,
+                            &entry.inscription_id,
+                            &entry.counterparty,
+                        ],
+                    )
+                    .await?;
+            }
+        }
+
+        for ticker in block.tickers.values() {
+            upsert_ticker(&transaction, ticker).await?;
+        }
+
+        transaction
+            .execute("DELETE FROM active_transfers", &[])
+            .await?;
+        for active_transfer in block.active_transfers.values().flatten() {
+            upsert_active_transfer(&transaction, active_transfer).await?;
+        }
+
+        if !block.events.is_empty() {
+            let statement = transaction.prepare(INSERT_EVENT).await?;
+            for event in &block.events {
+                transaction
+                    .execute(
+                        &statement,
+                        &[
+                            &event.id,
+                            &event.event_type.to_string(),
+                            &event.block_height,
+                            &document_to_json(&event.to_document()),
+                        ],
+                    )
+                    .await?;
+            }
+        }
+
+        transaction
+            .execute(
+                "INSERT INTO blocks_completed
+                 (block_height, block_hash, previous_block_hash, state_hash)
+                 VALUES ($1, $2, $3, $4)
+                 ON CONFLICT (block_height) DO UPDATE SET
+                     block_hash = EXCLUDED.block_hash,
+                     previous_block_hash = EXCLUDED.previous_block_hash,
+                     state_hash = EXCLUDED.state_hash",
+                &[
+                    &block.block_height,
+                    &block.block_hash,
+                    &block.previous_block_hash,
+                    &block.state_hash,
+                ],
+            )
+            .await?;
+
+        transaction.commit().await?;
+
+        Ok(())
+    }
+
+    async fn rollback_to_block_height(&self, start_block_height: i64) -> anyhow::Result<()> {
+        let mut client = self.client.lock().await;
+        // a failed rollback leaves the database untouched
+        let transaction = client.transaction().await?;
+
+        // transfers sent after the rollback point are active again
+        let restored = transaction
+            .query(
+                "SELECT document::TEXT FROM transfers
+                 WHERE block_height < $1 AND send_block_height >= $1",
+                &[&start_block_height],
+            )
+            .await?;
+        for row in &restored {
+            let mut transfer = document_from_json(row.try_get(0)?)?;
+            for field in TRANSFER_SEND_FIELDS {
+                transfer.insert(field, Bson::Null);
+            }
+            upsert_transfer(&transaction, &transfer).await?;
+
+            let txid = transfer.get_document("tx")?.get_str("txid")?.to_string();
+            let satpoint = SatPoint::new(
+                txid.clone(),
+                get_integer(&transfer, "vout").unwrap_or_default(),
+                get_integer(&transfer, "offset").unwrap_or_default(),
+            );
+            upsert_active_transfer(
+                &transaction,
+                &Brc20ActiveTransfer::new(
+                    txid,
+                    satpoint,
+                    get_block_height(&transfer)?,
+                    transfer.get_str("inscription_id")?.to_string(),
+                ),
+            )
+            .await?;
+        }
+        info!("Active Transfers restored: {}", restored.len());
+
+        // delete deploys, mints, transfers, tickers, invalids, entries, events
+        for table in ROLLBACK_TABLES {
+            let deleted = transaction
+                .execute(
+                    format!("DELETE FROM {} WHERE block_height >= $1", table).as_str(),
+                    &[&start_block_height],
+                )
+                .await?;
+            info!("Deleted {} rows from {}", deleted, table);
+        }
+
+        // events indexed again are delivered again
+        let first_event_id = block_event_id(start_block_height);
+        transaction
+            .execute(
+                "UPDATE webhook_cursors SET event_id = $1 WHERE event_id > $1",
+                &[&first_event_id],
+            )
+            .await?;
+
+        // recalculate total_minted of tickers minted after the rollback point
+        let reset_tickers = transaction
+            .query(
+                "UPDATE tickers SET
+                     total_minted = COALESCE(
+                         (SELECT SUM(amt) FROM mints WHERE mints.tick = tickers.tick), 0),
+                     updated_at_block = $1
+                 WHERE updated_at_block >= $1
+                 RETURNING tick",
+                &[&start_block_height],
+            )
+            .await?;
+        for row in reset_tickers {
+            info!(
+                "Reset total_minted for ticker: {}",
+                row.try_get::<_, &str>(0)?
+            );
+        }
+
+        // rebuild the balances changed after the rollback point from their entries
+        let rows = transaction
+            .query(
+                "DELETE FROM user_balances WHERE block_height >= $1 RETURNING address, tick",
+                &[&start_block_height],
+            )
+            .await?;
+        let mut deleted_user_balances = Vec::new();
+        for row in &rows {
+            deleted_user_balances
+                .push((row.try_get::<_, String>(0)?, row.try_get::<_, String>(1)?));
+        }
+        info!("Deleted User Balances: {:?}", deleted_user_balances);
+
+        for (address, tick) in deleted_user_balances {
+            let rows = transaction
+                .query(
+                    "SELECT block_height, amt::TEXT, entry_type FROM user_balance_entries
+                     WHERE address = $1 AND tick = $2 ORDER BY id",
+                    &[&address, &tick],
+                )
+                .await?;
+            if rows.is_empty() {
+                continue;
+            }
+
+            let mut user_balance =
+                UserBalance::new(address.clone(), tick.clone(), start_block_height as u64);
+            for row in &rows {
+                let entry = UserBalanceEntry::new(
+                    address.clone(),
+                    tick.clone(),
+                    row.try_get::<_, i64>(0)? as u64,
+                    get_row_amount(row, 1)?,
+                    get_row_entry_type(row, 2)?,
+                );
+                apply_user_balance_entry(&mut user_balance, &entry)?;
+            }
+            user_balance.block_height = start_block_height as u64;
+
+            upsert_user_balance(&transaction, &user_balance).await?;
+        }
+
+        transaction.commit().await?;
+
+        Ok(())
+    }
+
+    async fn repair_state(&self, repair: StateRepair) -> anyhow::Result<()> {
+        let mut client = self.client.lock().await;
+        let transaction = client.transaction().await?;
+
+        for ticker in &repair.tickers {
+            upsert_ticker(&transaction, ticker).await?;
+        }
+        for user_balance in &repair.user_balances {
+            upsert_user_balance(&transaction, user_balance).await?;
+        }
+        for (address, tick) in &repair.deleted_user_balances {
+            transaction
+                .execute(
+                    "DELETE FROM user_balances WHERE address = $1 AND tick = $2",
+                    &[address, tick],
+                )
+                .await?;
+        }
+
+        transaction.commit().await?;
+
+        Ok(())
+    }
+}
+
+const TICKER_COLUMNS: &str = "tick, lim::TEXT, max_supply::TEXT, total_minted::TEXT, decimals,
+     block_height, updated_at_block";
+
+// maps a row of TICKER_COLUMNS
+fn ticker_from_row(row: &Row) -> anyhow::Result<Brc20Ticker> {
+    Ok(Brc20Ticker {
+        tick: row.try_get(0)?,
+        limit: get_row_amount(row, 1)?,
+        max_supply: get_row_amount(row, 2)?,
+        total_minted: get_row_amount(row, 3)?,
+        decimals: u8::try_from(row.try_get::<_, i16>(4)?)?,
+        block_height: u32::try_from(row.try_get::<_, i64>(5)?)?,
+        updated_at_block: u32::try_from(row.try_get::<_, i64>(6)?)?,
+    })
+}
+
+const USER_BALANCE_COLUMNS: &str = "address, tick, overall_balance::TEXT, available_balance::TEXT,
+     transferable_balance::TEXT, block_height";
+
+// maps a row of USER_BALANCE_COLUMNS
+fn user_balance_from_row(row: &Row) -> anyhow::Result<UserBalance> {
+    Ok(UserBalance {
+        address: row.try_get(0)?,
+        tick: row.try_get(1)?,
+        overall_balance: get_row_amount(row, 2)?,
+        available_balance: get_row_amount(row, 3)?,
+        transferable_balance: get_row_amount(row, 4)?,
+        block_height: row.try_get::<_, i64>(5)? as u64,
+    })
+}
+
+const ENTRY_COLUMNS: &str = "address, tick, block_height, amt::TEXT, entry_type, txid, tx_index,
+     inscription_id, counterparty";
+
+// maps a row of ENTRY_COLUMNS
+fn entry_from_row(row: &Row) -> anyhow::Result<UserBalanceEntry> {
+    Ok(UserBalanceEntry {
+        address: row.try_get(0)?,
+        tick: row.try_get(1)?,
+        block_height: row.try_get::<_, i64>(2)? as u64,
+        amt: get_row_amount(row, 3)?,
+        entry_type: get_row_entry_type(row, 4)?,
+        txid: row.try_get(5)?,
+        tx_index: row.try_get(6)?,
+        inscription_id: row.try_get(7)?,
+        counterparty: row.try_get(8)?,
+    })
+}
+
+// NUMERIC amounts are selected as text
+fn get_row_amount(row: &Row, index: usize) -> anyhow::Result<Amount> {
+    row.try_get::<_, &str>(index)?
+        .parse()
+        .map_err(anyhow::Error::msg)
+}
+
+fn get_row_entry_type(row: &Row, index: usize) -> anyhow::Result<UserBalanceEntryType> {
+    row.try_get::<_, &str>(index)?
+        .parse()
+        .map_err(anyhow::Error::msg)
+}
+
+// lowercase symbol of the ticker an event document belongs to
+fn get_tick(document: &Document) -> anyhow::Result<String> {
+    Ok(document
+        .get_document("inscription")?
+        .get_str("tick")?
+        .to_lowercase())
+}
+
+fn get_block_height(document: &Document) -> anyhow::Result<i64> {
+    get_integer(document, "block_height")
+        .ok_or_else(|| anyhow::anyhow!("Document has no block height"))
+}
+
+fn quote_identifier(identifier: &str) -> String {
+    format!("\"{}\"", identifier.replace('"', "\"\""))
+}
+
+async fn upsert_transfer(transaction: &Transaction<'_>, transfer: &Document) -> anyhow::Result<()> {
+    transaction
+        .execute(
+            UPSERT_TRANSFER,
+            &[
+                &transfer.get_str("inscription_id")?,
+                &transfer.get_document("tx")?.get_str("txid")?,
+                &get_tick(transfer)?,
+                &transfer.get_str("from")?,
+                &transfer.get_str("to").ok(),
+                &get_amount(transfer, "amt").unwrap_or_default().to_string(),
+                &get_integer(transfer, "vout").unwrap_or_default(),
+                &get_integer(transfer, "offset").unwrap_or_default(),
+                &get_block_height(transfer)?,
+                &get_integer(transfer, "send_block_height"),
+                &document_to_json(transfer),
+            ],
+        )
+        .await?;
+
+    Ok(())
+}
+
+async fn upsert_ticker(transaction: &Transaction<'_>, ticker: &Brc20Ticker) -> anyhow::Result<()> {
+    transaction
+        .execute(
+            UPSERT_TICKER,
+            &[
+                &ticker.tick,
+                &ticker.limit.to_string(),
+                &ticker.max_supply.to_string(),
+                &ticker.total_minted.to_string(),
+                &i16::from(ticker.decimals),
+                &i64::from(ticker.block_height),
+                &i64::from(ticker.updated_at_block),
+            ],
+        )
+        .await?;
+
+    Ok(())
+}
+
+async fn upsert_user_balance(
+    transaction: &Transaction<'_>,
+    user_balance: &UserBalance,
+) -> anyhow::Result<()> {
+    transaction
+        .execute(
+            UPSERT_USER_BALANCE,
+            &[
+                &user_balance.address,
+                &user_balance.tick,
+                &user_balance.overall_balance.to_string(),
+                &user_balance.available_balance.to_string(),
+                &user_balance.transferable_balance.to_string(),
+                &(user_balance.block_height as i64),
+            ],
+        )
+        .await?;
+
+    Ok(())
+}
+
+async fn upsert_active_transfer(
+    transaction: &Transaction<'_>,
+    active_transfer: &Brc20ActiveTransfer,
+) -> anyhow::Result<()> {
+    transaction
+        .execute(
+            UPSERT_ACTIVE_TRANSFER,
+            &[
+                &active_transfer.inscription_id,
+                &active_transfer.tx_id,
+                &active_transfer.satpoint.to_string(),
+                &active_transfer.block_height,
+            ],
+        )
+        .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brc20_index::testing;
+    use std::env;
+
+    // Runs against the database at BRC20_TEST_POSTGRES_URL, each test in its own
+    // schema. The tests are ignored by default, run them with `cargo test -- --ignored`.
+    async fn test_store(schema: &str) -> PostgresStore {
+        let connection_string = env::var("BRC20_TEST_POSTGRES_URL")
+            .expect("BRC20_TEST_POSTGRES_URL must be set to run the PostgreSQL tests");
+
+        let (client, connection) = tokio_postgres::connect(&connection_string, NoTls)
+            .await
+            .unwrap();
+        tokio::spawn(connection);
+        client
+            .batch_execute(&format!(
+                "DROP SCHEMA IF EXISTS {} CASCADE",
+                quote_identifier(schema)
+            ))
+            .await
+            .unwrap();
+
+        PostgresStore::connect(&connection_string, schema)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a PostgreSQL server at BRC20_TEST_POSTGRES_URL"]
+    async fn test_index_deploy_mint_and_transfer() {
+        let store = test_store("brc20_test_index").await;
+        testing::check_deploy_mint_and_transfer(&store).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a PostgreSQL server at BRC20_TEST_POSTGRES_URL"]
+    async fn test_reorg_restores_sent_transfer() {
+        let store = test_store("brc20_test_reorg").await;
+        testing::check_reorg_restores_sent_transfer(&store).await;
+    }
+}
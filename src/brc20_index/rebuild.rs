@@ -0,0 +1,240 @@
+use super::{
+    amount::Amount,
+    brc20_ticker::Brc20Ticker,
+    consts, history,
+    store::{Brc20Store, StateRepair},
+    user_balance::UserBalance,
+};
+use log::{info, warn};
+use std::fmt;
+
+/// A stored value that doesn't match the one rebuilt from the event log.
+#[derive(Debug, Clone)]
+pub enum Difference {
+    TotalMinted {
+        tick: String,
+        stored: Amount,
+        rebuilt: Amount,
+    },
+    // `None` when there is no stored balance, or no entries to rebuild it from
+    UserBalance {
+        address: String,
+        tick: String,
+        stored: Option<UserBalance>,
+        rebuilt: Option<UserBalance>,
+    },
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Difference::TotalMinted {
+                tick,
+                stored,
+                rebuilt,
+            } => write!(
+                f,
+                "total_minted of {}: stored {}, rebuilt {}",
+                tick, stored, rebuilt
+            ),
+            Difference::UserBalance {
+                address,
+                tick,
+                stored,
+                rebuilt,
+            } => write!(
+                f,
+                "balance of {} for {}: stored {}, rebuilt {}",
+                address,
+                tick,
+                format_balance(stored.as_ref()),
+                format_balance(rebuilt.as_ref())
+            ),
+        }
+    }
+}
+
+// overall/available/transferable
+fn format_balance(user_balance: Option<&UserBalance>) -> String {
+    match user_balance {
+        Some(user_balance) => format!(
+            "{}/{}/{}",
+            user_balance.overall_balance,
+            user_balance.available_balance,
+            user_balance.transferable_balance
+        ),
+        None => "none".to_string(),
+    }
+}
+
+/// Recomputes every balance from the balance entries and every ticker's
+/// `total_minted` from the mints of the completed blocks, and returns where they
+/// differ from the stored values.
+///
+/// With `repair` the stored values that differ are overwritten with the rebuilt
+/// ones and balances without any entries are deleted. The indexer should be
+/// stopped while repairing, blocks indexed meanwhile aren't taken into account.
+pub async fn rebuild_balances(
+    store: &dyn Brc20Store,
+    repair: bool,
+) -> anyhow::Result<Vec<Difference>> {
+    let block_height = store
+        .get_last_completed_block_height()
+        .await?
+        .unwrap_or(consts::BRC20_STARTING_BLOCK_HEIGHT - 1);
+    info!("Rebuilding balances up to block {}", block_height);
+
+    let mut differences = Vec::new();
+    let mut state_repair = StateRepair::default();
+
+    let minted_amounts = store.get_minted_amounts(block_height).await?;
+    for ticker in store.get_tickers().await? {
+        let rebuilt = minted_amounts
+            .get(&ticker.tick)
+            .copied()
+            .unwrap_or(Amount::ZERO);
+        if rebuilt == ticker.total_minted {
+            continue;
+        }
+
+        differences.push(Difference::TotalMinted {
+            tick: ticker.tick.clone(),
+            stored: ticker.total_minted,
+            rebuilt,
+        });
+        state_repair.tickers.push(Brc20Ticker {
+            total_minted: rebuilt,
+            ..ticker
+        });
+    }
+
+    let entries = store
+        .get_user_balance_entries_until(None, None, block_height)
+        .await?;
+    let mut rebuilt_user_balances = history::fold_user_balance_entries(&entries)?;
+    for stored in store.get_all_user_balances().await? {
+        let key = (stored.address.clone(), stored.tick.clone());
+        let rebuilt = rebuilt_user_balances.remove(&key);
+        if same_amounts(Some(&stored), rebuilt.as_ref()) {
+            continue;
+        }
+
+        match &rebuilt {
+            Some(rebuilt) => state_repair.user_balances.push(rebuilt.clone()),
+            None => state_repair.deleted_user_balances.push(key.clone()),
+        }
+        differences.push(Difference::UserBalance {
+            address: key.0,
+            tick: key.1,
+            stored: Some(stored),
+            rebuilt,
+        });
+    }
+
+    // balances with entries that aren't stored at all
+    for ((address, tick), rebuilt) in rebuilt_user_balances {
+        if same_amounts(None, Some(&rebuilt)) {
+            continue;
+        }
+
+        state_repair.user_balances.push(rebuilt.clone());
+        differences.push(Difference::UserBalance {
+            address,
+            tick,
+            stored: None,
+            rebuilt: Some(rebuilt),
+        });
+    }
+
+    warn!("{} differences found", differences.len());
+    if repair && !differences.is_empty() {
+        info!(
+            "Repairing {} tickers and {} user balances, deleting {} user balances",
+            state_repair.tickers.len(),
+            state_repair.user_balances.len(),
+            state_repair.deleted_user_balances.len()
+        );
+        store.repair_state(state_repair).await?;
+    }
+
+    Ok(differences)
+}
+
+// a missing balance is the same as a zero one
+fn same_amounts(stored: Option<&UserBalance>, rebuilt: Option<&UserBalance>) -> bool {
+    let amounts = |user_balance: Option<&UserBalance>| {
+        user_balance.map_or((Amount::ZERO, Amount::ZERO, Amount::ZERO), |user_balance| {
+            (
+                user_balance.overall_balance,
+                user_balance.available_balance,
+                user_balance.transferable_balance,
+            )
+        })
+    };
+
+    amounts(stored) == amounts(rebuilt)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brc20_index::{
+        memory::MemoryStore,
+        testing::{index_chain, transfer_chain},
+    };
+
+    #[tokio::test]
+    async fn test_rebuild_reports_and_repairs_differences() {
+        let (chain, alice, bob) = transfer_chain();
+        let store = MemoryStore::new();
+        index_chain(&chain, &store).await;
+        assert!(rebuild_balances(&store, false).await.unwrap().is_empty());
+        // the chain mints in its first block
+        let first_height = consts::BRC20_STARTING_BLOCK_HEIGHT;
+        assert!(store
+            .get_minted_amounts(first_height - 1)
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            store.get_minted_amounts(first_height).await.unwrap()["ordi"],
+            Amount::parse("1000", 18).unwrap()
+        );
+
+        // corrupt a ticker, a balance and delete another balance
+        let mut ticker = store.get_ticker("ordi").await.unwrap().unwrap();
+        ticker.total_minted = Amount::from_raw(7);
+        let mut alice_balance = store
+            .get_user_balance(&alice, "ordi")
+            .await
+            .unwrap()
+            .unwrap();
+        alice_balance.available_balance = Amount::ZERO;
+        store
+            .repair_state(StateRepair {
+                tickers: vec![ticker],
+                user_balances: vec![alice_balance],
+                deleted_user_balances: vec![(bob.clone(), "ordi".to_string())],
+            })
+            .await
+            .unwrap();
+
+        let differences = rebuild_balances(&store, false).await.unwrap();
+        assert_eq!(differences.len(), 3);
+        assert!(store
+            .get_user_balance(&bob, "ordi")
+            .await
+            .unwrap()
+            .is_none());
+
+        rebuild_balances(&store, true).await.unwrap();
+        assert!(rebuild_balances(&store, false).await.unwrap().is_empty());
+        let ticker = store.get_ticker("ordi").await.unwrap().unwrap();
+        assert_eq!(ticker.total_minted, Amount::parse("1000", 18).unwrap());
+        let bob_balance = store.get_user_balance(&bob, "ordi").await.unwrap().unwrap();
+        assert_eq!(
+            bob_balance.overall_balance,
+            Amount::parse("400", 18).unwrap()
+        );
+    }
+}
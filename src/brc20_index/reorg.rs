@@ -0,0 +1,190 @@
+use super::{consts, store::Brc20Store};
+use bitcoin::Block;
+use bitcoincore_rpc::RpcApi;
+use log::{info, warn};
+use std::time::Instant;
+
+/// Checks whether `block` builds on the block we indexed at the previous height.
+///
+/// Returns the height of the last block both chains have in common when the node
+/// has switched to another branch, or `None` when the block extends our chain.
+/// Blocks completed before hashes were recorded can't be compared and are trusted.
+pub async fn detect_reorg(
+    rpc: &impl RpcApi,
+    store: &dyn Brc20Store,
+    block_height: i64,
+    block: &Block,
+) -> Result<Option<i64>, anyhow::Error> {
+    let previous_height = block_height - 1;
+    let stored_hash = match store.get_completed_block_hash(previous_height).await? {
+        Some(hash) => hash,
+        None => return Ok(None),
+    };
+
+    let previous_block_hash = block.header.prev_blockhash.to_string();
+    if stored_hash == previous_block_hash {
+        return Ok(None);
+    }
+
+    warn!(
+        "Reorg detected at block {}: expected previous block {}, node has {}",
+        block_height, stored_hash, previous_block_hash
+    );
+
+    find_fork_height(rpc, store, previous_height - 1)
+        .await
+        .map(Some)
+}
+
+// walk back from `from_height` until the hash we stored matches the node's hash
+async fn find_fork_height(
+    rpc: &impl RpcApi,
+    store: &dyn Brc20Store,
+    from_height: i64,
+) -> Result<i64, anyhow::Error> {
+    let lowest_height = std::cmp::max(
+        consts::BRC20_STARTING_BLOCK_HEIGHT - 1,
+        from_height - consts::REORG_MAX_DEPTH,
+    );
+
+    let mut height = from_height;
+    while height >= lowest_height {
+        let stored_hash = match store.get_completed_block_hash(height).await? {
+            Some(hash) => hash,
+            // nothing indexed at this height, or indexed before hashes were stored
+            None => return Ok(height),
+        };
+
+        let node_hash = rpc.get_block_hash(height as u64)?;
+        if node_hash.to_string() == stored_hash {
+            info!("Found common ancestor at block {}: {}", height, stored_hash);
+            return Ok(height);
+        }
+
+        height -= 1;
+    }
+
+    Err(anyhow::anyhow!(
+        "No common ancestor found within {} blocks of {}",
+        consts::REORG_MAX_DEPTH,
+        from_height
+    ))
+}
+
+/// Removes everything indexed at or above `start_block_height` so indexing can resume from it.
+///
+/// Deploys, mints, transfers, invalids, balance entries and completed blocks are
+/// deleted, tickers, active transfers and user balances are restored to their
+/// state before `start_block_height`.
+pub async fn rollback_to_block_height(
+    store: &dyn Brc20Store,
+    start_block_height: i64,
+) -> Result<(), anyhow::Error> {
+    info!("Rolling back to block height: {}", start_block_height);
+
+    let start = Instant::now();
+    store.rollback_to_block_height(start_block_height).await?;
+    warn!(
+        "Rolled back to block height: {} in {:?}",
+        start_block_height,
+        start.elapsed()
+    );
+
+    Ok(())
+}
+
+/// Rolls the index back to the state it had once `block_height` was indexed, the
+/// next run indexes from the block after it.
+///
+/// Unlike the rollback after a reorg this can go back any number of blocks, down to
+/// the block before `BRC20_STARTING_BLOCK_HEIGHT` which empties the index.
+pub async fn rollback_to_completed_block(
+    store: &dyn Brc20Store,
+    block_height: i64,
+) -> Result<(), anyhow::Error> {
+    if block_height < consts::BRC20_STARTING_BLOCK_HEIGHT - 1 {
+        return Err(anyhow::anyhow!(
+            "Can't roll back below block {}",
+            consts::BRC20_STARTING_BLOCK_HEIGHT - 1
+        ));
+    }
+
+    let last_completed_block_height = store.get_last_completed_block_height().await?;
+    match last_completed_block_height {
+        Some(last_height) if last_height > block_height => {
+            warn!(
+                "Rolling back {} blocks, from {} to {}",
+                last_height - block_height,
+                last_height,
+                block_height
+            );
+            rollback_to_block_height(store, block_height + 1).await
+        }
+        Some(last_height) if last_height == block_height => {
+            info!("Block {} is the last completed block already", block_height);
+            Ok(())
+        }
+        _ => Err(anyhow::anyhow!(
+            "Block {} isn't indexed yet, last completed block: {:?}",
+            block_height,
+            last_completed_block_height
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brc20_index::{
+        amount::Amount,
+        memory::MemoryStore,
+        testing::{index_chain, transfer_chain},
+    };
+
+    #[tokio::test]
+    async fn test_rollback_to_completed_block() {
+        let (chain, alice, bob) = transfer_chain();
+        let store = MemoryStore::new();
+        index_chain(&chain, &store).await;
+
+        let tip_height = i64::from(chain.tip_height());
+        assert!(rollback_to_completed_block(&store, tip_height + 1)
+            .await
+            .is_err());
+
+        // back to the deploy and mint, before the transfer was inscribed
+        let block_height = consts::BRC20_STARTING_BLOCK_HEIGHT;
+        rollback_to_completed_block(&store, block_height)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            store.get_last_completed_block_height().await.unwrap(),
+            Some(block_height)
+        );
+        let alice_balance = store
+            .get_user_balance(&alice, "ordi")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            alice_balance.available_balance,
+            Amount::parse("1000", 18).unwrap()
+        );
+        assert!(alice_balance.transferable_balance.is_zero());
+        assert!(store
+            .get_user_balance(&bob, "ordi")
+            .await
+            .unwrap()
+            .is_none());
+        assert!(store.load_active_transfers().await.unwrap().is_empty());
+
+        // indexing picks up again from the next block
+        index_chain(&chain, &store).await;
+        let bob_balance = store.get_user_balance(&bob, "ordi").await.unwrap().unwrap();
+        assert_eq!(
+            bob_balance.overall_balance,
+            Amount::parse("400", 18).unwrap()
+        );
+    }
+}
@@ -0,0 +1,80 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// Location of a single sat: the output holding it and its offset within that output.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct SatPoint {
+    pub txid: String,
+    pub vout: i64,
+    pub offset: i64,
+}
+
+impl SatPoint {
+    pub fn new(txid: String, vout: i64, offset: i64) -> Self {
+        SatPoint { txid, vout, offset }
+    }
+
+    /// The outpoint holding the sat, spending it moves the sat.
+    pub fn outpoint(&self) -> (String, i64) {
+        (self.txid.clone(), self.vout)
+    }
+}
+
+impl fmt::Display for SatPoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.txid, self.vout, self.offset)
+    }
+}
+
+impl FromStr for SatPoint {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(':').collect();
+        if parts.len() != 3 || parts[0].is_empty() {
+            return Err("Malformed satpoint");
+        }
+
+        let vout = parts[1].parse().map_err(|_| "Malformed satpoint vout")?;
+        let offset = parts[2].parse().map_err(|_| "Malformed satpoint offset")?;
+        Ok(SatPoint::new(parts[0].to_string(), vout, offset))
+    }
+}
+
+/// Finds the output holding the sat at `offset` of the transaction's outputs.
+///
+/// Returns the output index and the offset within that output, or `None` when
+/// the offset is past the last output, i.e. the sat is spent as fee.
+pub fn locate_offset(output_values: &[u64], offset: u64) -> Option<(usize, u64)> {
+    let mut output_start = 0u64;
+    for (vout, value) in output_values.iter().enumerate() {
+        if offset < output_start + value {
+            return Some((vout, offset - output_start));
+        }
+        output_start += value;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_locate_offset() {
+        let output_values = [546, 1000, 330];
+        assert_eq!(locate_offset(&output_values, 0), Some((0, 0)));
+        assert_eq!(locate_offset(&output_values, 546), Some((1, 0)));
+        assert_eq!(locate_offset(&output_values, 1600), Some((2, 54)));
+        assert_eq!(locate_offset(&output_values, 1876), None);
+    }
+
+    #[test]
+    fn test_satpoint_round_trip() {
+        let satpoint = SatPoint::new("ab".repeat(32), 1, 546);
+        let parsed: SatPoint = satpoint.to_string().parse().unwrap();
+        assert_eq!(parsed, satpoint);
+        assert!("abcd:1".parse::<SatPoint>().is_err());
+    }
+}
@@ -0,0 +1,671 @@
+use super::{
+    amount::{get_amount, Amount},
+    brc20_ticker::Brc20Ticker,
+    event::{block_event_id, Brc20Event},
+    store::{get_integer, BlockUpdate, Brc20Store, Page, StateRepair},
+    transfer::{insert_active_transfer, ActiveTransfers, Brc20ActiveTransfer},
+    user_balance::{UserBalance, UserBalanceEntry},
+};
+use async_trait::async_trait;
+use log::info;
+use mongodb::bson::{self, Document};
+use sled::transaction::TransactionResult;
+use sled::{Batch, Db, Transactional, Tree};
+use std::collections::{HashMap, HashSet};
+use std::ops::Bound;
+
+// trees of the store, by index
+const TICKERS: usize = 0;
+const USER_BALANCES: usize = 1;
+const ACTIVE_TRANSFERS: usize = 2;
+const DEPLOYS: usize = 3;
+const MINTS: usize = 4;
+const TRANSFERS: usize = 5;
+const INVALIDS: usize = 6;
+const USER_BALANCE_ENTRIES: usize = 7;
+const BLOCKS_COMPLETED: usize = 8;
+const HOLDERS: usize = 9;
+const EVENTS: usize = 10;
+const TREES: [&str; 11] = [
+    "tickers",
+    "user_balances",
+    "active_transfers",
+    "deploys",
+    "mints",
+    "transfers",
+    "invalids",
+    "user_balance_entries",
+    "blocks_completed",
+    "holders",
+    "events",
+];
+const UNDO_TREE: &str = "undo";
+const WEBHOOK_CURSORS_TREE: &str = "webhook_cursors";
+
+/// Keeps the index in an embedded sled database, without a round-trip per lookup.
+///
+/// Tickers, balances and active transfers are keyed by their identity, events by
+/// block height and balance entries by address. `holders` indexes balances by
+/// ticker and `events` holds the event log by event id. Every change made by a
+/// block is written atomically together with the values it replaces, kept as
+/// undo data under the block's height. A rollback writes those values back,
+/// newest first, so it only touches what the rolled back blocks changed. Repairs
+/// and webhook cursors don't belong to a block and have no undo data.
+///
+/// sled locks its directory, so only one process can open the database at a
+/// time: `serve` and the other commands can't run while `index` holds it.
+pub struct SledStore {
+    db: Db,
+    trees: Vec<Tree>,
+    undo: Tree,
+    // id of the last event delivered to each webhook, by url
+    webhook_cursors: Tree,
+}
+
+impl SledStore {
+    /// Opens the database at `path`, creating it if needed. Fails if another
+    /// process has it open.
+    pub fn open(path: &str) -> anyhow::Result<Self> {
+        let db = sled::open(path).map_err(|e| {
+            anyhow::anyhow!(
+                "Failed to open sled database {}, it can only be open in one process at a time: {}",
+                path,
+                e
+            )
+        })?;
+        let store = SledStore::with_db(db)?;
+        info!("Opened sled database: {}", path);
+        Ok(store)
+    }
+
+    fn with_db(db: Db) -> anyhow::Result<Self> {
+        let trees = TREES
+            .iter()
+            .map(|name| db.open_tree(name))
+            .collect::<Result<Vec<_>, _>>()?;
+        let undo = db.open_tree(UNDO_TREE)?;
+        let webhook_cursors = db.open_tree(WEBHOOK_CURSORS_TREE)?;
+
+        Ok(SledStore {
+            db,
+            trees,
+            undo,
+            webhook_cursors,
+        })
+    }
+
+    // writes the batches of every tree and of the undo data atomically
+    fn apply(&self, mut batches: Vec<Batch>, undo: Batch) -> anyhow::Result<()> {
+        let mut trees: Vec<&Tree> = self.trees.iter().collect();
+        trees.push(&self.undo);
+        batches.push(undo);
+
+        let result: TransactionResult<()> = trees[..].transaction(|views| {
+            for (view, batch) in views.iter().zip(&batches) {
+                view.apply_batch(batch)?;
+            }
+            Ok(())
+        });
+
+        result.map_err(|e| anyhow::anyhow!("Failed to write to sled: {:?}", e))
+    }
+}
+
+#[async_trait]
+impl Brc20Store for SledStore {
+    async fn get_ticker(&self, tick: &str) -> anyhow::Result<Option<Brc20Ticker>> {
+        match self.trees[TICKERS].get(tick)? {
+            Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn get_user_balance(
+        &self,
+        address: &str,
+        tick: &str,
+    ) -> anyhow::Result<Option<UserBalance>> {
+        match self.trees[USER_BALANCES].get(user_balance_key(address, tick))? {
+            Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn get_transfer(
+        &self,
+        inscription_id: &str,
+        _tx_id: &str,
+    ) -> anyhow::Result<Option<Document>> {
+        // every transfer stored here has its inscription id
+        match self.trees[TRANSFERS].get(inscription_id)? {
+            Some(value) => Ok(Some(bson::from_slice(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn load_active_transfers(&self) -> anyhow::Result<ActiveTransfers> {
+        let mut active_transfers = ActiveTransfers::new();
+        for value in self.trees[ACTIVE_TRANSFERS].iter().values() {
+            let active_transfer: Brc20ActiveTransfer = serde_json::from_slice(&value?)?;
+            insert_active_transfer(&mut active_transfers, active_transfer);
+        }
+
+        Ok(active_transfers)
+    }
+
+    async fn get_last_completed_block_height(&self) -> anyhow::Result<Option<i64>> {
+        match self.trees[BLOCKS_COMPLETED].last()? {
+            Some((key, _)) => Ok(Some(u64::from_be_bytes(key.as_ref().try_into()?) as i64)),
+            None => Ok(None),
+        }
+    }
+
+    async fn get_completed_block_hash(&self, block_height: i64) -> anyhow::Result<Option<String>> {
+        match self.trees[BLOCKS_COMPLETED].get(height_key(block_height))? {
+            Some(value) => {
+                let completed_block: Vec<String> = serde_json::from_slice(&value)?;
+                Ok(completed_block.into_iter().next())
+            }
+            None => Ok(None),
+        }
+    }
+
+    async fn get_state_hash(&self, block_height: i64) -> anyhow::Result<Option<String>> {
+        match self.trees[BLOCKS_COMPLETED].get(height_key(block_height))? {
+            // blocks completed before state hashes were recorded only hold two hashes
+            Some(value) => {
+                let completed_block: Vec<String> = serde_json::from_slice(&value)?;
+                Ok(completed_block.into_iter().nth(2))
+            }
+            None => Ok(None),
+        }
+    }
+
+    async fn get_events(&self, after: Option<&str>, limit: u64) -> anyhow::Result<Vec<Brc20Event>> {
+        let start = match after {
+            Some(after) => Bound::Excluded(after.as_bytes().to_vec()),
+            None => Bound::Unbounded,
+        };
+
+        let mut events = Vec::new();
+        for value in self.trees[EVENTS]
+            .range((start, Bound::Unbounded))
+            .values()
+            .take(limit as usize)
+        {
+            events.push(serde_json::from_slice(&value?)?);
+        }
+
+        Ok(events)
+    }
+
+    async fn get_webhook_cursor(&self, url: &str) -> anyhow::Result<Option<String>> {
+        match self.webhook_cursors.get(url)? {
+            Some(value) => Ok(Some(String::from_utf8(value.to_vec())?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn set_webhook_cursor(&self, url: &str, event_id: &str) -> anyhow::Result<()> {
+        self.webhook_cursors.insert(url, event_id.as_bytes())?;
+        self.db.flush_async().await?;
+        Ok(())
+    }
+
+    async fn get_user_balances(&self, address: &str) -> anyhow::Result<Vec<UserBalance>> {
+        // keys sort by tick after the address
+        let mut user_balances = Vec::new();
+        for value in self.trees[USER_BALANCES]
+            .scan_prefix(address_prefix(address))
+            .values()
+        {
+            user_balances.push(serde_json::from_slice(&value?)?);
+        }
+
+        Ok(user_balances)
+    }
+
+    async fn get_holders(&self, tick: &str, page: Page) -> anyhow::Result<Vec<UserBalance>> {
+        let prefix = address_prefix(tick);
+        let mut holders = Vec::new();
+        let mut skipped = 0;
+        for key in self.trees[HOLDERS].scan_prefix(&prefix).keys() {
+            if holders.len() as u64 >= page.limit {
+                break;
+            }
+
+            let address = std::str::from_utf8(&key?[prefix.len()..])?.to_string();
+            let user_balance: UserBalance =
+                match self.trees[USER_BALANCES].get(user_balance_key(&address, tick))? {
+                    Some(value) => serde_json::from_slice(&value)?,
+                    None => continue,
+                };
+            if user_balance.is_zero() {
+                continue;
+            }
+
+            if skipped < page.offset {
+                skipped += 1;
+            } else {
+                holders.push(user_balance);
+            }
+        }
+
+        Ok(holders)
+    }
+
+    async fn get_user_balance_entries(
+        &self,
+        address: &str,
+        tick: Option<&str>,
+        page: Page,
+    ) -> anyhow::Result<Vec<UserBalanceEntry>> {
+        let mut entries = Vec::new();
+        for value in self.trees[USER_BALANCE_ENTRIES]
+            .scan_prefix(address_prefix(address))
+            .values()
+        {
+            let entry: UserBalanceEntry = serde_json::from_slice(&value?)?;
+            if tick.map_or(true, |tick| entry.tick == tick) {
+                entries.push(entry);
+            }
+        }
+
+        Ok(entries
+            .into_iter()
+            .skip(page.offset as usize)
+            .take(page.limit as usize)
+            .collect())
+    }
+
+    async fn get_user_balance_entries_until(
+        &self,
+        address: Option<&str>,
+        tick: Option<&str>,
+        block_height: i64,
+    ) -> anyhow::Result<Vec<UserBalanceEntry>> {
+        // entries are keyed by address, without one every entry is scanned
+        let prefix = address.map(address_prefix).unwrap_or_default();
+        let mut entries = Vec::new();
+        for value in self.trees[USER_BALANCE_ENTRIES]
+            .scan_prefix(prefix)
+            .values()
+        {
+            let entry: UserBalanceEntry = serde_json::from_slice(&value?)?;
+            if tick.map_or(true, |tick| entry.tick == tick)
+                && entry.block_height as i64 <= block_height
+            {
+                entries.push(entry);
+            }
+        }
+
+        Ok(entries)
+    }
+
+    async fn get_transfers_by_tx_id(&self, tx_id: &str) -> anyhow::Result<Vec<Document>> {
+        // inscription ids are the reveal txid followed by i and the inscription's index
+        let mut transfers = Vec::new();
+        for value in self.trees[TRANSFERS]
+            .scan_prefix(format!("{}i", tx_id))
+            .values()
+        {
+            transfers.push(bson::from_slice(&value?)?);
+        }
+
+        Ok(transfers)
+    }
+
+    async fn get_tickers(&self) -> anyhow::Result<Vec<Brc20Ticker>> {
+        let mut tickers = Vec::new();
+        for value in self.trees[TICKERS].iter().values() {
+            tickers.push(serde_json::from_slice(&value?)?);
+        }
+
+        Ok(tickers)
+    }
+
+    async fn get_all_user_balances(&self) -> anyhow::Result<Vec<UserBalance>> {
+        let mut user_balances = Vec::new();
+        for value in self.trees[USER_BALANCES].iter().values() {
+            user_balances.push(serde_json::from_slice(&value?)?);
+        }
+
+        Ok(user_balances)
+    }
+
+    async fn get_minted_amounts(
+        &self,
+        block_height: i64,
+    ) -> anyhow::Result<HashMap<String, Amount>> {
+        let mut minted_amounts: HashMap<String, Amount> = HashMap::new();
+        for value in self.trees[MINTS].iter().values() {
+            let mint: Document = bson::from_slice(&value?)?;
+            if get_integer(&mint, "block_height").map_or(true, |height| height > block_height) {
+                continue;
+            }
+            let tick = mint.get_document("inscription")?.get_str("tick")?;
+            let minted = minted_amounts.entry(tick.to_lowercase()).or_default();
+            *minted = minted
+                .checked_add(get_amount(&mint, "amt").unwrap_or_default())
+                .ok_or_else(|| anyhow::anyhow!("Total minted overflow"))?;
+        }
+
+        Ok(minted_amounts)
+    }
+
+    async fn write_block(&self, block: BlockUpdate) -> anyhow::Result<()> {
+        let block_height = block.block_height;
+        let mut writer = BlockWriter::new(self, block_height);
+
+        for user_balance in block
+            .updated_user_balances
+            .values()
+            .chain(block.new_user_balances.values())
+        {
+            writer.put(
+                USER_BALANCES,
+                user_balance_key(&user_balance.address, &user_balance.tick),
+                Some(serde_json::to_vec(user_balance)?),
+            )?;
+            writer.put(
+                HOLDERS,
+                holder_key(&user_balance.tick, &user_balance.address),
+                Some(Vec::new()),
+            )?;
+        }
+
+        for (tree, documents) in [
+            (DEPLOYS, &block.deploys),
+            (MINTS, &block.mints),
+            (INVALIDS, &block.invalids),
+        ] {
+            for (index, document) in documents.iter().enumerate() {
+                writer.put(
+                    tree,
+                    event_key(block_height, index),
+                    Some(bson::to_vec(document)?),
+                )?;
+            }
+        }
+
+        for (index, entry) in block.user_balance_entries.iter().enumerate() {
+            writer.put(
+                USER_BALANCE_ENTRIES,
+                [
+                    &address_prefix(&entry.address)[..],
+                    &event_key(block_height, index)[..],
+                ]
+                .concat(),
+                Some(serde_json::to_vec(entry)?),
+            )?;
+        }
+
+        for event in &block.events {
+            writer.put(
+                EVENTS,
+                event.id.as_bytes().to_vec(),
+                Some(serde_json::to_vec(event)?),
+            )?;
+        }
+
+        // transfers inscribed in an earlier block are overwritten with their send
+        for transfer in block.transfers.iter().chain(&block.sent_transfers) {
+            writer.put(
+                TRANSFERS,
+                transfer.get_str("inscription_id")?.as_bytes().to_vec(),
+                Some(bson::to_vec(transfer)?),
+            )?;
+        }
+
+        for ticker in block.tickers.values() {
+            writer.put(
+                TICKERS,
+                ticker.tick.as_bytes().to_vec(),
+                Some(serde_json::to_vec(ticker)?),
+            )?;
+        }
+
+        // replace the stored active transfers
+        let mut removed = self.trees[ACTIVE_TRANSFERS]
+            .iter()
+            .keys()
+            .map(|key| key.map(|key| key.to_vec()))
+            .collect::<Result<HashSet<_>, _>>()?;
+        for active_transfer in block.active_transfers.values().flatten() {
+            let key = active_transfer.inscription_id.as_bytes().to_vec();
+            removed.remove(&key);
+            writer.put(
+                ACTIVE_TRANSFERS,
+                key,
+                Some(serde_json::to_vec(active_transfer)?),
+            )?;
+        }
+        for key in removed {
+            writer.put(ACTIVE_TRANSFERS, key, None)?;
+        }
+
+        writer.put(
+            BLOCKS_COMPLETED,
+            height_key(block_height).to_vec(),
+            Some(serde_json::to_vec(&(
+                &block.block_hash,
+                &block.previous_block_hash,
+                &block.state_hash,
+            ))?),
+        )?;
+
+        writer.commit()
+    }
+
+    async fn rollback_to_block_height(&self, start_block_height: i64) -> anyhow::Result<()> {
+        let mut batches = vec![Batch::default(); TREES.len()];
+        let mut undo = Batch::default();
+
+        // newest first, so each key ends up with its value from before start_block_height
+        let mut changes = 0;
+        for record in self.undo.range(height_key(start_block_height)..).rev() {
+            let (key, change) = record?;
+            let (tree, tree_key, previous) = decode_change(&change)?;
+            match previous {
+                Some(previous) => batches[tree].insert(tree_key, previous),
+                None => batches[tree].remove(tree_key),
+            }
+            undo.remove(key);
+            changes += 1;
+        }
+
+        self.apply(batches, undo)?;
+
+        // events indexed again are delivered again
+        let first_event_id = block_event_id(start_block_height);
+        for record in self.webhook_cursors.iter() {
+            let (url, event_id) = record?;
+            if event_id.as_ref() > first_event_id.as_bytes() {
+                self.webhook_cursors
+                    .insert(url, first_event_id.as_bytes())?;
+            }
+        }
+
+        self.db.flush_async().await?;
+        info!("Undone {} changes", changes);
+
+        Ok(())
+    }
+
+    async fn repair_state(&self, repair: StateRepair) -> anyhow::Result<()> {
+        let mut batches = vec![Batch::default(); TREES.len()];
+
+        for ticker in &repair.tickers {
+            batches[TICKERS].insert(ticker.tick.as_bytes(), serde_json::to_vec(ticker)?);
+        }
+        for user_balance in &repair.user_balances {
+            batches[USER_BALANCES].insert(
+                user_balance_key(&user_balance.address, &user_balance.tick),
+                serde_json::to_vec(user_balance)?,
+            );
+            batches[HOLDERS].insert(
+                holder_key(&user_balance.tick, &user_balance.address),
+                Vec::new(),
+            );
+        }
+        for (address, tick) in &repair.deleted_user_balances {
+            batches[USER_BALANCES].remove(user_balance_key(address, tick));
+            batches[HOLDERS].remove(holder_key(tick, address));
+        }
+
+        self.apply(batches, Batch::default())?;
+        self.db.flush_async().await?;
+
+        Ok(())
+    }
+}
+
+// Changes made by a block, with the value each key had before the block.
+struct BlockWriter<'a> {
+    store: &'a SledStore,
+    block_height: i64,
+    batches: Vec<Batch>,
+    undo: Batch,
+    // keys whose previous value is already in the undo data
+    written: HashSet<(usize, Vec<u8>)>,
+}
+
+impl<'a> BlockWriter<'a> {
+    fn new(store: &'a SledStore, block_height: i64) -> Self {
+        BlockWriter {
+            store,
+            block_height,
+            batches: vec![Batch::default(); TREES.len()],
+            undo: Batch::default(),
+            written: HashSet::new(),
+        }
+    }
+
+    // sets a key of a tree, `None` removes it
+    fn put(&mut self, tree: usize, key: Vec<u8>, value: Option<Vec<u8>>) -> anyhow::Result<()> {
+        let written = (tree, key);
+        if !self.written.contains(&written) {
+            let previous = self.store.trees[tree].get(&written.1)?;
+            if previous.as_deref() == value.as_deref() {
+                return Ok(());
+            }
+
+            self.undo.insert(
+                event_key(self.block_height, self.written.len()),
+                encode_change(tree, &written.1, previous.as_deref()),
+            );
+            self.written.insert(written.clone());
+        }
+
+        let (tree, key) = written;
+        match value {
+            Some(value) => self.batches[tree].insert(key, value),
+            None => self.batches[tree].remove(key),
+        }
+
+        Ok(())
+    }
+
+    fn commit(self) -> anyhow::Result<()> {
+        self.store.apply(self.batches, self.undo)
+    }
+}
+
+fn height_key(block_height: i64) -> [u8; 8] {
+    (block_height as u64).to_be_bytes()
+}
+
+// events of a block keep their order after the block height
+fn event_key(block_height: i64, index: usize) -> Vec<u8> {
+    [
+        &height_key(block_height)[..],
+        &(index as u32).to_be_bytes()[..],
+    ]
+    .concat()
+}
+
+// an address or ticker followed by a separator, so it doesn't prefix longer ones
+fn address_prefix(address: &str) -> Vec<u8> {
+    [address.as_bytes(), &[0]].concat()
+}
+
+fn user_balance_key(address: &str, tick: &str) -> Vec<u8> {
+    [address.as_bytes(), &[0], tick.as_bytes()].concat()
+}
+
+fn holder_key(tick: &str, address: &str) -> Vec<u8> {
+    [tick.as_bytes(), &[0], address.as_bytes()].concat()
+}
+
+// tree index, key length, key, then a flag and the previous value if there was one
+fn encode_change(tree: usize, key: &[u8], previous: Option<&[u8]>) -> Vec<u8> {
+    let mut change = vec![tree as u8];
+    change.extend_from_slice(&(key.len() as u32).to_be_bytes());
+    change.extend_from_slice(key);
+    match previous {
+        Some(previous) => {
+            change.push(1);
+            change.extend_from_slice(previous);
+        }
+        None => change.push(0),
+    }
+    change
+}
+
+fn decode_change(change: &[u8]) -> anyhow::Result<(usize, &[u8], Option<&[u8]>)> {
+    let malformed = || anyhow::anyhow!("Malformed undo record");
+
+    let (&tree, rest) = change.split_first().ok_or_else(malformed)?;
+    if usize::from(tree) >= TREES.len() || rest.len() < 4 {
+        return Err(malformed());
+    }
+    let (key_length, rest) = rest.split_at(4);
+    let key_length = u32::from_be_bytes(key_length.try_into()?) as usize;
+    if rest.len() <= key_length {
+        return Err(malformed());
+    }
+    let (key, rest) = rest.split_at(key_length);
+    let previous = match rest[0] {
+        0 => None,
+        _ => Some(&rest[1..]),
+    };
+
+    Ok((usize::from(tree), key, previous))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brc20_index::testing;
+
+    fn temporary_store() -> SledStore {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        SledStore::with_db(db).unwrap()
+    }
+
+    #[test]
+    fn test_undo_change_round_trip() {
+        let change = encode_change(TICKERS, b"ordi", Some(b"{}"));
+        assert_eq!(
+            decode_change(&change).unwrap(),
+            (TICKERS, &b"ordi"[..], Some(&b"{}"[..]))
+        );
+
+        let change = encode_change(MINTS, &event_key(779832, 1), None);
+        assert_eq!(
+            decode_change(&change).unwrap(),
+            (MINTS, &event_key(779832, 1)[..], None)
+        );
+        assert!(decode_change(&change[..4]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_index_deploy_mint_and_transfer() {
+        testing::check_deploy_mint_and_transfer(&temporary_store()).await;
+    }
+
+    #[tokio::test]
+    async fn test_reorg_restores_sent_transfer() {
+        testing::check_reorg_restores_sent_transfer(&temporary_store()).await;
+    }
+}
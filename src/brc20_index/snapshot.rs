@@ -0,0 +1,160 @@
+use super::{history, store::Brc20Store, user_balance::UserBalance};
+use log::info;
+use serde_json::json;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+const CSV_HEADER: &str = "tick,address,overall_balance,available_balance,transferable_balance";
+
+/// Writes the holders of one ticker, or of every ticker when `tick` is `None`, as
+/// they were once `block_height` was indexed.
+///
+/// Balances are replayed from the balance entries, so the indexer can keep running
+/// past the height, which has to be completed already, nothing is written before. Holders are written to `<tick>-<block_height>.csv` and
+/// `<tick>-<block_height>.jsonl` in `directory`, `all` standing for every ticker.
+/// Returns the paths written.
+pub async fn export_holders(
+    store: &dyn Brc20Store,
+    tick: Option<&str>,
+    block_height: i64,
+    directory: &Path,
+) -> anyhow::Result<Vec<PathBuf>> {
+    let holders = match tick {
+        Some(tick) => history::get_holders_at(store, &tick.to_lowercase(), block_height).await?,
+        None => history::get_all_holders_at(store, block_height).await?,
+    };
+    info!(
+        "Exporting {} holders at block {}",
+        holders.len(),
+        block_height
+    );
+
+    let name = format!(
+        "{}-{}",
+        tick.map_or("all".to_string(), |tick| tick.to_lowercase()),
+        block_height
+    );
+    let csv_path = directory.join(format!("{}.csv", name));
+    let json_lines_path = directory.join(format!("{}.jsonl", name));
+
+    let mut writer = BufWriter::new(File::create(&csv_path)?);
+    write_csv(&holders, &mut writer)?;
+    writer.flush()?;
+
+    let mut writer = BufWriter::new(File::create(&json_lines_path)?);
+    write_json_lines(&holders, &mut writer)?;
+    writer.flush()?;
+
+    Ok(vec![csv_path, json_lines_path])
+}
+
+/// Writes holders as CSV, with a header line.
+pub fn write_csv(holders: &[UserBalance], writer: &mut impl Write) -> anyhow::Result<()> {
+    writeln!(writer, "{}", CSV_HEADER)?;
+    for holder in holders {
+        writeln!(
+            writer,
+            "{},{},{},{},{}",
+            csv_field(&holder.tick),
+            csv_field(&holder.address),
+            holder.overall_balance,
+            holder.available_balance,
+            holder.transferable_balance,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Writes holders as JSON Lines, one object with the CSV columns per holder.
+pub fn write_json_lines(holders: &[UserBalance], writer: &mut impl Write) -> anyhow::Result<()> {
+    for holder in holders {
+        let line = json!({
+            "tick": holder.tick,
+            "address": holder.address,
+            "overall_balance": holder.overall_balance,
+            "available_balance": holder.available_balance,
+            "transferable_balance": holder.transferable_balance,
+        });
+        writeln!(writer, "{}", line)?;
+    }
+
+    Ok(())
+}
+
+// ticks are any four characters, quote the ones a CSV reader would split
+fn csv_field(value: &str) -> String {
+    if value.contains(|c| matches!(c, ',' | '"' | '\n' | '\r')) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brc20_index::{
+        consts,
+        history::BlockNotCompleted,
+        memory::MemoryStore,
+        testing::{index_chain, transfer_chain},
+    };
+
+    #[test]
+    fn test_csv_field_quotes_separators() {
+        assert_eq!(csv_field("ordi"), "ordi");
+        assert_eq!(csv_field("a,b\"c"), "\"a,b\"\"c\"");
+    }
+
+    #[tokio::test]
+    async fn test_export_holders_before_send() {
+        let (chain, alice, _) = transfer_chain();
+        let store = MemoryStore::new();
+        index_chain(&chain, &store).await;
+
+        let directory = std::env::temp_dir().join(format!("brc20-snapshot-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let block_height = consts::BRC20_STARTING_BLOCK_HEIGHT + 1;
+        let paths = export_holders(&store, None, block_height, &directory)
+            .await
+            .unwrap();
+
+        let csv = std::fs::read_to_string(&paths[0]).unwrap();
+        assert_eq!(
+            csv,
+            format!("{}\nordi,{},1000,600,400\n", CSV_HEADER, alice)
+        );
+
+        let json_lines = std::fs::read_to_string(&paths[1]).unwrap();
+        let holder: serde_json::Value = serde_json::from_str(json_lines.trim()).unwrap();
+        assert_eq!(holder["address"], alice);
+        assert_eq!(holder["transferable_balance"], "400");
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_export_holders_past_the_last_completed_block() {
+        let (chain, _, _) = transfer_chain();
+        let store = MemoryStore::new();
+        index_chain(&chain, &store).await;
+
+        let directory =
+            std::env::temp_dir().join(format!("brc20-snapshot-tip-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let block_height = i64::from(chain.tip_height()) + 1;
+        let error = export_holders(&store, Some("ordi"), block_height, &directory)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<BlockNotCompleted>(),
+            Some(&BlockNotCompleted(block_height))
+        );
+        // no snapshot of the current balances under the block's name
+        assert_eq!(std::fs::read_dir(&directory).unwrap().count(), 0);
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
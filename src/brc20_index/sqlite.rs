@@ -0,0 +1,1004 @@
+use super::{
+    amount::{get_amount, Amount},
+    brc20_ticker::Brc20Ticker,
+    event::{block_event_id, Brc20Event},
+    satpoint::SatPoint,
+    store::{
+        document_from_json, document_to_json, get_integer, BlockUpdate, Brc20Store, Page,
+        StateRepair,
+    },
+    transfer::{
+        insert_active_transfer, ActiveTransfers, Brc20ActiveTransfer, TRANSFER_SEND_FIELDS,
+    },
+    user_balance::{UserBalance, UserBalanceEntry, UserBalanceEntryType},
+    utils::apply_user_balance_entry,
+    ToDocument,
+};
+use async_trait::async_trait;
+use log::info;
+use mongodb::bson::{Bson, Document};
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+use rusqlite::{params, Connection, OptionalExtension, Params, Row, Transaction};
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard};
+
+// Event tables keep a few columns to query on next to the full event document,
+// stored as canonical extended JSON.
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS tickers (
+    tick TEXT PRIMARY KEY,
+    lim TEXT NOT NULL,
+    max_supply TEXT NOT NULL,
+    total_minted TEXT NOT NULL,
+    decimals INTEGER NOT NULL,
+    block_height INTEGER NOT NULL,
+    updated_at_block INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS tickers_block_height ON tickers (block_height);
+CREATE INDEX IF NOT EXISTS tickers_updated_at_block ON tickers (updated_at_block);
+
+CREATE TABLE IF NOT EXISTS deploys (
+    inscription_id TEXT PRIMARY KEY,
+    tick TEXT NOT NULL,
+    block_height INTEGER NOT NULL,
+    document TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS deploys_block_height ON deploys (block_height);
+
+CREATE TABLE IF NOT EXISTS mints (
+    inscription_id TEXT PRIMARY KEY,
+    tick TEXT NOT NULL,
+    address TEXT NOT NULL,
+    amt TEXT NOT NULL,
+    block_height INTEGER NOT NULL,
+    document TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS mints_tick ON mints (tick);
+CREATE INDEX IF NOT EXISTS mints_block_height ON mints (block_height);
+
+CREATE TABLE IF NOT EXISTS transfers (
+    inscription_id TEXT PRIMARY KEY,
+    tx_id TEXT NOT NULL,
+    tick TEXT NOT NULL,
+    from_address TEXT NOT NULL,
+    to_address TEXT,
+    amt TEXT NOT NULL,
+    vout INTEGER NOT NULL,
+    sat_offset INTEGER NOT NULL,
+    block_height INTEGER NOT NULL,
+    send_block_height INTEGER,
+    document TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS transfers_tx_id ON transfers (tx_id);
+CREATE INDEX IF NOT EXISTS transfers_block_height ON transfers (block_height);
+CREATE INDEX IF NOT EXISTS transfers_send_block_height ON transfers (send_block_height);
+
+CREATE TABLE IF NOT EXISTS invalids (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    tx_id TEXT NOT NULL,
+    reason TEXT NOT NULL,
+    block_height INTEGER NOT NULL,
+    document TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS invalids_block_height ON invalids (block_height);
+
+CREATE TABLE IF NOT EXISTS user_balances (
+    address TEXT NOT NULL,
+    tick TEXT NOT NULL,
+    overall_balance TEXT NOT NULL,
+    available_balance TEXT NOT NULL,
+    transferable_balance TEXT NOT NULL,
+    block_height INTEGER NOT NULL,
+    PRIMARY KEY (address, tick)
+);
+CREATE INDEX IF NOT EXISTS user_balances_tick ON user_balances (tick, address);
+CREATE INDEX IF NOT EXISTS user_balances_block_height ON user_balances (block_height);
+
+CREATE TABLE IF NOT EXISTS user_balance_entries (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    address TEXT NOT NULL,
+    tick TEXT NOT NULL,
+    block_height INTEGER NOT NULL,
+    amt TEXT NOT NULL,
+    entry_type TEXT NOT NULL,
+    txid TEXT,
+    tx_index INTEGER,
+    inscription_id TEXT,
+    counterparty TEXT
+);
+CREATE INDEX IF NOT EXISTS user_balance_entries_address_tick
+    ON user_balance_entries (address, tick, block_height);
+CREATE INDEX IF NOT EXISTS user_balance_entries_block_height
+    ON user_balance_entries (block_height);
+CREATE INDEX IF NOT EXISTS user_balance_entries_tick
+    ON user_balance_entries (tick, block_height);
+
+CREATE TABLE IF NOT EXISTS active_transfers (
+    inscription_id TEXT PRIMARY KEY,
+    tx_id TEXT NOT NULL,
+    satpoint TEXT NOT NULL,
+    block_height INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS active_transfers_block_height ON active_transfers (block_height);
+
+CREATE TABLE IF NOT EXISTS blocks_completed (
+    block_height INTEGER PRIMARY KEY,
+    block_hash TEXT NOT NULL,
+    previous_block_hash TEXT NOT NULL,
+    state_hash TEXT
+);
+
+CREATE TABLE IF NOT EXISTS events (
+    id TEXT PRIMARY KEY,
+    event_type TEXT NOT NULL,
+    block_height INTEGER NOT NULL,
+    document TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS events_block_height ON events (block_height);
+
+CREATE TABLE IF NOT EXISTS webhook_cursors (
+    url TEXT PRIMARY KEY,
+    event_id TEXT NOT NULL
+);
+";
+
+// tables whose rows are deleted by height on a rollback
+const ROLLBACK_TABLES: [&str; 9] = [
+    "deploys",
+    "mints",
+    "transfers",
+    "invalids",
+    "tickers",
+    "user_balance_entries",
+    "active_transfers",
+    "blocks_completed",
+    "events",
+];
+
+// (table, column, type) of the columns added to tables after they were created
+const ADDED_COLUMNS: [(&str, &str, &str); 5] = [
+    ("blocks_completed", "state_hash", "TEXT"),
+    ("user_balance_entries", "txid", "TEXT"),
+    ("user_balance_entries", "tx_index", "INTEGER"),
+    ("user_balance_entries", "inscription_id", "TEXT"),
+    ("user_balance_entries", "counterparty", "TEXT"),
+];
+
+/// Keeps the index in an embedded SQLite database file.
+///
+/// Every block is written in a single transaction, so the database never holds
+/// a partially indexed block. Rollbacks follow the MongoDB backend.
+pub struct SqliteStore {
+    connection: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    /// Opens the database at `path`, creating it and its tables if needed.
+    /// `:memory:` opens a database that isn't kept after exit.
+    pub fn open(path: &str) -> anyhow::Result<Self> {
+        let connection = Connection::open(path)?;
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        connection.pragma_update(None, "synchronous", "NORMAL")?;
+        connection.execute_batch(SCHEMA)?;
+
+        // databases created before state hashes and entry sources were recorded
+        for (table, column, column_type) in ADDED_COLUMNS {
+            let exists: bool = connection.query_row(
+                "SELECT COUNT(*) > 0 FROM pragma_table_info(?1) WHERE name = ?2",
+                params![table, column],
+                |row| row.get(0),
+            )?;
+            if !exists {
+                connection.execute(
+                    &format!(
+                        "ALTER TABLE {} ADD COLUMN {} {}",
+                        table, column, column_type
+                    ),
+                    [],
+                )?;
+            }
+        }
+        info!("Opened SQLite database: {}", path);
+
+        Ok(SqliteStore {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    fn connection(&self) -> anyhow::Result<MutexGuard<'_, Connection>> {
+        self.connection
+            .lock()
+            .map_err(|_| anyhow::anyhow!("SQLite connection lock poisoned"))
+    }
+}
+
+#[async_trait]
+impl Brc20Store for SqliteStore {
+    async fn get_ticker(&self, tick: &str) -> anyhow::Result<Option<Brc20Ticker>> {
+        let connection = self.connection()?;
+        let ticker = connection
+            .query_row(
+                &format!("SELECT {} FROM tickers WHERE tick = ?1", TICKER_COLUMNS),
+                params![tick],
+                ticker_from_row,
+            )
+            .optional()?;
+
+        Ok(ticker)
+    }
+
+    async fn get_user_balance(
+        &self,
+        address: &str,
+        tick: &str,
+    ) -> anyhow::Result<Option<UserBalance>> {
+        let connection = self.connection()?;
+        let user_balance = connection
+            .query_row(
+                &format!(
+                    "SELECT {} FROM user_balances WHERE address = ?1 AND tick = ?2",
+                    USER_BALANCE_COLUMNS
+                ),
+                params![address, tick],
+                user_balance_from_row,
+            )
+            .optional()?;
+
+        Ok(user_balance)
+    }
+
+    async fn get_transfer(
+        &self,
+        inscription_id: &str,
+        _tx_id: &str,
+    ) -> anyhow::Result<Option<Document>> {
+        // every transfer stored here has its inscription id
+        let connection = self.connection()?;
+        let document: Option<String> = connection
+            .query_row(
+                "SELECT document FROM transfers WHERE inscription_id = ?1",
+                params![inscription_id],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        document.as_deref().map(document_from_json).transpose()
+    }
+
+    async fn load_active_transfers(&self) -> anyhow::Result<ActiveTransfers> {
+        let connection = self.connection()?;
+        let rows = query_rows(
+            &connection,
+            "SELECT tx_id, satpoint, block_height, inscription_id FROM active_transfers",
+            [],
+            |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, i64>(2)?,
+                    row.get::<_, String>(3)?,
+                ))
+            },
+        )?;
+
+        let mut active_transfers = ActiveTransfers::new();
+        for (tx_id, satpoint, block_height, inscription_id) in rows {
+            let satpoint: SatPoint = satpoint.parse().map_err(anyhow::Error::msg)?;
+            insert_active_transfer(
+                &mut active_transfers,
+                Brc20ActiveTransfer::new(tx_id, satpoint, block_height, inscription_id),
+            );
+        }
+
+        Ok(active_transfers)
+    }
+
+    async fn get_last_completed_block_height(&self) -> anyhow::Result<Option<i64>> {
+        let connection = self.connection()?;
+        let block_height = connection.query_row(
+            "SELECT MAX(block_height) FROM blocks_completed",
+            [],
+            |row| row.get(0),
+        )?;
+
+        Ok(block_height)
+    }
+
+    async fn get_completed_block_hash(&self, block_height: i64) -> anyhow::Result<Option<String>> {
+        let connection = self.connection()?;
+        let block_hash = connection
+            .query_row(
+                "SELECT block_hash FROM blocks_completed WHERE block_height = ?1",
+                params![block_height],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        Ok(block_hash)
+    }
+
+    async fn get_state_hash(&self, block_height: i64) -> anyhow::Result<Option<String>> {
+        let connection = self.connection()?;
+        let state_hash = connection
+            .query_row(
+                "SELECT state_hash FROM blocks_completed WHERE block_height = ?1",
+                params![block_height],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        Ok(state_hash.flatten())
+    }
+
+    async fn get_events(&self, after: Option<&str>, limit: u64) -> anyhow::Result<Vec<Brc20Event>> {
+        let connection = self.connection()?;
+        // every id sorts after the empty string
+        let documents: Vec<String> = query_rows(
+            &connection,
+            "SELECT document FROM events WHERE id > ?1 ORDER BY id LIMIT ?2",
+            params![after.unwrap_or_default(), limit as i64],
+            |row| row.get(0),
+        )?;
+
+        documents
+            .iter()
+            .map(|document| {
+                Brc20Event::from_document(&document_from_json(document)?)
+                    .map_err(anyhow::Error::msg)
+            })
+            .collect()
+    }
+
+    async fn get_webhook_cursor(&self, url: &str) -> anyhow::Result<Option<String>> {
+        let connection = self.connection()?;
+        let event_id = connection
+            .query_row(
+                "SELECT event_id FROM webhook_cursors WHERE url = ?1",
+                params![url],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        Ok(event_id)
+    }
+
+    async fn set_webhook_cursor(&self, url: &str, event_id: &str) -> anyhow::Result<()> {
+        let connection = self.connection()?;
+        connection.execute(
+            "INSERT OR REPLACE INTO webhook_cursors (url, event_id) VALUES (?1, ?2)",
+            params![url, event_id],
+        )?;
+
+        Ok(())
+    }
+
+    async fn get_user_balances(&self, address: &str) -> anyhow::Result<Vec<UserBalance>> {
+        let connection = self.connection()?;
+        let user_balances = query_rows(
+            &connection,
+            &format!(
+                "SELECT {} FROM user_balances WHERE address = ?1 ORDER BY tick",
+                USER_BALANCE_COLUMNS
+            ),
+            params![address],
+            user_balance_from_row,
+        )?;
+
+        Ok(user_balances)
+    }
+
+    async fn get_holders(&self, tick: &str, page: Page) -> anyhow::Result<Vec<UserBalance>> {
+        let connection = self.connection()?;
+        let holders = query_rows(
+            &connection,
+            &format!(
+                "SELECT {} FROM user_balances WHERE tick = ?1 AND overall_balance != '0'
+                 ORDER BY address LIMIT ?2 OFFSET ?3",
+                USER_BALANCE_COLUMNS
+            ),
+            params![tick, page.limit as i64, page.offset as i64],
+            user_balance_from_row,
+        )?;
+
+        Ok(holders)
+    }
+
+    async fn get_user_balance_entries(
+        &self,
+        address: &str,
+        tick: Option<&str>,
+        page: Page,
+    ) -> anyhow::Result<Vec<UserBalanceEntry>> {
+        let connection = self.connection()?;
+        let entries = query_rows(
+            &connection,
+            &format!(
+                "SELECT {} FROM user_balance_entries
+                 WHERE address = ?1 AND (?2 IS NULL OR tick = ?2)
+                 ORDER BY id LIMIT ?3 OFFSET ?4",
+                ENTRY_COLUMNS
+            ),
+            params![address, tick, page.limit as i64, page.offset as i64],
+            entry_from_row,
+        )?;
+
+        Ok(entries)
+    }
+
+    async fn get_user_balance_entries_until(
+        &self,
+        address: Option<&str>,
+        tick: Option<&str>,
+        block_height: i64,
+    ) -> anyhow::Result<Vec<UserBalanceEntry>> {
+        let connection = self.connection()?;
+        let entries = query_rows(
+            &connection,
+            &format!(
+                "SELECT {} FROM user_balance_entries
+                 WHERE (?1 IS NULL OR address = ?1) AND (?2 IS NULL OR tick = ?2)
+                 AND block_height <= ?3
+                 ORDER BY id",
+                ENTRY_COLUMNS
+            ),
+            params![address, tick, block_height],
+            entry_from_row,
+        )?;
+
+        Ok(entries)
+    }
+
+    async fn get_transfers_by_tx_id(&self, tx_id: &str) -> anyhow::Result<Vec<Document>> {
+        let connection = self.connection()?;
+        let documents: Vec<String> = query_rows(
+            &connection,
+            "SELECT document FROM transfers WHERE tx_id = ?1 ORDER BY inscription_id",
+            params![tx_id],
+            |row| row.get(0),
+        )?;
+
+        documents
+            .iter()
+            .map(|document| document_from_json(document))
+            .collect()
+    }
+
+    async fn get_tickers(&self) -> anyhow::Result<Vec<Brc20Ticker>> {
+        let connection = self.connection()?;
+        let tickers = query_rows(
+            &connection,
+            &format!("SELECT {} FROM tickers ORDER BY tick", TICKER_COLUMNS),
+            [],
+            ticker_from_row,
+        )?;
+
+        Ok(tickers)
+    }
+
+    async fn get_all_user_balances(&self) -> anyhow::Result<Vec<UserBalance>> {
+        let connection = self.connection()?;
+        let user_balances = query_rows(
+            &connection,
+            &format!(
+                "SELECT {} FROM user_balances ORDER BY address, tick",
+                USER_BALANCE_COLUMNS
+            ),
+            [],
+            user_balance_from_row,
+        )?;
+
+        Ok(user_balances)
+    }
+
+    async fn get_minted_amounts(
+        &self,
+        block_height: i64,
+    ) -> anyhow::Result<HashMap<String, Amount>> {
+        let connection = self.connection()?;
+        // amounts are text, they are summed here rather than by SQLite
+        let mints: Vec<(String, Amount)> = query_rows(
+            &connection,
+            "SELECT tick, amt FROM mints WHERE block_height <= ?1",
+            [block_height],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+
+        let mut minted_amounts: HashMap<String, Amount> = HashMap::new();
+        for (tick, amount) in mints {
+            let minted = minted_amounts.entry(tick).or_default();
+            *minted = minted
+                .checked_add(amount)
+                .ok_or_else(|| anyhow::anyhow!("Total minted overflow"))?;
+        }
+
+        Ok(minted_amounts)
+    }
+
+    async fn write_block(&self, block: BlockUpdate) -> anyhow::Result<()> {
+        let mut connection = self.connection()?;
+        let transaction = connection.transaction()?;
+
+        for user_balance in block
+            .updated_user_balances
+            .values()
+            .chain(block.new_user_balances.values())
+        {
+            insert_user_balance(&transaction, user_balance)?;
+        }
+
+        for deploy in &block.deploys {
+            transaction
+                .prepare_cached(
+                    "INSERT INTO deploys (inscription_id, tick, block_height, document)
+                     VALUES (?1, ?2, ?3, ?4)",
+                )?
+                .execute(params![
+                    deploy.get_str("inscription_id")?,
+                    get_tick(deploy)?,
+                    get_block_height(deploy)?,
+                    document_to_json(deploy),
+                ])?;
+        }
+
+        for mint in &block.mints {
+            transaction
+                .prepare_cached(
+                    "INSERT INTO mints (inscription_id, tick, address, amt, block_height, document)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                )?
+                .execute(params![
+                    mint.get_str("inscription_id")?,
+                    get_tick(mint)?,
+                    mint.get_str("to")?,
+                    get_amount(mint, "amt").unwrap_or_default(),
+                    get_block_height(mint)?,
+                    document_to_json(mint),
+                ])?;
+        }
+
+        // transfers inscribed in an earlier block are overwritten with their send
+        for transfer in block.transfers.iter().chain(&block.sent_transfers) {
+            insert_transfer(&transaction, transfer)?;
+        }
+
+        for invalid in &block.invalids {
+            transaction
+                .prepare_cached(
+                    "INSERT INTO invalids (tx_id, reason, block_height, document)
+                     VALUES (?1, ?2, ?3, ?4)",
+                )?
+                .execute(params![
+                    invalid.get_str("tx_id")?,
+                    invalid.get_str("reason")?,
+                    get_block_height(invalid)?,
+                    document_to_json(invalid),
+                ])?;
+        }
+
+        for entry in &block.user_balance_entries {
+            insert_user_balance_entry(&transaction, entry)?;
+        }
+
+        for ticker in block.tickers.values() {
+            insert_ticker(&transaction, ticker)?;
+        }
+
+        transaction.execute("DELETE FROM active_transfers", [])?;
+        for active_transfer in block.active_transfers.values().flatten() {
+            insert_active_transfer_row(&transaction, active_transfer)?;
+        }
+
+        for event in &block.events {
+            transaction
+                .prepare_cached(
+                    "INSERT INTO events (id, event_type, block_height, document)
+                     VALUES (?1, ?2, ?3, ?4)",
+                )?
+                .execute(params![
+                    event.id,
+                    event.event_type.to_string(),
+                    event.block_height,
+                    document_to_json(&event.to_document()),
+                ])?;
+        }
+
+        transaction.execute(
+            "INSERT OR REPLACE INTO blocks_completed
+             (block_height, block_hash, previous_block_hash, state_hash)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![
+                block.block_height,
+                block.block_hash,
+                block.previous_block_hash,
+                block.state_hash
+            ],
+        )?;
+
+        transaction.commit()?;
+
+        Ok(())
+    }
+
+    async fn rollback_to_block_height(&self, start_block_height: i64) -> anyhow::Result<()> {
+        let mut connection = self.connection()?;
+        // a failed rollback leaves the database untouched
+        let transaction = connection.transaction()?;
+
+        // transfers sent after the rollback point are active again
+        let restored: Vec<String> = query_rows(
+            &transaction,
+            "SELECT document FROM transfers WHERE block_height < ?1 AND send_block_height >= ?1",
+            params![start_block_height],
+            |row| row.get(0),
+        )?;
+        for document in &restored {
+            let mut transfer = document_from_json(document)?;
+            for field in TRANSFER_SEND_FIELDS {
+                transfer.insert(field, Bson::Null);
+            }
+            insert_transfer(&transaction, &transfer)?;
+
+            let txid = transfer.get_document("tx")?.get_str("txid")?.to_string();
+            let satpoint = SatPoint::new(
+                txid.clone(),
+                get_integer(&transfer, "vout").unwrap_or_default(),
+                get_integer(&transfer, "offset").unwrap_or_default(),
+            );
+            insert_active_transfer_row(
+                &transaction,
+                &Brc20ActiveTransfer::new(
+                    txid,
+                    satpoint,
+                    get_block_height(&transfer)?,
+                    transfer.get_str("inscription_id")?.to_string(),
+                ),
+            )?;
+        }
+        info!("Active Transfers restored: {}", restored.len());
+
+        // delete deploys, mints, transfers, tickers, invalids, entries, events
+        for table in ROLLBACK_TABLES {
+            let deleted = transaction.execute(
+                &format!("DELETE FROM {} WHERE block_height >= ?1", table),
+                params![start_block_height],
+            )?;
+            info!("Deleted {} rows from {}", deleted, table);
+        }
+
+        // events indexed again are delivered again
+        let first_event_id = block_event_id(start_block_height);
+        transaction.execute(
+            "UPDATE webhook_cursors SET event_id = ?1 WHERE event_id > ?1",
+            params![first_event_id],
+        )?;
+
+        // recalculate total_minted of tickers minted after the rollback point
+        let ticks: Vec<String> = query_rows(
+            &transaction,
+            "SELECT tick FROM tickers WHERE updated_at_block >= ?1",
+            params![start_block_height],
+            |row| row.get(0),
+        )?;
+        for tick in ticks {
+            let amounts: Vec<Amount> = query_rows(
+                &transaction,
+                "SELECT amt FROM mints WHERE tick = ?1",
+                params![tick],
+                |row| row.get(0),
+            )?;
+
+            let mut total_minted = Amount::ZERO;
+            for amount in amounts {
+                total_minted = total_minted
+                    .checked_add(amount)
+                    .ok_or_else(|| anyhow::anyhow!("Total minted overflow"))?;
+            }
+
+            info!("Reset total_minted for ticker: {}", tick);
+            transaction.execute(
+                "UPDATE tickers SET total_minted = ?1, updated_at_block = ?2 WHERE tick = ?3",
+                params![total_minted, start_block_height, tick],
+            )?;
+        }
+
+        // rebuild the balances changed after the rollback point from their entries
+        let deleted_user_balances: Vec<(String, String)> = query_rows(
+            &transaction,
+            "SELECT address, tick FROM user_balances WHERE block_height >= ?1",
+            params![start_block_height],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        info!("Deleted User Balances: {:?}", deleted_user_balances);
+        transaction.execute(
+            "DELETE FROM user_balances WHERE block_height >= ?1",
+            params![start_block_height],
+        )?;
+
+        for (address, tick) in deleted_user_balances {
+            let entries = query_rows(
+                &transaction,
+                "SELECT block_height, amt, entry_type FROM user_balance_entries
+                 WHERE address = ?1 AND tick = ?2 ORDER BY id",
+                params![address, tick],
+                |row| {
+                    Ok(UserBalanceEntry::new(
+                        address.clone(),
+                        tick.clone(),
+                        row.get::<_, i64>(0)? as u64,
+                        row.get(1)?,
+                        row.get(2)?,
+                    ))
+                },
+            )?;
+            if entries.is_empty() {
+                continue;
+            }
+
+            let mut user_balance =
+                UserBalance::new(address.clone(), tick.clone(), start_block_height as u64);
+            for entry in &entries {
+                apply_user_balance_entry(&mut user_balance, entry)?;
+            }
+            user_balance.block_height = start_block_height as u64;
+
+            insert_user_balance(&transaction, &user_balance)?;
+        }
+
+        transaction.commit()?;
+
+        Ok(())
+    }
+
+    async fn repair_state(&self, repair: StateRepair) -> anyhow::Result<()> {
+        let mut connection = self.connection()?;
+        let transaction = connection.transaction()?;
+
+        for ticker in &repair.tickers {
+            insert_ticker(&transaction, ticker)?;
+        }
+        for user_balance in &repair.user_balances {
+            insert_user_balance(&transaction, user_balance)?;
+        }
+        for (address, tick) in &repair.deleted_user_balances {
+            transaction.execute(
+                "DELETE FROM user_balances WHERE address = ?1 AND tick = ?2",
+                params![address, tick],
+            )?;
+        }
+
+        transaction.commit()?;
+
+        Ok(())
+    }
+}
+
+const TICKER_COLUMNS: &str =
+    "tick, lim, max_supply, total_minted, decimals, block_height, updated_at_block";
+
+// maps a row of TICKER_COLUMNS
+fn ticker_from_row(row: &Row<'_>) -> rusqlite::Result<Brc20Ticker> {
+    Ok(Brc20Ticker {
+        tick: row.get(0)?,
+        limit: row.get(1)?,
+        max_supply: row.get(2)?,
+        total_minted: row.get(3)?,
+        decimals: row.get(4)?,
+        block_height: row.get(5)?,
+        updated_at_block: row.get(6)?,
+    })
+}
+
+const USER_BALANCE_COLUMNS: &str =
+    "address, tick, overall_balance, available_balance, transferable_balance, block_height";
+
+// maps a row of USER_BALANCE_COLUMNS
+fn user_balance_from_row(row: &Row<'_>) -> rusqlite::Result<UserBalance> {
+    Ok(UserBalance {
+        address: row.get(0)?,
+        tick: row.get(1)?,
+        overall_balance: row.get(2)?,
+        available_balance: row.get(3)?,
+        transferable_balance: row.get(4)?,
+        block_height: row.get::<_, i64>(5)? as u64,
+    })
+}
+
+const ENTRY_COLUMNS: &str =
+    "address, tick, block_height, amt, entry_type, txid, tx_index, inscription_id, counterparty";
+
+// maps a row of ENTRY_COLUMNS
+fn entry_from_row(row: &Row<'_>) -> rusqlite::Result<UserBalanceEntry> {
+    Ok(UserBalanceEntry {
+        address: row.get(0)?,
+        tick: row.get(1)?,
+        block_height: row.get::<_, i64>(2)? as u64,
+        amt: row.get(3)?,
+        entry_type: row.get(4)?,
+        txid: row.get(5)?,
+        tx_index: row.get(6)?,
+        inscription_id: row.get(7)?,
+        counterparty: row.get(8)?,
+    })
+}
+
+fn query_rows<T, P, F>(
+    connection: &Connection,
+    sql: &str,
+    params: P,
+    map: F,
+) -> rusqlite::Result<Vec<T>>
+where
+    P: Params,
+    F: FnMut(&Row<'_>) -> rusqlite::Result<T>,
+{
+    let mut statement = connection.prepare_cached(sql)?;
+    let rows = statement.query_map(params, map)?.collect();
+    rows
+}
+
+// amounts are stored as decimal strings, like in MongoDB
+impl ToSql for Amount {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.to_string()))
+    }
+}
+
+impl FromSql for Amount {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        value
+            .as_str()?
+            .parse()
+            .map_err(|error: &str| FromSqlError::Other(error.into()))
+    }
+}
+
+impl FromSql for UserBalanceEntryType {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        value
+            .as_str()?
+            .parse()
+            .map_err(|error: String| FromSqlError::Other(error.into()))
+    }
+}
+
+// lowercase symbol of the ticker an event document belongs to
+fn get_tick(document: &Document) -> anyhow::Result<String> {
+    Ok(document
+        .get_document("inscription")?
+        .get_str("tick")?
+        .to_lowercase())
+}
+
+fn get_block_height(document: &Document) -> anyhow::Result<i64> {
+    get_integer(document, "block_height")
+        .ok_or_else(|| anyhow::anyhow!("Document has no block height"))
+}
+
+fn insert_transfer(transaction: &Transaction, transfer: &Document) -> anyhow::Result<()> {
+    transaction
+        .prepare_cached(
+            "INSERT OR REPLACE INTO transfers
+             (inscription_id, tx_id, tick, from_address, to_address, amt, vout, sat_offset,
+              block_height, send_block_height, document)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+        )?
+        .execute(params![
+            transfer.get_str("inscription_id")?,
+            transfer.get_document("tx")?.get_str("txid")?,
+            get_tick(transfer)?,
+            transfer.get_str("from")?,
+            transfer.get_str("to").ok(),
+            get_amount(transfer, "amt").unwrap_or_default(),
+            get_integer(transfer, "vout").unwrap_or_default(),
+            get_integer(transfer, "offset").unwrap_or_default(),
+            get_block_height(transfer)?,
+            get_integer(transfer, "send_block_height"),
+            document_to_json(transfer),
+        ])?;
+
+    Ok(())
+}
+
+fn insert_ticker(transaction: &Transaction, ticker: &Brc20Ticker) -> anyhow::Result<()> {
+    transaction
+        .prepare_cached(
+            "INSERT OR REPLACE INTO tickers
+             (tick, lim, max_supply, total_minted, decimals, block_height, updated_at_block)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        )?
+        .execute(params![
+            ticker.tick,
+            ticker.limit,
+            ticker.max_supply,
+            ticker.total_minted,
+            ticker.decimals,
+            ticker.block_height,
+            ticker.updated_at_block,
+        ])?;
+
+    Ok(())
+}
+
+fn insert_user_balance(
+    transaction: &Transaction,
+    user_balance: &UserBalance,
+) -> anyhow::Result<()> {
+    transaction
+        .prepare_cached(
+            "INSERT OR REPLACE INTO user_balances
+             (address, tick, overall_balance, available_balance, transferable_balance, block_height)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        )?
+        .execute(params![
+            user_balance.address,
+            user_balance.tick,
+            user_balance.overall_balance,
+            user_balance.available_balance,
+            user_balance.transferable_balance,
+            user_balance.block_height as i64,
+        ])?;
+
+    Ok(())
+}
+
+fn insert_user_balance_entry(
+    transaction: &Transaction,
+    entry: &UserBalanceEntry,
+) -> anyhow::Result<()> {
+    transaction
+        .prepare_cached(
+            "INSERT INTO user_balance_entries (address, tick, block_height, amt, entry_type,
+                 txid, tx_index, inscription_id, counterparty)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+        )?
+        .execute(params![
+            entry.address,
+            entry.tick,
+            entry.block_height as i64,
+            entry.amt,
+            entry.entry_type.to_string(),
+            entry.txid,
+            entry.tx_index,
+            entry.inscription_id,
+            entry.counterparty,
+        ])?;
+
+    Ok(())
+}
+
+fn insert_active_transfer_row(
+    transaction: &Transaction,
+    active_transfer: &Brc20ActiveTransfer,
+) -> anyhow::Result<()> {
+    transaction
+        .prepare_cached(
+            "INSERT OR REPLACE INTO active_transfers (inscription_id, tx_id, satpoint, block_height)
+             VALUES (?1, ?2, ?3, ?4)",
+        )?
+        .execute(params![
+            active_transfer.inscription_id,
+            active_transfer.tx_id,
+            active_transfer.satpoint.to_string(),
+            active_transfer.block_height,
+        ])?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brc20_index::testing;
+
+    #[tokio::test]
+    async fn test_index_deploy_mint_and_transfer() {
+        let store = SqliteStore::open(":memory:").unwrap();
+        testing::check_deploy_mint_and_transfer(&store).await;
+    }
+
+    #[tokio::test]
+    async fn test_reorg_restores_sent_transfer() {
+        let store = SqliteStore::open(":memory:").unwrap();
+        testing::check_reorg_restores_sent_transfer(&store).await;
+    }
+}
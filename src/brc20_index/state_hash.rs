@@ -0,0 +1,139 @@
+use super::{
+    consts,
+    event::{Brc20Event, Brc20EventType},
+    store::{BlockUpdate, Brc20Store},
+};
+use bitcoin::hashes::{sha256, Hash};
+use log::warn;
+
+/// State hash the first indexed block chains from.
+pub const GENESIS_STATE_HASH: &str =
+    "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Computes the state hash of a block from the state hash of the block before it.
+///
+/// A block with no stored state hash before it, indexed before state hashes were
+/// recorded, chains from `GENESIS_STATE_HASH` like the first block does.
+pub async fn compute_state_hash(
+    store: &dyn Brc20Store,
+    block: &BlockUpdate,
+) -> anyhow::Result<String> {
+    let previous_height = block.block_height - 1;
+    let previous = if previous_height < consts::BRC20_STARTING_BLOCK_HEIGHT {
+        None
+    } else {
+        let previous = store.get_state_hash(previous_height).await?;
+        if previous.is_none() {
+            warn!(
+                "No state hash at block {}, state hashes restart at block {}",
+                previous_height, block.block_height
+            );
+        }
+        previous
+    };
+
+    Ok(state_hash(
+        previous.as_deref().unwrap_or(GENESIS_STATE_HASH),
+        &block_events(block),
+    ))
+}
+
+/// Hashes the events of a block, chained with the state hash of the block before it.
+///
+/// The hash is the hex SHA-256 of the previous state hash followed by every event,
+/// each line ending with a newline, so indexers agree on it exactly when they agree
+/// on every event up to the block.
+pub fn state_hash(previous: &str, events: &[String]) -> String {
+    let mut data = String::with_capacity(previous.len() + 1);
+    data.push_str(previous);
+    data.push('\n');
+    for event in events {
+        data.push_str(event);
+        data.push('\n');
+    }
+
+    sha256::Hash::hash(data.as_bytes()).to_string()
+}
+
+/// Lists the valid events of a block as lines of space-separated fields, in event
+/// id order, the order they happened in, see `event_id`:
+///
+/// `<event id> <event type> <tick> <amount> <from> <to>`
+///
+/// Ticks are lowercase, amounts are decimal and a field an event doesn't have,
+/// like the sender of a mint, is `-`. Invalid inscriptions change nothing and
+/// aren't hashed.
+pub fn block_events(block: &BlockUpdate) -> Vec<String> {
+    let mut events: Vec<&Brc20Event> = block
+        .events
+        .iter()
+        .filter(|event| event.event_type != Brc20EventType::Invalid)
+        .collect();
+    events.sort_by(|a, b| a.id.cmp(&b.id));
+
+    events
+        .into_iter()
+        .map(|event| {
+            format!(
+                "{} {} {} {} {} {}",
+                event.id,
+                event.event_type,
+                event.tick,
+                event
+                    .amount
+                    .map_or_else(|| "-".to_string(), |amount| amount.to_string()),
+                event.from.as_deref().unwrap_or("-"),
+                event.to.as_deref().unwrap_or("-")
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brc20_index::{
+        event,
+        memory::MemoryStore,
+        testing::{index_chain, transfer_chain},
+    };
+
+    #[test]
+    fn test_state_hash_chains() {
+        let events = vec!["0000779832:000001:r000000:ai0 mint ordi 1 - b".to_string()];
+        let hash = state_hash(GENESIS_STATE_HASH, &events);
+        assert_eq!(hash.len(), 64);
+        assert_eq!(hash, state_hash(GENESIS_STATE_HASH, &events));
+        assert_ne!(hash, state_hash(GENESIS_STATE_HASH, &[]));
+        assert_ne!(hash, state_hash(&hash, &events));
+    }
+
+    #[tokio::test]
+    async fn test_state_hashes_are_stored() {
+        let (chain, alice, bob) = transfer_chain();
+        let store = MemoryStore::new();
+        index_chain(&chain, &store).await;
+
+        let start = consts::BRC20_STARTING_BLOCK_HEIGHT;
+        let tip = i64::from(chain.tip_height());
+        let mut previous = GENESIS_STATE_HASH.to_string();
+        for block_height in start..=tip {
+            let hash = store.get_state_hash(block_height).await.unwrap().unwrap();
+            assert_ne!(hash, previous);
+            previous = hash;
+        }
+
+        // the last block only sends the transfer inscribed in the block before it
+        let events = store
+            .get_events(Some(&event::block_event_id(tip)), 10)
+            .await
+            .unwrap();
+        assert_eq!(events.len(), 1);
+        let lines = vec![format!(
+            "{} transfer_send ordi 400 {} {}",
+            events[0].id, alice, bob
+        )];
+        let before_tip = store.get_state_hash(tip - 1).await.unwrap().unwrap();
+        assert_eq!(previous, state_hash(&before_tip, &lines));
+    }
+}
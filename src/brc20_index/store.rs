@@ -0,0 +1,219 @@
+use super::{
+    amount::Amount,
+    brc20_ticker::Brc20Ticker,
+    consts,
+    event::Brc20Event,
+    transfer::ActiveTransfers,
+    user_balance::{UserBalance, UserBalanceEntry},
+};
+use async_trait::async_trait;
+use mongodb::bson::{Bson, Document};
+use std::collections::HashMap;
+
+/// Everything indexed in one block, written to the store once the block is processed.
+///
+/// Deploy, mint, transfer and invalid events are kept as the documents the
+/// indexer has always written, the state they change is typed.
+#[derive(Debug, Default)]
+pub struct BlockUpdate {
+    pub block_height: i64,
+    pub block_hash: String,
+    pub previous_block_hash: String,
+    pub deploys: Vec<Document>,
+    pub mints: Vec<Document>,
+    // transfer inscriptions made in this block, including the ones already sent
+    pub transfers: Vec<Document>,
+    // transfers inscribed in an earlier block and sent in this one
+    pub sent_transfers: Vec<Document>,
+    pub invalids: Vec<Document>,
+    pub user_balance_entries: Vec<UserBalanceEntry>,
+    // tickers deployed or minted in this block, by ticker symbol
+    pub tickers: HashMap<String, Brc20Ticker>,
+    // balances loaded from the store and changed in this block, by (address, tick)
+    pub updated_user_balances: HashMap<(String, String), UserBalance>,
+    // balances of holders seen for the first time, by (address, tick)
+    pub new_user_balances: HashMap<(String, String), UserBalance>,
+    // every active transfer after this block, replacing the stored ones
+    pub active_transfers: ActiveTransfers,
+    // the block's events hashed with the previous block's state hash, see `state_hash`
+    pub state_hash: String,
+    // appended to the event log, in the order they happened
+    pub events: Vec<Brc20Event>,
+}
+
+/// A page of a listing: at most `limit` items after skipping the first `offset`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Page {
+    pub offset: u64,
+    pub limit: u64,
+}
+
+/// Tickers and balances corrected outside of a block, see `rebuild`.
+#[derive(Debug, Default)]
+pub struct StateRepair {
+    pub tickers: Vec<Brc20Ticker>,
+    pub user_balances: Vec<UserBalance>,
+    // balances to delete, by (address, tick)
+    pub deleted_user_balances: Vec<(String, String)>,
+}
+
+/// Storage backend of the indexer.
+///
+/// The protocol logic only reads state through this trait and hands every
+/// change of a block over in a single [`BlockUpdate`], so it can run against
+/// any store and be tested without a database.
+#[async_trait]
+pub trait Brc20Store: Send + Sync {
+    /// Gets a deployed ticker by its lowercase symbol.
+    async fn get_ticker(&self, tick: &str) -> anyhow::Result<Option<Brc20Ticker>>;
+
+    /// Gets the balance of an address for a lowercase ticker symbol.
+    async fn get_user_balance(
+        &self,
+        address: &str,
+        tick: &str,
+    ) -> anyhow::Result<Option<UserBalance>>;
+
+    /// Gets a transfer inscription document, see `transfer_document_filter`.
+    async fn get_transfer(
+        &self,
+        inscription_id: &str,
+        tx_id: &str,
+    ) -> anyhow::Result<Option<Document>>;
+
+    /// Gets the transfer inscriptions that haven't been sent yet.
+    async fn load_active_transfers(&self) -> anyhow::Result<ActiveTransfers>;
+
+    async fn get_last_completed_block_height(&self) -> anyhow::Result<Option<i64>>;
+
+    /// Gets the hash a completed block was indexed with, `None` if it's unknown.
+    async fn get_completed_block_hash(&self, block_height: i64) -> anyhow::Result<Option<String>>;
+
+    /// Gets the state hash of a completed block, `None` if it's unknown.
+    async fn get_state_hash(&self, block_height: i64) -> anyhow::Result<Option<String>>;
+
+    /// Gets up to `limit` events of the event log ordered by id, starting after the
+    /// event with id `after` or from the first event.
+    async fn get_events(&self, after: Option<&str>, limit: u64) -> anyhow::Result<Vec<Brc20Event>>;
+
+    /// Gets the id of the last event delivered to a webhook, `None` before the first
+    /// delivery.
+    async fn get_webhook_cursor(&self, url: &str) -> anyhow::Result<Option<String>>;
+
+    /// Records the id of the last event delivered to a webhook.
+    async fn set_webhook_cursor(&self, url: &str, event_id: &str) -> anyhow::Result<()>;
+
+    /// Gets the balances of an address for every ticker, ordered by ticker.
+    async fn get_user_balances(&self, address: &str) -> anyhow::Result<Vec<UserBalance>>;
+
+    /// Gets a page of the addresses holding a ticker, ordered by address.
+    /// Balances that went back to zero aren't listed.
+    async fn get_holders(&self, tick: &str, page: Page) -> anyhow::Result<Vec<UserBalance>>;
+
+    /// Gets a page of the balance entries of an address in the order they were
+    /// indexed, for a single ticker or all of them.
+    async fn get_user_balance_entries(
+        &self,
+        address: &str,
+        tick: Option<&str>,
+        page: Page,
+    ) -> anyhow::Result<Vec<UserBalanceEntry>>;
+
+    /// Gets the balance entries indexed at or below a block height, of an address,
+    /// a ticker or an address for a ticker. The entries of each balance are in the
+    /// order they were indexed.
+    async fn get_user_balance_entries_until(
+        &self,
+        address: Option<&str>,
+        tick: Option<&str>,
+        block_height: i64,
+    ) -> anyhow::Result<Vec<UserBalanceEntry>>;
+
+    /// Gets the transfer inscriptions revealed by a transaction.
+    async fn get_transfers_by_tx_id(&self, tx_id: &str) -> anyhow::Result<Vec<Document>>;
+
+    /// Gets every deployed ticker, ordered by ticker.
+    async fn get_tickers(&self) -> anyhow::Result<Vec<Brc20Ticker>>;
+
+    /// Gets every balance, ordered by address and ticker.
+    async fn get_all_user_balances(&self) -> anyhow::Result<Vec<UserBalance>>;
+
+    /// Sums the amounts of the mints of every ticker indexed at or below a block
+    /// height, by lowercase ticker symbol.
+    async fn get_minted_amounts(
+        &self,
+        block_height: i64,
+    ) -> anyhow::Result<HashMap<String, Amount>>;
+
+    /// Writes everything indexed in a block and marks the block completed.
+    async fn write_block(&self, block: BlockUpdate) -> anyhow::Result<()>;
+
+    /// Removes everything indexed at or above `start_block_height`, restoring the
+    /// tickers, balances and active transfers as they were before it. Webhook
+    /// cursors past the removed events move back to `block_event_id`, so the
+    /// events indexed again are delivered again.
+    async fn rollback_to_block_height(&self, start_block_height: i64) -> anyhow::Result<()>;
+
+    /// Overwrites tickers and balances and deletes balances outside of a block,
+    /// no block is marked completed.
+    async fn repair_state(&self, repair: StateRepair) -> anyhow::Result<()>;
+}
+
+/// Height of the block after the last completed one, the first block to index in
+/// an empty store. Events from its `block_event_id` on aren't completed.
+pub async fn next_block_height(store: &dyn Brc20Store) -> anyhow::Result<i64> {
+    Ok(store
+        .get_last_completed_block_height()
+        .await?
+        .map_or(consts::BRC20_STARTING_BLOCK_HEIGHT, |height| height + 1))
+}
+
+/// Gets an integer field from a document, block heights are stored as both Int32
+/// and Int64 depending on the writer.
+pub fn get_integer(document: &Document, field: &str) -> Option<i64> {
+    match document.get(field) {
+        Some(Bson::Int32(value)) => Some(i64::from(*value)),
+        Some(Bson::Int64(value)) => Some(*value),
+        _ => None,
+    }
+}
+
+/// Serializes an event document as canonical extended JSON, keeping every BSON type.
+pub fn document_to_json(document: &Document) -> String {
+    Bson::Document(document.clone())
+        .into_canonical_extjson()
+        .to_string()
+}
+
+/// Parses an event document written by `document_to_json`.
+pub fn document_from_json(json: &str) -> anyhow::Result<Document> {
+    let value: serde_json::Value = serde_json::from_str(json)?;
+    match Bson::try_from(value)? {
+        Bson::Document(document) => Ok(document),
+        _ => Err(anyhow::anyhow!("Not a document: {}", json)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brc20_index::amount::Amount;
+    use mongodb::bson::{doc, DateTime};
+
+    #[test]
+    fn test_document_json_round_trip() {
+        let document = doc! {
+            "inscription_id": "abcdi0",
+            "amt": Amount::from_raw(1),
+            "block_height": 779832i64,
+            "tx_height": 3,
+            "to": Bson::Null,
+            "created_at": DateTime::from_millis(1_690_000_000_000),
+        };
+
+        let parsed = document_from_json(&document_to_json(&document)).unwrap();
+        assert_eq!(parsed, document);
+        assert_eq!(get_integer(&parsed, "block_height"), Some(779832));
+        assert_eq!(get_integer(&parsed, "tx_height"), Some(3));
+    }
+}
@@ -0,0 +1,546 @@
+use super::{
+    amount::Amount,
+    consts,
+    event::Brc20EventType,
+    fee_spend::{block_subsidy, FeeSpendPolicy},
+    index_block,
+    store::{Brc20Store, Page},
+    user_balance::UserBalanceEntryType,
+};
+use bitcoin::blockdata::opcodes::all::{OP_ENDIF, OP_IF};
+use bitcoin::blockdata::opcodes::OP_FALSE;
+use bitcoin::blockdata::script::{Builder, PushBytes, ScriptBuf};
+use bitcoin::consensus::encode::serialize_hex;
+use bitcoin::hashes::Hash;
+use bitcoin::{
+    absolute, block, Address, Block, BlockHash, CompactTarget, Network, OutPoint, Sequence,
+    Transaction, TxIn, TxMerkleNode, TxOut, Txid, WPubkeyHash, Witness,
+};
+use bitcoincore_rpc::{Error, RpcApi};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+const OUTPUT_VALUE: u64 = 546;
+
+const DEPLOY: &str = r#"{"p":"brc-20","op":"deploy","tick":"ordi","max":"21000000","lim":"1000"}"#;
+const MINT: &str = r#"{"p":"brc-20","op":"mint","tick":"ordi","amt":"1000"}"#;
+const TRANSFER: &str = r#"{"p":"brc-20","op":"transfer","tick":"ordi","amt":"400"}"#;
+
+/// A chain of synthetic blocks served over RPC, starting at the first BRC-20 block.
+pub struct TestChain {
+    start_height: u32,
+    blocks: Vec<Block>,
+    transactions: HashMap<Txid, Transaction>,
+    // tells apart blocks mined at the same height on different branches
+    nonce: u32,
+}
+
+impl Default for TestChain {
+    fn default() -> Self {
+        TestChain {
+            start_height: consts::BRC20_STARTING_BLOCK_HEIGHT as u32,
+            blocks: Vec::new(),
+            transactions: HashMap::new(),
+            nonce: 0,
+        }
+    }
+}
+
+impl TestChain {
+    pub fn tip_height(&self) -> u32 {
+        self.start_height + self.blocks.len() as u32 - 1
+    }
+
+    /// Mines a block holding a coinbase followed by `transactions`, returns its height.
+    pub fn mine(&mut self, transactions: Vec<Transaction>) -> u32 {
+        let height = self.start_height + self.blocks.len() as u32;
+        let subsidy = TxOut {
+            value: block_subsidy(height.into()),
+            script_pubkey: address(0xff),
+        };
+        self.mine_with_coinbase(transactions, vec![subsidy])
+    }
+
+    /// Mines a block like `mine`, with a coinbase paying `coinbase_outputs`.
+    pub fn mine_with_coinbase(
+        &mut self,
+        transactions: Vec<Transaction>,
+        coinbase_outputs: Vec<TxOut>,
+    ) -> u32 {
+        let height = self.start_height + self.blocks.len() as u32;
+        self.nonce += 1;
+
+        let coinbase = Transaction {
+            version: 1,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: Builder::new()
+                    .push_int(height.into())
+                    .push_int(self.nonce.into())
+                    .into_script(),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            }],
+            output: coinbase_outputs,
+        };
+
+        let prev_blockhash = match self.blocks.last() {
+            Some(block) => block.block_hash(),
+            None => BlockHash::all_zeros(),
+        };
+        let block = Block {
+            header: block::Header {
+                version: block::Version::ONE,
+                prev_blockhash,
+                merkle_root: TxMerkleNode::all_zeros(),
+                time: height,
+                bits: CompactTarget::from_consensus(0x207fffff),
+                nonce: self.nonce,
+            },
+            txdata: std::iter::once(coinbase).chain(transactions).collect(),
+        };
+
+        for transaction in &block.txdata {
+            self.transactions
+                .insert(transaction.txid(), transaction.clone());
+        }
+        self.blocks.push(block);
+
+        height
+    }
+
+    /// Drops the blocks from `height` on, the next mined block replaces it.
+    pub fn reorg(&mut self, height: u32) {
+        self.blocks.truncate((height - self.start_height) as usize);
+    }
+
+    fn block_at(&self, height: u64) -> Option<&Block> {
+        let index = height.checked_sub(self.start_height.into())?;
+        self.blocks.get(usize::try_from(index).ok()?)
+    }
+}
+
+impl RpcApi for TestChain {
+    fn call<T: for<'a> serde::de::Deserialize<'a>>(
+        &self,
+        cmd: &str,
+        args: &[Value],
+    ) -> bitcoincore_rpc::Result<T> {
+        let not_found = || Error::ReturnedError(format!("Not found: {} {:?}", cmd, args));
+
+        let result = match cmd {
+            "getblockhash" => {
+                let height = args[0].as_u64().ok_or_else(not_found)?;
+                let block = self.block_at(height).ok_or_else(not_found)?;
+                json!(block.block_hash().to_string())
+            }
+            "getblock" => {
+                let block_hash = args[0].as_str().ok_or_else(not_found)?;
+                let block = self
+                    .blocks
+                    .iter()
+                    .find(|block| block.block_hash().to_string() == block_hash)
+                    .ok_or_else(not_found)?;
+                json!(serialize_hex(block))
+            }
+            "getrawtransaction" => {
+                let txid: Txid = args[0]
+                    .as_str()
+                    .and_then(|txid| txid.parse().ok())
+                    .ok_or_else(not_found)?;
+                raw_transaction_info(self.transactions.get(&txid).ok_or_else(not_found)?)
+            }
+            _ => return Err(Error::ReturnedError(format!("Unsupported: {}", cmd))),
+        };
+
+        Ok(serde_json::from_value(result)?)
+    }
+}
+
+// the verbose getrawtransaction result for a transaction
+fn raw_transaction_info(transaction: &Transaction) -> Value {
+    let vin: Vec<Value> = transaction
+        .input
+        .iter()
+        .map(|input| {
+            let witness: Vec<String> = input.witness.iter().map(hex::encode).collect();
+            if input.previous_output.is_null() {
+                json!({
+                    "coinbase": hex::encode(input.script_sig.as_bytes()),
+                    "sequence": input.sequence.0,
+                    "txinwitness": witness,
+                })
+            } else {
+                json!({
+                    "txid": input.previous_output.txid.to_string(),
+                    "vout": input.previous_output.vout,
+                    "scriptSig": { "asm": "", "hex": hex::encode(input.script_sig.as_bytes()) },
+                    "sequence": input.sequence.0,
+                    "txinwitness": witness,
+                })
+            }
+        })
+        .collect();
+
+    let vout: Vec<Value> = transaction
+        .output
+        .iter()
+        .enumerate()
+        .map(|(n, output)| {
+            json!({
+                "value": bitcoin::Amount::from_sat(output.value).to_btc(),
+                "n": n,
+                "scriptPubKey": {
+                    "asm": "",
+                    "hex": hex::encode(output.script_pubkey.as_bytes()),
+                },
+            })
+        })
+        .collect();
+
+    json!({
+        "hex": serialize_hex(transaction),
+        "txid": transaction.txid().to_string(),
+        "hash": transaction.wtxid().to_string(),
+        "size": transaction.size(),
+        "vsize": transaction.vsize(),
+        "version": transaction.version,
+        "locktime": transaction.lock_time.to_consensus_u32(),
+        "vin": vin,
+        "vout": vout,
+    })
+}
+
+/// A P2WPKH output script, one per seed.
+pub fn address(seed: u8) -> ScriptBuf {
+    ScriptBuf::new_v0_p2wpkh(&WPubkeyHash::from_byte_array([seed; 20]))
+}
+
+/// The address string the indexer records for an output script.
+pub fn owner(script_pubkey: &ScriptBuf) -> String {
+    Address::from_script(script_pubkey, Network::Bitcoin)
+        .unwrap()
+        .to_string()
+}
+
+/// An outpoint outside of the test chain, to fund reveals with.
+pub fn funding(seed: u8) -> OutPoint {
+    OutPoint::new(Txid::from_byte_array([seed; 32]), 0)
+}
+
+/// Reveals a text inscription on the first sat of `funding`, sent to `owner`.
+pub fn inscribe(funding: OutPoint, owner: &ScriptBuf, body: &str) -> Transaction {
+    let mut pushes: Vec<&[u8]> = vec![
+        &b"ord"[..],
+        &[1][..],
+        &b"text/plain;charset=utf-8"[..],
+        &[][..],
+    ];
+    pushes.extend(body.as_bytes().chunks(520));
+
+    let mut builder = Builder::new().push_opcode(OP_FALSE).push_opcode(OP_IF);
+    for push in pushes {
+        let data: &PushBytes = push.try_into().unwrap();
+        builder = builder.push_slice(data);
+    }
+    let tapscript = builder.push_opcode(OP_ENDIF).into_script();
+
+    // signature, tapscript and control block of a script path spend
+    let witness = Witness::from_slice(&[vec![0; 64], tapscript.into_bytes(), vec![0xc0; 33]]);
+
+    Transaction {
+        version: 2,
+        lock_time: absolute::LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: funding,
+            script_sig: ScriptBuf::new(),
+            sequence: Sequence::MAX,
+            witness,
+        }],
+        output: vec![TxOut {
+            value: OUTPUT_VALUE,
+            script_pubkey: owner.clone(),
+        }],
+    }
+}
+
+/// Spends `outpoint` to a single output paying `receiver`.
+pub fn send(outpoint: OutPoint, receiver: &ScriptBuf) -> Transaction {
+    spend(
+        &[outpoint],
+        vec![TxOut {
+            value: OUTPUT_VALUE,
+            script_pubkey: receiver.clone(),
+        }],
+    )
+}
+
+/// Spends `outpoints` to `outputs`, what the outputs leave of the inputs is fee.
+pub fn spend(outpoints: &[OutPoint], outputs: Vec<TxOut>) -> Transaction {
+    Transaction {
+        version: 2,
+        lock_time: absolute::LockTime::ZERO,
+        input: outpoints
+            .iter()
+            .map(|outpoint| TxIn {
+                previous_output: *outpoint,
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            })
+            .collect(),
+        output: outputs,
+    }
+}
+
+/// Indexes the chain from the block after the store's last completed block up to the tip,
+/// checking the invariants of every block.
+pub async fn index_chain(chain: &TestChain, store: &dyn Brc20Store) {
+    index_chain_with_policy(chain, store, FeeSpendPolicy::default()).await;
+}
+
+/// Indexes the chain like `index_chain`, following transfers spent as fee with
+/// `fee_spend_policy`.
+pub async fn index_chain_with_policy(
+    chain: &TestChain,
+    store: &dyn Brc20Store,
+    fee_spend_policy: FeeSpendPolicy,
+) {
+    let mut height = match store.get_last_completed_block_height().await.unwrap() {
+        Some(height) => height as u32 + 1,
+        None => chain.start_height,
+    };
+
+    while height <= chain.tip_height() {
+        let block_hash = chain.get_block_hash(height.into()).unwrap();
+        let block = chain.get_block(&block_hash).unwrap();
+        height = index_block(
+            chain,
+            store,
+            height,
+            &block_hash,
+            &block,
+            fee_spend_policy,
+            true,
+        )
+        .await
+        .unwrap();
+    }
+}
+
+fn amount(amount: &str) -> Amount {
+    Amount::parse(amount, 18).unwrap()
+}
+
+/// Deploys and mints to alice, then inscribes a transfer and sends it to bob.
+/// Returns the chain with alice's and bob's addresses.
+pub fn transfer_chain() -> (TestChain, String, String) {
+    let (alice, bob) = (address(1), address(2));
+    let mut chain = TestChain::default();
+
+    chain.mine(vec![
+        inscribe(funding(1), &alice, DEPLOY),
+        inscribe(funding(2), &alice, MINT),
+    ]);
+    let transfer = inscribe(funding(3), &alice, TRANSFER);
+    let transfer_outpoint = OutPoint::new(transfer.txid(), 0);
+    chain.mine(vec![transfer]);
+    chain.mine(vec![send(transfer_outpoint, &bob)]);
+
+    (chain, owner(&alice), owner(&bob))
+}
+
+/// Indexes a deploy, a mint and a transfer sent to another address into an empty store.
+pub async fn check_deploy_mint_and_transfer(store: &dyn Brc20Store) {
+    let (chain, alice, bob) = transfer_chain();
+    index_chain(&chain, store).await;
+
+    let ticker = store.get_ticker("ordi").await.unwrap().unwrap();
+    assert_eq!(ticker.total_minted, amount("1000"));
+
+    let alice_balance = store
+        .get_user_balance(&alice, "ordi")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(alice_balance.overall_balance, amount("600"));
+    assert_eq!(alice_balance.available_balance, amount("600"));
+    assert!(alice_balance.transferable_balance.is_zero());
+
+    let bob_balance = store.get_user_balance(&bob, "ordi").await.unwrap().unwrap();
+    assert_eq!(bob_balance.overall_balance, amount("400"));
+    assert_eq!(bob_balance.available_balance, amount("400"));
+
+    // the receive records the send's transaction and the sender
+    let send_txid = chain.blocks.last().unwrap().txdata[1].txid().to_string();
+    let entries = store
+        .get_user_balance_entries(
+            &bob,
+            None,
+            Page {
+                offset: 0,
+                limit: 10,
+            },
+        )
+        .await
+        .unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].txid, Some(send_txid));
+    assert_eq!(entries[0].tx_index, Some(1));
+    assert_eq!(entries[0].counterparty, Some(alice.clone()));
+    assert!(entries[0].inscription_id.is_some());
+
+    assert!(store.load_active_transfers().await.unwrap().is_empty());
+    assert_eq!(
+        store.get_last_completed_block_height().await.unwrap(),
+        Some(i64::from(chain.tip_height()))
+    );
+
+    let events = store.get_events(None, 10).await.unwrap();
+    let event_types: Vec<Brc20EventType> = events.iter().map(|event| event.event_type).collect();
+    assert_eq!(
+        event_types,
+        vec![
+            Brc20EventType::Deploy,
+            Brc20EventType::Mint,
+            Brc20EventType::InscribeTransfer,
+            Brc20EventType::TransferSend,
+        ]
+    );
+    assert_eq!(
+        store.get_events(Some(&events[1].id), 1).await.unwrap(),
+        vec![events[2].clone()]
+    );
+}
+
+/// Indexes the same chain into an empty store, then reorgs out the block sending the transfer.
+pub async fn check_reorg_restores_sent_transfer(store: &dyn Brc20Store) {
+    let (mut chain, alice, bob) = transfer_chain();
+    index_chain(&chain, store).await;
+
+    // the block sending the transfer is replaced by a longer branch without it
+    let send_height = chain.tip_height();
+    chain.reorg(send_height);
+    chain.mine(vec![]);
+    chain.mine(vec![]);
+    index_chain(&chain, store).await;
+
+    let alice_balance = store
+        .get_user_balance(&alice, "ordi")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(alice_balance.overall_balance, amount("1000"));
+    assert_eq!(alice_balance.available_balance, amount("600"));
+    assert_eq!(alice_balance.transferable_balance, amount("400"));
+
+    assert!(store
+        .get_user_balance(&bob, "ordi")
+        .await
+        .unwrap()
+        .is_none());
+    assert_eq!(store.load_active_transfers().await.unwrap().len(), 1);
+    assert_eq!(
+        store.get_last_completed_block_height().await.unwrap(),
+        Some(i64::from(chain.tip_height()))
+    );
+
+    // the send's event was rolled back with its block
+    let events = store.get_events(None, 10).await.unwrap();
+    assert_eq!(events.len(), 3);
+    assert_eq!(events[2].event_type, Brc20EventType::InscribeTransfer);
+}
+
+/// Deploys and mints to alice and inscribes a transfer, then spends it as fee in a
+/// block's second fee paying transaction, with a miner's coinbase output claiming
+/// the sat under the coinbase policy.
+pub async fn check_transfer_spent_as_fee_to_coinbase(store: &dyn Brc20Store) {
+    let (alice, miner) = (address(1), address(3));
+    let mut chain = TestChain::default();
+
+    let deploy = inscribe(funding(1), &alice, DEPLOY);
+    let mint = inscribe(funding(2), &alice, MINT);
+    let deploy_outpoint = OutPoint::new(deploy.txid(), 0);
+    let mint_outpoint = OutPoint::new(mint.txid(), 0);
+    chain.mine(vec![deploy, mint]);
+    let transfer = inscribe(funding(3), &alice, TRANSFER);
+    let transfer_txid = transfer.txid().to_string();
+    let transfer_outpoint = OutPoint::new(transfer.txid(), 0);
+    chain.mine(vec![transfer]);
+
+    let change = |value| TxOut {
+        value,
+        script_pubkey: alice.clone(),
+    };
+    // pays 200 sats of fees
+    let first_fee = spend(&[deploy_outpoint], vec![change(OUTPUT_VALUE - 200)]);
+    // pays 792, the transfer's sat comes 246 sats into them, after the 300 sats of output
+    let fee_spend = spend(&[mint_outpoint, transfer_outpoint], vec![change(300)]);
+    // the coinbase holds the subsidy, then both fees, the sat lands 146 into the miner's output
+    let subsidy = block_subsidy(u64::from(chain.tip_height()) + 1);
+    chain.mine_with_coinbase(
+        vec![first_fee, fee_spend],
+        vec![
+            TxOut {
+                value: subsidy + 300,
+                script_pubkey: address(0xff),
+            },
+            TxOut {
+                value: 692,
+                script_pubkey: miner.clone(),
+            },
+        ],
+    );
+    index_chain_with_policy(&chain, store, FeeSpendPolicy::Coinbase).await;
+
+    let (alice, miner) = (owner(&alice), owner(&miner));
+    let alice_balance = store
+        .get_user_balance(&alice, "ordi")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(alice_balance.overall_balance, amount("600"));
+    assert!(alice_balance.transferable_balance.is_zero());
+
+    let miner_balance = store
+        .get_user_balance(&miner, "ordi")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(miner_balance.overall_balance, amount("400"));
+    assert_eq!(miner_balance.available_balance, amount("400"));
+
+    let entries = store
+        .get_user_balance_entries(
+            &miner,
+            None,
+            Page {
+                offset: 0,
+                limit: 10,
+            },
+        )
+        .await
+        .unwrap();
+    assert_eq!(entries.len(), 1);
+    assert!(matches!(
+        entries[0].entry_type,
+        UserBalanceEntryType::Receive
+    ));
+    assert_eq!(entries[0].counterparty, Some(alice));
+
+    let coinbase_txid = chain.blocks.last().unwrap().txdata[0].txid();
+    let transfers = store.get_transfers_by_tx_id(&transfer_txid).await.unwrap();
+    assert_eq!(transfers.len(), 1);
+    assert_eq!(transfers[0].get_str("fee_spend").unwrap(), "coinbase");
+    assert_eq!(
+        transfers[0].get_str("send_satpoint").unwrap(),
+        format!("{}:1:146", coinbase_txid)
+    );
+    assert!(store.load_active_transfers().await.unwrap().is_empty());
+
+    let events = store.get_events(None, 10).await.unwrap();
+    let send = events.last().unwrap();
+    assert_eq!(send.event_type, Brc20EventType::TransferSend);
+    assert_eq!(send.to, Some(miner));
+}
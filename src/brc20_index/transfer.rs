@@ -1,39 +1,90 @@
 use super::{
-    consts, invalid_brc20::InvalidBrc20Tx, mongo::MongoClient, user_balance::UserBalanceEntry,
+    amount::Amount,
+    brc20_ticker::Brc20Ticker,
+    invalid_brc20::InvalidBrc20Tx,
+    mint::get_ticker,
+    satpoint::SatPoint,
+    store::Brc20Store,
+    user_balance::{UserBalance, UserBalanceEntry},
     Brc20Inscription,
 };
 use crate::brc20_index::{
-    user_balance::UserBalanceEntryType, utils::update_sender_or_inscriber_user_balance_document,
+    user_balance::UserBalanceEntryType,
+    utils::{convert_to_amount, get_user_balance, update_sender_or_inscriber_user_balance},
     ToDocument,
 };
 use bitcoin::Address;
 use bitcoincore_rpc::bitcoincore_rpc_json::GetRawTransactionResult;
 use log::{debug, error, info};
 use mongodb::bson::{doc, Bson, DateTime, Document};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
+/// Fields recording the send of a transfer inscription, cleared when it becomes active again.
+pub const TRANSFER_SEND_FIELDS: [&str; 6] = [
+    "to",
+    "send_tx",
+    "send_satpoint",
+    "fee_spend",
+    "send_block_height",
+    "send_tx_height",
+];
+
+/// Active transfers by the outpoint holding their sat, an output can hold several.
+pub type ActiveTransfers = HashMap<(String, i64), Vec<Brc20ActiveTransfer>>;
+
 // create active transfer struct
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Brc20ActiveTransfer {
     pub tx_id: String,
-    pub vout: i64,
+    pub satpoint: SatPoint,
     pub block_height: i64,
+    pub inscription_id: String,
 }
 
 impl Brc20ActiveTransfer {
-    pub fn new(tx_id: String, vout: i64, block_height: i64) -> Self {
+    pub fn new(
+        tx_id: String,
+        satpoint: SatPoint,
+        block_height: i64,
+        inscription_id: String,
+    ) -> Self {
         Brc20ActiveTransfer {
             tx_id,
-            vout,
+            satpoint,
             block_height,
+            inscription_id,
         }
     }
 }
 
+/// Adds an active transfer under the outpoint holding its sat.
+pub fn insert_active_transfer(
+    active_transfers: &mut ActiveTransfers,
+    active_transfer: Brc20ActiveTransfer,
+) {
+    active_transfers
+        .entry(active_transfer.satpoint.outpoint())
+        .or_insert_with(Vec::new)
+        .push(active_transfer);
+}
+
+// Transfer documents written before inscription ids were recorded only have the
+// reveal txid, their inscription was always the first one of the transaction.
+pub fn transfer_document_filter(inscription_id: &str, tx_id: &str) -> Document {
+    doc! {
+        "$or": [
+            { "inscription_id": inscription_id },
+            { "tx.txid": tx_id, "inscription_id": { "$exists": false } },
+        ]
+    }
+}
+
 #[derive(Debug, Clone, Serialize)]
 pub struct Brc20Transfer {
-    pub amt: f64,
+    pub inscription_id: String,
+    pub satpoint: SatPoint,
+    pub amt: Amount,
     pub block_height: u32,
     pub tx_height: u32,
     pub tx: GetRawTransactionResult,
@@ -49,6 +100,8 @@ pub struct Brc20Transfer {
 impl Brc20Transfer {
     pub fn new(
         inscription_tx: &GetRawTransactionResult,
+        inscription_id: String,
+        satpoint: SatPoint,
         inscription: Brc20Inscription,
         block_height: u32,
         tx_height: u32,
@@ -57,10 +110,12 @@ impl Brc20Transfer {
         let amt = inscription
             .amt
             .as_ref()
-            .map(|amt_str| amt_str.parse::<f64>().unwrap_or(0.0))
-            .unwrap_or(0.0);
+            .and_then(|amt_str| amt_str.parse::<Amount>().ok())
+            .unwrap_or_default();
 
         Brc20Transfer {
+            inscription_id,
+            satpoint,
             amt,
             block_height,
             tx_height,
@@ -81,74 +136,55 @@ impl Brc20Transfer {
 
     pub async fn validate_inscribe_transfer(
         &mut self,
-        mongo_client: &MongoClient,
-        active_transfers: &mut Option<HashMap<(String, i64), Brc20ActiveTransfer>>,
-        user_balances_to_update: &mut HashMap<(String, String), Document>,
-        user_balances_to_insert: &mut HashMap<(String, String), Document>,
+        store: &dyn Brc20Store,
+        tickers: &mut HashMap<String, Brc20Ticker>,
+        active_transfers: &mut ActiveTransfers,
+        user_balances_to_update: &mut HashMap<(String, String), UserBalance>,
+        user_balances_to_insert: &mut HashMap<(String, String), UserBalance>,
         invalid_brc20_docs: &mut Vec<Document>,
     ) -> Result<UserBalanceEntry, Box<dyn std::error::Error>> {
         let ticker_symbol = &self.inscription.tick.to_lowercase();
         let mut user_balance_entry = UserBalanceEntry::default();
         let from = &self.from.to_string();
 
-        // Get the ticker document from MongoDB
-        let ticker_doc_from_mongo = mongo_client
-            .get_document_by_field(consts::COLLECTION_TICKERS, "tick", ticker_symbol)
-            .await?;
+        // Get the ticker from this block or the store
+        let decimals = match get_ticker(tickers, ticker_symbol, store).await? {
+            Some(ticker) => ticker.decimals,
+            None => {
+                // Ticker not found, create invalid transaction
+                let reason = "Ticker not found";
+                error!("INVALID Transfer Inscribe: {}", reason);
 
-        if ticker_doc_from_mongo.is_none() {
-            // Ticker not found, create invalid transaction
-            let reason = "Ticker not found";
-            error!("INVALID Transfer Inscribe: {}", reason);
+                self.insert_invalid_tx(reason, invalid_brc20_docs).await?;
 
-            self.insert_invalid_tx(reason, invalid_brc20_docs).await?;
-
-            return Err(Box::new(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                reason,
-            )));
-        }
-
-        // Get the user balance document from the hashmap
-        let user_balance_from =
-            user_balances_to_update.get_mut(&(from.clone(), ticker_symbol.clone()));
+                return Err(Box::new(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    reason,
+                )));
+            }
+        };
 
-        let user_balance = match user_balance_from {
+        // Get the user balance from this block or the store
+        let user_balance = match get_user_balance(
+            store,
+            user_balances_to_update,
+            user_balances_to_insert,
+            &(from.clone(), ticker_symbol.clone()),
+        )
+        .await?
+        {
             Some(user_balance) => user_balance,
             None => {
-                // User balance not found in the hashmap, check the 'user_balances_to_insert' hashmap
-                let user_balance =
-                    user_balances_to_insert.get_mut(&(from.clone(), ticker_symbol.clone()));
-
-                match user_balance {
-                    Some(user_balance) => user_balance,
-                    None => {
-                        // User balance not found in either hashmap, load it from the database
-                        let user_balance_doc = mongo_client
-                            .load_user_balance_with_retry(&(from.clone(), ticker_symbol.clone()))
-                            .await
-                            .map_err(|e| anyhow::anyhow!("{}", e))?;
-
-                        if let Some(doc) = user_balance_doc {
-                            user_balances_to_update
-                                .insert((from.clone(), ticker_symbol.clone()), doc.clone());
-                            user_balances_to_update
-                                .get_mut(&(from.clone(), ticker_symbol.clone()))
-                                .unwrap()
-                        } else {
-                            // User balance not found in the database either
-                            let reason = "User balance not found";
-                            error!("INVALID: {}", reason);
-
-                            self.insert_invalid_tx(reason, invalid_brc20_docs).await?;
-
-                            return Err(Box::new(std::io::Error::new(
-                                std::io::ErrorKind::Other,
-                                reason,
-                            )));
-                        }
-                    }
-                }
+                // User balance not found in the store either
+                let reason = "User balance not found";
+                error!("INVALID: {}", reason);
+
+                self.insert_invalid_tx(reason, invalid_brc20_docs).await?;
+
+                return Err(Box::new(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    reason,
+                )));
             }
         };
 
@@ -157,17 +193,23 @@ impl Brc20Transfer {
             user_balance
         );
 
-        let available_balance = mongo_client
-            .get_double(&user_balance, "available_balance")
-            .unwrap_or_default();
+        let available_balance = user_balance.available_balance;
 
-        // Get transfer amount
-        let transfer_amount = self
-            .inscription
-            .amt
-            .as_ref()
-            .and_then(|amt_str| amt_str.parse::<f64>().ok())
-            .unwrap_or(0.0);
+        // Get transfer amount, it can't have more decimals than the ticker allows
+        let transfer_amount = match self.inscription.amt.as_ref() {
+            Some(amt_str) => match convert_to_amount(amt_str, decimals) {
+                Ok(amount) => amount,
+                Err(reason) => {
+                    error!("INVALID: {}", reason);
+
+                    self.insert_invalid_tx(reason, invalid_brc20_docs).await?;
+
+                    return Ok(user_balance_entry);
+                }
+            },
+            None => Amount::ZERO,
+        };
+        self.amt = transfer_amount;
 
         // Check if the user has enough balance to transfer
         if available_balance >= transfer_amount {
@@ -175,33 +217,31 @@ impl Brc20Transfer {
             self.is_valid = true;
 
             // Insert user balance entry
-            user_balance_entry = mongo_client
-                .insert_user_balance_entry(
-                    &self.from.to_string(),
-                    transfer_amount,
-                    ticker_symbol,
-                    self.block_height.into(),
-                    UserBalanceEntryType::Inscription,
-                )
-                .await?;
-
-            // Update the user balance document
-            update_sender_or_inscriber_user_balance_document(user_balance, &user_balance_entry)?;
+            user_balance_entry = UserBalanceEntry::new(
+                self.from.to_string(),
+                ticker_symbol.clone(),
+                self.block_height.into(),
+                transfer_amount,
+                UserBalanceEntryType::Inscription,
+            )
+            .with_source(
+                &self.tx.txid.to_string(),
+                self.tx_height.into(),
+                &self.inscription_id,
+            );
+
+            // Update the user balance
+            update_sender_or_inscriber_user_balance(user_balance, &user_balance_entry)?;
 
             // Create a new active transfer when the inscription is valid
-            let active_transfer =
-                Brc20ActiveTransfer::new(self.tx.txid.to_string(), 0, self.block_height.into());
-
-            // If active_transfers is None, create a new HashMap and assign it to active_transfers
-            if active_transfers.is_none() {
-                *active_transfers = Some(HashMap::new());
-            }
-
-            // We know active_transfers is Some at this point, so we can unwrap it
-            active_transfers
-                .as_mut()
-                .unwrap()
-                .insert((self.tx.txid.to_string(), 0), active_transfer);
+            let active_transfer = Brc20ActiveTransfer::new(
+                self.tx.txid.to_string(),
+                self.satpoint.clone(),
+                self.block_height.into(),
+                self.inscription_id.clone(),
+            );
+
+            insert_active_transfer(active_transfers, active_transfer);
         } else {
             // If invalid, add invalid tx and return
             let reason = "Transfer amount exceeds available balance";
@@ -232,25 +272,36 @@ impl Brc20Transfer {
 }
 
 pub async fn handle_transfer_operation(
-    mongo_client: &MongoClient,
+    store: &dyn Brc20Store,
     block_height: u32,
     tx_height: u32,
+    inscription_id: String,
+    satpoint: SatPoint,
     inscription: Brc20Inscription,
     raw_tx: &GetRawTransactionResult,
     sender: Address,
-    active_transfers: &mut Option<HashMap<(String, i64), Brc20ActiveTransfer>>,
-    user_balances: &mut HashMap<(String, String), Document>,
-    user_balances_to_insert: &mut HashMap<(String, String), Document>,
+    tickers: &mut HashMap<String, Brc20Ticker>,
+    active_transfers: &mut ActiveTransfers,
+    user_balances: &mut HashMap<(String, String), UserBalance>,
+    user_balances_to_insert: &mut HashMap<(String, String), UserBalance>,
     invalid_brc20_docs: &mut Vec<Document>,
 ) -> Result<(Brc20Transfer, UserBalanceEntry), Box<dyn std::error::Error>> {
     // Create a new transfer transaction
-    let mut validated_transfer_tx =
-        Brc20Transfer::new(raw_tx, inscription, block_height, tx_height, sender);
+    let mut validated_transfer_tx = Brc20Transfer::new(
+        raw_tx,
+        inscription_id,
+        satpoint,
+        inscription,
+        block_height,
+        tx_height,
+        sender,
+    );
 
     // Handle the transfer inscription
     let user_balance_entry = validated_transfer_tx
         .validate_inscribe_transfer(
-            mongo_client,
+            store,
+            tickers,
             active_transfers,
             user_balances,
             user_balances_to_insert,
@@ -264,6 +315,10 @@ pub async fn handle_transfer_operation(
 impl ToDocument for Brc20Transfer {
     fn to_document(&self) -> Document {
         doc! {
+            "inscription_id": &self.inscription_id,
+            "satpoint": self.satpoint.to_string(),
+            "vout": self.satpoint.vout,
+            "offset": self.satpoint.offset,
             "amt": self.amt,
             "block_height": self.block_height,
             "tx_height": self.tx_height,
@@ -283,9 +338,12 @@ impl ToDocument for Brc20Transfer {
 impl ToDocument for Brc20ActiveTransfer {
     fn to_document(&self) -> Document {
         doc! {
-            "txid": self.tx_id.to_string(),
-            "vout": self.vout,
+            "tx_id": self.tx_id.to_string(),
+            "satpoint": self.satpoint.to_string(),
+            "vout": self.satpoint.vout,
+            "offset": self.satpoint.offset,
             "block_height": self.block_height,
+            "inscription_id": &self.inscription_id,
             "created_at": Bson::DateTime(DateTime::now())
         }
     }
@@ -298,18 +356,32 @@ impl Brc20ActiveTransfer {
             .map_err(|_| "Invalid txid".to_string())?
             .to_string();
 
-        let vout = document
-            .get_i64("vout")
-            .map_err(|_| "Invalid vout".to_string())?;
+        // active transfers stored before satpoints were recorded sit at offset 0 of their vout
+        let satpoint = match document.get_str("satpoint") {
+            Ok(satpoint) => satpoint.parse::<SatPoint>()?,
+            Err(_) => {
+                let vout = document
+                    .get_i64("vout")
+                    .map_err(|_| "Invalid vout".to_string())?;
+                SatPoint::new(tx_id.clone(), vout, 0)
+            }
+        };
 
         let block_height = document
             .get_i64("block_height")
             .map_err(|_| "Invalid block_height".to_string())?;
 
+        // active transfers stored before inscription ids were recorded
+        let inscription_id = match document.get_str("inscription_id") {
+            Ok(inscription_id) => inscription_id.to_string(),
+            Err(_) => format!("{}i0", tx_id),
+        };
+
         Ok(Self {
             tx_id,
-            vout,
+            satpoint,
             block_height,
+            inscription_id,
         })
     }
 }
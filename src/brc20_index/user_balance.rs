@@ -1,15 +1,21 @@
-use super::ToDocument;
-use mongodb::bson::{doc, Document};
-use serde::Serialize;
+use super::{
+    amount::{get_amount, Amount},
+    consts,
+    store::get_integer,
+    ToDocument,
+};
+use mongodb::bson::{doc, Bson, Document};
+use serde::{Deserialize, Serialize};
 use std::fmt;
+use std::str::FromStr;
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct UserBalance {
     pub address: String,
     pub tick: String,
-    pub overall_balance: f64,
-    pub available_balance: f64,
-    pub transferable_balance: f64,
+    pub overall_balance: Amount,
+    pub available_balance: Amount,
+    pub transferable_balance: Amount,
     pub block_height: u64,
 }
 
@@ -26,13 +32,69 @@ impl ToDocument for UserBalance {
     }
 }
 
-#[derive(Debug, Clone, Serialize)]
+impl UserBalance {
+    pub fn new(address: String, tick: String, block_height: u64) -> Self {
+        UserBalance {
+            address,
+            tick,
+            overall_balance: Amount::ZERO,
+            available_balance: Amount::ZERO,
+            transferable_balance: Amount::ZERO,
+            block_height,
+        }
+    }
+
+    pub fn from_document(document: &Document) -> Result<Self, String> {
+        let address = document
+            .get_str("address")
+            .map_err(|_| "Invalid address".to_string())?
+            .to_string();
+        let tick = document
+            .get_str("tick")
+            .map_err(|_| "Invalid tick".to_string())?
+            .to_string();
+
+        // block heights are stored as both Int32 and Int64 depending on the writer
+        let block_height = match document.get(consts::KEY_BLOCK_HEIGHT) {
+            Some(Bson::Int32(value)) => *value as u64,
+            Some(Bson::Int64(value)) => *value as u64,
+            _ => 0,
+        };
+
+        Ok(UserBalance {
+            address,
+            tick,
+            overall_balance: get_amount(document, consts::OVERALL_BALANCE).unwrap_or_default(),
+            available_balance: get_amount(document, consts::AVAILABLE_BALANCE).unwrap_or_default(),
+            transferable_balance: get_amount(document, consts::TRANSFERABLE_BALANCE)
+                .unwrap_or_default(),
+            block_height,
+        })
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.overall_balance.is_zero()
+            && self.available_balance.is_zero()
+            && self.transferable_balance.is_zero()
+    }
+}
+
+/// A change to a user balance, with the transaction and inscription that caused it.
+///
+/// Entries written before they recorded their source have no txid, tx index,
+/// inscription id or counterparty.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UserBalanceEntry {
     pub address: String,
     pub tick: String,
     pub block_height: u64,
-    pub amt: f64,
+    pub amt: Amount,
     pub entry_type: UserBalanceEntryType,
+    pub txid: Option<String>,
+    pub tx_index: Option<i64>,
+    pub inscription_id: Option<String>,
+    // the other address of a send or receive
+    pub counterparty: Option<String>,
 }
 
 impl Default for UserBalanceEntry {
@@ -41,8 +103,12 @@ impl Default for UserBalanceEntry {
             address: String::default(),
             tick: String::default(),
             block_height: 0,
-            amt: 0.0,
+            amt: Amount::ZERO,
             entry_type: UserBalanceEntryType::Inscription,
+            txid: None,
+            tx_index: None,
+            inscription_id: None,
+            counterparty: None,
         }
     }
 }
@@ -52,7 +118,7 @@ impl UserBalanceEntry {
         address: String,
         tick: String,
         block_height: u64,
-        amount: f64,
+        amount: Amount,
         entry_type: UserBalanceEntryType,
     ) -> Self {
         let entry = UserBalanceEntry {
@@ -61,9 +127,61 @@ impl UserBalanceEntry {
             block_height,
             amt: amount,
             entry_type,
+            txid: None,
+            tx_index: None,
+            inscription_id: None,
+            counterparty: None,
         };
         entry
     }
+
+    /// Records the transaction, at `tx_index` in its block, and the inscription
+    /// that caused the entry.
+    pub fn with_source(mut self, txid: &str, tx_index: i64, inscription_id: &str) -> Self {
+        self.txid = Some(txid.to_string());
+        self.tx_index = Some(tx_index);
+        self.inscription_id = Some(inscription_id.to_string());
+        self
+    }
+
+    /// Records the address the amount was sent to or received from.
+    pub fn with_counterparty(mut self, counterparty: &str) -> Self {
+        self.counterparty = Some(counterparty.to_string());
+        self
+    }
+
+    pub fn from_document(document: &Document) -> Result<Self, String> {
+        let address = document
+            .get_str("address")
+            .map_err(|_| "Invalid address".to_string())?
+            .to_string();
+        let tick = document
+            .get_str("tick")
+            .map_err(|_| "Invalid tick".to_string())?
+            .to_string();
+        let entry_type = document
+            .get_str("entry_type")
+            .map_err(|_| "Invalid entry type".to_string())?;
+
+        // block heights are stored as both Int32 and Int64 depending on the writer
+        let block_height = match document.get(consts::KEY_BLOCK_HEIGHT) {
+            Some(Bson::Int32(value)) => *value as u64,
+            Some(Bson::Int64(value)) => *value as u64,
+            _ => 0,
+        };
+
+        Ok(UserBalanceEntry {
+            address,
+            tick,
+            block_height,
+            amt: get_amount(document, "amt").unwrap_or_default(),
+            entry_type: entry_type.parse()?,
+            txid: document.get_str("txid").ok().map(str::to_string),
+            tx_index: get_integer(document, "tx_index"),
+            inscription_id: document.get_str("inscription_id").ok().map(str::to_string),
+            counterparty: document.get_str("counterparty").ok().map(str::to_string),
+        })
+    }
 }
 
 impl ToDocument for UserBalanceEntry {
@@ -74,17 +192,21 @@ impl ToDocument for UserBalanceEntry {
             "block_height": self.block_height as i64,
             "amt": self.amt,
             "entry_type": &self.entry_type.to_string(),
+            "txid": self.txid.clone(),
+            "tx_index": self.tx_index,
+            "inscription_id": self.inscription_id.clone(),
+            "counterparty": self.counterparty.clone(),
         }
     }
 }
 
-use std::convert::From;
-
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum UserBalanceEntryType {
     Inscription,
     Send,
     Receive,
+    // a transfer inscription spent as fee, the amount is available to the sender again
+    FeeReturn,
 }
 
 impl fmt::Display for UserBalanceEntryType {
@@ -93,17 +215,78 @@ impl fmt::Display for UserBalanceEntryType {
             UserBalanceEntryType::Inscription => write!(f, "inscription"),
             UserBalanceEntryType::Send => write!(f, "send"),
             UserBalanceEntryType::Receive => write!(f, "receive"),
+            UserBalanceEntryType::FeeReturn => write!(f, "fee_return"),
         }
     }
 }
 
-impl From<&str> for UserBalanceEntryType {
-    fn from(item: &str) -> Self {
-        match item {
-            "inscription" => UserBalanceEntryType::Inscription,
-            "send" => UserBalanceEntryType::Send,
-            "receive" => UserBalanceEntryType::Receive,
-            _ => panic!("Invalid UserBalanceEntryType"),
+impl FromStr for UserBalanceEntryType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "inscription" => Ok(UserBalanceEntryType::Inscription),
+            "send" => Ok(UserBalanceEntryType::Send),
+            "receive" => Ok(UserBalanceEntryType::Receive),
+            "fee_return" => Ok(UserBalanceEntryType::FeeReturn),
+            other => Err(format!("Invalid entry type: {}", other)),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_user_balance_document_round_trip() {
+        let mut user_balance =
+            UserBalance::new("bc1qholder".to_string(), "ordi".to_string(), 779832);
+        user_balance.overall_balance = Amount::parse("1000.5", 18).unwrap();
+        user_balance.available_balance = Amount::parse("900.5", 18).unwrap();
+        user_balance.transferable_balance = Amount::parse("100", 18).unwrap();
+
+        let parsed = UserBalance::from_document(&user_balance.to_document()).unwrap();
+        assert_eq!(parsed.address, user_balance.address);
+        assert_eq!(parsed.overall_balance, user_balance.overall_balance);
+        assert_eq!(parsed.available_balance, user_balance.available_balance);
+        assert_eq!(
+            parsed.transferable_balance,
+            user_balance.transferable_balance
+        );
+        assert_eq!(parsed.block_height, 779832);
+        assert!(!parsed.is_zero());
+    }
+
+    #[test]
+    fn test_user_balance_entry_document_round_trip() {
+        let entry = UserBalanceEntry::new(
+            "bc1qsender".to_string(),
+            "ordi".to_string(),
+            779834,
+            Amount::parse("400", 18).unwrap(),
+            UserBalanceEntryType::Send,
+        )
+        .with_source("tx", 2, "txi0")
+        .with_counterparty("bc1qreceiver");
+
+        let parsed = UserBalanceEntry::from_document(&entry.to_document()).unwrap();
+        assert_eq!(parsed.amt, entry.amt);
+        assert_eq!(parsed.txid.as_deref(), Some("tx"));
+        assert_eq!(parsed.tx_index, Some(2));
+        assert_eq!(parsed.inscription_id.as_deref(), Some("txi0"));
+        assert_eq!(parsed.counterparty.as_deref(), Some("bc1qreceiver"));
+
+        // entries written before they recorded their source
+        let mut document = entry.to_document();
+        for field in ["txid", "tx_index", "inscription_id", "counterparty"] {
+            document.remove(field);
+        }
+        let parsed = UserBalanceEntry::from_document(&document).unwrap();
+        assert!(parsed.txid.is_none() && parsed.counterparty.is_none());
+
+        // an unknown entry type is an error, not a panic
+        document.insert("entry_type", "airdrop");
+        assert!(UserBalanceEntry::from_document(&document).is_err());
+    }
+}
@@ -1,67 +1,124 @@
 use super::{
+    amount::Amount,
     brc20_ticker::Brc20Ticker,
-    consts,
-    mongo::MongoClient,
+    inscription::{Inscription, RevealedInscription},
+    satpoint::locate_offset,
+    store::Brc20Store,
     user_balance::{UserBalance, UserBalanceEntry, UserBalanceEntryType},
-    Brc20Inscription, ToDocument,
+    Brc20Inscription,
 };
 use bitcoin::{Address, Network, TxIn};
-use bitcoincore_rpc::{bitcoincore_rpc_json::GetRawTransactionResult, Client, RpcApi};
+use bitcoincore_rpc::{bitcoincore_rpc_json::GetRawTransactionResult, RpcApi};
 use log::{debug, error};
-use mongodb::bson::{Bson, Document};
 use serde::Serialize;
 use std::collections::HashMap;
 
-pub fn get_witness_data_from_raw_tx(
+/// Gets every inscription revealed in the transaction's inputs, in order.
+///
+/// Inscriptions land on the first sat of the input that reveals them, or on the
+/// sat their pointer field points to. Input values are only fetched over RPC
+/// when an input other than the first holds an envelope.
+pub fn get_inscriptions_from_raw_tx(
+    rpc: &impl RpcApi,
     raw_tx_info: &GetRawTransactionResult,
-) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+) -> Result<Vec<RevealedInscription>, Box<dyn std::error::Error>> {
     let transaction = raw_tx_info.transaction()?;
+    let txid = transaction.txid();
+
+    let output_values: Vec<u64> = transaction
+        .output
+        .iter()
+        .map(|output| output.value)
+        .collect();
+    let total_output_value: u64 = output_values.iter().sum();
+
+    let mut input_values = Vec::new();
+    let mut revealed_inscriptions = Vec::new();
+    for (input_index, input) in transaction.input.iter().enumerate() {
+        let inscriptions = Inscription::from_witness(&input.witness);
+        if inscriptions.is_empty() {
+            continue;
+        }
 
-    let mut witness_data_strings: Vec<String> = Vec::new();
-
-    // Get the first transaction input
-    if let Some(input) = transaction.input.first() {
-        // Iterate through each witness of the input
-        for witness in &input.witness {
-            let witness_string = String::from_utf8_lossy(witness).into_owned();
-            witness_data_strings.push(witness_string);
+        let input_offset = input_offset(rpc, &transaction.input, input_index, &mut input_values)?;
+
+        for inscription in inscriptions {
+            let offset = match inscription.pointer {
+                Some(pointer) if pointer < total_output_value => pointer,
+                _ => input_offset,
+            };
+
+            let index = revealed_inscriptions.len();
+            revealed_inscriptions.push(RevealedInscription {
+                id: format!("{}i{}", txid, index),
+                index,
+                input_index,
+                location: locate_offset(&output_values, offset),
+                inscription,
+            });
         }
     }
 
-    Ok(witness_data_strings)
+    Ok(revealed_inscriptions)
+}
+
+/// Offset of the first sat of input `input_index` among all sats spent by the transaction.
+///
+/// Sats flow first-in-first-out, so this is the sum of the values of the inputs
+/// before it. `input_values` caches the values fetched over RPC so far and is
+/// only extended as far as needed.
+pub fn input_offset(
+    rpc: &impl RpcApi,
+    inputs: &[TxIn],
+    input_index: usize,
+    input_values: &mut Vec<u64>,
+) -> anyhow::Result<u64> {
+    if input_values.len() < input_index {
+        let missing = transaction_inputs_to_values(rpc, &inputs[input_values.len()..input_index])?;
+        input_values.extend(missing);
+    }
+
+    Ok(input_values[..input_index].iter().sum())
 }
 
 // extracts only inscriptions that read "brc-20", many will be invalid
-pub fn extract_and_process_witness_data(witness_data: String) -> Option<Brc20Inscription> {
-    // Check for the correct MIME type and find its end
-    let mime_end_index = if witness_data.contains("text/plain") {
-        witness_data.find("text/plain").unwrap() + "text/plain".len()
-    } else if witness_data.contains("application/json") {
-        witness_data.find("application/json").unwrap() + "application/json".len()
-    } else {
+pub fn extract_brc20_inscription(inscription: &Inscription) -> Option<Brc20Inscription> {
+    // Check for the correct MIME type
+    match inscription.media_type() {
+        Some("text/plain") | Some("application/json") => {}
+        _ => return None,
+    }
+
+    // compressed content isn't valid brc-20
+    if inscription.content_encoding.is_some() {
         return None;
+    }
+
+    // ord doesn't bind inscriptions with unrecognized even fields
+    if inscription.unrecognized_even_field {
+        return None;
+    }
+
+    let body = inscription.body.as_ref()?;
+    let json_data = match std::str::from_utf8(body) {
+        Ok(json_data) => json_data,
+        Err(e) => {
+            debug!("Inscription body is not UTF-8: {:?}", e);
+            return None;
+        }
     };
 
-    // Start searching for the JSON data only after the MIME type
-    if let Some(json_start) = witness_data[mime_end_index..].find('{') {
-        let json_start = mime_end_index + json_start; // Adjust json_start to be relative to the original string
-        if let Some(json_end) = witness_data[json_start..].rfind('}') {
-            // Extract the JSON string
-            let json_data = &witness_data[json_start..json_start + json_end + 1];
-
-            // Try to parse the JSON data
-            match serde_json::from_str::<Brc20Inscription>(json_data) {
-                Ok(parsed_data) => {
-                    // Only return the parsed data if it contains brc-20
-                    if parsed_data.p == "brc-20" {
-                        return Some(parsed_data);
-                    }
-                }
-                Err(e) => {
-                    debug!("JSON parsing failed: {:?}", e);
-                }
+    // Try to parse the JSON data
+    match serde_json::from_str::<Brc20Inscription>(json_data) {
+        Ok(parsed_data) => {
+            // Only return the parsed data if it contains brc-20
+            if parsed_data.p == "brc-20" {
+                return Some(parsed_data);
             }
         }
+        Err(e) => {
+            debug!("JSON parsing failed: {:?}", e);
+        }
     }
 
     None
@@ -95,35 +152,18 @@ pub fn get_owner_of_vout(
     Ok(this_address)
 }
 
-pub fn convert_to_float(number_string: &str, decimals: u8) -> Result<f64, &'static str> {
-    let parts: Vec<&str> = number_string.split('.').collect();
-    match parts.len() {
-        1 => {
-            // No decimal point in the string
-            let result = number_string.parse::<f64>();
-            match result {
-                Ok(value) => Ok(value),
-                Err(_) => Err("Malformed inscription"),
-            }
-        }
-        2 => {
-            // There is a decimal point in the string
-            if parts[1].len() > decimals as usize {
-                error!("There are too many digits to the right of the decimal");
-                return Err("There are too many digits to the right of the decimal");
-            } else {
-                let result = number_string.parse::<f64>();
-                match result {
-                    Ok(value) => Ok(value),
-                    Err(_) => Err("Malformed inscription"),
-                }
-            }
-        }
-        _ => Err("Malformed inscription"), // More than one decimal point
+pub fn convert_to_amount(number_string: &str, decimals: u8) -> Result<Amount, &'static str> {
+    let result = Amount::parse(number_string, decimals);
+    if let Err(reason) = result {
+        error!("{}: {}", reason, number_string);
     }
+    result
 }
 
-pub fn transaction_inputs_to_values(client: &Client, inputs: &[TxIn]) -> anyhow::Result<Vec<u64>> {
+pub fn transaction_inputs_to_values(
+    client: &impl RpcApi,
+    inputs: &[TxIn],
+) -> anyhow::Result<Vec<u64>> {
     let mut values: Vec<u64> = vec![];
 
     for input in inputs {
@@ -148,10 +188,34 @@ pub fn transaction_inputs_to_values(client: &Client, inputs: &[TxIn]) -> anyhow:
     }
 }
 
-pub async fn update_receiver_balance_document(
-    mongo_client: &MongoClient,
-    user_balance_docs_to_update: &mut HashMap<(String, String), Document>,
-    user_balance_docs_to_insert: &mut HashMap<(String, String), Document>,
+/// Loads a user balance from the block's balances, or from the store the first time
+/// it is used in the block. `None` if the address never held the ticker.
+pub async fn get_user_balance<'a>(
+    store: &dyn Brc20Store,
+    user_balances_to_update: &'a mut HashMap<(String, String), UserBalance>,
+    user_balances_to_insert: &'a mut HashMap<(String, String), UserBalance>,
+    key: &(String, String),
+) -> Result<Option<&'a mut UserBalance>, anyhow::Error> {
+    if user_balances_to_insert.contains_key(key) {
+        return Ok(user_balances_to_insert.get_mut(key));
+    }
+
+    if !user_balances_to_update.contains_key(key) {
+        match store.get_user_balance(&key.0, &key.1).await? {
+            Some(user_balance) => {
+                user_balances_to_update.insert(key.clone(), user_balance);
+            }
+            None => return Ok(None),
+        }
+    }
+
+    Ok(user_balances_to_update.get_mut(key))
+}
+
+pub async fn update_receiver_balance(
+    store: &dyn Brc20Store,
+    user_balances_to_update: &mut HashMap<(String, String), UserBalance>,
+    user_balances_to_insert: &mut HashMap<(String, String), UserBalance>,
     user_balance_entry: &UserBalanceEntry,
 ) -> Result<(), anyhow::Error> {
     // Create the key from the address and ticker
@@ -160,145 +224,103 @@ pub async fn update_receiver_balance_document(
         user_balance_entry.tick.clone(),
     );
 
-    // Check if the user balance document exists in the 'user_balance_docs_to_update' hashmap
-    if let Some(user_balance) = user_balance_docs_to_update.get_mut(&key) {
-        // Document found in 'user_balance_docs', update it
-        debug!(
-            "Updating existing user balance document: {:?}",
-            user_balance
-        );
-        update_receiver(user_balance, user_balance_entry)?;
-    } else {
-        // Check if the user balance document exists in the 'user_balance_docs_to_insert' hashmap
-        if let Some(user_balance) = user_balance_docs_to_insert.get_mut(&key) {
-            // Document found in 'user_balance_docs_to_insert', update it
-            debug!(
-                "Updating existing user balance document (to insert): {:?}",
-                user_balance
-            );
+    match get_user_balance(
+        store,
+        user_balances_to_update,
+        user_balances_to_insert,
+        &key,
+    )
+    .await?
+    {
+        Some(user_balance) => {
+            debug!("Updating existing user balance: {:?}", user_balance);
             update_receiver(user_balance, user_balance_entry)?;
-        } else {
-            // Load the user balance document from MongoDB with retry
-            let user_balance_doc = mongo_client
-                .load_user_balance_with_retry(&key)
-                .await
-                .map_err(|e| anyhow::anyhow!("{}", e))?;
-
-            if let Some(user_balance) = user_balance_doc {
-                // Document found in MongoDB, update it and add it to 'user_balance_docs'
-                debug!(
-                    "Updating existing user balance document from MongoDB: {:?}",
-                    user_balance
-                );
-                update_receiver(
-                    user_balance_docs_to_update
-                        .entry(key.clone())
-                        .or_insert_with(|| user_balance.clone()),
-                    user_balance_entry,
-                )?;
-            } else {
-                // Document not found, create a new user balance document
-                let new_user_balance = UserBalance {
-                    address: user_balance_entry.address.to_string(),
-                    tick: user_balance_entry.tick.clone(),
-                    overall_balance: user_balance_entry.amt,
-                    available_balance: user_balance_entry.amt,
-                    transferable_balance: 0.0,
-                    block_height: user_balance_entry.block_height,
-                };
-
-                // Convert the UserBalance to a Document
-                let new_user_balance_doc = new_user_balance.to_document();
-
-                // Insert the new user balance document into the 'user_balance_docs_to_insert' hashmap
-                debug!(
-                    "Adding new user balance document to insert: {:?}",
-                    new_user_balance_doc
-                );
-                user_balance_docs_to_insert.insert(key, new_user_balance_doc);
-            }
+        }
+        None => {
+            // Balance not found, create a new user balance
+            let mut new_user_balance = UserBalance::new(
+                user_balance_entry.address.to_string(),
+                user_balance_entry.tick.clone(),
+                user_balance_entry.block_height,
+            );
+            update_receiver(&mut new_user_balance, user_balance_entry)?;
+
+            debug!("Adding new user balance to insert: {:?}", new_user_balance);
+            user_balances_to_insert.insert(key, new_user_balance);
         }
     }
 
     Ok(())
 }
 
-/// Update the receiver's balance document
+/// Update the receiver's balance
 fn update_receiver(
-    user_balance: &mut Document,
+    user_balance: &mut UserBalance,
     user_balance_entry: &UserBalanceEntry,
 ) -> Result<(), anyhow::Error> {
-    // Get the overall and available balance values from the document
-    let overall_balance = user_balance
-        .get_f64(consts::OVERALL_BALANCE)
-        .unwrap_or_default();
-    let available_balance = user_balance
-        .get_f64(consts::AVAILABLE_BALANCE)
-        .unwrap_or_default();
-
-    // Update the values
-    let updated_overall_balance = overall_balance + user_balance_entry.amt;
-    let updated_available_balance = available_balance + user_balance_entry.amt;
-
-    // Update the document
-    user_balance.insert(
-        consts::OVERALL_BALANCE.to_string(),
-        Bson::Double(updated_overall_balance),
-    );
-    user_balance.insert(
-        consts::AVAILABLE_BALANCE.to_string(),
-        Bson::Double(updated_available_balance),
-    );
+    user_balance.overall_balance = user_balance
+        .overall_balance
+        .checked_add(user_balance_entry.amt)
+        .ok_or_else(|| anyhow::anyhow!("Overall balance overflow"))?;
+    user_balance.available_balance = user_balance
+        .available_balance
+        .checked_add(user_balance_entry.amt)
+        .ok_or_else(|| anyhow::anyhow!("Available balance overflow"))?;
+
     // Update the block height
-    user_balance.insert(
-        consts::KEY_BLOCK_HEIGHT.to_string(),
-        Bson::Int64(user_balance_entry.block_height as i64),
-    );
+    user_balance.block_height = user_balance_entry.block_height;
 
     Ok(())
 }
 
-pub fn update_sender_or_inscriber_user_balance_document(
-    user_balance: &mut Document,
+/// Applies a balance entry of any type to a user balance, used to rebuild balances
+/// from their entries.
+pub fn apply_user_balance_entry(
+    user_balance: &mut UserBalance,
+    user_balance_entry: &UserBalanceEntry,
+) -> Result<(), anyhow::Error> {
+    match user_balance_entry.entry_type {
+        UserBalanceEntryType::Receive => update_receiver(user_balance, user_balance_entry),
+        _ => update_sender_or_inscriber_user_balance(user_balance, user_balance_entry),
+    }
+}
+
+pub fn update_sender_or_inscriber_user_balance(
+    user_balance: &mut UserBalance,
     user_balance_entry: &UserBalanceEntry,
 ) -> Result<(), anyhow::Error> {
-    // Get the available balance, transferable balance, and overall balance values
-    let available_balance = user_balance
-        .get_f64(consts::AVAILABLE_BALANCE)
-        .unwrap_or_default();
-    let transferable_balance = user_balance
-        .get_f64(consts::TRANSFERABLE_BALANCE)
-        .unwrap_or_default();
-    let overall_balance = user_balance
-        .get_f64(consts::OVERALL_BALANCE)
-        .unwrap_or_default();
+    let amount = user_balance_entry.amt;
 
     match user_balance_entry.entry_type {
         UserBalanceEntryType::Send => {
-            let updated_transferable_balance = transferable_balance - user_balance_entry.amt;
-            let updated_overall_balance = overall_balance - user_balance_entry.amt;
-
-            user_balance.insert(
-                consts::TRANSFERABLE_BALANCE.to_string(),
-                Bson::Double(updated_transferable_balance),
-            );
-            user_balance.insert(
-                consts::OVERALL_BALANCE.to_string(),
-                Bson::Double(updated_overall_balance),
-            );
+            user_balance.transferable_balance = user_balance
+                .transferable_balance
+                .checked_sub(amount)
+                .ok_or_else(|| anyhow::anyhow!("Transferable balance would be negative"))?;
+            user_balance.overall_balance = user_balance
+                .overall_balance
+                .checked_sub(amount)
+                .ok_or_else(|| anyhow::anyhow!("Overall balance would be negative"))?;
         }
         UserBalanceEntryType::Inscription => {
-            let updated_available_balance = available_balance - user_balance_entry.amt;
-            let updated_transferable_balance = transferable_balance + user_balance_entry.amt;
-
-            user_balance.insert(
-                consts::AVAILABLE_BALANCE.to_string(),
-                Bson::Double(updated_available_balance),
-            );
-            user_balance.insert(
-                consts::TRANSFERABLE_BALANCE.to_string(),
-                Bson::Double(updated_transferable_balance),
-            );
+            user_balance.available_balance = user_balance
+                .available_balance
+                .checked_sub(amount)
+                .ok_or_else(|| anyhow::anyhow!("Available balance would be negative"))?;
+            user_balance.transferable_balance = user_balance
+                .transferable_balance
+                .checked_add(amount)
+                .ok_or_else(|| anyhow::anyhow!("Transferable balance overflow"))?;
+        }
+        UserBalanceEntryType::FeeReturn => {
+            user_balance.transferable_balance = user_balance
+                .transferable_balance
+                .checked_sub(amount)
+                .ok_or_else(|| anyhow::anyhow!("Transferable balance would be negative"))?;
+            user_balance.available_balance = user_balance
+                .available_balance
+                .checked_add(amount)
+                .ok_or_else(|| anyhow::anyhow!("Available balance overflow"))?;
         }
         _ => {
             // Other entry types are not applicable for this function
@@ -307,18 +329,15 @@ pub fn update_sender_or_inscriber_user_balance_document(
     }
 
     // Update the block height
-    user_balance.insert(
-        consts::KEY_BLOCK_HEIGHT.to_string(),
-        Bson::Int64(user_balance_entry.block_height as i64),
-    );
+    user_balance.block_height = user_balance_entry.block_height;
 
     Ok(())
 }
 
-pub async fn update_sender_user_balance_document(
-    mongo_client: &MongoClient,
-    user_balances: &mut HashMap<(String, String), Document>,
-    user_balance_docs_to_insert: &mut HashMap<(String, String), Document>,
+pub async fn update_sender_user_balance(
+    store: &dyn Brc20Store,
+    user_balances_to_update: &mut HashMap<(String, String), UserBalance>,
+    user_balances_to_insert: &mut HashMap<(String, String), UserBalance>,
     user_balance_entry: &UserBalanceEntry,
 ) -> Result<(), anyhow::Error> {
     // Create the key from the address and ticker
@@ -327,36 +346,20 @@ pub async fn update_sender_user_balance_document(
         user_balance_entry.tick.clone(),
     );
 
-    // Check if the user balance document exists in the in-memory hashmap
-    if let Some(user_balance) = user_balances.get_mut(&key) {
-        // Update the existing user balance document
-        update_sender_or_inscriber_user_balance_document(user_balance, user_balance_entry)?;
-    } else {
-        // Check if the user balance document exists in the 'user_balance_docs_to_insert' hashmap
-        let user_balance = user_balance_docs_to_insert.get_mut(&key);
-
-        if let Some(user_balance) = user_balance {
-            // Update the existing user balance document
-            update_sender_or_inscriber_user_balance_document(user_balance, user_balance_entry)?;
-        } else {
-            // Load the user balance document with retry
-            let user_balance_doc = mongo_client
-                .load_user_balance_with_retry(&key)
-                .await
-                .map_err(|e| anyhow::anyhow!("{}", e))?;
-
-            if let Some(user_balance) = user_balance_doc {
-                // Update the existing user balance document
-                update_sender_or_inscriber_user_balance_document(
-                    user_balances
-                        .entry(key.clone())
-                        .or_insert_with(|| user_balance.clone()),
-                    user_balance_entry,
-                )?;
-            } else {
-                // User balance document not found in any hashmap or database
-                return Err(anyhow::anyhow!("User balance document not found"));
-            }
+    match get_user_balance(
+        store,
+        user_balances_to_update,
+        user_balances_to_insert,
+        &key,
+    )
+    .await?
+    {
+        Some(user_balance) => {
+            update_sender_or_inscriber_user_balance(user_balance, user_balance_entry)?;
+        }
+        None => {
+            // User balance not found in the block or the store
+            return Err(anyhow::anyhow!("User balance document not found"));
         }
     }
 
@@ -366,9 +369,9 @@ pub async fn update_sender_user_balance_document(
 //this is for logging to file
 #[derive(Serialize)]
 struct BalanceInfo {
-    overall_balance: f64,
-    available_balance: f64,
-    transferable_balance: f64,
+    overall_balance: Amount,
+    available_balance: Amount,
+    transferable_balance: Amount,
 }
 
 #[derive(Serialize)]
@@ -381,35 +384,80 @@ struct TickerWithBalances {
 mod tests {
     use super::*;
 
+    fn brc20_inscription(content_type: &str, body: &str) -> Inscription {
+        Inscription {
+            content_type: Some(content_type.to_string()),
+            body: Some(body.as_bytes().to_vec()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_extract_brc20_inscription() {
+        let inscription = brc20_inscription(
+            "text/plain;charset=utf-8",
+            r#"{"p":"brc-20","op":"mint","tick":"ordi","amt":"1000"}"#,
+        );
+        let brc20 = extract_brc20_inscription(&inscription).unwrap();
+        assert_eq!(brc20.op, "mint");
+        assert_eq!(brc20.amt.as_deref(), Some("1000"));
+    }
+
+    #[test]
+    fn test_extract_brc20_inscription_rejects_other_content() {
+        let body = r#"{"p":"brc-20","op":"mint","tick":"ordi","amt":"1000"}"#;
+        assert!(extract_brc20_inscription(&brc20_inscription("image/png", body)).is_none());
+
+        let trailing_garbage = format!("{}garbage", body);
+        let inscription = brc20_inscription("text/plain", &trailing_garbage);
+        assert!(extract_brc20_inscription(&inscription).is_none());
+    }
+
     #[test]
-    fn test_convert_to_float_no_decimal() {
-        let result = convert_to_float("1000", 2);
+    fn test_extract_brc20_inscription_rejects_unrecognized_even_field() {
+        let mut inscription = brc20_inscription(
+            "text/plain",
+            r#"{"p":"brc-20","op":"mint","tick":"ordi","amt":"1000"}"#,
+        );
+        inscription.unrecognized_even_field = true;
+        assert!(extract_brc20_inscription(&inscription).is_none());
+    }
+
+    #[test]
+    fn test_convert_to_amount_no_decimal() {
+        let result = convert_to_amount("1000", 2);
         assert!(result.is_ok());
-        assert_eq!(result.unwrap(), 1000.0);
+        assert_eq!(result.unwrap().to_string(), "1000");
     }
 
     #[test]
-    fn test_convert_to_float_with_decimal() {
-        let result = convert_to_float("1234.56", 2);
+    fn test_convert_to_amount_with_decimal() {
+        let result = convert_to_amount("1234.56", 2);
         assert!(result.is_ok());
-        assert_eq!(result.unwrap(), 1234.56);
+        assert_eq!(result.unwrap().to_string(), "1234.56");
     }
 
     #[test]
-    fn test_convert_to_float_too_many_decimals() {
-        let result = convert_to_float("1234.567", 2);
+    fn test_convert_to_amount_too_many_decimals() {
+        let result = convert_to_amount("1234.567", 2);
         assert!(result.is_err());
     }
 
     #[test]
-    fn test_convert_to_float_not_a_number() {
-        let result = convert_to_float("abcd", 2);
+    fn test_convert_to_amount_not_a_number() {
+        let result = convert_to_amount("abcd", 2);
         assert!(result.is_err());
     }
 
     #[test]
-    fn test_convert_to_float_multiple_decimal_points() {
-        let result = convert_to_float("1.2.3", 2);
+    fn test_convert_to_amount_multiple_decimal_points() {
+        let result = convert_to_amount("1.2.3", 2);
         assert!(result.is_err());
     }
+
+    #[test]
+    fn test_convert_to_amount_rejects_signs() {
+        assert!(convert_to_amount("-1", 2).is_err());
+        assert!(convert_to_amount("+1", 2).is_err());
+    }
 }
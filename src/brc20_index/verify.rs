@@ -0,0 +1,309 @@
+use super::{amount::Amount, store::Brc20Store, user_balance::UserBalance};
+use log::info;
+use mongodb::bson::Bson;
+use std::collections::HashMap;
+use std::fmt;
+
+/// An invariant of the index that doesn't hold.
+///
+/// Amounts are unsigned, a stored negative balance fails to load and makes the
+/// verification fail instead.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Violation {
+    /// The overall balances of a ticker's holders don't add up to its total minted.
+    /// `holders` is `None` when their sum overflows.
+    HoldersNotTotalMinted {
+        tick: String,
+        holders: Option<Amount>,
+        total_minted: Amount,
+    },
+    TotalMintedAboveMaxSupply {
+        tick: String,
+        total_minted: Amount,
+        max_supply: Amount,
+    },
+    /// The available and transferable balances don't add up to the overall balance.
+    BalanceMismatch {
+        address: String,
+        tick: String,
+        overall: Amount,
+        available: Amount,
+        transferable: Amount,
+    },
+    /// An active transfer without a valid transfer inscription that hasn't been sent.
+    InvalidActiveTransfer {
+        inscription_id: String,
+        reason: String,
+    },
+    /// A balance entry of a block that would drive a balance negative.
+    NegativeBalance {
+        address: String,
+        tick: String,
+        reason: String,
+    },
+    /// A block's balance differs from the stored balance with the block's entries applied.
+    BalanceNotEntries {
+        address: String,
+        tick: String,
+        balance: Option<UserBalance>,
+        entries: UserBalance,
+    },
+    /// A block's total minted differs from the stored total minted plus the block's mints.
+    TotalMintedNotMints {
+        tick: String,
+        previous: Amount,
+        minted: Amount,
+        total_minted: Amount,
+    },
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::HoldersNotTotalMinted {
+                tick,
+                holders,
+                total_minted,
+            } => {
+                let holders = match holders {
+                    Some(holders) => holders.to_string(),
+                    None => "more than an amount holds".to_string(),
+                };
+                write!(
+                    f,
+                    "{}: holders' overall balances add up to {}, total minted is {}",
+                    tick, holders, total_minted
+                )
+            }
+            Violation::TotalMintedAboveMaxSupply {
+                tick,
+                total_minted,
+                max_supply,
+            } => write!(
+                f,
+                "{}: total minted {} is above the max supply {}",
+                tick, total_minted, max_supply
+            ),
+            Violation::BalanceMismatch {
+                address,
+                tick,
+                overall,
+                available,
+                transferable,
+            } => write!(
+                f,
+                "{} {}: available {} + transferable {} isn't overall {}",
+                address, tick, available, transferable, overall
+            ),
+            Violation::InvalidActiveTransfer {
+                inscription_id,
+                reason,
+            } => write!(f, "active transfer {}: {}", inscription_id, reason),
+            Violation::NegativeBalance {
+                address,
+                tick,
+                reason,
+            } => write!(f, "{} {}: {}", address, tick, reason),
+            Violation::BalanceNotEntries {
+                address,
+                tick,
+                balance,
+                entries,
+            } => {
+                let balance = match balance {
+                    Some(balance) => format!(
+                        "{}/{}/{}",
+                        balance.overall_balance,
+                        balance.available_balance,
+                        balance.transferable_balance
+                    ),
+                    None => "unchanged".to_string(),
+                };
+                write!(
+                    f,
+                    "{} {}: overall/available/transferable is {}, the entries add up to {}/{}/{}",
+                    address,
+                    tick,
+                    balance,
+                    entries.overall_balance,
+                    entries.available_balance,
+                    entries.transferable_balance
+                )
+            }
+            Violation::TotalMintedNotMints {
+                tick,
+                previous,
+                minted,
+                total_minted,
+            } => write!(
+                f,
+                "{}: total minted {} isn't the previous {} plus {} minted",
+                tick, total_minted, previous, minted
+            ),
+        }
+    }
+}
+
+/// Checks the invariants of the whole index and returns the ones that don't hold.
+pub async fn verify(store: &dyn Brc20Store) -> anyhow::Result<Vec<Violation>> {
+    let mut violations = Vec::new();
+
+    // overall balances by ticker, `None` once the sum overflows
+    let mut holders: HashMap<String, Option<Amount>> = HashMap::new();
+    let user_balances = store.get_all_user_balances().await?;
+    info!("Verifying {} user balances", user_balances.len());
+    for user_balance in &user_balances {
+        let sum = holders
+            .entry(user_balance.tick.clone())
+            .or_insert(Some(Amount::ZERO));
+        *sum = sum.and_then(|sum| sum.checked_add(user_balance.overall_balance));
+
+        let available_and_transferable = user_balance
+            .available_balance
+            .checked_add(user_balance.transferable_balance);
+        if available_and_transferable != Some(user_balance.overall_balance) {
+            violations.push(Violation::BalanceMismatch {
+                address: user_balance.address.clone(),
+                tick: user_balance.tick.clone(),
+                overall: user_balance.overall_balance,
+                available: user_balance.available_balance,
+                transferable: user_balance.transferable_balance,
+            });
+        }
+    }
+
+    let tickers = store.get_tickers().await?;
+    info!("Verifying {} tickers", tickers.len());
+    for ticker in tickers {
+        let holders_sum = holders.remove(&ticker.tick).unwrap_or(Some(Amount::ZERO));
+        if holders_sum != Some(ticker.total_minted) {
+            violations.push(Violation::HoldersNotTotalMinted {
+                tick: ticker.tick.clone(),
+                holders: holders_sum,
+                total_minted: ticker.total_minted,
+            });
+        }
+
+        if ticker.total_minted > ticker.max_supply {
+            violations.push(Violation::TotalMintedAboveMaxSupply {
+                tick: ticker.tick,
+                total_minted: ticker.total_minted,
+                max_supply: ticker.max_supply,
+            });
+        }
+    }
+
+    // balances of tickers that were never deployed
+    for (tick, holders_sum) in holders {
+        violations.push(Violation::HoldersNotTotalMinted {
+            tick,
+            holders: holders_sum,
+            total_minted: Amount::ZERO,
+        });
+    }
+
+    let active_transfers = store.load_active_transfers().await?;
+    info!("Verifying {} active transfers", active_transfers.len());
+    for active_transfer in active_transfers.values().flatten() {
+        let reason = match store
+            .get_transfer(&active_transfer.inscription_id, &active_transfer.tx_id)
+            .await?
+        {
+            None => Some("no transfer inscription".to_string()),
+            Some(transfer) if !transfer.get_bool("is_valid").unwrap_or(true) => {
+                Some("the transfer inscription is invalid".to_string())
+            }
+            Some(transfer) if !matches!(transfer.get("send_tx"), None | Some(Bson::Null)) => {
+                Some(format!(
+                    "the transfer inscription was sent at block {}",
+                    transfer
+                        .get("send_block_height")
+                        .map_or("unknown".to_string(), |height| height.to_string())
+                ))
+            }
+            Some(_) => None,
+        };
+
+        if let Some(reason) = reason {
+            violations.push(Violation::InvalidActiveTransfer {
+                inscription_id: active_transfer.inscription_id.clone(),
+                reason,
+            });
+        }
+    }
+
+    Ok(violations)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brc20_index::{
+        memory::MemoryStore,
+        satpoint::SatPoint,
+        store::{BlockUpdate, StateRepair},
+        testing::{index_chain, transfer_chain},
+        transfer::{insert_active_transfer, ActiveTransfers, Brc20ActiveTransfer},
+    };
+
+    #[tokio::test]
+    async fn test_verify_reports_violations() {
+        let (chain, alice, _) = transfer_chain();
+        let store = MemoryStore::new();
+        index_chain(&chain, &store).await;
+        assert_eq!(verify(&store).await.unwrap(), vec![]);
+
+        let mut ticker = store.get_ticker("ordi").await.unwrap().unwrap();
+        ticker.max_supply = Amount::parse("999", 18).unwrap();
+        let mut alice_balance = store
+            .get_user_balance(&alice, "ordi")
+            .await
+            .unwrap()
+            .unwrap();
+        alice_balance.available_balance = Amount::ZERO;
+        store
+            .repair_state(StateRepair {
+                tickers: vec![ticker],
+                user_balances: vec![alice_balance],
+                deleted_user_balances: vec![],
+            })
+            .await
+            .unwrap();
+
+        // an active transfer nothing was inscribed for
+        let tx_id = "00".repeat(32);
+        let mut active_transfers = ActiveTransfers::new();
+        insert_active_transfer(
+            &mut active_transfers,
+            Brc20ActiveTransfer::new(
+                tx_id.clone(),
+                SatPoint::new(tx_id.clone(), 0, 0),
+                i64::from(chain.tip_height()),
+                format!("{}i0", tx_id),
+            ),
+        );
+        store
+            .write_block(BlockUpdate {
+                block_height: i64::from(chain.tip_height()) + 1,
+                active_transfers,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+
+        let violations = verify(&store).await.unwrap();
+        assert_eq!(violations.len(), 3);
+        assert!(matches!(
+            violations[0],
+            Violation::BalanceMismatch { ref address, .. } if *address == alice
+        ));
+        assert!(matches!(
+            violations[1],
+            Violation::TotalMintedAboveMaxSupply { .. }
+        ));
+        assert!(matches!(
+            violations[2],
+            Violation::InvalidActiveTransfer { .. }
+        ));
+    }
+}
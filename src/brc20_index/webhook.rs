@@ -0,0 +1,405 @@
+use super::{
+    consts,
+    event::{block_event_id, Brc20Event, EventFilter},
+    store::{next_block_height, Brc20Store},
+};
+use futures_util::future::join_all;
+use log::{error, info, warn};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use std::time::Duration;
+
+// how often the event log is checked for new blocks to deliver
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A URL the event log is posted to, only the events passing its filter.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Webhook {
+    pub url: String,
+    // deliver the whole event log to a new webhook instead of starting at the tip
+    #[serde(default)]
+    pub backfill: bool,
+    #[serde(flatten)]
+    pub filter: EventFilter,
+}
+
+/// Posts the event log to webhooks as blocks are completed.
+///
+/// Delivery runs in its own task per webhook, apart from indexing, so a slow or
+/// unreachable webhook holds up neither the indexer nor the other webhooks. Every webhook has a cursor in the store,
+/// the id of the last event delivered to it, so events indexed while the indexer
+/// was stopped or a webhook was down are delivered later, in order. A new webhook
+/// starts at the tip unless it's set to backfill. The matching events are posted
+/// as batches of `{"events": [...]}`, a failed post is retried with a doubling
+/// backoff and the cursor only moves past a batch once it's delivered, so an
+/// event can be delivered twice but is never skipped.
+pub struct Webhooks {
+    webhooks: Vec<Webhook>,
+    client: reqwest::Client,
+    // delay before the first retry, doubled after every failed attempt
+    backoff: Duration,
+}
+
+impl Webhooks {
+    pub fn new(webhooks: Vec<Webhook>) -> Result<Self, reqwest::Error> {
+        Webhooks::with_backoff(webhooks, Duration::from_secs(1))
+    }
+
+    fn with_backoff(webhooks: Vec<Webhook>, backoff: Duration) -> Result<Self, reqwest::Error> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(30))
+            .build()?;
+
+        Ok(Webhooks {
+            webhooks,
+            client,
+            backoff,
+        })
+    }
+
+    /// Delivers the events of completed blocks until the process is stopped,
+    /// checking for new blocks every second.
+    pub async fn run(self, store: Arc<dyn Brc20Store>) {
+        self.start_at_tip(store.as_ref()).await;
+
+        // every webhook polls on its own, one that's down doesn't delay the others
+        let webhooks = Arc::new(self);
+        let tasks = (0..webhooks.webhooks.len()).map(|index| {
+            let (webhooks, store) = (webhooks.clone(), store.clone());
+            tokio::spawn(async move {
+                let webhook = &webhooks.webhooks[index];
+                loop {
+                    webhooks.deliver_and_log(store.as_ref(), webhook).await;
+                    tokio::time::sleep(POLL_INTERVAL).await;
+                }
+            })
+        });
+        join_all(tasks).await;
+    }
+
+    // moves the cursor of every new webhook that doesn't backfill to the end of
+    // the completed blocks, so it only gets the events indexed from now on
+    async fn start_at_tip(&self, store: &dyn Brc20Store) {
+        for webhook in self.webhooks.iter().filter(|webhook| !webhook.backfill) {
+            if let Err(e) = start_at_tip(store, webhook).await {
+                error!("Failed to set the cursor of {}: {:?}", webhook.url, e);
+            }
+        }
+    }
+
+    /// Delivers the events of completed blocks after each webhook's cursor, to
+    /// every webhook at once. A webhook that can't be reached is tried again on
+    /// the next call.
+    pub async fn deliver(&self, store: &dyn Brc20Store) {
+        join_all(
+            self.webhooks
+                .iter()
+                .map(|webhook| self.deliver_and_log(store, webhook)),
+        )
+        .await;
+    }
+
+    async fn deliver_and_log(&self, store: &dyn Brc20Store, webhook: &Webhook) {
+        match self.deliver_to(store, webhook).await {
+            Ok(0) => (),
+            Ok(delivered) => info!("Delivered {} events to {}", delivered, webhook.url),
+            Err(e) => error!("Failed to deliver events to {}: {:?}", webhook.url, e),
+        }
+    }
+
+    // posts the events after the webhook's cursor up to the last completed block
+    // and returns how many matched
+    async fn deliver_to(&self, store: &dyn Brc20Store, webhook: &Webhook) -> anyhow::Result<usize> {
+        // events from here on belong to the block being indexed, or were rolled back
+        let next_event_id = block_event_id(next_block_height(store).await?);
+        let mut cursor = store.get_webhook_cursor(&webhook.url).await?;
+        if cursor.as_deref() > Some(next_event_id.as_str()) {
+            store
+                .set_webhook_cursor(&webhook.url, &next_event_id)
+                .await?;
+            cursor = Some(next_event_id.clone());
+        }
+        let mut delivered = 0;
+
+        loop {
+            let events: Vec<Brc20Event> = store
+                .get_events(cursor.as_deref(), consts::WEBHOOK_BATCH_SIZE)
+                .await?
+                .into_iter()
+                .take_while(|event| event.id < next_event_id)
+                .collect();
+            let last_event_id = match events.last() {
+                Some(event) => event.id.clone(),
+                None => return Ok(delivered),
+            };
+
+            let batch: Vec<&Brc20Event> = events
+                .iter()
+                .filter(|event| webhook.filter.matches(event))
+                .collect();
+            if !batch.is_empty() {
+                self.post(&webhook.url, &json!({ "events": batch })).await?;
+                delivered += batch.len();
+            }
+
+            store
+                .set_webhook_cursor(&webhook.url, &last_event_id)
+                .await?;
+            cursor = Some(last_event_id);
+        }
+    }
+
+    async fn post(&self, url: &str, body: &Value) -> anyhow::Result<()> {
+        let retries = consts::WEBHOOK_RETRIES;
+        let mut backoff = self.backoff;
+
+        for attempt in 0..=retries {
+            let result = self
+                .client
+                .post(url)
+                .json(body)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status());
+            match result {
+                Ok(_) => return Ok(()),
+                Err(e) => {
+                    warn!(
+                        "Attempt {}/{} to post events to {} failed: {}",
+                        attempt + 1,
+                        retries + 1,
+                        url,
+                        e
+                    );
+                    if attempt < retries {
+                        tokio::time::sleep(backoff).await;
+                        backoff *= 2;
+                    }
+                }
+            }
+        }
+
+        Err(anyhow::anyhow!(
+            "Failed to post events to {} after all retries",
+            url
+        ))
+    }
+}
+
+async fn start_at_tip(store: &dyn Brc20Store, webhook: &Webhook) -> anyhow::Result<()> {
+    if store.get_webhook_cursor(&webhook.url).await?.is_none() {
+        let tip = block_event_id(next_block_height(store).await?);
+        store.set_webhook_cursor(&webhook.url, &tip).await?;
+        info!("Delivering events to {} from {}", webhook.url, tip);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brc20_index::{
+        memory::MemoryStore,
+        testing::{index_chain, transfer_chain},
+    };
+    use axum::{extract::State, http::StatusCode, routing::post, Json, Router};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    // a local stand-in for a webhook receiver, failing its first `failures` requests
+    #[derive(Clone, Default)]
+    struct Receiver {
+        batches: Arc<Mutex<Vec<Value>>>,
+        failures: Arc<AtomicUsize>,
+    }
+
+    async fn receive(State(receiver): State<Receiver>, Json(batch): Json<Value>) -> StatusCode {
+        let failing = receiver
+            .failures
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |failures| {
+                failures.checked_sub(1)
+            })
+            .is_ok();
+        if failing {
+            return StatusCode::SERVICE_UNAVAILABLE;
+        }
+
+        receiver.batches.lock().unwrap().push(batch);
+        StatusCode::OK
+    }
+
+    // serves the receiver on a free local port and returns its url
+    fn serve(receiver: Receiver) -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/events", listener.local_addr().unwrap());
+        let app = Router::new()
+            .route("/events", post(receive))
+            .with_state(receiver);
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+
+        url
+    }
+
+    #[tokio::test]
+    async fn test_deliver_matching_events_once() {
+        let (chain, _, bob) = transfer_chain();
+        let store = MemoryStore::new();
+        index_chain(&chain, &store).await;
+
+        let receiver = Receiver::default();
+        let url = serve(receiver.clone());
+        let webhooks = Webhooks::with_backoff(
+            vec![Webhook {
+                url: url.clone(),
+                backfill: true,
+                filter: EventFilter {
+                    ticks: vec!["ORDI".to_string()],
+                    addresses: vec![bob.clone()],
+                    ..Default::default()
+                },
+            }],
+            Duration::from_millis(1),
+        )
+        .unwrap();
+
+        webhooks.deliver(&store).await;
+        // nothing was indexed since
+        webhooks.deliver(&store).await;
+
+        let batches = receiver.batches.lock().unwrap().clone();
+        assert_eq!(batches.len(), 1);
+        let events = batches[0]["events"].as_array().unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0]["event_type"], "transfer_send");
+        assert_eq!(events[0]["to"], json!(bob));
+
+        let last_event = store.get_events(None, 10).await.unwrap().pop().unwrap();
+        assert_eq!(
+            store.get_webhook_cursor(&url).await.unwrap(),
+            Some(last_event.id)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_deliver_retries_and_redelivers_rolled_back_events() {
+        let (chain, _, _) = transfer_chain();
+        let store = MemoryStore::new();
+        index_chain(&chain, &store).await;
+
+        let receiver = Receiver::default();
+        receiver.failures.store(2, Ordering::SeqCst);
+        let url = serve(receiver.clone());
+        let webhooks = Webhooks::with_backoff(
+            vec![Webhook {
+                url: url.clone(),
+                backfill: true,
+                filter: EventFilter::default(),
+            }],
+            Duration::from_millis(1),
+        )
+        .unwrap();
+
+        webhooks.deliver(&store).await;
+        assert_eq!(
+            receiver.batches.lock().unwrap()[0]["events"]
+                .as_array()
+                .unwrap()
+                .len(),
+            4
+        );
+
+        // the block sending the transfer is indexed again
+        let tip = i64::from(chain.tip_height());
+        store.rollback_to_block_height(tip).await.unwrap();
+        index_chain(&chain, &store).await;
+        webhooks.deliver(&store).await;
+
+        let batches = receiver.batches.lock().unwrap().clone();
+        assert_eq!(batches.len(), 2);
+        assert_eq!(batches[1]["events"].as_array().unwrap().len(), 1);
+        assert_eq!(batches[1]["events"][0]["event_type"], "transfer_send");
+    }
+
+    #[tokio::test]
+    async fn test_new_webhook_starts_at_the_tip() {
+        let (chain, _, _) = transfer_chain();
+        let store = MemoryStore::new();
+        index_chain(&chain, &store).await;
+
+        let receiver = Receiver::default();
+        let url = serve(receiver.clone());
+        let webhooks = Webhooks::with_backoff(
+            vec![Webhook {
+                url: url.clone(),
+                backfill: false,
+                filter: EventFilter::default(),
+            }],
+            Duration::from_millis(1),
+        )
+        .unwrap();
+
+        webhooks.start_at_tip(&store).await;
+        webhooks.deliver(&store).await;
+        assert!(receiver.batches.lock().unwrap().is_empty());
+
+        // a restart keeps the cursor, events indexed since are delivered
+        let tip = i64::from(chain.tip_height());
+        assert_eq!(
+            store.get_webhook_cursor(&url).await.unwrap(),
+            Some(block_event_id(tip + 1))
+        );
+        store.rollback_to_block_height(tip).await.unwrap();
+        index_chain(&chain, &store).await;
+        webhooks.start_at_tip(&store).await;
+        webhooks.deliver(&store).await;
+
+        let batches = receiver.batches.lock().unwrap().clone();
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0]["events"][0]["event_type"], "transfer_send");
+    }
+
+    #[tokio::test]
+    async fn test_webhook_that_is_down_doesnt_delay_the_others() {
+        let (chain, _, _) = transfer_chain();
+        let store = Arc::new(MemoryStore::new());
+        index_chain(&chain, store.as_ref()).await;
+
+        let down = Receiver::default();
+        down.failures.store(usize::MAX, Ordering::SeqCst);
+        let up = Receiver::default();
+        let webhook = |url| Webhook {
+            url,
+            backfill: true,
+            filter: EventFilter::default(),
+        };
+        // the retries of the webhook that's down take over 6 seconds
+        let webhooks = Arc::new(
+            Webhooks::with_backoff(
+                vec![webhook(serve(down.clone())), webhook(serve(up.clone()))],
+                Duration::from_millis(200),
+            )
+            .unwrap(),
+        );
+
+        let delivery = {
+            let (webhooks, store) = (webhooks.clone(), store.clone());
+            tokio::spawn(async move { webhooks.deliver(store.as_ref()).await })
+        };
+        let delivered = tokio::time::timeout(Duration::from_secs(2), async {
+            while up.batches.lock().unwrap().is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await;
+        delivery.abort();
+
+        assert!(delivered.is_ok());
+        assert!(down.batches.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,86 @@
+use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+
+/// BRC-20 indexer. Every command reads the same configuration from Consul or the
+/// environment, see `.env.example`.
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Index new blocks from the node until the tip, the default command.
+    Index,
+    /// Serve the index read-only over HTTP.
+    Serve {
+        /// Address to listen on.
+        #[arg(long, env = "API_ADDRESS", default_value = "0.0.0.0:8080")]
+        address: String,
+    },
+    /// Roll the index back to a completed block, deleting everything indexed after it.
+    Rollback {
+        /// Last block kept in the index.
+        #[arg(long)]
+        to: i64,
+    },
+    /// Recompute balances and total minted from the event log and print where they
+    /// differ from the stored values.
+    RebuildBalances {
+        /// Overwrite the stored values that differ, with the indexer stopped.
+        #[arg(long)]
+        repair: bool,
+    },
+    /// Check the invariants of the whole index, exits non-zero when one doesn't hold.
+    Verify,
+    /// Write the holders as of a block height to CSV and JSON Lines.
+    Export {
+        /// Block height of the snapshot.
+        #[arg(long)]
+        block_height: i64,
+        /// Ticker to export, every ticker when omitted.
+        #[arg(long)]
+        tick: Option<String>,
+        /// Directory the files are written to.
+        #[arg(long, env = "SNAPSHOT_DIR", default_value = ".")]
+        output: PathBuf,
+    },
+    /// Print the last completed block of the index.
+    Status,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn test_cli() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn test_parse_subcommands() {
+        let cli = Cli::parse_from(["btc-indexer"]);
+        assert!(cli.command.is_none());
+
+        let cli = Cli::parse_from(["btc-indexer", "export", "--block-height", "800000"]);
+        match cli.command {
+            Some(Command::Export {
+                block_height, tick, ..
+            }) => {
+                assert_eq!(block_height, 800000);
+                assert!(tick.is_none());
+            }
+            other => panic!("Unexpected command: {:?}", other),
+        }
+
+        let cli = Cli::parse_from(["btc-indexer", "rollback", "--to", "800000"]);
+        assert!(matches!(
+            cli.command,
+            Some(Command::Rollback { to: 800000 })
+        ));
+    }
+}
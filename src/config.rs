@@ -0,0 +1,219 @@
+use crate::brc20_index::{
+    fee_spend::FeeSpendPolicy, memory::MemoryStore, mongo::MongoClient, postgres::PostgresStore,
+    sled_store::SledStore, sqlite::SqliteStore, store::Brc20Store,
+};
+use bitcoincore_rpc::{Auth, Client};
+use consulrs::{
+    client::{ConsulClient, ConsulClientSettingsBuilder},
+    kv,
+};
+use log::{info, warn};
+use serde_json;
+use serde_json::Value;
+use std::env;
+use std::error::Error;
+
+/// Configuration shared by every command, read from Consul when `CONSUL_HOST` is
+/// set and from the environment otherwise.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub rpc_url: String,
+    pub rpc_user: String,
+    pub rpc_password: String,
+    pub mongo_connection_str: String,
+    pub mongo_direct_connection: bool,
+    pub fee_spend_policy: FeeSpendPolicy,
+    pub check_invariants: bool,
+    pub webhooks: Vec<Webhook>,
+}
+
+impl Config {
+    pub async fn load() -> Result<Self, Box<dyn Error>> {
+        // Variables for configuration
+        let rpc_url: String;
+        let rpc_user: String;
+        let rpc_password: String;
+        let mongo_connection_str: String;
+        let mut mongo_direct_connection_str: String;
+        let mongo_direct_connection;
+
+        // Check for CONSUL_HOST environment variable
+        if let Ok(consul_host) = env::var("CONSUL_HOST") {
+            let client = ConsulClient::new(
+                ConsulClientSettingsBuilder::default()
+                    .address(consul_host)
+                    .build()
+                    .unwrap(),
+            )
+            .unwrap();
+            let mut res = kv::read(&client, "omnisat-api", None).await.unwrap();
+            let mykey: String = res
+                .response
+                .pop()
+                .unwrap()
+                .value
+                .unwrap()
+                .try_into()
+                .unwrap();
+            let json_value: Value = serde_json::from_str(&mykey).unwrap();
+
+            rpc_url = json_value
+                .get("btc_rpc_host")
+                .unwrap()
+                .as_str()
+                .unwrap()
+                .to_string();
+
+            rpc_user = json_value
+                .get("btc_rpc_user")
+                .unwrap()
+                .as_str()
+                .unwrap()
+                .to_string();
+
+            rpc_password = json_value
+                .get("btc_rpc_pass")
+                .unwrap()
+                .as_str()
+                .unwrap()
+                .to_string();
+
+            mongo_direct_connection_str = json_value
+                .get("mongo_direct_connection")
+                .unwrap()
+                .as_str()
+                .unwrap()
+                .to_string();
+
+            // mongo_direct_connection = mongo_direct_connection_str.to_lowercase() == "true";
+            // let mongo_direct_connection_str_env = env::var("MONGO_DIRECT_CONNECTION").ok();
+            if let Ok(mongo_direct_connection_str_env) = env::var("MONGO_DIRECT_CONNECTION") {
+                mongo_direct_connection_str = mongo_direct_connection_str_env;
+            }
+
+            mongo_direct_connection = mongo_direct_connection_str.to_lowercase() == "true";
+            //MongoDB connection string
+            let mongo_host_consul = json_value.get("mongo_rc").unwrap().as_array().unwrap();
+            let mongo_host_env = env::var("MONGO_DB_HOST").ok();
+
+            mongo_connection_str = if let Some(mongo_host_env) = mongo_host_env {
+                format!("mongodb://{}:27017", mongo_host_env)
+            } else {
+                format!(
+                    "mongodb://{}:27017,{}:27017,{}:27017/omnisat?replicaSet=rs0",
+                    mongo_host_consul[0].as_str().unwrap(),
+                    mongo_host_consul[1].as_str().unwrap(),
+                    mongo_host_consul[2].as_str().unwrap(),
+                )
+            };
+        } else {
+            // MongoDB settings are only required when indexing into MongoDB
+            mongo_direct_connection_str = env::var("MONGO_DIRECT_CONNECTION").unwrap_or_default();
+            mongo_direct_connection = mongo_direct_connection_str.to_lowercase() == "true";
+
+            // Pick up environment vars from .env file, only indexing needs a node
+            rpc_url = env::var("RPC_URL").unwrap_or_default();
+            rpc_user = env::var("RPC_USER").unwrap_or_default();
+            rpc_password = env::var("RPC_PASSWORD").unwrap_or_default();
+
+            let mongo_user = env::var("MONGO_USER").ok();
+            let mongo_password = env::var("MONGO_PASSWORD").ok();
+            let mongo_db_host = env::var("MONGO_DB_HOST").unwrap_or_default();
+
+            mongo_connection_str =
+                if let (Some(user), Some(password)) = (mongo_user, mongo_password) {
+                    format!("mongodb://{}:{}@{}:27017", user, password, mongo_db_host)
+                } else {
+                    format!("mongodb://{}:27017", mongo_db_host)
+                };
+        }
+
+        // what happens to transfer inscriptions spent as fee, returned to the sender by default
+        let fee_spend_policy = match env::var("BRC20_FEE_SPEND_POLICY") {
+            Ok(policy) => policy.parse::<FeeSpendPolicy>()?,
+            Err(_) => FeeSpendPolicy::default(),
+        };
+
+        // check every block before it's written and stop at the first one breaking an invariant
+        let check_invariants = env::var("BRC20_CHECK_INVARIANTS")
+            .map(|check| check.to_lowercase() == "true")
+            .unwrap_or(false);
+
+        // webhooks the event log is posted to as blocks are completed, a JSON array
+        let webhooks = match env::var("BRC20_WEBHOOKS") {
+            Ok(webhooks) => serde_json::from_str(&webhooks)
+                .map_err(|e| format!("Invalid BRC20_WEBHOOKS: {}", e))?,
+            Err(_) => Vec::new(),
+        };
+
+        Ok(Config {
+            rpc_url,
+            rpc_user,
+            rpc_password,
+            mongo_connection_str,
+            mongo_direct_connection,
+            fee_spend_policy,
+            check_invariants,
+            webhooks,
+        })
+    }
+
+    /// Creates a client of the Bitcoin Core RPC server, nothing is sent until it's used.
+    pub fn rpc(&self) -> Result<Client, Box<dyn Error>> {
+        Ok(Client::new(
+            &self.rpc_url,
+            Auth::UserPass(self.rpc_user.clone(), self.rpc_password.clone()),
+        )?)
+    }
+
+    /// Opens the store selected with `BRC20_STORE`, or an empty in-memory store for a
+    /// dry run.
+    pub async fn open_store(&self) -> Result<Box<dyn Brc20Store>, Box<dyn Error>> {
+        // a dry run indexes into memory, nothing is written to the database
+        let dry_run = env::var("BRC20_DRY_RUN")
+            .map(|dry_run| dry_run.to_lowercase() == "true")
+            .unwrap_or(false);
+        if dry_run {
+            warn!("Dry run, indexing into memory");
+            return Ok(Box::new(MemoryStore::new()));
+        }
+
+        // storage backend, MongoDB by default
+        let store_kind = env::var("BRC20_STORE").unwrap_or_else(|_| "mongodb".to_string());
+        info!("Store: {}", store_kind);
+
+        let store: Box<dyn Brc20Store> = match store_kind.to_lowercase().as_str() {
+            "mongodb" => {
+                // Get the mongo database name from environment variable
+                let db_name = env::var("MONGO_DB_NAME").map_err(|_| "MONGO_DB_NAME must be set")?;
+                let mongo_client = MongoClient::new(
+                    &self.mongo_connection_str,
+                    &db_name,
+                    self.mongo_direct_connection,
+                )
+                .await?;
+
+                // Call create_indexes after MongoClient has been initialized
+                mongo_client.create_indexes().await?;
+                Box::new(mongo_client)
+            }
+            "postgres" => {
+                let connection_string =
+                    env::var("POSTGRES_URL").map_err(|_| "POSTGRES_URL must be set")?;
+                let schema = env::var("POSTGRES_SCHEMA").unwrap_or_else(|_| "public".to_string());
+                Box::new(PostgresStore::connect(&connection_string, &schema).await?)
+            }
+            "sled" => {
+                let path = env::var("SLED_PATH").unwrap_or_else(|_| "brc20.sled".to_string());
+                Box::new(SledStore::open(&path)?)
+            }
+            "sqlite" => {
+                let path = env::var("SQLITE_PATH").unwrap_or_else(|_| "brc20.sqlite".to_string());
+                Box::new(SqliteStore::open(&path)?)
+            }
+            other => return Err(format!("Invalid BRC20_STORE: {}", other).into()),
+        };
+
+        Ok(store)
+    }
+}